@@ -6,6 +6,8 @@ use yasi::InternedString;
 
 mod action;
 mod address;
+mod container;
+mod device;
 mod health_check;
 mod image;
 mod interface;
@@ -15,6 +17,8 @@ mod volume;
 
 pub use action::ActionId;
 pub use address::AddressId;
+pub use container::ContainerId;
+pub use device::DeviceId;
 pub use health_check::HealthCheckId;
 pub use image::ImageId;
 pub use interface::InterfaceId;
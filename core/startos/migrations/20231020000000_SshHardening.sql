@@ -0,0 +1,30 @@
+-- Add migration script here
+ALTER TABLE
+    ssh_keys
+ADD
+    COLUMN from_restriction TEXT,
+ADD
+    COLUMN force_command TEXT,
+ADD
+    COLUMN expires_at TIMESTAMP;
+
+CREATE TABLE IF NOT EXISTS ssh_ca_keys (
+    fingerprint TEXT NOT NULL,
+    openssh_pubkey TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    PRIMARY KEY (fingerprint)
+);
+
+CREATE TABLE IF NOT EXISTS ssh_config (
+    id INTEGER PRIMARY KEY DEFAULT 0 CHECK (id = 0),
+    password_auth BOOLEAN NOT NULL DEFAULT TRUE,
+    port INTEGER NOT NULL DEFAULT 22 CHECK (
+        port > 0
+        AND port < 65536
+    )
+);
+
+INSERT INTO
+    ssh_config (id)
+VALUES
+    (0) ON CONFLICT DO NOTHING;
@@ -0,0 +1,15 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS config_revisions (
+    package_id TEXT NOT NULL,
+    revision INTEGER NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    source TEXT NOT NULL,
+    session TEXT,
+    config TEXT NOT NULL,
+    auto_configured TEXT NOT NULL,
+    PRIMARY KEY (package_id, revision)
+);
+CREATE TABLE IF NOT EXISTS config_revision_counters (
+    package_id TEXT NOT NULL PRIMARY KEY,
+    revision INTEGER NOT NULL
+);
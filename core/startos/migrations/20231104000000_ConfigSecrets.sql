@@ -0,0 +1,8 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS config_secrets (
+    package_id TEXT NOT NULL,
+    id TEXT NOT NULL,
+    value BYTEA NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (package_id, id)
+);
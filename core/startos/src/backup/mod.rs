@@ -14,6 +14,7 @@ use tokio::io::AsyncWriteExt;
 use tracing::instrument;
 
 use self::target::PackageBackupInfo;
+use crate::config::secret;
 use crate::context::RpcContext;
 use crate::install::PKG_ARCHIVE_DIR;
 use crate::manager::manager_seed::ManagerSeed;
@@ -68,6 +69,9 @@ struct BackupMetadata {
     pub network_keys: BTreeMap<InterfaceId, Base64<[u8; 32]>>,
     #[serde(default)]
     pub tor_keys: BTreeMap<InterfaceId, Base32<[u8; 64]>>, // DEPRECATED
+    /// Decrypted, since the key they are stored with belongs to the server
+    #[serde(default)]
+    pub config_secrets: BTreeMap<String, String>,
     pub marketplace_url: Option<Url>,
 }
 
@@ -131,6 +135,7 @@ impl BackupActions {
                     ))
                 })
                 .unzip();
+        let config_secrets = secret::load(ctx, pkg_id).await?;
         let marketplace_url = ctx
             .db
             .peek()
@@ -176,6 +181,7 @@ impl BackupActions {
                 timestamp,
                 network_keys,
                 tor_keys,
+                config_secrets,
                 marketplace_url,
             })?)
             .await?;
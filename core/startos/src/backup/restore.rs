@@ -17,6 +17,7 @@ use tracing::instrument;
 use super::target::BackupTargetId;
 use crate::backup::os::OsBackup;
 use crate::backup::BackupMetadata;
+use crate::config::secret;
 use crate::context::rpc::RpcContextConfig;
 use crate::context::{RpcContext, SetupContext};
 use crate::db::model::{PackageDataEntry, PackageDataEntryRestoring, StaticFiles};
@@ -415,6 +416,7 @@ async fn restore_package<'a>(
         )
         .execute(secrets_tx.as_mut()).await?;
     }
+    secret::seal(secrets_tx.as_mut(), &id, metadata.config_secrets).await?;
     secrets_tx.commit().await?;
     drop(secrets);
 
@@ -450,7 +452,8 @@ async fn restore_package<'a>(
     Ok((
         progress.clone(),
         async move {
-            download_install_s9pk(ctx, manifest, marketplace_url, progress, file, None).await?;
+            download_install_s9pk(ctx, manifest, marketplace_url, progress, file, None, false)
+                .await?;
 
             guard.unmount().await?;
 
@@ -8,10 +8,16 @@ use tokio::signal::unix::signal;
 use tracing::instrument;
 
 use crate::context::{DiagnosticContext, RpcContext};
+use crate::db::history::launch_db_history_task;
+use crate::devices::launch_device_hotplug_task;
+use crate::install::update::launch_auto_update_task;
+use crate::logs::forward::launch_log_forward_task;
 use crate::net::web_server::WebServer;
 use crate::shutdown::Shutdown;
+use crate::ssh::launch_ssh_key_expiry_task;
 use crate::system::launch_metrics_task;
 use crate::util::logger::EmbassyLogger;
+use crate::volume::launch_volume_usage_task;
 use crate::{Error, ErrorKind, ResultExt};
 
 #[instrument(skip_all)]
@@ -72,6 +78,21 @@ async fn inner_main(cfg_path: Option<PathBuf>) -> Result<Option<Shutdown>, Error
             .await
         });
 
+        let ssh_ctx = rpc_ctx.clone();
+        let ssh_key_expiry_task = tokio::spawn(async move {
+            launch_ssh_key_expiry_task(&ssh_ctx.secret_store, || ssh_ctx.shutdown.subscribe()).await
+        });
+
+        let log_forward_task = tokio::spawn(launch_log_forward_task(rpc_ctx.clone()));
+
+        let db_history_task = tokio::spawn(launch_db_history_task(rpc_ctx.clone()));
+
+        let auto_update_task = tokio::spawn(launch_auto_update_task(rpc_ctx.clone()));
+
+        let volume_usage_task = tokio::spawn(launch_volume_usage_task(rpc_ctx.clone()));
+
+        let device_hotplug_task = tokio::spawn(launch_device_hotplug_task(rpc_ctx.clone()));
+
         crate::sound::CHIME.play().await?;
 
         metrics_task
@@ -90,6 +111,12 @@ async fn inner_main(cfg_path: Option<PathBuf>) -> Result<Option<Shutdown>, Error
             .with_kind(crate::ErrorKind::Unknown)?;
 
         sig_handler.abort();
+        ssh_key_expiry_task.abort();
+        log_forward_task.abort();
+        db_history_task.abort();
+        auto_update_task.abort();
+        volume_usage_task.abort();
+        device_hotplug_task.abort();
 
         Ok::<_, Error>((rpc_ctx, server, shutdown))
     }
@@ -6,13 +6,15 @@ use patch_db::HasModel;
 use serde::{Deserialize, Serialize};
 use tracing::instrument;
 
-use super::{Config, ConfigSpec};
+use super::migrate::EnumRenames;
+use super::util::Constraint;
+use super::{Config, ConfigSpec, NoMatchWithPath};
 use crate::context::RpcContext;
 use crate::dependencies::Dependencies;
 use crate::prelude::*;
 use crate::procedure::docker::DockerContainers;
 use crate::procedure::{PackageProcedure, ProcedureName};
-use crate::s9pk::manifest::PackageId;
+use crate::s9pk::manifest::{Manifest, PackageId};
 use crate::status::health_check::HealthCheckId;
 use crate::util::Version;
 use crate::volume::Volumes;
@@ -26,10 +28,18 @@ pub struct ConfigRes {
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, HasModel)]
+#[serde(rename_all = "kebab-case")]
 #[model = "Model<Self>"]
 pub struct ConfigActions {
     pub get: PackageProcedure,
     pub set: PackageProcedure,
+    /// Enum values renamed since earlier versions, used to migrate the stored config on update
+    #[serde(default)]
+    pub renames: EnumRenames,
+    /// Rules across the top-level fields of the config, like the `constraints` of an object spec
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub constraints: Vec<Constraint>,
 }
 impl ConfigActions {
     #[instrument(skip_all)]
@@ -72,6 +82,21 @@ impl ConfigActions {
             })
     }
 
+    /// Checks `config` against `spec`, including the [constraints](Self::constraints) across its
+    /// fields. This is the only check a config should pass before it is applied, since
+    /// [ConfigSpec::matches] alone does not know about them.
+    pub fn check(
+        &self,
+        spec: &ConfigSpec,
+        manifest: &Manifest,
+        config: &Config,
+    ) -> Result<(), NoMatchWithPath> {
+        spec.validate_constraints(&self.constraints)?;
+        spec.validate(manifest)?;
+        spec.matches(config)?;
+        spec.check_constraints(&self.constraints, config)
+    }
+
     #[instrument(skip_all)]
     pub async fn set(
         &self,
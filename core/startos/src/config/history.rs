@@ -0,0 +1,439 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::NaiveDateTime;
+use clap::ArgMatches;
+use color_eyre::eyre::eyre;
+use patch_db::Value;
+use rpc_toolkit::command;
+use rpc_toolkit::command_helpers::prelude::RequestParts;
+use serde::{Deserialize, Serialize};
+use sqlx::{Executor, PgPool, Postgres};
+use tracing::instrument;
+
+use super::{configure, secret, Config, ConfigureContext, Configured};
+use crate::context::RpcContext;
+use crate::middleware::auth::HashSessionToken;
+use crate::prelude::*;
+use crate::s9pk::manifest::PackageId;
+use crate::util::display_none;
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::volume::snapshot::{take_auto, SnapshotReason};
+
+/// What caused a config to be written
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RevisionSource {
+    Set,
+    Revert,
+    Migrate,
+    AutoConfigure,
+}
+impl RevisionSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RevisionSource::Set => "set",
+            RevisionSource::Revert => "revert",
+            RevisionSource::Migrate => "migrate",
+            RevisionSource::AutoConfigure => "auto-configure",
+        }
+    }
+}
+impl fmt::Display for RevisionSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+impl FromStr for RevisionSource {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "set" => RevisionSource::Set,
+            "revert" => RevisionSource::Revert,
+            "migrate" => RevisionSource::Migrate,
+            "auto-configure" => RevisionSource::AutoConfigure,
+            _ => {
+                return Err(Error::new(
+                    eyre!("Invalid Config Revision Source: {}", s),
+                    ErrorKind::ParseDbField,
+                ))
+            }
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConfigRevision {
+    pub revision: u32,
+    pub created_at: NaiveDateTime,
+    pub source: RevisionSource,
+    /// The session that wrote the config, if it was written by a user
+    pub session: Option<String>,
+    /// The other packages whose config was applied as part of the same change
+    pub auto_configured: BTreeSet<PackageId>,
+    pub config: Config,
+}
+
+/// How many times a revision is retried if its number was taken by another one
+const RECORD_ATTEMPTS: usize = 3;
+/// How many of the most recent revisions are kept for each package
+pub const REVISION_RETENTION: u32 = 100;
+
+/// Stores `config` as the next revision of the config of `id`
+#[instrument(skip_all)]
+pub async fn record(
+    secrets: &PgPool,
+    id: &PackageId,
+    source: RevisionSource,
+    session: Option<&str>,
+    config: &Config,
+    auto_configured: &BTreeSet<PackageId>,
+) -> Result<u32, Error> {
+    let config = serde_json::to_string(config).with_kind(ErrorKind::Serialization)?;
+    let auto_configured =
+        serde_json::to_string(auto_configured).with_kind(ErrorKind::Serialization)?;
+    let mut attempt = 1;
+    loop {
+        let mut tx = secrets.begin().await?;
+        // the counter row stays locked until the transaction ends, so concurrent writers of the
+        // same package are numbered one after the other
+        let revision = sqlx::query!(
+            "INSERT INTO config_revision_counters (package_id, revision) VALUES ($1, 1) ON CONFLICT (package_id) DO UPDATE SET revision = config_revision_counters.revision + 1 RETURNING revision",
+            &*id,
+        )
+        .fetch_one(&mut *tx)
+        .await?
+        .revision;
+        let res = sqlx::query!(
+            "INSERT INTO config_revisions (package_id, revision, source, session, config, auto_configured) VALUES ($1, $2, $3, $4, $5, $6)",
+            &*id,
+            revision,
+            source.as_str(),
+            session,
+            &config,
+            &auto_configured,
+        )
+        .execute(&mut *tx)
+        .await;
+        match res {
+            // the counter fell behind the revisions, so it is caught up before trying again
+            Err(sqlx::Error::Database(e))
+                if e.is_unique_violation() && attempt < RECORD_ATTEMPTS =>
+            {
+                tx.rollback().await?;
+                sqlx::query!(
+                    "UPDATE config_revision_counters SET revision = (SELECT MAX(revision) FROM config_revisions WHERE package_id = $1) WHERE package_id = $1",
+                    &*id,
+                )
+                .execute(secrets)
+                .await?;
+                attempt += 1;
+            }
+            res => {
+                res?;
+                tx.commit().await?;
+                return Ok(revision as u32);
+            }
+        }
+    }
+}
+
+/// Removes all but the [REVISION_RETENTION] most recent revisions of `id`
+#[instrument(skip_all)]
+pub async fn prune(secrets: &PgPool, id: &PackageId) -> Result<(), Error> {
+    sqlx::query!(
+        "DELETE FROM config_revisions WHERE package_id = $1 AND revision <= (SELECT MAX(revision) FROM config_revisions WHERE package_id = $1) - $2",
+        &*id,
+        REVISION_RETENTION as i32,
+    )
+    .execute(secrets)
+    .await?;
+    Ok(())
+}
+
+/// Records a revision after a config was written, logging instead of failing since the config
+/// has already been applied. Old revisions are pruned, along with the secrets no remaining
+/// revision refers to.
+pub async fn record_applied(
+    ctx: &RpcContext,
+    id: &PackageId,
+    source: RevisionSource,
+    session: Option<&str>,
+    configured: &Configured,
+) {
+    let config = &configured.config;
+    let res = async {
+        record(
+            &ctx.secret_store,
+            id,
+            source,
+            session,
+            config,
+            &configured.auto_configured,
+        )
+        .await?;
+        prune(&ctx.secret_store, id).await?;
+        let mut keep = BTreeSet::new();
+        secret::references(&Value::Object(config.clone()), &mut keep);
+        for r in sqlx::query!(
+            "SELECT config FROM config_revisions WHERE package_id = $1",
+            &*id
+        )
+        .fetch_all(&ctx.secret_store)
+        .await?
+        {
+            let config: Value =
+                serde_json::from_str(&r.config).with_kind(ErrorKind::ParseDbField)?;
+            secret::references(&config, &mut keep);
+        }
+        secret::prune(ctx, id, &keep).await
+    }
+    .await;
+    if let Err(e) = res {
+        tracing::error!("Failed to record config revision for {}: {}", id, e);
+        tracing::debug!("{:?}", e);
+    }
+}
+
+#[instrument(skip_all)]
+pub async fn remove_all<Ex>(secrets: &mut Ex, id: &PackageId) -> Result<(), Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Postgres>,
+{
+    sqlx::query!("DELETE FROM config_revisions WHERE package_id = $1", &*id)
+        .execute(&mut *secrets)
+        .await?;
+    sqlx::query!(
+        "DELETE FROM config_revision_counters WHERE package_id = $1",
+        &*id
+    )
+    .execute(&mut *secrets)
+    .await?;
+    Ok(())
+}
+
+async fn load(ctx: &RpcContext, id: &PackageId, revision: u32) -> Result<ConfigRevision, Error> {
+    let r = sqlx::query!(
+        "SELECT revision, created_at, source, session, config, auto_configured FROM config_revisions WHERE package_id = $1 AND revision = $2",
+        &*id,
+        revision as i32
+    )
+    .fetch_optional(&ctx.secret_store)
+    .await?
+    .ok_or_else(|| {
+        Error::new(
+            eyre!("{} has no config revision {}", id, revision),
+            ErrorKind::NotFound,
+        )
+    })?;
+    Ok(ConfigRevision {
+        revision: r.revision as u32,
+        created_at: r.created_at,
+        source: r.source.parse()?,
+        session: r.session,
+        auto_configured: serde_json::from_str(&r.auto_configured)
+            .with_kind(ErrorKind::ParseDbField)?,
+        config: serde_json::from_str(&r.config).with_kind(ErrorKind::ParseDbField)?,
+    })
+}
+
+fn display_history(history: Vec<ConfigRevision>, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(history, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "REVISION", "CREATED AT", "SOURCE", "SESSION", "AUTO-CONFIGURED"]);
+    for revision in history {
+        table.add_row(row![
+            revision.revision,
+            &format!("{}", revision.created_at),
+            revision.source,
+            revision.session.as_deref().unwrap_or("N/A"),
+            revision
+                .auto_configured
+                .iter()
+                .map(|id| &**id)
+                .collect::<Vec<_>>()
+                .join(", "),
+        ]);
+    }
+    table.print_tty(false).unwrap();
+}
+
+/// Lists the revisions of the config of a service, oldest first
+#[command(display(display_history))]
+#[instrument(skip_all)]
+pub async fn history(
+    #[context] ctx: RpcContext,
+    #[parent_data] id: PackageId,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<ConfigRevision>, Error> {
+    sqlx::query!(
+        "SELECT revision, created_at, source, session, config, auto_configured FROM config_revisions WHERE package_id = $1 ORDER BY revision",
+        &*id
+    )
+    .fetch_all(&ctx.secret_store)
+    .await?
+    .into_iter()
+    .map(|r| {
+        Ok(ConfigRevision {
+            revision: r.revision as u32,
+            created_at: r.created_at,
+            source: r.source.parse()?,
+            session: r.session,
+            auto_configured: serde_json::from_str(&r.auto_configured)
+                .with_kind(ErrorKind::ParseDbField)?,
+            config: serde_json::from_str(&r.config).with_kind(ErrorKind::ParseDbField)?,
+        })
+    })
+    .collect()
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConfigDiff {
+    pub path: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+/// Lists the values that differ between two configs, descending into objects
+pub fn diff_configs(before: &Config, after: &Config) -> Vec<ConfigDiff> {
+    fn diff_into(path: &str, before: &Config, after: &Config, res: &mut Vec<ConfigDiff>) {
+        let keys = before
+            .keys()
+            .chain(after.keys().filter(|k| !before.contains_key(&***k)));
+        for key in keys {
+            let path = if path.is_empty() {
+                key.to_string()
+            } else {
+                format!("{}.{}", path, key)
+            };
+            match (before.get(&**key), after.get(&**key)) {
+                (Some(Value::Object(b)), Some(Value::Object(a))) => diff_into(&path, b, a, res),
+                (b, a) if b != a => res.push(ConfigDiff {
+                    path,
+                    before: b.cloned(),
+                    after: a.cloned(),
+                }),
+                _ => (),
+            }
+        }
+    }
+
+    let mut res = Vec::new();
+    diff_into("", before, after, &mut res);
+    res
+}
+
+pub(super) fn display_diff(diff: Vec<ConfigDiff>, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(diff, matches);
+    }
+
+    let show = |v: &Option<Value>| {
+        v.as_ref().map_or("-".to_owned(), |v| {
+            serde_json::to_string(v).unwrap_or_default()
+        })
+    };
+    let mut table = Table::new();
+    table.add_row(row![bc => "PATH", "BEFORE", "AFTER"]);
+    for entry in diff {
+        table.add_row(row![&entry.path, show(&entry.before), show(&entry.after)]);
+    }
+    table.print_tty(false).unwrap();
+}
+
+/// Shows what changed in the config of a service between two revisions
+#[command(display(display_diff))]
+#[instrument(skip_all)]
+pub async fn diff(
+    #[context] ctx: RpcContext,
+    #[parent_data] id: PackageId,
+    #[arg(rename = "rev-a")] rev_a: u32,
+    #[arg(rename = "rev-b")] rev_b: u32,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<ConfigDiff>, Error> {
+    let a = load(&ctx, &id, rev_a).await?;
+    let b = load(&ctx, &id, rev_b).await?;
+    Ok(diff_configs(&a.config, &b.config))
+}
+
+/// Applies the config of an earlier revision, as a new revision
+#[command(display(display_none), metadata(sync_db = true))]
+#[instrument(skip_all)]
+pub async fn revert(
+    #[context] ctx: RpcContext,
+    #[request] req: &RequestParts,
+    #[parent_data] id: PackageId,
+    #[arg] revision: u32,
+) -> Result<(), Error> {
+    let config = load(&ctx, &id, revision).await?.config;
+    let session = HashSessionToken::from_request_parts(req)
+        .ok()
+        .map(|s| s.as_hash());
+    take_auto(&ctx, &id, SnapshotReason::Config).await;
+    let configured = configure(
+        &ctx,
+        &id,
+        ConfigureContext {
+            breakages: BTreeMap::new(),
+            timeout: None,
+            config: Some(config),
+            dry_run: false,
+            overrides: BTreeMap::new(),
+        },
+    )
+    .await?;
+    record_applied(
+        &ctx,
+        &id,
+        RevisionSource::Revert,
+        session.as_deref(),
+        &configured,
+    )
+    .await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(value: serde_json::Value) -> Config {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn diffs_nested_configs() {
+        let before = config(serde_json::json!({
+            "port": 8080,
+            "advanced": { "verbose": false, "peers": ["a"] },
+            "legacy": true,
+        }));
+        let after = config(serde_json::json!({
+            "port": 8080,
+            "advanced": { "verbose": true, "peers": ["a"] },
+            "tor": "enabled",
+        }));
+        let diff = diff_configs(&before, &after);
+        assert_eq!(
+            diff.iter().map(|d| d.path.as_str()).collect::<Vec<_>>(),
+            ["advanced.verbose", "legacy", "tor"]
+        );
+        assert!(diff[1].after.is_none());
+        assert!(diff[2].before.is_none());
+        assert!(diff_configs(&after, &after).is_empty());
+    }
+}
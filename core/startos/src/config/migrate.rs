@@ -0,0 +1,369 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use imbl_value::InternedString;
+use patch_db::Value;
+use rand::{CryptoRng, Rng};
+use serde::{Deserialize, Serialize};
+
+use super::spec::{ValueSpec, ValueSpecAny, ValueSpecEnum, ValueSpecList, ValueSpecUnion};
+use super::{Config, ConfigSpec, ConfigurationError, Defaultable};
+
+/// Renamed enum values (and union variants), declared by the package as
+/// `path -> (old value -> new value)`. Paths are the dot separated keys leading to the field,
+/// without list indices, so a hint applies to every element of a list.
+pub type EnumRenames = BTreeMap<String, BTreeMap<String, String>>;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[serde(tag = "type")]
+pub enum ConfigChange {
+    /// The key is no longer part of the spec
+    Removed { path: String, value: Value },
+    /// The key is new to the spec, so it was given its default
+    Added { path: String, value: Value },
+    /// The value was renamed by a hint
+    Renamed {
+        path: String,
+        from: String,
+        to: String,
+    },
+    /// The value no longer matches the spec, so it was replaced with its default
+    Reset {
+        path: String,
+        from: Value,
+        to: Value,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConfigMigration {
+    pub config: Config,
+    pub changes: Vec<ConfigChange>,
+}
+
+struct Migrator<'a, R> {
+    renames: &'a EnumRenames,
+    rng: &'a mut R,
+    timeout: &'a Option<Duration>,
+    changes: Vec<ConfigChange>,
+}
+impl<'a, R: Rng + CryptoRng + Sync + Send> Migrator<'a, R> {
+    fn rename(&mut self, path: &str, value: &str) -> Option<String> {
+        let to = self.renames.get(path)?.get(value)?;
+        self.changes.push(ConfigChange::Renamed {
+            path: path.to_owned(),
+            from: value.to_owned(),
+            to: to.clone(),
+        });
+        Some(to.clone())
+    }
+
+    fn reset(
+        &mut self,
+        spec: &ValueSpecAny,
+        path: &str,
+        from: Value,
+    ) -> Result<Value, ConfigurationError> {
+        let to = spec.gen(self.rng, self.timeout)?;
+        self.changes.push(ConfigChange::Reset {
+            path: path.to_owned(),
+            from,
+            to: to.clone(),
+        });
+        Ok(to)
+    }
+
+    /// `ignore` is a key that belongs to the enclosing value rather than `spec`, i.e. a union tag
+    fn config(
+        &mut self,
+        spec: &ConfigSpec,
+        path: &str,
+        ignore: Option<&InternedString>,
+        mut cfg: Config,
+    ) -> Result<Config, ConfigurationError> {
+        let mut res = Config::new();
+        if let Some(key) = ignore {
+            if let Some(v) = cfg.remove(&**key) {
+                res.insert(key.clone(), v);
+            }
+        }
+        for (key, vs) in spec.0.iter() {
+            let path = join(path, key);
+            let value = match cfg.remove(&**key) {
+                Some(v) => self.value(vs, &path, v)?,
+                None => {
+                    let value = vs.gen(self.rng, self.timeout)?;
+                    self.changes.push(ConfigChange::Added {
+                        path,
+                        value: value.clone(),
+                    });
+                    value
+                }
+            };
+            res.insert(key.clone(), value);
+        }
+        for (key, value) in cfg {
+            self.changes.push(ConfigChange::Removed {
+                path: join(path, &key),
+                value,
+            });
+        }
+        Ok(res)
+    }
+
+    fn enum_value(&mut self, spec: &ValueSpecEnum, path: &str, value: Value) -> Value {
+        match &value {
+            Value::String(s) if !spec.values.contains(&**s) => self
+                .rename(path, s)
+                .map_or(value, |to| Value::String(to.into())),
+            _ => value,
+        }
+    }
+
+    fn union_value(
+        &mut self,
+        spec: &ValueSpecUnion,
+        path: &str,
+        value: Value,
+    ) -> Result<Value, ConfigurationError> {
+        let mut o = match value {
+            Value::Object(o) => o,
+            a => return Ok(a),
+        };
+        let tag = match o.get(&*spec.tag.id) {
+            Some(Value::String(tag)) => tag.clone(),
+            _ => return Ok(Value::Object(o)),
+        };
+        let tag = if spec.variants.contains_key(&*tag) {
+            (*tag).clone()
+        } else if let Some(to) = self.rename(path, &tag) {
+            o.insert(spec.tag.id.clone(), Value::String(to.clone().into()));
+            to
+        } else {
+            return Ok(Value::Object(o));
+        };
+        match spec.variants.get(&tag) {
+            Some(variant) => Ok(Value::Object(self.config(
+                variant,
+                path,
+                Some(&spec.tag.id),
+                o,
+            )?)),
+            None => Ok(Value::Object(o)),
+        }
+    }
+
+    fn list_value(
+        &mut self,
+        spec: &ValueSpecList,
+        path: &str,
+        value: Value,
+    ) -> Result<Value, ConfigurationError> {
+        let list = match value {
+            Value::Array(l) => l,
+            a => return Ok(a),
+        };
+        let list = list
+            .into_iter()
+            .map(|v| match spec {
+                ValueSpecList::Enum(e) => Ok(self.enum_value(&e.inner.inner.spec, path, v)),
+                ValueSpecList::Object(o) => match v {
+                    Value::Object(cfg) => self
+                        .config(&o.inner.inner.spec.spec, path, None, cfg)
+                        .map(Value::Object),
+                    a => Ok(a),
+                },
+                ValueSpecList::Union(u) => self.union_value(&u.inner.inner.spec.inner, path, v),
+                ValueSpecList::Number(_) | ValueSpecList::String(_) => Ok(v),
+            })
+            .collect::<Result<_, ConfigurationError>>()?;
+        Ok(Value::Array(list))
+    }
+
+    fn value(
+        &mut self,
+        spec: &ValueSpecAny,
+        path: &str,
+        value: Value,
+    ) -> Result<Value, ConfigurationError> {
+        let value = match spec {
+            ValueSpecAny::Enum(e) => self.enum_value(&e.inner.inner, path, value),
+            ValueSpecAny::Object(o) => match value {
+                Value::Object(cfg) => Value::Object(self.config(&o.inner.spec, path, None, cfg)?),
+                a => a,
+            },
+            ValueSpecAny::Union(u) => self.union_value(&u.inner.inner, path, value)?,
+            ValueSpecAny::List(l) => self.list_value(l, path, value)?,
+            _ => value,
+        };
+        if spec.matches(&value).is_ok() {
+            Ok(value)
+        } else {
+            self.reset(spec, path, value)
+        }
+    }
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_owned()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+impl ConfigSpec {
+    /// Brings a config written for an older version of this spec up to date: unknown keys are
+    /// removed, new keys are given their defaults, renamed enum values are remapped using
+    /// `renames`, and anything else that no longer matches is reset to its default.
+    pub fn migrate<R: Rng + CryptoRng + Sync + Send>(
+        &self,
+        cfg: Config,
+        renames: &EnumRenames,
+        rng: &mut R,
+        timeout: &Option<Duration>,
+    ) -> Result<ConfigMigration, ConfigurationError> {
+        let mut migrator = Migrator {
+            renames,
+            rng,
+            timeout,
+            changes: Vec::new(),
+        };
+        let config = migrator.config(self, "", None, cfg)?;
+        Ok(ConfigMigration {
+            config,
+            changes: migrator.changes,
+        })
+    }
+}
+
+impl ConfigChange {
+    pub fn path(&self) -> &str {
+        match self {
+            ConfigChange::Removed { path, .. }
+            | ConfigChange::Added { path, .. }
+            | ConfigChange::Renamed { path, .. }
+            | ConfigChange::Reset { path, .. } => path,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn spec() -> ConfigSpec {
+        serde_json::from_value(serde_json::json!({
+            "enabled": { "type": "boolean", "name": "Enabled", "default": true },
+            "mode": {
+                "type": "enum",
+                "name": "Mode",
+                "values": ["fast", "safe"],
+                "default": "safe",
+            },
+            "advanced": {
+                "type": "object",
+                "name": "Advanced",
+                "spec": {
+                    "verbose": { "type": "boolean", "name": "Verbose", "default": false },
+                },
+            },
+            "backend": {
+                "type": "union",
+                "name": "Backend",
+                "tag": "type",
+                "default": "internal",
+                "variants": {
+                    "internal": {},
+                    "external": {
+                        "tls": { "type": "boolean", "name": "TLS", "default": true },
+                    },
+                },
+            },
+        }))
+        .unwrap()
+    }
+
+    fn config(value: serde_json::Value) -> Config {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn migrate(cfg: Config, renames: &EnumRenames) -> ConfigMigration {
+        spec()
+            .migrate(
+                cfg,
+                renames,
+                &mut rand::rngs::StdRng::seed_from_u64(0),
+                &None,
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn migrates_structure() {
+        let res = migrate(
+            config(serde_json::json!({
+                "enabled": "yes",
+                "mode": "fast",
+                "legacy": 1,
+                "backend": { "type": "external", "tls": false, "port": 80 },
+            })),
+            &EnumRenames::new(),
+        );
+        assert!(spec().matches(&res.config).is_ok());
+        assert_eq!(
+            res.changes.iter().map(|c| c.path()).collect::<Vec<_>>(),
+            ["enabled", "advanced", "backend.port", "legacy"]
+        );
+        assert!(matches!(res.changes[0], ConfigChange::Reset { .. }));
+        assert!(matches!(res.changes[1], ConfigChange::Added { .. }));
+        assert!(matches!(res.changes[2], ConfigChange::Removed { .. }));
+        assert_eq!(
+            res.config.get("mode"),
+            Some(&Value::String(Arc::new("fast".to_owned())))
+        );
+        assert_eq!(
+            res.config.get("backend"),
+            Some(&Value::Object(config(serde_json::json!({
+                "type": "external",
+                "tls": false,
+            }))))
+        );
+    }
+
+    #[test]
+    fn remaps_renamed_values() {
+        let renames: EnumRenames = serde_json::from_value(serde_json::json!({
+            "mode": { "quick": "fast" },
+            "backend": { "remote": "external" },
+        }))
+        .unwrap();
+        let res = migrate(
+            config(serde_json::json!({
+                "enabled": true,
+                "mode": "quick",
+                "advanced": { "verbose": true },
+                "backend": { "type": "remote", "tls": false },
+            })),
+            &renames,
+        );
+        assert!(spec().matches(&res.config).is_ok());
+        assert_eq!(res.changes.len(), 2);
+        assert_eq!(
+            res.config.get("mode"),
+            Some(&Value::String(Arc::new("fast".to_owned())))
+        );
+        assert_eq!(
+            res.config.get("backend"),
+            Some(&Value::Object(config(serde_json::json!({
+                "type": "external",
+                "tls": false,
+            }))))
+        );
+    }
+}
@@ -1,26 +1,40 @@
-use std::collections::BTreeMap;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 
+use clap::ArgMatches;
 use color_eyre::eyre::eyre;
 use indexmap::IndexSet;
 use itertools::Itertools;
 use models::{ErrorKind, OptionExt};
 use patch_db::value::InternedString;
 use patch_db::Value;
+use rand::SeedableRng;
 use regex::Regex;
 use rpc_toolkit::command;
+use rpc_toolkit::command_helpers::prelude::RequestParts;
 use tracing::instrument;
 
 use crate::context::RpcContext;
+use crate::install::cleanup::cleanup;
+use crate::install::trust::check_developer_key;
+use crate::install::unpack_s9pk;
+use crate::middleware::auth::HashSessionToken;
 use crate::prelude::*;
-use crate::s9pk::manifest::PackageId;
+use crate::s9pk::manifest::{Manifest, PackageId};
+use crate::s9pk::reader::S9pkReader;
 use crate::util::display_none;
 use crate::util::serde::{display_serializable, parse_stdin_deserializable, IoFormat};
+use crate::volume::asset_dir;
+use crate::volume::snapshot::{take_auto, SnapshotReason};
 use crate::Error;
 
 pub mod action;
+pub mod history;
+pub mod migrate;
+pub mod portable;
+pub mod secret;
 pub mod spec;
 pub mod util;
 
@@ -28,6 +42,8 @@ pub use spec::{ConfigSpec, Defaultable};
 use util::NumRange;
 
 use self::action::ConfigRes;
+use self::history::{record_applied, RevisionSource};
+use self::migrate::{ConfigChange, ConfigMigration};
 use self::spec::ValueSpecPointer;
 
 pub type Config = patch_db::value::InOMap<InternedString, Value>;
@@ -130,6 +146,16 @@ pub enum MatchError {
     InvalidKey(String),
     #[error("Value In List Is Not Unique")]
     ListUniquenessViolation,
+    #[error("Field Is Required When {0:?} Is {1}")]
+    RequiredIf(InternedString, String),
+    #[error("Field Conflicts With {0:?}")]
+    MutuallyExclusive(InternedString),
+    #[error("Value Must Be {0} {1:?}")]
+    Comparison(util::Comparison, InternedString),
+    #[error("Value Must Differ From {0:?}")]
+    NotUnique(InternedString),
+    #[error("Constraint References Unknown Field {0:?}")]
+    UnknownField(InternedString),
 }
 
 #[command(rename = "config-spec", cli_only, blocking, display(display_none))]
@@ -152,7 +178,16 @@ pub fn verify_spec(#[arg] path: PathBuf) -> Result<(), Error> {
     Ok(())
 }
 
-#[command(subcommands(get, set))]
+#[command(subcommands(
+    get,
+    set,
+    migrate,
+    history::history,
+    history::diff,
+    history::revert,
+    portable::export,
+    portable::import
+))]
 pub fn config(#[arg] id: PackageId) -> Result<PackageId, Error> {
     Ok(id)
 }
@@ -165,6 +200,7 @@ pub async fn get(
     #[allow(unused_variables)]
     #[arg(long = "format")]
     format: Option<IoFormat>,
+    #[arg(long = "reveal")] reveal: bool,
 ) -> Result<ConfigRes, Error> {
     let db = ctx.db.peek().await;
     let manifest = db
@@ -181,7 +217,16 @@ pub async fn get(
 
     let volumes = manifest.as_volumes().de()?;
     let version = manifest.as_version().de()?;
-    action.get(&ctx, &id, &version, &volumes).await
+    let mut res = action.get(&ctx, &id, &version, &volumes).await?;
+    if reveal {
+        if let Some(config) = res.config.take() {
+            res.config = Some(secret::reveal_config(
+                config,
+                &secret::load(&ctx, &id).await?,
+            ));
+        }
+    }
+    Ok(res)
 }
 
 #[command(
@@ -191,21 +236,30 @@ pub async fn get(
 )]
 #[instrument(skip_all)]
 pub fn set(
+    #[request] req: &RequestParts,
     #[parent_data] id: PackageId,
     #[allow(unused_variables)]
     #[arg(long = "format")]
     format: Option<IoFormat>,
     #[arg(long = "timeout")] timeout: Option<crate::util::serde::Duration>,
     #[arg(stdin, parse(parse_stdin_deserializable))] config: Option<Config>,
-) -> Result<(PackageId, Option<Config>, Option<Duration>), Error> {
-    Ok((id, config, timeout.map(|d| *d)))
+) -> Result<(PackageId, Option<Config>, Option<Duration>, Option<String>), Error> {
+    let session = HashSessionToken::from_request_parts(req)
+        .ok()
+        .map(|s| s.as_hash());
+    Ok((id, config, timeout.map(|d| *d), session))
 }
 
 #[command(rename = "dry", display(display_serializable))]
 #[instrument(skip_all)]
 pub async fn set_dry(
     #[context] ctx: RpcContext,
-    #[parent_data] (id, config, timeout): (PackageId, Option<Config>, Option<Duration>),
+    #[parent_data] (id, config, timeout, _): (
+        PackageId,
+        Option<Config>,
+        Option<Duration>,
+        Option<String>,
+    ),
 ) -> Result<BTreeMap<PackageId, String>, Error> {
     let breakages = BTreeMap::new();
     let overrides = Default::default();
@@ -217,11 +271,215 @@ pub async fn set_dry(
         dry_run: true,
         overrides,
     };
-    let breakages = configure(&ctx, &id, configure_context).await?;
+    let Configured { breakages, .. } = configure(&ctx, &id, configure_context).await?;
 
     Ok(breakages)
 }
 
+/// Migrates the stored config of a service to its current spec
+#[command(
+    subcommands(self(migrate_impl(async, context(RpcContext))), migrate_dry),
+    display(display_none),
+    metadata(sync_db = true)
+)]
+pub fn migrate(
+    #[request] req: &RequestParts,
+    #[parent_data] id: PackageId,
+) -> Result<(PackageId, Option<String>), Error> {
+    let session = HashSessionToken::from_request_parts(req)
+        .ok()
+        .map(|s| s.as_hash());
+    Ok((id, session))
+}
+
+fn display_changes(changes: Vec<ConfigChange>, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(changes, matches);
+    }
+
+    let show = |v: &Value| serde_json::to_string(v).unwrap_or_default();
+    let mut table = Table::new();
+    table.add_row(row![bc => "PATH", "CHANGE", "FROM", "TO"]);
+    for change in changes {
+        let (kind, from, to) = match &change {
+            ConfigChange::Removed { value, .. } => ("removed", show(value), "-".to_owned()),
+            ConfigChange::Added { value, .. } => ("added", "-".to_owned(), show(value)),
+            ConfigChange::Renamed { from, to, .. } => ("renamed", from.clone(), to.clone()),
+            ConfigChange::Reset { from, to, .. } => ("reset", show(from), show(to)),
+        };
+        table.add_row(row![change.path(), kind, from, to]);
+    }
+    table.print_tty(false).unwrap();
+}
+
+/// Previews the migration, to the spec of the s9pk at `--s9pk` if given, e.g. before updating to it
+#[command(rename = "dry", display(display_changes))]
+#[instrument(skip_all)]
+pub async fn migrate_dry(
+    #[context] ctx: RpcContext,
+    #[parent_data] (id, _): (PackageId, Option<String>),
+    #[arg(long = "s9pk")] s9pk: Option<PathBuf>,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<ConfigChange>, Error> {
+    let migration = match s9pk {
+        Some(path) => preview_update(&ctx, &id, &path).await?,
+        None => plan_migration(&ctx, &id, None).await?,
+    };
+    Ok(migration.map(|m| m.changes).unwrap_or_default())
+}
+
+/// Computes how the stored config of a service would be migrated by updating it to the s9pk at
+/// `path`. The s9pk is unpacked to run its config procedure, and removed again unless its version
+/// was already on the server.
+#[instrument(skip_all)]
+async fn preview_update(
+    ctx: &RpcContext,
+    id: &PackageId,
+    path: &Path,
+) -> Result<Option<ConfigMigration>, Error> {
+    let mut rdr = S9pkReader::open(path, true).await?;
+    rdr.validate().await?;
+    rdr.validated();
+    rdr.reset().await?;
+    let target = rdr.manifest().await?;
+    if &target.id != id {
+        return Err(Error::new(
+            eyre!(
+                "{} is a package of {}, not {}",
+                path.display(),
+                target.id,
+                id
+            ),
+            crate::ErrorKind::InvalidRequest,
+        ));
+    }
+    let installed_key = ctx
+        .db
+        .peek()
+        .await
+        .as_package_data()
+        .as_idx(id)
+        .and_then(|p| p.as_installed())
+        .map(|i| i.as_developer_key().de())
+        .transpose()?;
+    // the package is run to read its spec, so it must be signed by a key that could install it
+    let developer_key = rdr.developer_key().clone();
+    let _pins = check_developer_key(
+        ctx.secret_store.acquire().await?.as_mut(),
+        id,
+        &developer_key,
+        installed_key.as_ref(),
+        false,
+    )
+    .await?;
+
+    let unpack = tokio::fs::metadata(asset_dir(&ctx.datadir, id, &target.version))
+        .await
+        .is_err();
+    if unpack {
+        unpack_s9pk(&ctx.datadir, &target, &mut rdr).await?;
+    }
+    let res = plan_migration(ctx, id, Some(&target)).await;
+    if unpack {
+        if let Err(e) = cleanup(ctx, id, &target.version).await {
+            tracing::warn!("Failed to remove {} {}: {}", id, target.version, e);
+            tracing::debug!("{:?}", e);
+        }
+    }
+    res
+}
+
+#[instrument(skip_all)]
+pub async fn migrate_impl(
+    ctx: RpcContext,
+    (id, session): (PackageId, Option<String>),
+) -> Result<(), Error> {
+    let migration = match plan_migration(&ctx, &id, None).await? {
+        Some(m) if !m.changes.is_empty() => m,
+        _ => return Ok(()),
+    };
+    take_auto(&ctx, &id, SnapshotReason::Config).await;
+    let configured = configure(
+        &ctx,
+        &id,
+        ConfigureContext {
+            breakages: BTreeMap::new(),
+            timeout: None,
+            config: Some(migration.config),
+            dry_run: false,
+            overrides: BTreeMap::new(),
+        },
+    )
+    .await?;
+    record_applied(
+        &ctx,
+        &id,
+        RevisionSource::Migrate,
+        session.as_deref(),
+        &configured,
+    )
+    .await;
+    Ok(())
+}
+
+/// Computes how the stored config of a service would be migrated to the spec of `target`, or of the
+/// installed version if `None`. Returns `None` if the service has never been configured.
+#[instrument(skip_all)]
+pub async fn plan_migration(
+    ctx: &RpcContext,
+    id: &PackageId,
+    target: Option<&Manifest>,
+) -> Result<Option<ConfigMigration>, Error> {
+    let manifest = ctx
+        .db
+        .peek()
+        .await
+        .as_package_data()
+        .as_idx(id)
+        .or_not_found(id)?
+        .as_installed()
+        .or_not_found(id)?
+        .as_manifest()
+        .de()?;
+    let action = manifest
+        .config
+        .as_ref()
+        .ok_or_else(|| Error::new(eyre!("{} has no config", id), crate::ErrorKind::NotFound))?;
+    let ConfigRes { config, spec } = action
+        .get(ctx, id, &manifest.version, &manifest.volumes)
+        .await?;
+    let (spec, renames) = match target {
+        Some(target) => {
+            let action = target.config.as_ref().ok_or_else(|| {
+                Error::new(
+                    eyre!("{} {} has no config", id, target.version),
+                    crate::ErrorKind::NotFound,
+                )
+            })?;
+            let ConfigRes { spec, .. } = action
+                .get(ctx, id, &target.version, &target.volumes)
+                .await?;
+            (spec, &action.renames)
+        }
+        None => (spec, &action.renames),
+    };
+    config
+        .map(|config| {
+            spec.migrate(
+                config,
+                renames,
+                &mut rand::rngs::StdRng::from_entropy(),
+                &None,
+            )
+        })
+        .transpose()
+        .map_err(Error::from)
+}
+
 pub struct ConfigureContext {
     pub breakages: BTreeMap<PackageId, String>,
     pub timeout: Option<Duration>,
@@ -230,31 +488,89 @@ pub struct ConfigureContext {
     pub dry_run: bool,
 }
 
+/// The outcome of applying a config
+pub struct Configured {
+    /// The config as applied, with its secrets replaced by references
+    pub config: Config,
+    /// Dependents whose config no longer passes their check, with the reason
+    pub breakages: BTreeMap<PackageId, String>,
+    /// The other packages whose config was applied as part of the same change
+    pub auto_configured: BTreeSet<PackageId>,
+}
+
 #[instrument(skip_all)]
 pub async fn set_impl(
     ctx: RpcContext,
-    (id, config, timeout): (PackageId, Option<Config>, Option<Duration>),
+    (id, config, timeout, session): (PackageId, Option<Config>, Option<Duration>, Option<String>),
 ) -> Result<(), Error> {
+    let config = match config {
+        Some(config) => config,
+        None => current_config(&ctx, &id, &timeout).await?,
+    };
     let breakages = BTreeMap::new();
     let overrides = Default::default();
 
     let configure_context = ConfigureContext {
         breakages,
         timeout,
-        config,
+        config: Some(config),
         dry_run: false,
         overrides,
     };
-    configure(&ctx, &id, configure_context).await?;
+    take_auto(&ctx, &id, SnapshotReason::Config).await;
+    let configured = configure(&ctx, &id, configure_context).await?;
+    record_applied(
+        &ctx,
+        &id,
+        RevisionSource::Set,
+        session.as_deref(),
+        &configured,
+    )
+    .await;
     Ok(())
 }
 
+/// The config a service is running with, or its defaults if it has never been configured
+#[instrument(skip_all)]
+async fn current_config(
+    ctx: &RpcContext,
+    id: &PackageId,
+    timeout: &Option<Duration>,
+) -> Result<Config, Error> {
+    let manifest = ctx
+        .db
+        .peek()
+        .await
+        .as_package_data()
+        .as_idx(id)
+        .or_not_found(id)?
+        .as_installed()
+        .or_not_found(id)?
+        .as_manifest()
+        .de()?;
+    let action = manifest
+        .config
+        .as_ref()
+        .ok_or_else(|| Error::new(eyre!("{} has no config", id), crate::ErrorKind::NotFound))?;
+    let ConfigRes { config, spec } = action
+        .get(ctx, id, &manifest.version, &manifest.volumes)
+        .await?;
+    match config {
+        Some(config) => Ok(config),
+        None => Ok(spec.gen_satisfying(
+            &action.constraints,
+            &mut rand::rngs::StdRng::from_entropy(),
+            timeout,
+        )?),
+    }
+}
+
 #[instrument(skip_all)]
 pub async fn configure(
     ctx: &RpcContext,
     id: &PackageId,
     configure_context: ConfigureContext,
-) -> Result<BTreeMap<PackageId, String>, Error> {
+) -> Result<Configured, Error> {
     let db = ctx.db.peek().await;
     let package = db
         .as_package_data()
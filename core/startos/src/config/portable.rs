@@ -0,0 +1,292 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use clap::ArgMatches;
+use color_eyre::eyre::eyre;
+use rand::SeedableRng;
+use rpc_toolkit::command;
+use rpc_toolkit::command_helpers::prelude::RequestParts;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use super::action::ConfigRes;
+use super::history::{diff_configs, display_diff, ConfigDiff};
+use super::{secret, set_impl, Config, ConfigSpec};
+use crate::context::RpcContext;
+use crate::middleware::auth::HashSessionToken;
+use crate::prelude::*;
+use crate::s9pk::manifest::PackageId;
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::util::Version;
+
+/// The config of a service in a form that can be applied on another server
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConfigExport {
+    pub package_id: PackageId,
+    /// The version of the package whose config spec the config was written for
+    pub version: Version,
+    /// Secrets are blank unless the export was asked to include them
+    pub config: Config,
+}
+impl ConfigExport {
+    /// Refuses an export of another package, whose config could happen to match the spec
+    fn check_source(&self, id: &PackageId) -> Result<(), Error> {
+        if &self.package_id != id {
+            return Err(Error::new(
+                eyre!("Config was exported from {}, not {}", self.package_id, id),
+                ErrorKind::InvalidRequest,
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The config to export: with the plaintexts of its secrets if `secrets` are given, or blanked
+fn exported(
+    spec: &ConfigSpec,
+    mut config: Config,
+    secrets: Option<&BTreeMap<String, String>>,
+) -> Config {
+    match secrets {
+        Some(secrets) => secret::reveal_config(config, secrets),
+        None => {
+            secret::strip(spec, &mut config);
+            config
+        }
+    }
+}
+
+/// Prints the config of a service, to be applied later with `config import`
+#[command(display(display_serializable))]
+#[instrument(skip_all)]
+pub async fn export(
+    #[context] ctx: RpcContext,
+    #[parent_data] id: PackageId,
+    #[arg(long = "include-secrets")] include_secrets: bool,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<ConfigExport, Error> {
+    let manifest = ctx
+        .db
+        .peek()
+        .await
+        .as_package_data()
+        .as_idx(&id)
+        .or_not_found(&id)?
+        .as_installed()
+        .or_not_found(&id)?
+        .as_manifest()
+        .de()?;
+    let ConfigRes { config, spec } = manifest
+        .config
+        .as_ref()
+        .ok_or_else(|| Error::new(eyre!("{} has no config", id), ErrorKind::NotFound))?
+        .get(&ctx, &id, &manifest.version, &manifest.volumes)
+        .await?;
+    let config =
+        config.ok_or_else(|| Error::new(eyre!("{} is not configured", id), ErrorKind::NotFound))?;
+    let secrets = if include_secrets {
+        Some(secret::load(&ctx, &id).await?)
+    } else {
+        None
+    };
+    Ok(ConfigExport {
+        package_id: id,
+        version: manifest.version,
+        config: exported(&spec, config, secrets.as_ref()),
+    })
+}
+
+fn parse_export(arg: &str, _: &ArgMatches) -> Result<ConfigExport, Error> {
+    let path = Path::new(arg);
+    let format = match path.extension().and_then(|s| s.to_str()) {
+        Some("json") => IoFormat::Json,
+        Some("toml") => IoFormat::Toml,
+        Some("cbor") => IoFormat::Cbor,
+        // json is also valid yaml
+        _ => IoFormat::Yaml,
+    };
+    format.from_reader(std::fs::File::open(path).with_ctx(|_| (ErrorKind::Filesystem, arg))?)
+}
+
+/// Applies a config written by `config export`, printing how it differs from the current one
+#[command(
+    subcommands(self(import_impl(async, context(RpcContext))), import_dry),
+    display(display_diff),
+    metadata(sync_db = true)
+)]
+pub fn import(
+    #[request] req: &RequestParts,
+    #[parent_data] id: PackageId,
+    #[arg(parse(parse_export))] file: ConfigExport,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<(PackageId, ConfigExport, Option<String>), Error> {
+    let session = HashSessionToken::from_request_parts(req)
+        .ok()
+        .map(|s| s.as_hash());
+    Ok((id, file, session))
+}
+
+/// Checks an exported config against the installed spec, returning the config to apply and how
+/// it differs from the current one
+#[instrument(skip_all)]
+async fn prepare(
+    ctx: &RpcContext,
+    id: &PackageId,
+    export: ConfigExport,
+) -> Result<(Config, Vec<ConfigDiff>), Error> {
+    export.check_source(id)?;
+    let manifest = ctx
+        .db
+        .peek()
+        .await
+        .as_package_data()
+        .as_idx(id)
+        .or_not_found(id)?
+        .as_installed()
+        .or_not_found(id)?
+        .as_manifest()
+        .de()?;
+    let action = manifest
+        .config
+        .as_ref()
+        .ok_or_else(|| Error::new(eyre!("{} has no config", id), ErrorKind::NotFound))?;
+    let ConfigRes {
+        config: current,
+        spec,
+    } = action
+        .get(ctx, id, &manifest.version, &manifest.volumes)
+        .await?;
+    let current = current.unwrap_or_else(Config::new);
+
+    let mut config = export.config;
+    if export.version != manifest.version {
+        config = spec
+            .migrate(
+                config,
+                &action.renames,
+                &mut rand::rngs::StdRng::from_entropy(),
+                &None,
+            )?
+            .config;
+    }
+    let known = secret::stored(ctx, id).await?;
+    secret::restore(&spec, &mut config, &current, &known);
+    action.check(&spec, &manifest, &config)?;
+    // pointers are resolved against this server, so the diff shows what will be applied
+    spec.update(ctx, &manifest, &BTreeMap::new(), &mut config)
+        .await?;
+
+    let mut shown = config.clone();
+    secret::extract(&spec, &mut shown);
+    let diff = diff_configs(&current, &shown);
+    Ok((config, diff))
+}
+
+#[command(rename = "dry", display(display_diff))]
+#[instrument(skip_all)]
+pub async fn import_dry(
+    #[context] ctx: RpcContext,
+    #[parent_data] (id, export, _): (PackageId, ConfigExport, Option<String>),
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<ConfigDiff>, Error> {
+    Ok(prepare(&ctx, &id, export).await?.1)
+}
+
+#[instrument(skip_all)]
+pub async fn import_impl(
+    ctx: RpcContext,
+    (id, export, session): (PackageId, ConfigExport, Option<String>),
+) -> Result<Vec<ConfigDiff>, Error> {
+    let (config, diff) = prepare(&ctx, &id, export).await?;
+    if !diff.is_empty() {
+        set_impl(ctx, (id, Some(config), None, session)).await?;
+    }
+    Ok(diff)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use imbl_value::InternedString;
+
+    use super::*;
+
+    fn spec() -> ConfigSpec {
+        serde_json::from_value(serde_json::json!({
+            "user": { "type": "string", "name": "User", "nullable": false, "default": "admin" },
+            "password": { "type": "secret", "name": "Password", "nullable": true, "default": null },
+        }))
+        .unwrap()
+    }
+
+    fn config(value: serde_json::Value) -> Config {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn refuses_other_package() {
+        let export = ConfigExport {
+            package_id: "bitcoind".parse().unwrap(),
+            version: "0.1.0".parse().unwrap(),
+            config: Config::new(),
+        };
+        assert!(export.check_source(&"bitcoind".parse().unwrap()).is_ok());
+        let err = export.check_source(&"lnd".parse().unwrap()).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::InvalidRequest);
+    }
+
+    #[test]
+    fn strips_secrets_on_export() {
+        let spec = spec();
+        let mut current = config(serde_json::json!({ "user": "satoshi", "password": "hunter2" }));
+        let plaintexts = secret::extract(&spec, &mut current);
+
+        let stripped = exported(&spec, current.clone(), None);
+        assert_eq!(
+            stripped,
+            config(serde_json::json!({ "user": "satoshi", "password": null }))
+        );
+
+        let revealed = exported(&spec, current, Some(&plaintexts));
+        assert_eq!(
+            revealed,
+            config(serde_json::json!({ "user": "satoshi", "password": "hunter2" }))
+        );
+    }
+
+    #[test]
+    fn restores_secrets_on_import() {
+        let spec = spec();
+        let mut current = config(serde_json::json!({ "user": "satoshi", "password": "hunter2" }));
+        let plaintexts = secret::extract(&spec, &mut current);
+        let known: BTreeSet<String> = plaintexts.keys().cloned().collect();
+        let password = InternedString::intern("password");
+
+        // a stripped export keeps the secret already stored on this server
+        let mut imported = config(serde_json::json!({ "user": "hal", "password": null }));
+        secret::restore(&spec, &mut imported, &current, &known);
+        let mut expected = config(serde_json::json!({ "user": "hal" }));
+        expected.insert(password.clone(), current.get("password").unwrap().clone());
+        assert_eq!(imported, expected);
+
+        // a reference to a secret of another server is not kept either
+        let mut foreign = current.clone();
+        foreign.insert(password.clone(), secret::reference("elsewhere"));
+        secret::restore(&spec, &mut foreign, &current, &known);
+        assert_eq!(foreign, current);
+
+        // while a revealed secret replaces the stored one
+        let revealed = config(serde_json::json!({ "user": "satoshi", "password": "hunter3" }));
+        let mut restored = revealed.clone();
+        secret::restore(&spec, &mut restored, &current, &known);
+        assert_eq!(restored, revealed);
+    }
+}
@@ -0,0 +1,548 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use color_eyre::eyre::eyre;
+use hmac::{Hmac, Mac};
+use imbl_value::InternedString;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use patch_db::Value;
+use sha2::Sha256;
+use sqlx::{Executor, Postgres};
+use tracing::instrument;
+
+use super::spec::{ValueSpecAny, ValueSpecList, ValueSpecUnion};
+use super::{Config, ConfigSpec};
+use crate::context::RpcContext;
+use crate::prelude::*;
+use crate::s9pk::manifest::PackageId;
+
+/// Decrypted secrets of running services are written here, on the host's tmpfs
+pub const SECRETS_RUN_DIR: &str = "/run/embassy/secrets";
+/// Where the secrets of a service are mounted in its container, one file per secret
+pub const SECRETS_MOUNT: &str = "/run/secrets";
+/// Separates the key of the secrets from the other uses of the network key
+const KEY_CONTEXT: &[u8] = b"embassy config secrets v1";
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// What a config holds in place of a secret: `{ "secret": <id>, "path": <file in container> }`
+pub fn reference(id: &str) -> Value {
+    let mut r = Config::new();
+    r.insert(
+        InternedString::intern("secret"),
+        Value::String(Arc::new(id.to_owned())),
+    );
+    r.insert(
+        InternedString::intern("path"),
+        Value::String(Arc::new(format!("{}/{}", SECRETS_MOUNT, id))),
+    );
+    Value::Object(r)
+}
+
+/// The id of the secret `value` refers to, if it is a reference
+pub fn reference_id(value: &Value) -> Option<&str> {
+    let o = match value {
+        Value::Object(o) => o,
+        _ => return None,
+    };
+    match (o.get("secret"), o.get("path")) {
+        (Some(Value::String(id)), Some(Value::String(path)))
+            if **path == format!("{}/{}", SECRETS_MOUNT, id) =>
+        {
+            Some(&***id)
+        }
+        _ => None,
+    }
+}
+
+fn new_id() -> String {
+    base32::encode(
+        base32::Alphabet::RFC4648 { padding: false },
+        &rand::random::<[u8; 10]>(),
+    )
+    .to_lowercase()
+}
+
+/// Calls `f` on the value of every secret field of `cfg`
+fn visit_secrets(spec: &ConfigSpec, cfg: &mut Config, f: &mut dyn FnMut(&mut Value)) {
+    for (key, vs) in spec.0.iter() {
+        if let Some(value) = cfg.get_mut(&**key) {
+            visit_value(vs, value, f);
+        }
+    }
+}
+
+fn visit_union(spec: &ValueSpecUnion, value: &mut Value, f: &mut dyn FnMut(&mut Value)) {
+    if let Value::Object(o) = value {
+        let variant = match o.get(&*spec.tag.id) {
+            Some(Value::String(tag)) => spec.variants.get(&**tag),
+            _ => None,
+        };
+        if let Some(variant) = variant {
+            visit_secrets(variant, o, f);
+        }
+    }
+}
+
+fn visit_value(spec: &ValueSpecAny, value: &mut Value, f: &mut dyn FnMut(&mut Value)) {
+    match (spec, value) {
+        (ValueSpecAny::Secret(_), value) => f(value),
+        (ValueSpecAny::Object(o), Value::Object(cfg)) => visit_secrets(&o.inner.spec, cfg, f),
+        (ValueSpecAny::Union(u), value) => visit_union(&u.inner.inner, value, f),
+        (ValueSpecAny::List(ValueSpecList::Object(o)), Value::Array(l)) => {
+            for value in l.iter_mut() {
+                if let Value::Object(cfg) = value {
+                    visit_secrets(&o.inner.inner.spec.spec, cfg, f);
+                }
+            }
+        }
+        (ValueSpecAny::List(ValueSpecList::Union(u)), Value::Array(l)) => {
+            for value in l.iter_mut() {
+                visit_union(&u.inner.inner.spec.inner, value, f);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Replaces the plaintext secrets in `cfg` with references to new ids, returning the plaintexts
+/// by id
+pub fn extract(spec: &ConfigSpec, cfg: &mut Config) -> BTreeMap<String, String> {
+    let mut res = BTreeMap::new();
+    visit_secrets(spec, cfg, &mut |value| {
+        if let Value::String(s) = value {
+            let id = new_id();
+            res.insert(id.clone(), (**s).clone());
+            *value = reference(&id);
+        }
+    });
+    res
+}
+
+/// The ids of the secrets `value` refers to
+pub fn references(value: &Value, res: &mut BTreeSet<String>) {
+    if let Some(id) = reference_id(value) {
+        res.insert(id.to_owned());
+        return;
+    }
+    match value {
+        Value::Object(o) => o.iter().for_each(|(_, v)| references(v, res)),
+        Value::Array(l) => l.iter().for_each(|v| references(v, res)),
+        _ => (),
+    }
+}
+
+/// Replaces the references in `value` with the plaintexts in `secrets`
+pub fn reveal(value: Value, secrets: &BTreeMap<String, String>) -> Value {
+    if let Some(secret) = reference_id(&value).and_then(|id| secrets.get(id)) {
+        return Value::String(Arc::new(secret.clone()));
+    }
+    match value {
+        Value::Object(o) => {
+            let mut res = Config::new();
+            for (k, v) in o {
+                res.insert(k, reveal(v, secrets));
+            }
+            Value::Object(res)
+        }
+        Value::Array(l) => Value::Array(l.into_iter().map(|v| reveal(v, secrets)).collect()),
+        a => a,
+    }
+}
+
+/// Blanks the secrets of `cfg`, so it can be shared without them
+pub fn strip(spec: &ConfigSpec, cfg: &mut Config) {
+    visit_secrets(spec, cfg, &mut |value| *value = Value::Null);
+}
+
+/// Fills the secrets of `cfg` that are blank, or refer to secrets not in `known`, with the value at
+/// the same place in `current`
+pub fn restore(spec: &ConfigSpec, cfg: &mut Config, current: &Config, known: &BTreeSet<String>) {
+    restore_config(spec, cfg, Some(current), known)
+}
+
+fn restore_config(
+    spec: &ConfigSpec,
+    cfg: &mut Config,
+    current: Option<&Config>,
+    known: &BTreeSet<String>,
+) {
+    for (key, vs) in spec.0.iter() {
+        let current = current.and_then(|c| c.get(&**key));
+        match cfg.get_mut(&**key) {
+            Some(value) => restore_value(vs, value, current, known),
+            None => {
+                if let (ValueSpecAny::Secret(_), Some(current)) = (vs, current) {
+                    cfg.insert(key.clone(), current.clone());
+                }
+            }
+        }
+    }
+}
+
+fn restore_union(
+    spec: &ValueSpecUnion,
+    value: &mut Value,
+    current: Option<&Value>,
+    known: &BTreeSet<String>,
+) {
+    if let Value::Object(o) = value {
+        let tag = match o.get(&*spec.tag.id) {
+            Some(Value::String(tag)) => tag.clone(),
+            _ => return,
+        };
+        // secrets of another variant do not carry over
+        let current = match current {
+            Some(Value::Object(c)) if c.get(&*spec.tag.id) == o.get(&*spec.tag.id) => Some(c),
+            _ => None,
+        };
+        if let Some(variant) = spec.variants.get(&**tag) {
+            restore_config(variant, o, current, known);
+        }
+    }
+}
+
+fn restore_value(
+    spec: &ValueSpecAny,
+    value: &mut Value,
+    current: Option<&Value>,
+    known: &BTreeSet<String>,
+) {
+    let nth = |idx: usize| match current {
+        Some(Value::Array(c)) => c.get(idx),
+        _ => None,
+    };
+    match (spec, value) {
+        (ValueSpecAny::Secret(_), value) => {
+            let missing = match &*value {
+                Value::Null => true,
+                value => reference_id(value).map_or(false, |id| !known.contains(id)),
+            };
+            if let (true, Some(current)) = (missing, current) {
+                *value = current.clone();
+            }
+        }
+        (ValueSpecAny::Object(o), Value::Object(cfg)) => {
+            let current = match current {
+                Some(Value::Object(c)) => Some(c),
+                _ => None,
+            };
+            restore_config(&o.inner.spec, cfg, current, known)
+        }
+        (ValueSpecAny::Union(u), value) => restore_union(&u.inner.inner, value, current, known),
+        (ValueSpecAny::List(ValueSpecList::Object(o)), Value::Array(l)) => {
+            for (idx, value) in l.iter_mut().enumerate() {
+                if let Value::Object(cfg) = value {
+                    let current = match nth(idx) {
+                        Some(Value::Object(c)) => Some(c),
+                        _ => None,
+                    };
+                    restore_config(&o.inner.inner.spec.spec, cfg, current, known);
+                }
+            }
+        }
+        (ValueSpecAny::List(ValueSpecList::Union(u)), Value::Array(l)) => {
+            for (idx, value) in l.iter_mut().enumerate() {
+                restore_union(&u.inner.inner.spec.inner, value, nth(idx), known);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Replaces the references in `cfg` with the plaintexts in `secrets`
+pub fn reveal_config(cfg: Config, secrets: &BTreeMap<String, String>) -> Config {
+    let mut res = Config::new();
+    for (k, v) in cfg {
+        res.insert(k, reveal(v, secrets));
+    }
+    res
+}
+
+/// The key secrets are encrypted with, derived from the network key of the server so that it is
+/// never used for two purposes as is
+async fn key<Ex>(secrets: &mut Ex) -> Result<Vec<u8>, Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Postgres>,
+{
+    let network_key = sqlx::query!("SELECT network_key FROM account WHERE id = 0")
+        .fetch_one(&mut *secrets)
+        .await?
+        .network_key;
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(&network_key).expect("HMAC takes keys of any size");
+    mac.update(KEY_CONTEXT);
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+/// The key is already uniformly random, so each secret is encrypted with it as is, with AES-256-GCM:
+/// `nonce || ciphertext || tag`. The package and secret ids are authenticated, so a stored value
+/// can not be moved to another secret.
+fn encrypt(
+    key: &[u8],
+    id: &PackageId,
+    secret_id: &str,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    let mut tag = [0; TAG_LEN];
+    let ciphertext = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(&nonce),
+        format!("{}/{}", id, secret_id).as_bytes(),
+        plaintext,
+        &mut tag,
+    )
+    .with_kind(ErrorKind::OpenSsl)?;
+    let mut res = Vec::with_capacity(NONCE_LEN + ciphertext.len() + TAG_LEN);
+    res.extend_from_slice(&nonce);
+    res.extend_from_slice(&ciphertext);
+    res.extend_from_slice(&tag);
+    Ok(res)
+}
+
+/// Decrypts the output of [encrypt]
+fn decrypt(key: &[u8], id: &PackageId, secret_id: &str, value: &[u8]) -> Result<Vec<u8>, Error> {
+    if value.len() < NONCE_LEN + TAG_LEN {
+        return Err(Error::new(
+            eyre!("secret {} of {} is truncated", secret_id, id),
+            ErrorKind::Incoherent,
+        ));
+    }
+    let (nonce, rest) = value.split_at(NONCE_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+    decrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(nonce),
+        format!("{}/{}", id, secret_id).as_bytes(),
+        ciphertext,
+        tag,
+    )
+    .map_err(|_| {
+        Error::new(
+            eyre!("secret {} of {} could not be decrypted", secret_id, id),
+            ErrorKind::Incoherent,
+        )
+    })
+}
+
+/// Stores the plaintexts taken out of a config by [extract], encrypted, under their ids
+#[instrument(skip_all)]
+pub async fn seal<Ex>(
+    secrets: &mut Ex,
+    id: &PackageId,
+    plaintexts: BTreeMap<String, String>,
+) -> Result<(), Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Postgres>,
+{
+    if plaintexts.is_empty() {
+        return Ok(());
+    }
+    let key = key(secrets).await?;
+    for (secret_id, plaintext) in plaintexts {
+        let value = encrypt(&key, id, &secret_id, plaintext.as_bytes())?;
+        sqlx::query!(
+            "INSERT INTO config_secrets (package_id, id, value) VALUES ($1, $2, $3) ON CONFLICT (package_id, id) DO NOTHING",
+            &*id,
+            secret_id,
+            value,
+        )
+        .execute(&mut *secrets)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Decrypts the stored secrets of a service
+#[instrument(skip_all)]
+pub async fn load(ctx: &RpcContext, id: &PackageId) -> Result<BTreeMap<String, String>, Error> {
+    let mut secrets = ctx.secret_store.acquire().await?;
+    let rows = sqlx::query!(
+        "SELECT id, value FROM config_secrets WHERE package_id = $1",
+        &*id
+    )
+    .fetch_all(secrets.as_mut())
+    .await?;
+    if rows.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    let key = key(secrets.as_mut()).await?;
+    rows.into_iter()
+        .map(|r| {
+            let plaintext = decrypt(&key, id, &r.id, &r.value)?;
+            Ok((
+                r.id,
+                String::from_utf8(plaintext).with_kind(ErrorKind::Utf8)?,
+            ))
+        })
+        .collect()
+}
+
+/// The ids of the stored secrets of a service
+pub async fn stored(ctx: &RpcContext, id: &PackageId) -> Result<BTreeSet<String>, Error> {
+    Ok(
+        sqlx::query!("SELECT id FROM config_secrets WHERE package_id = $1", &*id)
+            .fetch_all(&ctx.secret_store)
+            .await?
+            .into_iter()
+            .map(|r| r.id)
+            .collect(),
+    )
+}
+
+/// Deletes the stored secrets of a service that are not in `keep`
+#[instrument(skip_all)]
+pub async fn prune(ctx: &RpcContext, id: &PackageId, keep: &BTreeSet<String>) -> Result<(), Error> {
+    let ids = sqlx::query!("SELECT id FROM config_secrets WHERE package_id = $1", &*id)
+        .fetch_all(&ctx.secret_store)
+        .await?;
+    for secret_id in ids.into_iter().map(|r| r.id).filter(|i| !keep.contains(i)) {
+        sqlx::query!(
+            "DELETE FROM config_secrets WHERE package_id = $1 AND id = $2",
+            &*id,
+            secret_id
+        )
+        .execute(&ctx.secret_store)
+        .await?;
+    }
+    Ok(())
+}
+
+#[instrument(skip_all)]
+pub async fn remove_all<Ex>(secrets: &mut Ex, id: &PackageId) -> Result<(), Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Postgres>,
+{
+    sqlx::query!("DELETE FROM config_secrets WHERE package_id = $1", &*id)
+        .execute(&mut *secrets)
+        .await?;
+    let dir = run_dir(id);
+    if tokio::fs::metadata(&dir).await.is_ok() {
+        tokio::fs::remove_dir_all(&dir)
+            .await
+            .with_ctx(|_| (ErrorKind::Filesystem, dir.display().to_string()))?;
+    }
+    Ok(())
+}
+
+fn run_dir(id: &PackageId) -> PathBuf {
+    Path::new(SECRETS_RUN_DIR).join(&**id)
+}
+
+/// Writes the secrets of a service to its run directory and returns the arguments that mount it
+/// into the container. Files are replaced in place, since the directory may already be mounted.
+#[instrument(skip_all)]
+pub async fn docker_args(ctx: &RpcContext, id: &PackageId) -> Result<Vec<OsString>, Error> {
+    let secrets = load(ctx, id).await?;
+    if secrets.is_empty() {
+        return Ok(Vec::new());
+    }
+    let dir = run_dir(id);
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, dir.display().to_string()))?;
+    // only the directory of the service is mounted, so the parent keeps the secrets from other
+    // users of the host, while the service may run as any user inside its container
+    tokio::fs::set_permissions(SECRETS_RUN_DIR, std::fs::Permissions::from_mode(0o700)).await?;
+    tokio::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).await?;
+    let mut stale = tokio::fs::read_dir(&dir).await?;
+    while let Some(entry) = stale.next_entry().await? {
+        if !secrets.contains_key(&*entry.file_name().to_string_lossy()) {
+            tokio::fs::remove_file(entry.path()).await?;
+        }
+    }
+    for (secret_id, plaintext) in &secrets {
+        let path = dir.join(secret_id);
+        tokio::fs::write(&path, plaintext)
+            .await
+            .with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()))?;
+        tokio::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o444)).await?;
+    }
+    Ok(vec![
+        "--mount".into(),
+        format!(
+            "type=bind,src={},dst={},readonly",
+            dir.display(),
+            SECRETS_MOUNT
+        )
+        .into(),
+    ])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn extracts_and_reveals_secrets() {
+        let spec: ConfigSpec = serde_json::from_value(serde_json::json!({
+            "user": { "type": "string", "name": "User", "nullable": false, "default": "admin" },
+            "password": { "type": "secret", "name": "Password", "nullable": true, "default": null },
+            "peers": {
+                "type": "list",
+                "subtype": "object",
+                "name": "Peers",
+                "range": "[0,*)",
+                "default": [],
+                "spec": {
+                    "spec": {
+                        "token": { "type": "secret", "name": "Token", "nullable": false, "default": null },
+                    },
+                },
+            },
+        }))
+        .unwrap();
+        let mut cfg: Config = serde_json::from_value(serde_json::json!({
+            "user": "admin",
+            "password": "hunter2",
+            "peers": [{ "token": "abc" }],
+        }))
+        .unwrap();
+        let original = cfg.clone();
+        let plaintexts = extract(&spec, &mut cfg);
+        assert_eq!(plaintexts.len(), 2);
+        assert!(!serde_json::to_string(&cfg).unwrap().contains("hunter2"));
+        assert_eq!(cfg.get("user"), original.get("user"));
+
+        let mut ids = BTreeSet::new();
+        references(&Value::Object(cfg.clone()), &mut ids);
+        assert_eq!(ids, plaintexts.keys().cloned().collect());
+
+        // references are left as they are
+        let mut resealed = cfg.clone();
+        assert!(extract(&spec, &mut resealed).is_empty());
+
+        assert_eq!(
+            reveal(Value::Object(cfg.clone()), &plaintexts),
+            Value::Object(original)
+        );
+
+        // a stripped copy gets its secrets back from the config it replaces
+        let mut stripped = cfg.clone();
+        strip(&spec, &mut stripped);
+        let mut none = BTreeSet::new();
+        references(&Value::Object(stripped.clone()), &mut none);
+        assert!(none.is_empty());
+        restore(&spec, &mut stripped, &cfg, &ids);
+        assert_eq!(stripped, cfg);
+    }
+
+    #[test]
+    fn encrypts_with_ids_authenticated() {
+        let key = [7; 32];
+        let id: PackageId = "bitcoind".parse().unwrap();
+        let value = encrypt(&key, &id, "abc", b"hunter2").unwrap();
+        assert_eq!(decrypt(&key, &id, "abc", &value).unwrap(), b"hunter2");
+        assert!(decrypt(&key, &id, "def", &value).is_err());
+        assert!(decrypt(&[8; 32], &id, "abc", &value).is_err());
+        let other: PackageId = "lnd".parse().unwrap();
+        assert!(decrypt(&key, &other, "abc", &value).is_err());
+        assert!(decrypt(&key, &id, "abc", &value[..NONCE_LEN]).is_err());
+    }
+}
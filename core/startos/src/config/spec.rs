@@ -21,7 +21,8 @@ use serde::de::{MapAccess, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use sqlx::PgPool;
 
-use super::util::{self, CharSet, NumRange, UniqueBy, STATIC_NULL};
+use super::secret;
+use super::util::{self, CharSet, Constraint, NumRange, UniqueBy, STATIC_NULL};
 use super::{Config, MatchError, NoMatchWithPath, TimeoutError, TypeOf};
 use crate::config::ConfigurationError;
 use crate::context::RpcContext;
@@ -340,6 +341,7 @@ pub enum ValueSpecAny {
     String(WithDescription<WithDefault<WithNullable<ValueSpecString>>>),
     Union(WithDescription<WithDefault<ValueSpecUnion>>),
     Pointer(WithDescription<ValueSpecPointer>),
+    Secret(WithDescription<WithDefault<WithNullable<ValueSpecSecret>>>),
 }
 impl ValueSpecAny {
     pub fn name(&self) -> &'_ str {
@@ -358,6 +360,7 @@ impl ValueSpecAny {
             ValueSpecAny::Pointer(p) => p.name.as_str(),
             ValueSpecAny::String(s) => s.name.as_str(),
             ValueSpecAny::Union(u) => u.name.as_str(),
+            ValueSpecAny::Secret(s) => s.name.as_str(),
         }
     }
 }
@@ -373,6 +376,7 @@ impl ValueSpec for ValueSpecAny {
             ValueSpecAny::String(a) => a.matches(value),
             ValueSpecAny::Union(a) => a.matches(value),
             ValueSpecAny::Pointer(a) => a.matches(value),
+            ValueSpecAny::Secret(a) => a.matches(value),
         }
     }
     fn validate(&self, manifest: &Manifest) -> Result<(), NoMatchWithPath> {
@@ -385,6 +389,7 @@ impl ValueSpec for ValueSpecAny {
             ValueSpecAny::String(a) => a.validate(manifest),
             ValueSpecAny::Union(a) => a.validate(manifest),
             ValueSpecAny::Pointer(a) => a.validate(manifest),
+            ValueSpecAny::Secret(a) => a.validate(manifest),
         }
     }
     async fn update(
@@ -403,6 +408,7 @@ impl ValueSpec for ValueSpecAny {
             ValueSpecAny::String(a) => a.update(ctx, manifest, config_overrides, value).await,
             ValueSpecAny::Union(a) => a.update(ctx, manifest, config_overrides, value).await,
             ValueSpecAny::Pointer(a) => a.update(ctx, manifest, config_overrides, value).await,
+            ValueSpecAny::Secret(a) => a.update(ctx, manifest, config_overrides, value).await,
         }
     }
     fn pointers(&self, value: &Value) -> Result<BTreeSet<ValueSpecPointer>, NoMatchWithPath> {
@@ -415,6 +421,7 @@ impl ValueSpec for ValueSpecAny {
             ValueSpecAny::String(a) => a.pointers(value),
             ValueSpecAny::Union(a) => a.pointers(value),
             ValueSpecAny::Pointer(a) => a.pointers(value),
+            ValueSpecAny::Secret(a) => a.pointers(value),
         }
     }
     fn requires(&self, id: &PackageId, value: &Value) -> bool {
@@ -427,6 +434,7 @@ impl ValueSpec for ValueSpecAny {
             ValueSpecAny::String(a) => a.requires(id, value),
             ValueSpecAny::Union(a) => a.requires(id, value),
             ValueSpecAny::Pointer(a) => a.requires(id, value),
+            ValueSpecAny::Secret(a) => a.requires(id, value),
         }
     }
     fn eq(&self, lhs: &Value, rhs: &Value) -> bool {
@@ -439,6 +447,7 @@ impl ValueSpec for ValueSpecAny {
             ValueSpecAny::String(a) => a.eq(lhs, rhs),
             ValueSpecAny::Union(a) => a.eq(lhs, rhs),
             ValueSpecAny::Pointer(a) => a.eq(lhs, rhs),
+            ValueSpecAny::Secret(a) => a.eq(lhs, rhs),
         }
     }
 }
@@ -459,6 +468,7 @@ impl Defaultable for ValueSpecAny {
             ValueSpecAny::String(a) => a.gen(rng, timeout).map_err(ConfigurationError::from),
             ValueSpecAny::Union(a) => a.gen(rng, timeout),
             ValueSpecAny::Pointer(a) => a.gen(rng, timeout),
+            ValueSpecAny::Secret(a) => a.gen(rng, timeout).map_err(ConfigurationError::from),
         }
     }
 }
@@ -927,12 +937,18 @@ pub struct ValueSpecObject {
     pub display_as: Option<String>,
     #[serde(default)]
     pub unique_by: UniqueBy,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub constraints: Vec<Constraint>,
 }
 #[async_trait]
 impl ValueSpec for ValueSpecObject {
     fn matches(&self, value: &Value) -> Result<(), NoMatchWithPath> {
         match value {
-            Value::Object(o) => self.spec.matches(o),
+            Value::Object(o) => {
+                self.spec.matches(o)?;
+                self.spec.check_constraints(&self.constraints, o)
+            }
             Value::Null => Err(NoMatchWithPath::new(MatchError::NotNullable)),
             a => Err(NoMatchWithPath::new(MatchError::InvalidType(
                 "object",
@@ -941,6 +957,7 @@ impl ValueSpec for ValueSpecObject {
         }
     }
     fn validate(&self, manifest: &Manifest) -> Result<(), NoMatchWithPath> {
+        self.spec.validate_constraints(&self.constraints)?;
         self.spec.validate(manifest)
     }
     async fn update(
@@ -1003,13 +1020,22 @@ impl Defaultable for ValueSpecObject {
         rng: &mut R,
         timeout: &Option<Duration>,
     ) -> Result<Value, Self::Error> {
-        self.spec.gen(rng, timeout).map(Value::Object)
+        Ok(Value::Object(self.spec.gen_satisfying(
+            &self.constraints,
+            rng,
+            timeout,
+        )?))
     }
 }
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct ConfigSpec(pub IndexMap<InternedString, ValueSpecAny>);
 impl ConfigSpec {
+    // generated defaults can be random, so a few attempts are made to satisfy the constraints
+    const GEN_ATTEMPTS: usize = 16;
+
+    /// Checks each field of `value`, but not the constraints across them: a package config is
+    /// checked with [ConfigActions::check](super::action::ConfigActions::check)
     pub fn matches(&self, value: &Config) -> Result<(), NoMatchWithPath> {
         for (key, val) in self.0.iter() {
             if let Some(v) = value.get(&**key) {
@@ -1034,6 +1060,48 @@ impl ConfigSpec {
         Ok(res)
     }
 
+    /// Like `gen`, but retries until the generated config satisfies `constraints`
+    pub fn gen_satisfying<R: Rng + CryptoRng + Sync + Send>(
+        &self,
+        constraints: &[Constraint],
+        rng: &mut R,
+        timeout: &Option<Duration>,
+    ) -> Result<Config, ConfigurationError> {
+        let mut attempts = 1;
+        loop {
+            let cfg = self.gen(rng, timeout)?;
+            match self.check_constraints(constraints, &cfg) {
+                Ok(()) => return Ok(cfg),
+                Err(e) if attempts >= Self::GEN_ATTEMPTS => return Err(e.into()),
+                Err(_) => attempts += 1,
+            }
+        }
+    }
+
+    /// Checks the rules across the fields of `cfg`, which must already match this spec
+    pub fn check_constraints(
+        &self,
+        constraints: &[Constraint],
+        cfg: &Config,
+    ) -> Result<(), NoMatchWithPath> {
+        for constraint in constraints {
+            constraint.check(cfg)?;
+        }
+        Ok(())
+    }
+
+    /// Checks that `constraints` only refer to fields of this spec
+    pub fn validate_constraints(&self, constraints: &[Constraint]) -> Result<(), NoMatchWithPath> {
+        for field in constraints.iter().flat_map(|c| c.fields()) {
+            if !self.0.contains_key(field) {
+                return Err(NoMatchWithPath::new(MatchError::UnknownField(
+                    field.clone(),
+                )));
+            }
+        }
+        Ok(())
+    }
+
     pub fn validate(&self, manifest: &Manifest) -> Result<(), NoMatchWithPath> {
         for (name, val) in &self.0 {
             val.validate(manifest)
@@ -1271,6 +1339,66 @@ impl DefaultableWith for ValueSpecString {
     }
 }
 
+/// A string that is kept in the secret store rather than in the config. The config holds a
+/// reference to it instead, and the plaintext is only accepted when the config is set.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ValueSpecSecret {
+    #[serde(flatten)]
+    pub inner: ValueSpecString,
+}
+#[async_trait]
+impl ValueSpec for ValueSpecSecret {
+    fn matches(&self, value: &Value) -> Result<(), NoMatchWithPath> {
+        if secret::reference_id(value).is_some() {
+            return Ok(());
+        }
+        self.inner.matches(value).map_err(|mut e| {
+            // never echo the secret back in the error
+            if let MatchError::Pattern(_, pattern) = e.error {
+                e.error = MatchError::Pattern(Arc::new("<redacted>".to_owned()), pattern);
+            }
+            e
+        })
+    }
+    fn validate(&self, manifest: &Manifest) -> Result<(), NoMatchWithPath> {
+        self.inner.validate(manifest)
+    }
+    async fn update(
+        &self,
+        _ctx: &RpcContext,
+        _manifest: &Manifest,
+        _config_overrides: &BTreeMap<PackageId, Config>,
+        _value: &mut Value,
+    ) -> Result<(), ConfigurationError> {
+        Ok(())
+    }
+    fn pointers(&self, _value: &Value) -> Result<BTreeSet<ValueSpecPointer>, NoMatchWithPath> {
+        Ok(BTreeSet::new())
+    }
+    fn requires(&self, _id: &PackageId, _value: &Value) -> bool {
+        false
+    }
+    fn eq(&self, lhs: &Value, rhs: &Value) -> bool {
+        match (secret::reference_id(lhs), secret::reference_id(rhs)) {
+            (Some(lhs), Some(rhs)) => lhs == rhs,
+            _ => self.inner.eq(lhs, rhs),
+        }
+    }
+}
+impl DefaultableWith for ValueSpecSecret {
+    type DefaultSpec = Option<DefaultString>;
+    type Error = TimeoutError;
+
+    fn gen_with<R: Rng + CryptoRng + Sync + Send + Sync + Send>(
+        &self,
+        spec: &Self::DefaultSpec,
+        rng: &mut R,
+        timeout: &Option<Duration>,
+    ) -> Result<Value, TimeoutError> {
+        self.inner.gen_with(spec, rng, timeout)
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum DefaultString {
@@ -2011,3 +2139,53 @@ fn regex_control() {
     .unwrap();
     println!("{}", serde_json::to_string_pretty(&spec).unwrap());
 }
+
+#[test]
+fn object_constraints() {
+    let spec: ValueSpecObject = serde_json::from_value(serde_json::json!({
+        "spec": {
+            "port": { "type": "number", "name": "Port", "nullable": false, "default": 8080, "integral": true, "range": "[1,65535]" },
+            "rpc-port": { "type": "number", "name": "RPC Port", "nullable": false, "default": 8332, "integral": true, "range": "[1,65535]" },
+            "tls": { "type": "boolean", "name": "TLS", "default": false },
+            "cert": { "type": "string", "name": "Certificate", "nullable": true, "default": null },
+            "password": { "type": "string", "name": "Password", "nullable": true, "default": null },
+        },
+        "constraints": [
+            { "type": "unique", "fields": ["port", "rpc-port"] },
+            { "type": "compare", "field": "port", "op": "<", "other": "rpc-port" },
+            { "type": "required-if", "field": "cert", "when": "tls" },
+            { "type": "mutually-exclusive", "fields": ["cert", "password"] },
+        ],
+    }))
+    .unwrap();
+    let check = |cfg: serde_json::Value| {
+        spec.matches(&serde_json::from_value(cfg).unwrap())
+            .map_err(|e| e.to_string())
+    };
+    let valid = serde_json::json!({ "port": 8080, "rpc-port": 8332, "tls": false, "cert": null, "password": null });
+    assert!(check(valid.clone()).is_ok());
+    let with = |key: &str, value: serde_json::Value| {
+        let mut cfg = valid.clone();
+        cfg[key] = value;
+        cfg
+    };
+    assert!(check(with("rpc-port", 8080.into()))
+        .unwrap_err()
+        .starts_with("rpc-port: "));
+    assert!(check(with("port", 9000.into()))
+        .unwrap_err()
+        .starts_with("port: "));
+    assert!(check(with("tls", true.into()))
+        .unwrap_err()
+        .starts_with("cert: "));
+    let mut both = with("cert", "abc".into());
+    both["password"] = "def".into();
+    assert!(check(both).unwrap_err().starts_with("password: "));
+    let generated = spec
+        .gen(
+            &mut <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(0),
+            &None,
+        )
+        .unwrap();
+    assert!(spec.matches(&generated).is_ok());
+}
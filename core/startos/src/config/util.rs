@@ -1,11 +1,12 @@
 use std::borrow::Cow;
 use std::ops::{Bound, RangeBounds, RangeInclusive};
 
+use imbl_value::InternedString;
 use patch_db::Value;
 use rand::distributions::Distribution;
 use rand::Rng;
 
-use super::Config;
+use super::{Config, MatchError, NoMatchWithPath, TypeOf};
 
 pub const STATIC_NULL: Value = Value::Null;
 
@@ -404,3 +405,161 @@ impl serde::ser::Serialize for UniqueBy {
         }
     }
 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Comparison {
+    #[serde(rename = "<")]
+    Lt,
+    #[serde(rename = "<=")]
+    Le,
+    #[serde(rename = ">")]
+    Gt,
+    #[serde(rename = ">=")]
+    Ge,
+    #[serde(rename = "==")]
+    Eq,
+    #[serde(rename = "!=")]
+    Ne,
+}
+impl Comparison {
+    /// `None` if the values cannot be ordered
+    pub fn holds(&self, lhs: &Value, rhs: &Value) -> Option<bool> {
+        use std::cmp::Ordering;
+
+        let ord = match (lhs, rhs) {
+            (Value::Number(l), Value::Number(r)) => l.as_f64()?.partial_cmp(&r.as_f64()?),
+            (Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+            _ => None,
+        };
+        Some(match self {
+            Comparison::Eq => lhs == rhs,
+            Comparison::Ne => lhs != rhs,
+            Comparison::Lt => ord? == Ordering::Less,
+            Comparison::Le => ord? != Ordering::Greater,
+            Comparison::Gt => ord? == Ordering::Greater,
+            Comparison::Ge => ord? != Ordering::Less,
+        })
+    }
+}
+impl std::fmt::Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Comparison::Lt => write!(f, "Less Than"),
+            Comparison::Le => write!(f, "At Most"),
+            Comparison::Gt => write!(f, "Greater Than"),
+            Comparison::Ge => write!(f, "At Least"),
+            Comparison::Eq => write!(f, "Equal To"),
+            Comparison::Ne => write!(f, "Different From"),
+        }
+    }
+}
+
+/// A rule across the fields of an object, on top of the specs of the fields themselves. Fields
+/// that are null count as unset.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[serde(tag = "type")]
+pub enum Constraint {
+    /// `field` must be set when `when` is equal to `equals`, or when `when` is set and not false
+    /// if `equals` is omitted
+    #[serde(rename_all = "kebab-case")]
+    RequiredIf {
+        field: InternedString,
+        when: InternedString,
+        #[serde(default)]
+        equals: Option<Value>,
+    },
+    /// At most one of `fields` may be set
+    MutuallyExclusive { fields: Vec<InternedString> },
+    /// `field` must compare to `other` by `op`, if both are set
+    Compare {
+        field: InternedString,
+        op: Comparison,
+        other: InternedString,
+    },
+    /// No two of `fields` may be set to the same value
+    Unique { fields: Vec<InternedString> },
+}
+impl Constraint {
+    pub fn fields(&self) -> Vec<&InternedString> {
+        match self {
+            Constraint::RequiredIf { field, when, .. } => vec![field, when],
+            Constraint::MutuallyExclusive { fields } | Constraint::Unique { fields } => {
+                fields.iter().collect()
+            }
+            Constraint::Compare { field, other, .. } => vec![field, other],
+        }
+    }
+
+    pub fn check(&self, cfg: &Config) -> Result<(), NoMatchWithPath> {
+        fn get<'a>(cfg: &'a Config, key: &InternedString) -> Option<&'a Value> {
+            cfg.get(&**key).filter(|v| !matches!(v, Value::Null))
+        }
+
+        match self {
+            Constraint::RequiredIf {
+                field,
+                when,
+                equals,
+            } => {
+                let applies = match equals {
+                    Some(equals) => cfg.get(&**when).unwrap_or(&STATIC_NULL) == equals,
+                    None => !matches!(get(cfg, when), None | Some(Value::Bool(false))),
+                };
+                if applies && get(cfg, field).is_none() {
+                    return Err(NoMatchWithPath::new(MatchError::RequiredIf(
+                        when.clone(),
+                        equals.as_ref().map_or("Set".to_owned(), |v| {
+                            serde_json::to_string(v).unwrap_or_default()
+                        }),
+                    ))
+                    .prepend(field.clone()));
+                }
+            }
+            Constraint::MutuallyExclusive { fields } => {
+                let mut set = fields.iter().filter(|f| get(cfg, f).is_some());
+                if let (Some(first), Some(second)) = (set.next(), set.next()) {
+                    return Err(
+                        NoMatchWithPath::new(MatchError::MutuallyExclusive(first.clone()))
+                            .prepend(second.clone()),
+                    );
+                }
+            }
+            Constraint::Compare { field, op, other } => {
+                if let (Some(lhs), Some(rhs)) = (get(cfg, field), get(cfg, other)) {
+                    match op.holds(lhs, rhs) {
+                        Some(true) => (),
+                        Some(false) => {
+                            return Err(NoMatchWithPath::new(MatchError::Comparison(
+                                *op,
+                                other.clone(),
+                            ))
+                            .prepend(field.clone()))
+                        }
+                        None => {
+                            return Err(NoMatchWithPath::new(MatchError::InvalidType(
+                                rhs.type_of(),
+                                lhs.type_of(),
+                            ))
+                            .prepend(field.clone()))
+                        }
+                    }
+                }
+            }
+            Constraint::Unique { fields } => {
+                for (i, field) in fields.iter().enumerate() {
+                    if let Some(value) = get(cfg, field) {
+                        if let Some(other) = fields[..i]
+                            .iter()
+                            .find(|other| get(cfg, other) == Some(value))
+                        {
+                            return Err(NoMatchWithPath::new(MatchError::NotUnique(other.clone()))
+                                .prepend(field.clone()));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -15,19 +15,21 @@ use rpc_toolkit::Context;
 use serde::Deserialize;
 use sqlx::postgres::PgConnectOptions;
 use sqlx::PgPool;
-use tokio::sync::{broadcast, oneshot, Mutex, RwLock};
+use tokio::sync::{broadcast, oneshot, watch, Mutex, RwLock};
 use tokio::time::Instant;
 use tracing::instrument;
 
 use super::setup::CURRENT_SECRET;
 use crate::account::AccountInfo;
 use crate::core::rpc_continuations::{RequestGuid, RestHandler, RpcContinuation};
+use crate::db::history::{DbHistory, DEFAULT_REVISION_CACHE_SIZE};
 use crate::db::model::{CurrentDependents, Database, PackageDataEntryMatchModelRef};
 use crate::db::prelude::PatchDbExt;
 use crate::dependencies::compute_dependency_config_errs;
 use crate::disk::OsPartitionInfo;
 use crate::init::{check_time_is_synchronized, init_postgres};
 use crate::install::cleanup::{cleanup_failed, uninstall};
+use crate::logs::forward::{DiskBuffer, LogForwardConfig};
 use crate::manager::ManagerMap;
 use crate::middleware::auth::HashSessionToken;
 use crate::net::net_controller::NetController;
@@ -111,6 +113,7 @@ pub struct RpcContextSeed {
     pub datadir: PathBuf,
     pub disk_guid: Arc<String>,
     pub db: PatchDb,
+    pub db_history: DbHistory,
     pub secret_store: PgPool,
     pub account: RwLock<AccountInfo>,
     pub net_controller: Arc<NetController>,
@@ -126,6 +129,8 @@ pub struct RpcContextSeed {
     pub client: Client,
     pub hardware: Hardware,
     pub start_time: Instant,
+    pub log_forward: watch::Sender<LogForwardConfig>,
+    pub log_forward_buffer: RwLock<Option<Arc<DiskBuffer>>>,
 }
 
 pub struct Hardware {
@@ -188,6 +193,9 @@ impl RpcContext {
             });
         }
 
+        let (log_forward, _) =
+            watch::channel(db.peek().await.as_server_info().as_log_forwarding().de()?);
+
         let seed = Arc::new(RpcContextSeed {
             is_closed: AtomicBool::new(false),
             datadir: base.datadir().to_path_buf(),
@@ -196,6 +204,10 @@ impl RpcContext {
             ethernet_interface: base.ethernet_interface,
             disk_guid,
             db,
+            db_history: DbHistory::new(
+                base.revision_cache_size
+                    .unwrap_or(DEFAULT_REVISION_CACHE_SIZE),
+            ),
             secret_store,
             account: RwLock::new(account),
             net_controller,
@@ -231,6 +243,8 @@ impl RpcContext {
                 .with_kind(crate::ErrorKind::ParseUrl)?,
             hardware: Hardware { devices, ram },
             start_time: Instant::now(),
+            log_forward,
+            log_forward_buffer: RwLock::new(None),
         });
 
         let res = Self(seed.clone());
@@ -0,0 +1,456 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use chrono::{DateTime, Utc};
+use patch_db::json_patch::{self, Patch, PatchOperation};
+use patch_db::json_ptr::JsonPointer;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tracing::instrument;
+
+use crate::context::RpcContext;
+use crate::prelude::*;
+
+pub const DEFAULT_REVISION_CACHE_SIZE: usize = 512;
+
+/// Subtrees of the database that `db.revert` is allowed to touch. Everything else is either
+/// derived from the state of the system or mirrored into running services, so rewriting it would
+/// only make the database lie.
+pub const SAFE_REVERT_PATHS: &[&str] = &["/ui"];
+
+/// The paths an operation reads from or writes to
+pub fn op_paths(op: &PatchOperation) -> Vec<String> {
+    match op {
+        PatchOperation::Add(op) => vec![op.path.to_string()],
+        PatchOperation::Remove(op) => vec![op.path.to_string()],
+        PatchOperation::Replace(op) => vec![op.path.to_string()],
+        PatchOperation::Test(op) => vec![op.path.to_string()],
+        PatchOperation::Move(op) => vec![op.path.to_string(), op.from.to_string()],
+        PatchOperation::Copy(op) => vec![op.path.to_string(), op.from.to_string()],
+    }
+}
+
+fn patch_paths(patch: &Patch) -> impl Iterator<Item = String> + '_ {
+    patch.0.iter().flat_map(op_paths)
+}
+
+pub fn apply_patch(doc: &mut Value, patch: &Patch) -> Result<(), Error> {
+    json_patch::patch(doc, patch)
+        .map(|_| ())
+        .map_err(|e| Error::new(eyre!("{}", e), ErrorKind::Database))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct HistoryEntry {
+    pub id: u64,
+    pub timestamp: DateTime<Utc>,
+    /// The RPC method that produced this revision, if it is known
+    pub source: Option<String>,
+    pub patch: Patch,
+}
+
+#[derive(Deserialize)]
+struct RawRevision {
+    id: u64,
+    patch: Patch,
+}
+
+struct HistoryInner {
+    /// The database right before the oldest entry. Values in patch-db share structure, so this
+    /// only costs memory for the parts that have changed since.
+    base: Value,
+    base_id: u64,
+    entries: VecDeque<HistoryEntry>,
+    /// Sources for revisions that were tagged before this task received them
+    pending_tags: BTreeMap<u64, String>,
+}
+impl HistoryInner {
+    fn sequence(&self) -> u64 {
+        self.entries.back().map_or(self.base_id, |e| e.id)
+    }
+
+    fn at(&self, revision: u64) -> Result<Value, Error> {
+        let mut state = self.base.clone();
+        for entry in self.entries.iter().take_while(|e| e.id <= revision) {
+            apply_patch(&mut state, &entry.patch)?;
+        }
+        Ok(state)
+    }
+}
+
+/// A bounded log of the most recent database revisions. Past states are reconstructed by
+/// replaying patches on top of a snapshot taken before the oldest of them.
+pub struct DbHistory {
+    capacity: usize,
+    inner: RwLock<Option<HistoryInner>>,
+}
+impl DbHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: RwLock::new(None),
+        }
+    }
+
+    async fn reset(&self, sequence: u64, state: Value) {
+        *self.inner.write().await = Some(HistoryInner {
+            base: state,
+            base_id: sequence,
+            entries: VecDeque::with_capacity(self.capacity),
+            pending_tags: BTreeMap::new(),
+        });
+    }
+
+    async fn record(&self, id: u64, patch: Patch) -> Result<(), Error> {
+        let mut guard = self.inner.write().await;
+        let Some(inner) = guard.as_mut() else {
+            return Ok(());
+        };
+        if id <= inner.sequence() {
+            return Ok(());
+        }
+        let source = inner.pending_tags.remove(&id);
+        inner.pending_tags.retain(|pending, _| *pending > id);
+        inner.entries.push_back(HistoryEntry {
+            id,
+            timestamp: Utc::now(),
+            source,
+            patch,
+        });
+        while inner.entries.len() > self.capacity {
+            if let Some(oldest) = inner.entries.pop_front() {
+                apply_patch(&mut inner.base, &oldest.patch)?;
+                inner.base_id = oldest.id;
+            }
+        }
+        Ok(())
+    }
+
+    /// Attributes `revision`, as returned by the write that produced it, to the RPC method
+    /// `source`.
+    pub async fn tag(&self, revision: u64, source: &str) {
+        let mut guard = self.inner.write().await;
+        let Some(inner) = guard.as_mut() else {
+            return;
+        };
+        if revision > inner.sequence() {
+            inner
+                .pending_tags
+                .entry(revision)
+                .or_insert_with(|| source.to_owned());
+        } else if let Some(entry) = inner.entries.iter_mut().find(|e| e.id == revision) {
+            entry.source.get_or_insert_with(|| source.to_owned());
+        }
+    }
+
+    /// Most recent revisions first
+    pub async fn list(&self, limit: Option<usize>) -> Result<Vec<HistoryEntry>, Error> {
+        let guard = self.inner.read().await;
+        let inner = guard.as_ref().ok_or_else(not_ready)?;
+        Ok(inner
+            .entries
+            .iter()
+            .rev()
+            .take(limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect())
+    }
+
+    /// Reconstructs the database as it was right after `revision` was applied.
+    pub async fn at(&self, revision: u64) -> Result<Value, Error> {
+        let guard = self.inner.read().await;
+        let inner = guard.as_ref().ok_or_else(not_ready)?;
+        if revision > inner.sequence() {
+            return Err(Error::new(
+                eyre!(
+                    "revision {} is in the future (current revision is {})",
+                    revision,
+                    inner.sequence()
+                ),
+                ErrorKind::NotFound,
+            ));
+        }
+        if revision < inner.base_id {
+            return Err(Error::new(
+                eyre!(
+                    "revision {} is no longer in history (earliest available is {})",
+                    revision,
+                    inner.base_id
+                ),
+                ErrorKind::NotFound,
+            ));
+        }
+        inner.at(revision)
+    }
+
+    /// Prepares the [Revert] of `revision`, after checking that it only touches
+    /// [SAFE_REVERT_PATHS] and that nothing it changed has been modified since.
+    pub async fn revert(&self, revision: u64) -> Result<Revert, Error> {
+        let guard = self.inner.read().await;
+        let inner = guard.as_ref().ok_or_else(not_ready)?;
+        let entry = inner
+            .entries
+            .iter()
+            .find(|e| e.id == revision)
+            .ok_or_else(|| {
+                Error::new(
+                    eyre!("revision {} is not in history", revision),
+                    ErrorKind::NotFound,
+                )
+            })?;
+        let after = inner.at(revision)?;
+        let inverse = json_patch::diff(&after, &inner.at(revision - 1)?);
+        let changed = patch_paths(&entry.patch)
+            .chain(patch_paths(&inverse))
+            .collect::<Vec<_>>();
+        if let Some(path) = changed.iter().find(|p| !is_safe_path(p)) {
+            return Err(Error::new(
+                eyre!(
+                    "revision {} modifies {}, which cannot be reverted",
+                    revision,
+                    path
+                ),
+                ErrorKind::InvalidRequest,
+            ));
+        }
+        for later in inner.entries.iter().filter(|e| e.id > revision) {
+            if let Some(path) =
+                patch_paths(&later.patch).find(|path| changed.iter().any(|p| overlaps(p, path)))
+            {
+                return Err(Error::new(
+                    eyre!(
+                        "{} was modified again in revision {}, revert that first",
+                        path,
+                        later.id
+                    ),
+                    ErrorKind::InvalidRequest,
+                ));
+            }
+        }
+        let expected = inverse
+            .0
+            .iter()
+            .flat_map(op_paths)
+            .map(|path| {
+                let ptr = path.parse::<JsonPointer>().with_kind(ErrorKind::Database)?;
+                let value = ptr.get(&after).cloned();
+                Ok((ptr, value))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Revert {
+            revision,
+            expected,
+            inverse,
+        })
+    }
+}
+
+/// Undoes a single revision. Meant to be applied from within the same database write that reads
+/// the current state, so that it can never clobber a change made after it was prepared.
+#[derive(Debug)]
+pub struct Revert {
+    revision: u64,
+    /// What each path touched by the revision is expected to still hold
+    expected: Vec<(JsonPointer, Option<Value>)>,
+    inverse: Patch,
+}
+impl Revert {
+    pub fn apply(&self, db: &mut Value) -> Result<(), Error> {
+        if let Some((ptr, _)) = self
+            .expected
+            .iter()
+            .find(|(ptr, expected)| ptr.get(db) != expected.as_ref())
+        {
+            return Err(Error::new(
+                eyre!(
+                    "{} no longer matches revision {}, revert the later change first",
+                    ptr,
+                    self.revision
+                ),
+                ErrorKind::InvalidRequest,
+            ));
+        }
+        apply_patch(db, &self.inverse)
+    }
+}
+
+fn not_ready() -> Error {
+    Error::new(
+        eyre!("database history is not available yet"),
+        ErrorKind::Database,
+    )
+}
+
+fn is_prefix(prefix: &str, path: &str) -> bool {
+    path == prefix
+        || path
+            .strip_prefix(prefix)
+            .map_or(false, |rest| rest.starts_with('/'))
+}
+
+fn is_safe_path(path: &str) -> bool {
+    SAFE_REVERT_PATHS.iter().any(|safe| is_prefix(safe, path))
+}
+
+fn overlaps(a: &str, b: &str) -> bool {
+    is_prefix(a, b) || is_prefix(b, a)
+}
+
+#[instrument(skip_all)]
+pub async fn launch_db_history_task(ctx: RpcContext) {
+    let mut shutdown = ctx.shutdown.subscribe();
+    loop {
+        let (dump, mut sub) = ctx.db.dump_and_sub().await;
+        ctx.db_history.reset(dump.id, dump.value).await;
+        loop {
+            let rev = tokio::select! {
+                _ = shutdown.recv() => return,
+                rev = sub.recv() => match rev {
+                    Some(rev) => rev,
+                    None => return,
+                },
+            };
+            let res = match to_value(&*rev).and_then(from_value::<RawRevision>) {
+                Ok(rev) => ctx.db_history.record(rev.id, rev.patch).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = res {
+                tracing::error!("Error recording database history, resyncing: {}", e);
+                tracing::debug!("{:?}", e);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    fn value(v: serde_json::Value) -> Value {
+        serde_json::from_value(v).unwrap()
+    }
+
+    fn patch(v: serde_json::Value) -> Patch {
+        serde_json::from_value(v).unwrap()
+    }
+
+    #[tokio::test]
+    async fn reconstructs_past_revisions() {
+        let history = DbHistory::new(2);
+        history.reset(1, value(json!({ "ui": { "n": 0 } }))).await;
+        for i in 2..=4 {
+            history
+                .record(
+                    i,
+                    patch(json!([{ "op": "replace", "path": "/ui/n", "value": i }])),
+                )
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            history.at(4).await.unwrap(),
+            value(json!({ "ui": { "n": 4 } }))
+        );
+        assert_eq!(
+            history.at(2).await.unwrap(),
+            value(json!({ "ui": { "n": 2 } }))
+        );
+        assert!(history.at(1).await.is_err());
+        assert!(history.at(5).await.is_err());
+        assert_eq!(history.list(Some(1)).await.unwrap()[0].id, 4);
+    }
+
+    #[tokio::test]
+    async fn revert_rejects_unsafe_and_conflicting() {
+        let history = DbHistory::new(16);
+        history
+            .reset(
+                1,
+                value(json!({ "ui": { "a": 1, "b": 1 }, "server-info": { "zram": true } })),
+            )
+            .await;
+        history
+            .record(
+                2,
+                patch(json!([{ "op": "replace", "path": "/server-info/zram", "value": false }])),
+            )
+            .await
+            .unwrap();
+        history
+            .record(
+                3,
+                patch(json!([{ "op": "replace", "path": "/ui/a", "value": 2 }])),
+            )
+            .await
+            .unwrap();
+        history
+            .record(
+                4,
+                patch(json!([{ "op": "replace", "path": "/ui/a", "value": 3 }])),
+            )
+            .await
+            .unwrap();
+        history
+            .record(5, patch(json!([{ "op": "remove", "path": "/ui/b" }])))
+            .await
+            .unwrap();
+        history.tag(5, "db.put.ui").await;
+
+        assert!(history.revert(2).await.is_err());
+        assert!(history.revert(3).await.is_err());
+        let mut state = history.at(5).await.unwrap();
+        history.revert(5).await.unwrap().apply(&mut state).unwrap();
+        assert_eq!(
+            state,
+            value(json!({ "ui": { "a": 3, "b": 1 }, "server-info": { "zram": false } }))
+        );
+        assert_eq!(
+            history.list(Some(1)).await.unwrap()[0].source.as_deref(),
+            Some("db.put.ui")
+        );
+    }
+
+    #[tokio::test]
+    async fn revert_fails_if_state_moved_on() {
+        let history = DbHistory::new(16);
+        history.reset(1, value(json!({ "ui": { "a": 1 } }))).await;
+        history
+            .record(
+                2,
+                patch(json!([{ "op": "replace", "path": "/ui/a", "value": 2 }])),
+            )
+            .await
+            .unwrap();
+        let revert = history.revert(2).await.unwrap();
+
+        // a write that the history task has not seen yet
+        let mut state = value(json!({ "ui": { "a": 3 } }));
+        assert!(revert.apply(&mut state).is_err());
+        assert_eq!(state, value(json!({ "ui": { "a": 3 } })));
+
+        let mut state = value(json!({ "ui": { "a": 2 } }));
+        revert.apply(&mut state).unwrap();
+        assert_eq!(state, value(json!({ "ui": { "a": 1 } })));
+    }
+
+    #[tokio::test]
+    async fn tags_revisions_recorded_later() {
+        let history = DbHistory::new(16);
+        history.reset(1, value(json!({ "ui": {} }))).await;
+        history.tag(2, "db.apply").await;
+        history
+            .record(
+                2,
+                patch(json!([{ "op": "add", "path": "/ui/a", "value": 1 }])),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            history.list(Some(1)).await.unwrap()[0].source.as_deref(),
+            Some("db.apply")
+        );
+    }
+}
@@ -1,11 +1,14 @@
+pub mod history;
 pub mod model;
 pub mod package;
 pub mod prelude;
 
 use std::future::Future;
+use std::panic::UnwindSafe;
 use std::path::PathBuf;
 use std::sync::Arc;
 
+use clap::ArgMatches;
 use futures::{FutureExt, SinkExt, StreamExt};
 use patch_db::json_ptr::JsonPointer;
 use patch_db::{Dump, Revision};
@@ -174,7 +177,7 @@ pub async fn subscribe(ctx: RpcContext, req: Request<Body>) -> Result<Response<B
     Ok(res)
 }
 
-#[command(subcommands(dump, put, apply))]
+#[command(subcommands(dump, put, apply, history, at, revert))]
 pub fn db() -> Result<(), RpcError> {
     Ok(())
 }
@@ -325,25 +328,50 @@ pub async fn apply(
     #[arg]
     path: Option<PathBuf>,
 ) -> Result<(), Error> {
-    ctx.db
+    mutate_tagged(&ctx, "db.apply", |db| {
+        let res = apply_expr(
+            serde_json::to_value(db.clone())
+                .with_kind(ErrorKind::Deserialization)?
+                .into(),
+            &expr,
+        )?;
+
+        *db = to_value(
+            &serde_json::from_value::<model::Database>(res.clone().into()).with_ctx(|_| {
+                (
+                    crate::ErrorKind::Deserialization,
+                    "result does not match database model",
+                )
+            })?,
+        )?;
+        Ok(())
+    })
+    .await
+}
+
+/// Runs `f` on the raw database within a single write, and attributes the resulting revision to
+/// `source` in the history. Revisions that can't be told apart from a concurrent write are left
+/// untagged.
+async fn mutate_tagged(
+    ctx: &RpcContext,
+    source: &str,
+    f: impl FnOnce(&mut patch_db::Value) -> Result<(), Error> + UnwindSafe + Send,
+) -> Result<(), Error> {
+    let before = ctx.db.sequence().await;
+    let changed = ctx
+        .db
         .mutate(|db| {
-            let res = apply_expr(
-                serde_json::to_value(patch_db::Value::from(db.clone()))
-                    .with_kind(ErrorKind::Deserialization)?
-                    .into(),
-                &expr,
-            )?;
-
-            db.ser(
-                &serde_json::from_value::<model::Database>(res.clone().into()).with_ctx(|_| {
-                    (
-                        crate::ErrorKind::Deserialization,
-                        "result does not match database model",
-                    )
-                })?,
-            )
+            let db = <&mut patch_db::Value>::from(db);
+            let prev = db.clone();
+            f(db)?;
+            Ok(*db != prev)
         })
-        .await
+        .await?;
+    let after = ctx.db.sequence().await;
+    if changed && after == before + 1 {
+        ctx.db_history.tag(after, source).await;
+    }
+    Ok(())
 }
 
 #[command(subcommands(ui))]
@@ -365,6 +393,77 @@ pub async fn ui(
         .parse::<JsonPointer>()
         .with_kind(ErrorKind::Database)?
         + &pointer;
-    ctx.db.put(&ptr, &value).await?;
+    if let Some(rev) = ctx.db.put(&ptr, &value).await? {
+        ctx.db_history.tag(rev.id, "db.put.ui").await;
+    }
     Ok(())
 }
+
+fn display_history(entries: Vec<history::HistoryEntry>, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(entries, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "REVISION", "TIME", "SOURCE", "PATHS"]);
+    for entry in entries {
+        table.add_row(row![
+            entry.id,
+            entry.timestamp.to_rfc3339(),
+            entry.source.as_deref().unwrap_or("N/A"),
+            entry
+                .patch
+                .0
+                .iter()
+                .flat_map(history::op_paths)
+                .collect::<Vec<_>>()
+                .join("\n"),
+        ]);
+    }
+    table.print_tty(false).unwrap();
+}
+
+#[command(display(display_history))]
+pub async fn history(
+    #[context] ctx: RpcContext,
+    #[arg(long = "limit")] limit: Option<usize>,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<history::HistoryEntry>, Error> {
+    ctx.db_history.list(limit).await
+}
+
+#[command(display(display_serializable))]
+pub async fn at(
+    #[context] ctx: RpcContext,
+    #[arg(long = "revision")] revision: u64,
+    #[arg] pointer: Option<JsonPointer>,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Value, Error> {
+    let state = serde_json::to_value(ctx.db_history.at(revision).await?)
+        .with_kind(ErrorKind::Serialization)?;
+    match pointer {
+        Some(ptr) => state.pointer(&ptr.to_string()).cloned().ok_or_else(|| {
+            Error::new(
+                eyre!("{} does not exist at revision {}", ptr, revision),
+                ErrorKind::NotFound,
+            )
+        }),
+        None => Ok(state),
+    }
+}
+
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn revert(
+    #[context] ctx: RpcContext,
+    #[arg(long = "revision")] revision: u64,
+) -> Result<(), Error> {
+    let revert = ctx.db_history.revert(revision).await?;
+    mutate_tagged(&ctx, "db.revert", |db| revert.apply(db)).await
+}
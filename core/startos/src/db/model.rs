@@ -8,7 +8,7 @@ use imbl_value::InternedString;
 use ipnet::{Ipv4Net, Ipv6Net};
 use isocountry::CountryCode;
 use itertools::Itertools;
-use models::{DataUrl, HealthCheckId, InterfaceId};
+use models::{DataUrl, DeviceId, HealthCheckId, InterfaceId, VolumeId};
 use openssl::hash::MessageDigest;
 use patch_db::{HasModel, Value};
 use reqwest::Url;
@@ -17,14 +17,19 @@ use ssh_key::public::Ed25519PublicKey;
 
 use crate::account::AccountInfo;
 use crate::config::spec::PackagePointerSpec;
+use crate::devices::DeviceMatch;
 use crate::install::progress::InstallProgress;
+use crate::install::update::AutoUpdateConfig;
+use crate::logs::forward::LogForwardConfig;
 use crate::net::utils::{get_iface_ipv4_addr, get_iface_ipv6_addr};
 use crate::prelude::*;
+use crate::procedure::docker::ResourceLimits;
 use crate::s9pk::manifest::{Manifest, PackageId};
 use crate::status::Status;
 use crate::util::cpupower::{Governor};
 use crate::util::Version;
 use crate::version::{Current, VersionT};
+use crate::volume::snapshot::AutoSnapshotConfig;
 use crate::{ARCH, PLATFORM};
 
 #[derive(Debug, Deserialize, Serialize, HasModel)]
@@ -85,6 +90,12 @@ impl Database {
                 ntp_synced: false,
                 zram: true,
                 governor: None,
+                log_forwarding: LogForwardConfig::default(),
+                registry_server: false,
+                auto_update: AutoUpdateConfig::default(),
+                rollback_retention: crate::install::rollback::default_retention(),
+                volume_usage_thresholds: crate::volume::default_usage_thresholds(),
+                auto_snapshot: AutoSnapshotConfig::default(),
             },
             package_data: AllPackageData::default(),
             ui: serde_json::from_str(include_str!(concat!(
@@ -137,6 +148,20 @@ pub struct ServerInfo {
     #[serde(default)]
     pub zram: bool,
     pub governor: Option<Governor>,
+    #[serde(default)]
+    pub log_forwarding: LogForwardConfig,
+    #[serde(default)]
+    pub registry_server: bool,
+    #[serde(default)]
+    pub auto_update: AutoUpdateConfig,
+    /// How many previous versions of each package are kept for rollback
+    #[serde(default = "crate::install::rollback::default_retention")]
+    pub rollback_retention: usize,
+    /// Percentages of a volume quota at which a notification is issued
+    #[serde(default = "crate::volume::default_usage_thresholds")]
+    pub volume_usage_thresholds: Vec<u8>,
+    #[serde(default)]
+    pub auto_snapshot: AutoSnapshotConfig,
 }
 
 #[derive(Debug, Deserialize, Serialize, HasModel)]
@@ -459,6 +484,15 @@ pub struct InstalledPackageInfo {
     pub current_dependents: CurrentDependents,
     pub current_dependencies: CurrentDependencies,
     pub interface_addresses: InterfaceAddressMap,
+    /// Overrides of the resource limits declared in the manifest
+    #[serde(default)]
+    pub resource_limits: ResourceLimits,
+    /// Overrides of the quotas declared in the manifest, in MB
+    #[serde(default)]
+    pub volume_quotas: BTreeMap<VolumeId, u64>,
+    /// Device requests the user approved, with the matcher they approved
+    #[serde(default)]
+    pub approved_devices: BTreeMap<DeviceId, DeviceMatch>,
 }
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
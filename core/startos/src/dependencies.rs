@@ -10,6 +10,7 @@ use serde::{Deserialize, Serialize};
 use tracing::instrument;
 
 use crate::config::action::ConfigRes;
+use crate::config::history::{record_applied, RevisionSource};
 use crate::config::spec::PackagePointerSpec;
 use crate::config::{not_found, Config, ConfigSpec, ConfigureContext};
 use crate::context::RpcContext;
@@ -145,7 +146,15 @@ pub async fn configure_impl(
         dry_run: false,
         overrides,
     };
-    crate::config::configure(&ctx, &dep_id, configure_context).await?;
+    let configured = crate::config::configure(&ctx, &dep_id, configure_context).await?;
+    record_applied(
+        &ctx,
+        &dep_id,
+        RevisionSource::AutoConfigure,
+        None,
+        &configured,
+    )
+    .await;
     Ok(())
 }
 
@@ -213,7 +222,8 @@ pub async fn configure_logic(
     let old_config = if let Some(config) = maybe_config {
         config
     } else {
-        spec.gen(
+        spec.gen_satisfying(
+            &dependency_config_action.constraints,
             &mut rand::rngs::StdRng::from_entropy(),
             &Some(Duration::new(10, 0)),
         )?
@@ -0,0 +1,727 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::ArgMatches;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use models::DeviceId;
+use rpc_toolkit::command;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+use tracing::instrument;
+
+use crate::context::RpcContext;
+use crate::prelude::*;
+use crate::s9pk::manifest::PackageId;
+use crate::util::display_none;
+use crate::util::lshw::businfo;
+use crate::util::serde::{display_serializable, IoFormat};
+
+const USB_SYSFS_DIR: &str = "/sys/bus/usb/devices";
+const PCI_SYSFS_DIR: &str = "/sys/bus/pci/devices";
+const BLOCK_SYSFS_DIR: &str = "/sys/dev/block";
+
+/// lshw classes a package may ask for. The others (storage, bridge, bus, ...) would hand the
+/// container the controllers the host's own disks and buses hang off of.
+const ALLOWED_CLASSES: &[&str] = &[
+    "communication",
+    "display",
+    "generic",
+    "input",
+    "multimedia",
+    "printer",
+];
+
+/// A USB vendor or product id, written as 4 hex digits like `lsusb` does
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct UsbId(pub u16);
+impl std::fmt::Display for UsbId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04x}", self.0)
+    }
+}
+impl std::str::FromStr for UsbId {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        u16::from_str_radix(s.trim().trim_start_matches("0x"), 16)
+            .map(UsbId)
+            .with_kind(ErrorKind::ParseNumber)
+    }
+}
+impl<'de> Deserialize<'de> for UsbId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+impl Serialize for UsbId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+/// Which host devices a request applies to
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "kebab-case")]
+pub enum DeviceMatch {
+    /// USB devices with this vendor id, and product id if given
+    #[serde(rename_all = "kebab-case")]
+    Usb {
+        vendor_id: UsbId,
+        #[serde(default)]
+        product_id: Option<UsbId>,
+    },
+    /// USB devices with an interface of this class, e.g. 3 for HID
+    #[serde(rename_all = "kebab-case")]
+    UsbClass { class: u8 },
+    /// Devices of an lshw class, e.g. `display` for GPUs
+    #[serde(rename_all = "kebab-case")]
+    Class { class: String },
+}
+impl std::fmt::Display for DeviceMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Usb {
+                vendor_id,
+                product_id: Some(product_id),
+            } => write!(f, "usb {}:{}", vendor_id, product_id),
+            Self::Usb { vendor_id, .. } => write!(f, "usb {}:*", vendor_id),
+            Self::UsbClass { class } => write!(f, "usb class {:02x}", class),
+            Self::Class { class } => write!(f, "class {}", class),
+        }
+    }
+}
+
+/// Host devices a package asks for. Nothing is passed through until the user approves the
+/// request with `package.device.approve`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DeviceRequest {
+    /// Shown to the user when asked to approve
+    pub description: String,
+    #[serde(flatten)]
+    pub matches: DeviceMatch,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct DeviceRequests(pub BTreeMap<DeviceId, DeviceRequest>);
+impl DeviceRequests {
+    #[instrument(skip_all)]
+    pub fn validate(&self) -> Result<(), Error> {
+        for (id, request) in &self.0 {
+            if let DeviceMatch::Class { class } = &request.matches {
+                if !ALLOWED_CLASSES.contains(&class.as_str()) {
+                    return Err(Error::new(
+                        eyre!(
+                            "Device {}: class {:?} cannot be passed through, must be one of {}",
+                            id,
+                            class,
+                            ALLOWED_CLASSES.join(", ")
+                        ),
+                        ErrorKind::ValidateS9pk,
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A USB device plugged into the host
+#[derive(Clone, Debug, Default)]
+struct UsbDevice {
+    sysfs: PathBuf,
+    vendor_id: Option<UsbId>,
+    product_id: Option<UsbId>,
+    classes: BTreeSet<u8>,
+}
+impl UsbDevice {
+    fn matches(&self, m: &DeviceMatch) -> bool {
+        match m {
+            DeviceMatch::Usb {
+                vendor_id,
+                product_id,
+            } => {
+                self.vendor_id == Some(*vendor_id)
+                    && product_id.map_or(true, |p| self.product_id == Some(p))
+            }
+            DeviceMatch::UsbClass { class } => self.classes.contains(class),
+            DeviceMatch::Class { .. } => false,
+        }
+    }
+}
+
+async fn read_attr(dir: &Path, attr: &str) -> Option<String> {
+    tokio::fs::read_to_string(dir.join(attr))
+        .await
+        .ok()
+        .map(|s| s.trim().to_owned())
+}
+
+async fn usb_devices() -> Result<Vec<UsbDevice>, Error> {
+    let mut devices = Vec::new();
+    if tokio::fs::metadata(USB_SYSFS_DIR).await.is_err() {
+        return Ok(devices);
+    }
+    let mut entries = tokio::fs::read_dir(USB_SYSFS_DIR).await?;
+    let mut interfaces = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let sysfs = tokio::fs::canonicalize(entry.path()).await?;
+        if name.contains(':') {
+            if let Some(class) = read_attr(&sysfs, "bInterfaceClass").await {
+                interfaces.push((name, class));
+            }
+            continue;
+        }
+        let Some(vendor_id) = read_attr(&sysfs, "idVendor").await else {
+            continue;
+        };
+        let mut device = UsbDevice {
+            vendor_id: vendor_id.parse().ok(),
+            product_id: read_attr(&sysfs, "idProduct")
+                .await
+                .and_then(|p| p.parse().ok()),
+            classes: read_attr(&sysfs, "bDeviceClass")
+                .await
+                .and_then(|c| u8::from_str_radix(&c, 16).ok())
+                .into_iter()
+                .collect(),
+            sysfs,
+        };
+        device.classes.retain(|c| *c != 0); // 0 means "defined by the interfaces"
+        devices.push((name, device));
+    }
+    for (name, class) in interfaces {
+        let Some((device, _)) = name.split_once(':') else {
+            continue;
+        };
+        if let (Some((_, d)), Ok(class)) = (
+            devices.iter_mut().find(|(n, _)| n == device),
+            u8::from_str_radix(&class, 16),
+        ) {
+            d.classes.insert(class);
+        }
+    }
+    Ok(devices.into_iter().map(|(_, d)| d).collect())
+}
+
+/// The sysfs directory of the device at `businfo` as reported by lshw
+fn businfo_sysfs(businfo: &str) -> Option<PathBuf> {
+    let (bus, addr) = businfo.split_once('@')?;
+    match bus {
+        "pci" => Some(Path::new(PCI_SYSFS_DIR).join(addr)),
+        "usb" => {
+            let (bus, port) = addr.split_once(':')?;
+            Some(Path::new(USB_SYSFS_DIR).join(format!("{}-{}", bus, port)))
+        }
+        _ => None,
+    }
+}
+
+fn uevent_devname(uevent: &str) -> Option<&str> {
+    uevent
+        .lines()
+        .find_map(|l| l.strip_prefix("DEVNAME="))
+        .map(|d| d.trim())
+}
+
+/// Finds the device nodes of `sysfs` and its children, without following the links sysfs uses
+/// to point at other devices
+fn device_nodes<'a>(sysfs: &'a Path, res: &'a mut BTreeSet<PathBuf>) -> BoxFuture<'a, ()> {
+    async move {
+        if let Some(devname) = tokio::fs::read_to_string(sysfs.join("uevent"))
+            .await
+            .ok()
+            .as_deref()
+            .and_then(uevent_devname)
+        {
+            res.insert(Path::new("/dev").join(devname));
+        }
+        let Ok(mut entries) = tokio::fs::read_dir(sysfs).await else {
+            return;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if entry.file_type().await.map_or(false, |t| t.is_dir()) {
+                device_nodes(&entry.path(), res).await;
+            }
+        }
+    }
+    .boxed()
+}
+
+/// The `major:minor` ids of the devices with a mounted filesystem, and the sources of those mounts
+/// that are device nodes, from `/proc/self/mountinfo`. Mounts of some filesystems, like btrfs,
+/// report an anonymous id, so only the source identifies the device.
+fn parse_mountinfo(mountinfo: &str) -> (BTreeSet<String>, BTreeSet<PathBuf>) {
+    let mut ids = BTreeSet::new();
+    let mut sources = BTreeSet::new();
+    for line in mountinfo.lines() {
+        if let Some(id) = line.split_whitespace().nth(2) {
+            ids.insert(id.to_owned());
+        }
+        if let Some((_, rest)) = line.split_once(" - ") {
+            if let Some(source) = rest.split_whitespace().nth(1) {
+                if source.starts_with("/dev/") {
+                    sources.insert(PathBuf::from(source));
+                }
+            }
+        }
+    }
+    (ids, sources)
+}
+
+fn block_dev_id(meta: &std::fs::Metadata) -> Option<String> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    if !meta.file_type().is_block_device() {
+        return None;
+    }
+    Some(format!(
+        "{}:{}",
+        nix::sys::stat::major(meta.rdev()),
+        nix::sys::stat::minor(meta.rdev())
+    ))
+}
+
+/// The `major:minor` ids of every block device the host's mounted filesystems live on: the
+/// mounted devices, the disks they are partitions of, and the devices they are layered on top of
+/// (dm-crypt, lvm, raid).
+#[instrument(skip_all)]
+async fn host_block_devices() -> Result<BTreeSet<String>, Error> {
+    let (mut queue, sources) =
+        parse_mountinfo(&tokio::fs::read_to_string("/proc/self/mountinfo").await?);
+    for source in sources {
+        if let Ok(meta) = tokio::fs::metadata(&source).await {
+            queue.extend(block_dev_id(&meta));
+        }
+    }
+    let mut queue = queue.into_iter().collect::<Vec<_>>();
+    let mut res = BTreeSet::new();
+    while let Some(id) = queue.pop() {
+        if !res.insert(id.clone()) {
+            continue;
+        }
+        let Ok(sysfs) = tokio::fs::canonicalize(Path::new(BLOCK_SYSFS_DIR).join(&id)).await else {
+            continue;
+        };
+        // the sysfs directory of a partition is inside the one of its disk
+        if let Some(disk) = sysfs.parent() {
+            queue.extend(read_attr(disk, "dev").await);
+        }
+        if let Ok(mut slaves) = tokio::fs::read_dir(sysfs.join("slaves")).await {
+            while let Ok(Some(slave)) = slaves.next_entry().await {
+                queue.extend(read_attr(&slave.path(), "dev").await);
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// The device nodes on the host that `matches` applies to. Block devices in `host_devices`, as
+/// returned by [host_block_devices], are never included.
+#[instrument(skip_all)]
+async fn matching_nodes(
+    matches: &DeviceMatch,
+    usb: &[UsbDevice],
+    host_devices: &BTreeSet<String>,
+) -> Result<BTreeSet<PathBuf>, Error> {
+    let mut dirs = Vec::new();
+    if let DeviceMatch::Class { class } = matches {
+        for businfo in businfo(class).await? {
+            if let Some(dir) = businfo_sysfs(&businfo) {
+                if let Ok(dir) = tokio::fs::canonicalize(dir).await {
+                    dirs.push(dir);
+                }
+            }
+        }
+    } else {
+        dirs.extend(
+            usb.iter()
+                .filter(|d| d.matches(matches))
+                .map(|d| d.sysfs.clone()),
+        );
+    }
+    let mut nodes = BTreeSet::new();
+    for dir in dirs {
+        device_nodes(&dir, &mut nodes).await;
+    }
+    nodes.retain(|n| n.starts_with("/dev"));
+    let mut res = BTreeSet::new();
+    for node in nodes {
+        let in_use = tokio::fs::metadata(&node)
+            .await
+            .ok()
+            .and_then(|m| block_dev_id(&m))
+            .map_or(false, |id| host_devices.contains(&id));
+        if in_use {
+            tracing::warn!(
+                "Not passing through {}: it holds a filesystem used by the host",
+                node.display()
+            );
+        } else {
+            res.insert(node);
+        }
+    }
+    Ok(res)
+}
+
+/// The device requests of `pkg_id` that the user approved. An approval only holds for the
+/// matcher it was given for, so a package cannot widen its access in an update.
+async fn approved(ctx: &RpcContext, pkg_id: &PackageId) -> Result<Vec<DeviceMatch>, Error> {
+    let Some(installed) = ctx
+        .db
+        .peek()
+        .await
+        .as_package_data()
+        .as_idx(pkg_id)
+        .and_then(|p| p.as_installed())
+        .map(|i| i.de())
+        .transpose()?
+    else {
+        return Ok(Vec::new());
+    };
+    Ok(installed
+        .manifest
+        .devices
+        .0
+        .into_iter()
+        .filter(|(id, r)| installed.approved_devices.get(id) == Some(&r.matches))
+        .map(|(_, r)| r.matches)
+        .collect())
+}
+
+/// The device nodes to pass through to the main container of `pkg_id`
+pub async fn approved_nodes(
+    ctx: &RpcContext,
+    pkg_id: &PackageId,
+) -> Result<BTreeSet<PathBuf>, Error> {
+    let approved = approved(ctx, pkg_id).await?;
+    let mut nodes = BTreeSet::new();
+    if approved.is_empty() {
+        return Ok(nodes);
+    }
+    let usb = usb_devices().await?;
+    let host_devices = host_block_devices().await?;
+    for m in approved {
+        nodes.extend(matching_nodes(&m, &usb, &host_devices).await?);
+    }
+    Ok(nodes)
+}
+
+pub async fn docker_args(ctx: &RpcContext, pkg_id: &PackageId) -> Result<Vec<OsString>, Error> {
+    Ok(approved_nodes(ctx, pkg_id)
+        .await?
+        .into_iter()
+        .flat_map(|n| [OsString::from("--device"), n.into_os_string()])
+        .collect())
+}
+
+#[command(subcommands(list, approve, revoke))]
+pub fn device() -> Result<(), Error> {
+    Ok(())
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DeviceInfo {
+    pub id: DeviceId,
+    pub description: String,
+    pub matches: DeviceMatch,
+    pub approved: bool,
+    /// Device nodes on the host that the request currently applies to
+    pub nodes: BTreeSet<PathBuf>,
+}
+
+fn display_devices(devices: Vec<DeviceInfo>, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(devices, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "DEVICE", "DESCRIPTION", "MATCHES", "APPROVED", "NODES"]);
+    for device in devices {
+        table.add_row(row![
+            &device.id.to_string(),
+            &device.description,
+            &device.matches.to_string(),
+            &device.approved.to_string(),
+            &device
+                .nodes
+                .iter()
+                .map(|n| n.display().to_string())
+                .collect::<Vec<_>>()
+                .join("\n"),
+        ]);
+    }
+    table.print_tty(false).unwrap();
+}
+
+/// Lists the devices a service asks for, and which host devices they currently match
+#[command(display(display_devices))]
+pub async fn list(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<DeviceInfo>, Error> {
+    let installed = ctx
+        .db
+        .peek()
+        .await
+        .as_package_data()
+        .as_idx(&id)
+        .and_then(|p| p.as_installed())
+        .or_not_found(&id)?
+        .de()?;
+    let usb = usb_devices().await?;
+    let host_devices = host_block_devices().await?;
+    let mut res = Vec::new();
+    for (device_id, request) in installed.manifest.devices.0 {
+        res.push(DeviceInfo {
+            approved: installed.approved_devices.get(&device_id) == Some(&request.matches),
+            nodes: matching_nodes(&request.matches, &usb, &host_devices).await?,
+            id: device_id,
+            description: request.description,
+            matches: request.matches,
+        });
+    }
+    Ok(res)
+}
+
+async fn restart(ctx: &RpcContext, id: &PackageId) -> Result<(), Error> {
+    let version = ctx
+        .db
+        .peek()
+        .await
+        .as_package_data()
+        .as_idx(id)
+        .and_then(|p| p.as_installed())
+        .or_not_found(id)?
+        .as_manifest()
+        .as_version()
+        .de()?;
+    if let Some(manager) = ctx.managers.get(&(id.clone(), version)).await {
+        manager.restart().await;
+    }
+    Ok(())
+}
+
+/// Lets a service use the host devices matching one of its requests. A running service is
+/// restarted to pick them up.
+#[command(display(display_none), metadata(sync_db = true))]
+#[instrument(skip_all)]
+pub async fn approve(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg] device: DeviceId,
+) -> Result<(), Error> {
+    ctx.db
+        .mutate(|db| {
+            let installed = db
+                .as_package_data_mut()
+                .as_idx_mut(&id)
+                .and_then(|p| p.as_installed_mut())
+                .or_not_found(&id)?;
+            let request = installed
+                .as_manifest()
+                .as_devices()
+                .de()?
+                .0
+                .remove(&device)
+                .ok_or_else(|| {
+                    Error::new(
+                        eyre!("{} does not request device {}", id, device),
+                        ErrorKind::NotFound,
+                    )
+                })?;
+            installed
+                .as_approved_devices_mut()
+                .insert(&device, &request.matches)
+        })
+        .await?;
+    restart(&ctx, &id).await
+}
+
+/// Withdraws the approval of a device request. A running service is restarted so the devices
+/// are removed from it.
+#[command(display(display_none), metadata(sync_db = true))]
+#[instrument(skip_all)]
+pub async fn revoke(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg] device: DeviceId,
+) -> Result<(), Error> {
+    let removed = ctx
+        .db
+        .mutate(|db| {
+            Ok(db
+                .as_package_data_mut()
+                .as_idx_mut(&id)
+                .and_then(|p| p.as_installed_mut())
+                .or_not_found(&id)?
+                .as_approved_devices_mut()
+                .remove(&device)?
+                .is_some())
+        })
+        .await?;
+    if removed {
+        restart(&ctx, &id).await?;
+    }
+    Ok(())
+}
+
+async fn bound_nodes(ctx: &RpcContext) -> Result<BTreeMap<PackageId, BTreeSet<PathBuf>>, Error> {
+    let ids = ctx.db.peek().await.as_package_data().keys()?;
+    let mut res = BTreeMap::new();
+    for id in ids {
+        let nodes = approved_nodes(ctx, &id).await?;
+        if !nodes.is_empty() {
+            res.insert(id, nodes);
+        }
+    }
+    Ok(res)
+}
+
+/// Restarts the services whose approved devices were plugged in or removed, so they run with the
+/// device nodes that are present
+async fn watch_hotplug(ctx: &RpcContext) -> Result<(), Error> {
+    let mut monitor = Command::new("udevadm")
+        .arg("monitor")
+        .arg("--udev")
+        .arg("--subsystem-match=usb")
+        .arg("--subsystem-match=tty")
+        .arg("--subsystem-match=hidraw")
+        .arg("--subsystem-match=drm")
+        .stdout(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut lines = BufReader::new(monitor.stdout.take().or_not_found("udevadm stdout")?).lines();
+    let mut bound = bound_nodes(ctx).await?;
+    while let Some(line) = lines.next_line().await? {
+        if !line.contains(" add ") && !line.contains(" remove ") {
+            continue;
+        }
+        // a device usually comes with several events, wait for them to settle
+        while let Ok(Ok(Some(_))) =
+            tokio::time::timeout(Duration::from_secs(1), lines.next_line()).await
+        {}
+        let current = bound_nodes(ctx).await?;
+        for id in bound.keys().chain(current.keys()).collect::<BTreeSet<_>>() {
+            if bound.get(id) != current.get(id) {
+                tracing::info!("Devices of {} changed, restarting it", id);
+                restart(ctx, id).await?;
+            }
+        }
+        bound = current;
+    }
+    Err(Error::new(
+        eyre!("udevadm monitor exited"),
+        ErrorKind::Unknown,
+    ))
+}
+
+pub async fn launch_device_hotplug_task(ctx: RpcContext) {
+    let mut shutdown = ctx.shutdown.subscribe();
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => return,
+            res = watch_hotplug(&ctx) => if let Err(e) = res {
+                tracing::error!("Device hotplug monitor failed: {}", e);
+                tracing::debug!("{:?}", e);
+            },
+        }
+        tokio::select! {
+            _ = shutdown.recv() => return,
+            _ = tokio::time::sleep(Duration::from_secs(30)) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_device_requests() {
+        let requests: DeviceRequests = serde_json::from_value(serde_json::json!({
+            "trezor": {
+                "description": "Trezor hardware wallet",
+                "type": "usb",
+                "vendor-id": "1209",
+                "product-id": "53c1"
+            },
+            "gpu": { "description": "GPU", "type": "class", "class": "display" }
+        }))
+        .unwrap();
+        requests.validate().unwrap();
+        let id: DeviceId = "trezor".parse().unwrap();
+        let trezor = &requests.0[&id].matches;
+        let device = UsbDevice {
+            vendor_id: Some(UsbId(0x1209)),
+            product_id: Some(UsbId(0x53c1)),
+            ..Default::default()
+        };
+        assert!(device.matches(trezor));
+        assert!(!device.matches(&DeviceMatch::UsbClass { class: 3 }));
+        assert_eq!(trezor.to_string(), "usb 1209:53c1");
+    }
+
+    #[test]
+    fn refuses_storage_classes() {
+        for class in ["storage", "disk", "volume", "bridge", "Display", ""] {
+            let requests: DeviceRequests = serde_json::from_value(serde_json::json!({
+                "dev": { "description": "device", "type": "class", "class": class }
+            }))
+            .unwrap();
+            assert!(requests.validate().is_err(), "{} was allowed", class);
+        }
+    }
+
+    #[test]
+    fn finds_mounted_devices() {
+        let (ids, sources) = parse_mountinfo(
+            "22 1 8:2 / / rw,relatime shared:1 - ext4 /dev/sda2 rw\n\
+            23 22 0:21 / /proc rw,nosuid shared:12 - proc proc rw\n\
+            24 22 0:31 / /embassy-data rw,relatime shared:7 - btrfs /dev/mapper/data rw\n",
+        );
+        assert_eq!(
+            ids,
+            ["0:21", "0:31", "8:2"]
+                .into_iter()
+                .map(String::from)
+                .collect()
+        );
+        assert_eq!(
+            sources,
+            ["/dev/mapper/data", "/dev/sda2"]
+                .into_iter()
+                .map(PathBuf::from)
+                .collect()
+        );
+    }
+
+    #[test]
+    fn finds_sysfs_paths() {
+        assert_eq!(
+            businfo_sysfs("pci@0000:00:02.0"),
+            Some(PathBuf::from("/sys/bus/pci/devices/0000:00:02.0"))
+        );
+        assert_eq!(
+            businfo_sysfs("usb@1:3.2"),
+            Some(PathBuf::from("/sys/bus/usb/devices/1-3.2"))
+        );
+        assert_eq!(businfo_sysfs("cpu@0"), None);
+        assert_eq!(
+            uevent_devname("MAJOR=188\nMINOR=0\nDEVNAME=ttyUSB0\n"),
+            Some("ttyUSB0")
+        );
+    }
+}
@@ -7,7 +7,7 @@ use rpc_toolkit::yajrc::RpcError;
 use crate::context::DiagnosticContext;
 use crate::disk::repair;
 use crate::init::SYSTEM_REBUILD_PATH;
-use crate::logs::{fetch_logs, LogResponse, LogSource};
+use crate::logs::{fetch_logs, LogFilter, LogResponse, LogSource};
 use crate::shutdown::Shutdown;
 use crate::util::display_none;
 use crate::Error;
@@ -28,7 +28,14 @@ pub async fn logs(
     #[arg] cursor: Option<String>,
     #[arg] before: bool,
 ) -> Result<LogResponse, Error> {
-    Ok(fetch_logs(LogSource::System, limit, cursor, before).await?)
+    Ok(fetch_logs(
+        LogSource::System,
+        limit,
+        cursor,
+        before,
+        LogFilter::default(),
+    )
+    .await?)
 }
 
 #[command(display(display_none))]
@@ -13,6 +13,7 @@ use crate::Error;
 pub mod fsck;
 pub mod main;
 pub mod mount;
+pub mod quota;
 pub mod util;
 
 pub const BOOT_RW_PATH: &str = "/media/boot-rw";
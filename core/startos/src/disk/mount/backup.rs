@@ -71,7 +71,7 @@ impl<G: GenericMountGuard> BackupMountGuard<G> {
                         )
                     })?;
             check_password(hash, password)?;
-            String::from_utf8(decrypt_slice(wrapped_key, password))?
+            String::from_utf8(decrypt_slice(wrapped_key, password)?)?
         } else {
             base32::encode(
                 base32::Alphabet::RFC4648 { padding: false },
@@ -92,7 +92,7 @@ impl<G: GenericMountGuard> BackupMountGuard<G> {
         if unencrypted_metadata.wrapped_key.is_none() {
             unencrypted_metadata.wrapped_key = Some(base32::encode(
                 base32::Alphabet::RFC4648 { padding: true },
-                &encrypt_slice(&enc_key, password),
+                &encrypt_slice(&enc_key, password)?,
             ));
         }
 
@@ -140,7 +140,7 @@ impl<G: GenericMountGuard> BackupMountGuard<G> {
         );
         self.unencrypted_metadata.wrapped_key = Some(base32::encode(
             base32::Alphabet::RFC4648 { padding: false },
-            &encrypt_slice(&self.enc_key, new_password),
+            &encrypt_slice(&self.enc_key, new_password)?,
         ));
         Ok(())
     }
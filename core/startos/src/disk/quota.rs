@@ -0,0 +1,332 @@
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tracing::instrument;
+
+use crate::prelude::*;
+use crate::util::Invoke;
+
+/// How the size of a directory is limited, depending on the filesystem it lives on
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QuotaBackend {
+    /// The directory is a btrfs subvolume limited by its qgroup
+    Btrfs,
+    /// The directory is an ext4 or xfs project, limited by its project quota
+    Project,
+}
+impl QuotaBackend {
+    #[instrument(skip_all)]
+    pub async fn detect(path: impl AsRef<Path>) -> Result<Option<Self>, Error> {
+        let fs_type = String::from_utf8(
+            Command::new("stat")
+                .arg("-f")
+                .arg("-c")
+                .arg("%T")
+                .arg(path.as_ref())
+                .invoke(ErrorKind::Filesystem)
+                .await?,
+        )?;
+        Ok(match fs_type.trim() {
+            "btrfs" => Some(Self::Btrfs),
+            "ext2/ext3" | "xfs" => Some(Self::Project),
+            _ => None,
+        })
+    }
+}
+
+async fn mountpoint(path: &Path) -> Result<PathBuf, Error> {
+    let out = Command::new("findmnt")
+        .arg("-n")
+        .arg("-o")
+        .arg("TARGET")
+        .arg("-T")
+        .arg(path)
+        .invoke(ErrorKind::Filesystem)
+        .await?;
+    Ok(PathBuf::from(String::from_utf8(out)?.trim()))
+}
+
+/// Project ids only need to be unique within the filesystem, so the inode of the directory is used
+async fn project_id(path: &Path) -> Result<u64, Error> {
+    Ok(tokio::fs::metadata(path)
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()))?
+        .ino())
+}
+
+pub async fn is_subvolume(path: &Path) -> bool {
+    Command::new("btrfs")
+        .arg("subvolume")
+        .arg("show")
+        .arg(path)
+        .invoke(ErrorKind::Filesystem)
+        .await
+        .is_ok()
+}
+
+async fn has_project(path: &Path) -> Result<bool, Error> {
+    let out = Command::new("lsattr")
+        .arg("-p")
+        .arg("-d")
+        .arg(path)
+        .invoke(ErrorKind::Filesystem)
+        .await?;
+    Ok(String::from_utf8(out)?
+        .split_whitespace()
+        .next()
+        .map_or(false, |id| id != "0"))
+}
+
+/// How the size of the existing directory `path` can be limited, if at all
+#[instrument(skip_all)]
+pub async fn backend(path: impl AsRef<Path>) -> Result<Option<QuotaBackend>, Error> {
+    let path = path.as_ref();
+    Ok(match QuotaBackend::detect(path).await? {
+        Some(QuotaBackend::Btrfs) if !is_subvolume(path).await => None,
+        Some(QuotaBackend::Project) if !has_project(path).await? => None,
+        a => a,
+    })
+}
+
+/// Creates `path` so that its size can be limited. Directories that already exist are left as
+/// they are, so they can only be limited if they were created this way.
+#[instrument(skip_all)]
+pub async fn create_dir(path: impl AsRef<Path>) -> Result<Option<QuotaBackend>, Error> {
+    let path = path.as_ref();
+    if tokio::fs::metadata(path).await.is_ok() {
+        return backend(path).await;
+    }
+    let parent = path.parent().unwrap_or(path);
+    tokio::fs::create_dir_all(parent).await?;
+    let backend = QuotaBackend::detect(parent).await?;
+    match backend {
+        Some(QuotaBackend::Btrfs) => {
+            Command::new("btrfs")
+                .arg("subvolume")
+                .arg("create")
+                .arg(path)
+                .invoke(ErrorKind::Filesystem)
+                .await?;
+        }
+        Some(QuotaBackend::Project) => {
+            tokio::fs::create_dir(path).await?;
+            let res = async {
+                Command::new("chattr")
+                    .arg("+P")
+                    .arg("-p")
+                    .arg(project_id(path).await?.to_string())
+                    .arg(path)
+                    .invoke(ErrorKind::Filesystem)
+                    .await
+            }
+            .await;
+            if let Err(e) = res {
+                // project quotas may not be enabled on the filesystem
+                tracing::warn!("Failed to assign a project to {}: {}", path.display(), e);
+                tracing::debug!("{:?}", e);
+                return Ok(None);
+            }
+        }
+        None => tokio::fs::create_dir(path).await?,
+    }
+    Ok(backend)
+}
+
+/// Turns the existing directory `path`, created before its size could be limited, into one that
+/// can be, keeping its contents. Nothing may be writing to it in the meantime.
+#[instrument(skip_all)]
+pub async fn convert_dir(path: impl AsRef<Path>) -> Result<Option<QuotaBackend>, Error> {
+    let path = path.as_ref();
+    if let Some(backend) = backend(path).await? {
+        return Ok(Some(backend));
+    }
+    let backend = QuotaBackend::detect(path).await?;
+    match backend {
+        Some(QuotaBackend::Btrfs) => {
+            let name = path.file_name().ok_or_else(|| {
+                Error::new(
+                    eyre!("{} is not a directory", path.display()),
+                    ErrorKind::Filesystem,
+                )
+            })?;
+            let tmp = path.with_file_name(format!("{}.subvol", name.to_string_lossy()));
+            let old = path.with_file_name(format!("{}.old", name.to_string_lossy()));
+            if is_subvolume(&tmp).await {
+                Command::new("btrfs")
+                    .arg("subvolume")
+                    .arg("delete")
+                    .arg(&tmp)
+                    .invoke(ErrorKind::Filesystem)
+                    .await?;
+            }
+            Command::new("btrfs")
+                .arg("subvolume")
+                .arg("create")
+                .arg(&tmp)
+                .invoke(ErrorKind::Filesystem)
+                .await?;
+            Command::new("cp")
+                .arg("-a")
+                .arg("--reflink=auto")
+                .arg(path.join("."))
+                .arg(&tmp)
+                .invoke(ErrorKind::Filesystem)
+                .await?;
+            tokio::fs::rename(path, &old).await?;
+            tokio::fs::rename(&tmp, path).await?;
+            tokio::fs::remove_dir_all(&old).await?;
+        }
+        Some(QuotaBackend::Project) => {
+            // files keep the project they were created with, so tag everything already there
+            let res = async {
+                Command::new("chattr")
+                    .arg("-R")
+                    .arg("+P")
+                    .arg("-p")
+                    .arg(project_id(path).await?.to_string())
+                    .arg(path)
+                    .invoke(ErrorKind::Filesystem)
+                    .await
+            }
+            .await;
+            if let Err(e) = res {
+                tracing::warn!("Failed to assign a project to {}: {}", path.display(), e);
+                tracing::debug!("{:?}", e);
+                return Ok(None);
+            }
+        }
+        None => (),
+    }
+    Ok(backend)
+}
+
+/// Limits the size of `path` to `limit` bytes, or removes the limit if `None`
+#[instrument(skip_all)]
+pub async fn set_limit(
+    path: impl AsRef<Path>,
+    backend: QuotaBackend,
+    limit: Option<u64>,
+) -> Result<(), Error> {
+    let path = path.as_ref();
+    match backend {
+        QuotaBackend::Btrfs => {
+            // enabling quotas is a no-op if they already are
+            Command::new("btrfs")
+                .arg("quota")
+                .arg("enable")
+                .arg(path)
+                .invoke(ErrorKind::Filesystem)
+                .await?;
+            Command::new("btrfs")
+                .arg("qgroup")
+                .arg("limit")
+                .arg(limit.map_or("none".to_owned(), |l| l.to_string()))
+                .arg(path)
+                .invoke(ErrorKind::Filesystem)
+                .await?;
+        }
+        QuotaBackend::Project => {
+            let limit_kb = limit.map_or(0, |l| (l + 1023) / 1024).to_string();
+            Command::new("setquota")
+                .arg("-P")
+                .arg(project_id(path).await?.to_string())
+                .arg("0")
+                .arg(&limit_kb)
+                .arg("0")
+                .arg("0")
+                .arg(mountpoint(path).await?)
+                .invoke(ErrorKind::Filesystem)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Parses `btrfs qgroup show --raw -r -f`, returning the referenced bytes and the limit
+fn parse_qgroup_show(out: &str) -> Result<(u64, Option<u64>), Error> {
+    let row = out
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .find(|l| {
+            l.split_whitespace()
+                .next()
+                .map_or(false, |id| id.contains('/'))
+        })
+        .ok_or_else(|| Error::new(eyre!("no qgroup in output: {}", out), ErrorKind::Filesystem))?;
+    let mut fields = row.split_whitespace().skip(1);
+    let mut next = || {
+        fields.next().ok_or_else(|| {
+            Error::new(
+                eyre!("missing field in qgroup: {}", row),
+                ErrorKind::Filesystem,
+            )
+        })
+    };
+    let rfer = next()?.parse().with_kind(ErrorKind::ParseNumber)?;
+    let _excl = next()?;
+    let max_rfer = match next()? {
+        "none" => None,
+        a => Some(a.parse().with_kind(ErrorKind::ParseNumber)?),
+    };
+    Ok((rfer, max_rfer))
+}
+
+async fn du(path: &Path) -> Result<u64, Error> {
+    let out = String::from_utf8(
+        Command::new("du")
+            .arg("-s")
+            .arg("-b")
+            .arg(path)
+            .invoke(ErrorKind::Filesystem)
+            .await?,
+    )?;
+    out.split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .parse()
+        .with_kind(ErrorKind::ParseNumber)
+}
+
+/// Returns the bytes used by `path`. Directories that are not limited are measured with `du`.
+#[instrument(skip_all)]
+pub async fn usage(path: impl AsRef<Path>, backend: Option<QuotaBackend>) -> Result<u64, Error> {
+    let path = path.as_ref();
+    match backend {
+        Some(QuotaBackend::Btrfs) => {
+            let out = Command::new("btrfs")
+                .arg("qgroup")
+                .arg("show")
+                .arg("--raw")
+                .arg("-r")
+                .arg("-f")
+                .arg(path)
+                .invoke(ErrorKind::Filesystem)
+                .await?;
+            Ok(parse_qgroup_show(std::str::from_utf8(&out)?)?.0)
+        }
+        _ => du(path).await,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_qgroup_show() {
+        let out = "qgroupid         rfer         excl     max_rfer \n\
+                   --------         ----         ----     -------- \n\
+                   0/261       1073741824        16384   2147483648 \n";
+        assert_eq!(
+            parse_qgroup_show(out).unwrap(),
+            (1073741824, Some(2147483648))
+        );
+        let out =
+            "qgroupid rfer excl max_rfer\n-------- ---- ---- --------\n0/257 16384 16384 none\n";
+        assert_eq!(parse_qgroup_show(out).unwrap(), (16384, None));
+        assert!(parse_qgroup_show("qgroupid rfer excl max_rfer\n").is_err());
+    }
+}
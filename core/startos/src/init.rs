@@ -220,6 +220,13 @@ pub async fn init(cfg: &RpcContextConfig) -> Result<InitResult, Error> {
     crate::ssh::sync_keys_from_db(&secret_store, "/home/start9/.ssh/authorized_keys").await?;
     tracing::info!("Synced SSH Keys");
 
+    if let Err(e) = crate::ssh::sync_sshd_config(&secret_store).await {
+        tracing::error!("Error Syncing SSHD Config: {}", e);
+        tracing::debug!("{:?}", e);
+    } else {
+        tracing::info!("Synced SSHD Config");
+    }
+
     let account = AccountInfo::load(&secret_store).await?;
     let db = cfg.db(&account).await?;
     tracing::info!("Opened PatchDB");
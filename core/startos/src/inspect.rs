@@ -1,14 +1,19 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::PathBuf;
 
+use clap::ArgMatches;
 use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-use crate::s9pk::manifest::Manifest;
-use crate::s9pk::reader::S9pkReader;
-use crate::util::display_none;
+use crate::s9pk::docker::{ImageLayers, SbomPackage};
+use crate::s9pk::manifest::{Manifest, PackageId};
+use crate::s9pk::reader::{ImageTag, S9pkReader};
 use crate::util::serde::{display_serializable, IoFormat};
-use crate::Error;
+use crate::util::{display_none, Version};
+use crate::{Error, ResultExt};
 
-#[command(subcommands(hash, manifest, license, icon, instructions, docker_images))]
+#[command(subcommands(hash, manifest, license, icon, instructions, docker_images, diff, sbom))]
 pub fn inspect() -> Result<(), Error> {
     Ok(())
 }
@@ -90,3 +95,407 @@ pub async fn docker_images(
     .await?;
     Ok(())
 }
+
+/// Keys of a manifest map that were added, removed or changed
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct KeyDiff {
+    pub added: BTreeSet<String>,
+    pub removed: BTreeSet<String>,
+    pub changed: BTreeSet<String>,
+}
+impl KeyDiff {
+    fn new(old: Option<&Value>, new: Option<&Value>) -> Self {
+        let empty = serde_json::Map::new();
+        let old = old.and_then(|v| v.as_object()).unwrap_or(&empty);
+        let new = new.and_then(|v| v.as_object()).unwrap_or(&empty);
+        let mut diff = KeyDiff::default();
+        for (k, v) in new {
+            match old.get(k) {
+                None => {
+                    diff.added.insert(k.clone());
+                }
+                Some(old_v) if old_v != v => {
+                    diff.changed.insert(k.clone());
+                }
+                _ => (),
+            }
+        }
+        diff.removed = old
+            .keys()
+            .filter(|k| !new.contains_key(*k))
+            .cloned()
+            .collect();
+        diff
+    }
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ValueChange {
+    pub path: String,
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+}
+
+fn diff_values(path: &str, old: Option<&Value>, new: Option<&Value>, res: &mut Vec<ValueChange>) {
+    match (old, new) {
+        (Some(Value::Object(old)), Some(Value::Object(new))) => {
+            for k in old.keys().chain(new.keys()).collect::<BTreeSet<_>>() {
+                diff_values(&format!("{}.{}", path, k), old.get(k), new.get(k), res);
+            }
+        }
+        (old, new) if old != new => res.push(ValueChange {
+            path: path.to_owned(),
+            old: old.cloned(),
+            new: new.cloned(),
+        }),
+        _ => (),
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ManifestDiff {
+    /// Host devices the package asks for
+    pub devices: KeyDiff,
+    pub volumes: KeyDiff,
+    pub interfaces: KeyDiff,
+    pub dependencies: KeyDiff,
+    pub actions: KeyDiff,
+    /// Every changed value, by its path in the manifest
+    pub changes: Vec<ValueChange>,
+}
+impl ManifestDiff {
+    fn new(old: &Manifest, new: &Manifest) -> Result<Self, Error> {
+        let old = serde_json::to_value(old).with_kind(crate::ErrorKind::Serialization)?;
+        let new = serde_json::to_value(new).with_kind(crate::ErrorKind::Serialization)?;
+        let key_diff = |key: &str| KeyDiff::new(old.get(key), new.get(key));
+        let mut changes = Vec::new();
+        diff_values("", Some(&old), Some(&new), &mut changes);
+        Ok(ManifestDiff {
+            devices: KeyDiff::new(
+                old.pointer("/hardware-requirements/device"),
+                new.pointer("/hardware-requirements/device"),
+            ),
+            volumes: key_diff("volumes"),
+            interfaces: key_diff("interfaces"),
+            dependencies: key_diff("dependencies"),
+            actions: key_diff("actions"),
+            changes,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Layer {
+    pub path: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ImageDiff {
+    pub added_layers: Vec<Layer>,
+    pub removed_layers: Vec<Layer>,
+    pub unchanged_layers: usize,
+    pub size_delta: i64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SizeDiff {
+    pub old: Option<u64>,
+    pub new: Option<u64>,
+    pub delta: i64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct S9pkDiff {
+    pub id: PackageId,
+    pub from: Version,
+    pub to: Version,
+    pub manifest: ManifestDiff,
+    /// By image id. Images that only exist in one of the packages have all layers added or removed.
+    pub images: BTreeMap<String, ImageDiff>,
+    /// Decompressed size of each section
+    pub sizes: BTreeMap<String, SizeDiff>,
+}
+
+/// Layers of each image in the package, keyed by image id since the tags contain the version
+async fn image_layers(s9pk: &mut S9pkReader) -> Result<BTreeMap<String, Vec<Layer>>, Error> {
+    let layers = ImageLayers::scan(s9pk.docker_images().await?, false).await?;
+    Ok(layers
+        .images
+        .iter()
+        .map(|(tag, paths)| {
+            (
+                tag.parse::<ImageTag>()
+                    .map(|t| t.image_id.to_string())
+                    .unwrap_or_else(|_| tag.clone()),
+                paths
+                    .iter()
+                    .map(|path| Layer {
+                        path: path.clone(),
+                        size: layers.layers.get(path).copied().unwrap_or_default(),
+                    })
+                    .collect(),
+            )
+        })
+        .collect())
+}
+
+fn size_delta(old: u64, new: u64) -> i64 {
+    new as i64 - old as i64
+}
+
+/// Compares two versions of a package
+#[command(cli_only, display(display_diff))]
+pub async fn diff(
+    #[arg] old: PathBuf,
+    #[arg] new: PathBuf,
+    #[arg(rename = "no-verify", long = "no-verify")] no_verify: bool,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<S9pkDiff, Error> {
+    let mut old = S9pkReader::open(old, !no_verify).await?;
+    let mut new = S9pkReader::open(new, !no_verify).await?;
+    let old_man = old.manifest().await?;
+    let new_man = new.manifest().await?;
+    if old_man.id != new_man.id {
+        tracing::warn!(
+            "Comparing different packages: {} and {}",
+            old_man.id,
+            new_man.id
+        );
+    }
+
+    let mut old_images = image_layers(&mut old).await?;
+    let mut images = BTreeMap::new();
+    for (id, new_layers) in image_layers(&mut new).await? {
+        let old_layers = old_images.remove(&id).unwrap_or_default();
+        let old_paths = old_layers.iter().map(|l| &l.path).collect::<BTreeSet<_>>();
+        let new_paths = new_layers.iter().map(|l| &l.path).collect::<BTreeSet<_>>();
+        let old_size = old_layers.iter().map(|l| l.size).sum();
+        let new_size = new_layers.iter().map(|l| l.size).sum();
+        let unchanged_layers = new_paths.intersection(&old_paths).count();
+        let (removed_layers, added_layers) = (
+            old_layers
+                .iter()
+                .filter(|l| !new_paths.contains(&l.path))
+                .map(|l| Layer {
+                    path: l.path.clone(),
+                    size: l.size,
+                })
+                .collect(),
+            new_layers
+                .iter()
+                .filter(|l| !old_paths.contains(&l.path))
+                .map(|l| Layer {
+                    path: l.path.clone(),
+                    size: l.size,
+                })
+                .collect(),
+        );
+        images.insert(
+            id,
+            ImageDiff {
+                added_layers,
+                removed_layers,
+                unchanged_layers,
+                size_delta: size_delta(old_size, new_size),
+            },
+        );
+    }
+    for (id, old_layers) in old_images {
+        images.insert(
+            id,
+            ImageDiff {
+                size_delta: -(old_layers.iter().map(|l| l.size).sum::<u64>() as i64),
+                removed_layers: old_layers,
+                ..Default::default()
+            },
+        );
+    }
+
+    let mut old_sizes = old.section_sizes();
+    let mut sizes = BTreeMap::new();
+    for (label, new_size) in new.section_sizes() {
+        let old_size = old_sizes.remove(&label);
+        sizes.insert(
+            label,
+            SizeDiff {
+                old: old_size,
+                new: Some(new_size),
+                delta: size_delta(old_size.unwrap_or_default(), new_size),
+            },
+        );
+    }
+    for (label, old_size) in old_sizes {
+        sizes.insert(
+            label,
+            SizeDiff {
+                old: Some(old_size),
+                new: None,
+                delta: size_delta(old_size, 0),
+            },
+        );
+    }
+
+    Ok(S9pkDiff {
+        manifest: ManifestDiff::new(&old_man, &new_man)?,
+        id: new_man.id,
+        from: old_man.version,
+        to: new_man.version,
+        images,
+        sizes,
+    })
+}
+
+fn display_diff(diff: S9pkDiff, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(diff, matches);
+    }
+
+    println!("{}: {} -> {}", diff.id, diff.from, diff.to);
+    for (label, keys) in [
+        ("DEVICES", &diff.manifest.devices),
+        ("VOLUMES", &diff.manifest.volumes),
+        ("INTERFACES", &diff.manifest.interfaces),
+        ("DEPENDENCIES", &diff.manifest.dependencies),
+        ("ACTIONS", &diff.manifest.actions),
+    ] {
+        if keys.is_empty() {
+            continue;
+        }
+        println!("{}:", label);
+        for k in &keys.added {
+            println!("  + {}", k);
+        }
+        for k in &keys.removed {
+            println!("  - {}", k);
+        }
+        for k in &keys.changed {
+            println!("  ~ {}", k);
+        }
+    }
+
+    let value = |v: &Option<Value>| v.as_ref().map(|v| v.to_string()).unwrap_or_default();
+    let mut changes = Table::new();
+    changes.add_row(row![bc => "PATH", "OLD", "NEW"]);
+    for change in &diff.manifest.changes {
+        changes.add_row(row![&change.path, &value(&change.old), &value(&change.new)]);
+    }
+    changes.print_tty(false).unwrap();
+
+    let mut images = Table::new();
+    images.add_row(
+        row![bc => "IMAGE", "ADDED LAYERS", "REMOVED LAYERS", "UNCHANGED LAYERS", "SIZE DELTA"],
+    );
+    for (id, image) in &diff.images {
+        images.add_row(row![
+            id,
+            &image.added_layers.len().to_string(),
+            &image.removed_layers.len().to_string(),
+            &image.unchanged_layers.to_string(),
+            &format!("{:+}", image.size_delta),
+        ]);
+    }
+    images.print_tty(false).unwrap();
+
+    let size = |s: Option<u64>| s.map(|s| s.to_string()).unwrap_or_default();
+    let mut sizes = Table::new();
+    sizes.add_row(row![bc => "SECTION", "OLD", "NEW", "DELTA"]);
+    for (label, s) in &diff.sizes {
+        sizes.add_row(row![
+            label,
+            &size(s.old),
+            &size(s.new),
+            &format!("{:+}", s.delta),
+        ]);
+    }
+    sizes.print_tty(false).unwrap();
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Sbom {
+    /// Packages installed in each image, by tag
+    pub images: BTreeMap<String, Vec<SbomPackage>>,
+}
+
+/// Lists the packages installed in the docker images of an s9pk, according to the dpkg and apk
+/// databases in their layers
+#[command(cli_only, display(display_sbom))]
+pub async fn sbom(
+    #[arg] path: PathBuf,
+    #[arg(rename = "no-verify", long = "no-verify")] no_verify: bool,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Sbom, Error> {
+    let mut s9pk = S9pkReader::open(path, !no_verify).await?;
+    let layers = ImageLayers::scan(s9pk.docker_images().await?, true).await?;
+    Ok(Sbom {
+        images: layers
+            .images
+            .keys()
+            .map(|tag| (tag.clone(), layers.packages(tag)))
+            .collect(),
+    })
+}
+
+fn display_sbom(sbom: Sbom, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(sbom, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "IMAGE", "PACKAGE", "VERSION", "TYPE"]);
+    for (tag, packages) in &sbom.images {
+        for package in packages {
+            table.add_row(row![
+                tag,
+                &package.name,
+                &package.version,
+                &package.kind.to_string()
+            ]);
+        }
+    }
+    table.print_tty(false).unwrap();
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn diffs_manifest_values() {
+        let old = json!({ "volumes": { "main": { "type": "data" } }, "title": "A" });
+        let new = json!({
+            "volumes": { "main": { "type": "data" }, "certs": { "type": "certificate" } },
+            "title": "B",
+        });
+        let mut changes = Vec::new();
+        diff_values("", Some(&old), Some(&new), &mut changes);
+        let paths = changes.iter().map(|c| c.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(paths, vec![".title", ".volumes.certs"]);
+        assert!(changes[1].old.is_none());
+
+        let volumes = KeyDiff::new(old.get("volumes"), new.get("volumes"));
+        assert_eq!(volumes.added, BTreeSet::from(["certs".to_owned()]));
+        assert!(volumes.removed.is_empty() && volumes.changed.is_empty());
+    }
+}
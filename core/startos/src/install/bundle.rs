@@ -0,0 +1,696 @@
+//! Offline bundles: several s9pks in one signed tarball, for servers that cannot reach a registry.
+//!
+//! A bundle (`.s9pb`) is a tar archive of
+//! - `index.json`: a [RegistryIndex] listing every package in the bundle
+//! - `signature.json`: the signature of `index.json` by the key that created the bundle
+//! - `packages/{id}/{version}/{id}.s9pk`
+//!
+//! The index records the hash of every s9pk, so the signature covers the whole bundle.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use base64::Engine;
+use chrono::Utc;
+use clap::ArgMatches;
+use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+use futures::{FutureExt, TryStreamExt};
+use http::header::CONTENT_LENGTH;
+use http::{Request, Response, StatusCode};
+use hyper::Body;
+use reqwest::Url;
+use rpc_toolkit::command;
+use rpc_toolkit::yajrc::RpcError;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha512};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::instrument;
+
+use super::plan::{installed_version, order, revert};
+use super::sideload_s9pk;
+use crate::context::{CliContext, RpcContext, SdkContext};
+use crate::core::rpc_continuations::{RequestGuid, RpcContinuation};
+use crate::db::model::DatabaseModel;
+use crate::disk::mount::filesystem::block_dev::BlockDev;
+use crate::disk::mount::filesystem::ReadOnly;
+use crate::disk::mount::guard::TmpMountGuard;
+use crate::install::trust::{check_bundle_signer, encode_key};
+use crate::notifications::NotificationLevel;
+use crate::prelude::*;
+use crate::registry::server::{RegistryIndex, RegistryS9pk, RegistryVersion};
+use crate::s9pk::builder::REPRODUCIBLE_MTIME;
+use crate::s9pk::manifest::{Manifest, PackageId};
+use crate::s9pk::reader::S9pkReader;
+use crate::util::io::response_to_reader;
+use crate::util::{display_none, Version};
+
+pub const PKG_BUNDLE_DIR: &str = "package-data/tmp/bundles";
+pub const BUNDLE_SIG_CONTEXT: &[u8] = b"s9pb";
+const INDEX_PATH: &str = "index.json";
+const SIGNATURE_PATH: &str = "signature.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BundleSignature {
+    /// base64 encoded ed25519 public key
+    pub pubkey: String,
+    /// base64 encoded ed25519ph signature of `index.json`
+    pub signature: String,
+}
+impl BundleSignature {
+    fn sign(key: &SigningKey, index: &[u8]) -> Result<Self, Error> {
+        let signature =
+            key.sign_prehashed(Sha512::new_with_prefix(index), Some(BUNDLE_SIG_CONTEXT))?;
+        Ok(BundleSignature {
+            pubkey: encode_key(&key.verifying_key()),
+            signature: base64::engine::general_purpose::STANDARD.encode(signature.to_bytes()),
+        })
+    }
+    fn verify(&self, index: &[u8]) -> Result<VerifyingKey, Error> {
+        let decode = |s: &str| {
+            base64::engine::general_purpose::STANDARD
+                .decode(s)
+                .with_kind(ErrorKind::InvalidSignature)
+        };
+        let pubkey: [u8; 32] = decode(&self.pubkey)?.try_into().map_err(|_| {
+            Error::new(
+                eyre!("bundle key must be 32 bytes"),
+                ErrorKind::InvalidSignature,
+            )
+        })?;
+        let pubkey = VerifyingKey::from_bytes(&pubkey).with_kind(ErrorKind::InvalidSignature)?;
+        let signature = Signature::from_slice(&decode(&self.signature)?)
+            .with_kind(ErrorKind::InvalidSignature)?;
+        pubkey.verify_prehashed(
+            Sha512::new_with_prefix(index),
+            Some(BUNDLE_SIG_CONTEXT),
+            &signature,
+        )?;
+        Ok(pubkey)
+    }
+}
+
+/// Splits `packages/{id}/{version}/{file}`
+fn parse_package_path(path: &str) -> Option<(PackageId, Version, &str)> {
+    let mut parts = path.strip_prefix("packages/")?.split('/');
+    let (id, version, file) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((id.parse().ok()?, version.parse().ok()?, file))
+}
+
+/// A bundle that has been checked and unpacked, ready to install
+struct StagedBundle {
+    dir: PathBuf,
+    signer: VerifyingKey,
+    packages: BTreeMap<PackageId, (Manifest, PathBuf)>,
+    order: Vec<PackageId>,
+}
+
+/// Checks the signature of the bundle at `path`, unpacks it to a staging directory and checks
+/// every package in it, along with their dependencies. Bundles signed by a key that is not trusted
+/// are refused before anything is unpacked, unless `allow_untrusted_key` is set.
+#[instrument(skip_all)]
+async fn stage(
+    ctx: &RpcContext,
+    path: &Path,
+    allow_untrusted_key: bool,
+) -> Result<StagedBundle, Error> {
+    let dir = ctx
+        .datadir
+        .join(PKG_BUNDLE_DIR)
+        .join(rand::random::<u64>().to_string());
+    tokio::fs::create_dir_all(&dir).await?;
+    let res = stage_to(ctx, path, &dir, allow_untrusted_key).await;
+    match res {
+        Ok((signer, packages, order)) => Ok(StagedBundle {
+            dir,
+            signer,
+            packages,
+            order,
+        }),
+        Err(e) => {
+            if let Err(e) = tokio::fs::remove_dir_all(&dir).await {
+                tracing::warn!("Failed to remove {}: {}", dir.display(), e);
+            }
+            Err(e)
+        }
+    }
+}
+
+async fn stage_to(
+    ctx: &RpcContext,
+    path: &Path,
+    dir: &Path,
+    allow_untrusted_key: bool,
+) -> Result<
+    (
+        VerifyingKey,
+        BTreeMap<PackageId, (Manifest, PathBuf)>,
+        Vec<PackageId>,
+    ),
+    Error,
+> {
+    let mut archive = tokio_tar::Archive::new(File::open(path).await?);
+    let mut entries = archive.entries()?;
+    let mut index_bytes = None;
+    let mut verified: Option<(VerifyingKey, RegistryIndex)> = None;
+    while let Some(mut entry) = entries.try_next().await? {
+        let entry_path = entry.path()?.to_string_lossy().into_owned();
+        match entry_path.as_str() {
+            INDEX_PATH => {
+                let mut buf = Vec::new();
+                entry.read_to_end(&mut buf).await?;
+                index_bytes = Some(buf);
+            }
+            SIGNATURE_PATH => {
+                let index_bytes = index_bytes.as_ref().ok_or_else(|| {
+                    Error::new(
+                        eyre!("Bundle index must come before its signature"),
+                        ErrorKind::ParseS9pk,
+                    )
+                })?;
+                let mut buf = Vec::new();
+                entry.read_to_end(&mut buf).await?;
+                let signature: BundleSignature =
+                    serde_json::from_slice(&buf).with_kind(ErrorKind::Deserialization)?;
+                let signer = signature.verify(index_bytes)?;
+                check_bundle_signer(
+                    ctx.secret_store.acquire().await?.as_mut(),
+                    &signer,
+                    allow_untrusted_key,
+                )
+                .await?;
+                let index: RegistryIndex =
+                    serde_json::from_slice(index_bytes).with_kind(ErrorKind::Deserialization)?;
+                verified = Some((signer, index));
+            }
+            _ => {
+                let (_, index) = verified.as_ref().ok_or_else(|| {
+                    Error::new(
+                        eyre!("Bundle signature must come before its packages"),
+                        ErrorKind::ParseS9pk,
+                    )
+                })?;
+                let Some((id, version, file)) = parse_package_path(&entry_path) else {
+                    tracing::warn!("Ignoring unexpected bundle entry {}", entry_path);
+                    continue;
+                };
+                let listed = index
+                    .packages
+                    .get(&id)
+                    .and_then(|versions| versions.get(&version))
+                    .map_or(false, |v| v.s9pks.iter().any(|s| s.file == file));
+                if !listed {
+                    tracing::warn!("Ignoring unindexed bundle entry {}", entry_path);
+                    continue;
+                }
+                let dst_dir = dir.join(&id).join(version.as_str());
+                tokio::fs::create_dir_all(&dst_dir).await?;
+                let mut dst = File::create(dst_dir.join(format!("{}.s9pk", id))).await?;
+                tokio::io::copy(&mut entry, &mut dst).await?;
+                dst.sync_all().await?;
+            }
+        }
+    }
+    let (signer, index) = verified
+        .ok_or_else(|| Error::new(eyre!("Bundle is not signed"), ErrorKind::InvalidSignature))?;
+
+    let mut packages = BTreeMap::new();
+    for (id, versions) in index.packages {
+        let mut versions = versions.into_iter();
+        let (Some((version, info)), None) = (versions.next(), versions.next()) else {
+            return Err(Error::new(
+                eyre!("Bundle must contain exactly one version of {}", id),
+                ErrorKind::ParseS9pk,
+            ));
+        };
+        let s9pk_path = dir
+            .join(&id)
+            .join(version.as_str())
+            .join(format!("{}.s9pk", id));
+        if tokio::fs::metadata(&s9pk_path).await.is_err() {
+            return Err(Error::new(
+                eyre!("Bundle is missing {}@{}", id, version),
+                ErrorKind::ParseS9pk,
+            ));
+        }
+        let mut rdr = S9pkReader::open(&s9pk_path, true).await?;
+        if info
+            .s9pks
+            .iter()
+            .all(|s| s.hash.as_deref() != rdr.hash_str())
+        {
+            return Err(Error::new(
+                eyre!("Hash mismatch for {}@{}", id, version),
+                ErrorKind::ValidateS9pk,
+            ));
+        }
+        let manifest = rdr.manifest().await?;
+        if manifest.id != id || manifest.version != version {
+            return Err(Error::new(
+                eyre!(
+                    "{}@{} contains {}@{}",
+                    id,
+                    version,
+                    manifest.id,
+                    manifest.version
+                ),
+                ErrorKind::ValidateS9pk,
+            ));
+        }
+        packages.insert(id, (manifest, s9pk_path));
+    }
+
+    let order = install_order(&ctx.db.peek().await, &packages)?;
+    Ok((signer, packages, order))
+}
+
+/// Checks that the required dependencies of every package are either in the bundle or installed,
+/// and orders the packages so that each comes after its dependencies in the bundle.
+fn install_order(
+    db: &DatabaseModel,
+    packages: &BTreeMap<PackageId, (Manifest, PathBuf)>,
+) -> Result<Vec<PackageId>, Error> {
+    let mut deps = BTreeMap::new();
+    for (id, (manifest, _)) in packages {
+        let mut in_bundle = BTreeSet::new();
+        for (dep, info) in &manifest.dependencies.0 {
+            if !info.requirement.required() {
+                continue;
+            }
+            let available = match packages.get(dep) {
+                Some((dep_manifest, _)) => {
+                    in_bundle.insert(dep.clone());
+                    Some(dep_manifest.version.clone())
+                }
+                None => installed_version(db, dep)?,
+            };
+            if !available.map_or(false, |v| v.satisfies(&info.version)) {
+                return Err(Error::new(
+                    eyre!(
+                        "{} requires {} {}, which is neither in the bundle nor installed",
+                        id,
+                        dep,
+                        info.version
+                    ),
+                    ErrorKind::Dependency,
+                ));
+            }
+        }
+        deps.insert(id.clone(), in_bundle);
+    }
+    order(&deps)
+}
+
+/// Installs the packages of `bundle` in dependency order. If one fails, the ones installed before
+/// it are reverted.
+#[instrument(skip_all)]
+async fn install(ctx: RpcContext, bundle: StagedBundle, allow_untrusted_key: bool) {
+    let mut done = Vec::new();
+    let res: Result<(), Error> = async {
+        for id in &bundle.order {
+            let (manifest, path) = bundle.packages.get(id).or_not_found(id)?;
+            let from = installed_version(&ctx.db.peek().await, id)?;
+            if from.as_ref() == Some(&manifest.version) {
+                tracing::info!("{}@{} is already installed", id, manifest.version);
+                continue;
+            }
+            sideload_s9pk(ctx.clone(), path, allow_untrusted_key).await?;
+            done.push((id.clone(), from));
+        }
+        Ok(())
+    }
+    .await;
+    let (level, title, msg) = match res {
+        Ok(()) => (
+            NotificationLevel::Success,
+            "Bundle Installed",
+            format!(
+                "Installed {} package(s) from bundle signed by {}",
+                done.len(),
+                encode_key(&bundle.signer)
+            ),
+        ),
+        Err(e) => {
+            tracing::error!("Bundle Install Failed: {}", e);
+            tracing::debug!("{:?}", e);
+            let reverted = match revert(&ctx, done).await {
+                Ok(()) => "Packages installed from the bundle have been reverted".to_owned(),
+                Err(e) => format!(
+                    "Some packages installed from the bundle could not be reverted: {}",
+                    e
+                ),
+            };
+            (
+                NotificationLevel::Error,
+                "Bundle Install Failed",
+                format!("{}. {}.", e, reverted),
+            )
+        }
+    };
+    if let Err(e) = ctx
+        .notification_manager
+        .notify(ctx.db.clone(), None, level, title.to_owned(), msg, (), None)
+        .await
+    {
+        tracing::error!("Failed to issue Notification: {}", e);
+        tracing::debug!("{:?}", e);
+    }
+    if let Err(e) = tokio::fs::remove_dir_all(&bundle.dir).await {
+        tracing::warn!("Failed to remove {}: {}", bundle.dir.display(), e);
+    }
+}
+
+/// Installs an offline bundle. With `logicalname`, `path` is read from that drive. With only
+/// `path`, it is a path on the server. Otherwise, returns a continuation to upload the bundle to.
+#[command(
+    rename = "sideload-bundle",
+    custom_cli(cli_sideload_bundle(async, context(CliContext))),
+    display(display_none),
+    metadata(sync_db = true)
+)]
+#[instrument(skip_all)]
+pub async fn sideload_bundle(
+    #[context] ctx: RpcContext,
+    #[arg] path: Option<PathBuf>,
+    #[arg] logicalname: Option<PathBuf>,
+    #[arg(long = "allow-untrusted-key", rename = "allow-untrusted-key", default)]
+    allow_untrusted_key: bool,
+) -> Result<Option<RequestGuid>, Error> {
+    let staged = match (path, logicalname) {
+        (Some(path), Some(logicalname)) => {
+            let guard = TmpMountGuard::mount(&BlockDev::new(&logicalname), ReadOnly).await?;
+            let res = stage(
+                &ctx,
+                &guard.as_ref().join(path.strip_prefix("/").unwrap_or(&path)),
+                allow_untrusted_key,
+            )
+            .await;
+            guard.unmount().await?;
+            res?
+        }
+        (Some(path), None) => stage(&ctx, &path, allow_untrusted_key).await?,
+        (None, Some(_)) => {
+            return Err(Error::new(
+                eyre!("A path on the drive is required"),
+                ErrorKind::InvalidRequest,
+            ))
+        }
+        (None, None) => {
+            let guid = RequestGuid::new();
+            let upload_path = ctx
+                .datadir
+                .join(PKG_BUNDLE_DIR)
+                .join(format!("upload-{}.s9pb", guid));
+            let handler = Box::new(move |req: Request<Body>| {
+                async move {
+                    tokio::fs::create_dir_all(upload_path.parent().unwrap_or(&upload_path)).await?;
+                    let mut file = File::create(&upload_path).await?;
+                    tokio::io::copy(
+                        &mut tokio_util::io::StreamReader::new(
+                            req.into_body()
+                                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e)),
+                        ),
+                        &mut file,
+                    )
+                    .await?;
+                    file.sync_all().await?;
+                    drop(file);
+                    let res = stage(&ctx, &upload_path, allow_untrusted_key).await;
+                    if let Err(e) = tokio::fs::remove_file(&upload_path).await {
+                        tracing::warn!("Failed to remove {}: {}", upload_path.display(), e);
+                    }
+                    match res {
+                        Ok(staged) => {
+                            tokio::spawn(install(ctx, staged, allow_untrusted_key));
+                            Response::builder()
+                                .status(StatusCode::OK)
+                                .body(Body::empty())
+                                .with_kind(ErrorKind::Network)
+                        }
+                        Err(e) => Response::builder()
+                            .status(StatusCode::BAD_REQUEST)
+                            .body(Body::from(e.to_string()))
+                            .with_kind(ErrorKind::Network),
+                    }
+                }
+                .boxed()
+            });
+            ctx.add_continuation(
+                guid.clone(),
+                RpcContinuation::rest(handler, Duration::from_secs(30)),
+            )
+            .await;
+            return Ok(Some(guid));
+        }
+    };
+    tokio::spawn(install(ctx, staged, allow_untrusted_key));
+    Ok(None)
+}
+
+/// Uploads a local bundle, unless it is to be read from a drive attached to the server
+#[instrument(skip_all)]
+async fn cli_sideload_bundle(
+    ctx: CliContext,
+    path: Option<PathBuf>,
+    logicalname: Option<PathBuf>,
+    allow_untrusted_key: bool,
+) -> Result<(), RpcError> {
+    let params = serde_json::json!({
+        "path": if logicalname.is_some() { path.clone() } else { None },
+        "logicalname": logicalname,
+        "allow-untrusted-key": allow_untrusted_key,
+    });
+    let guid = rpc_toolkit::command_helpers::call_remote(
+        ctx.clone(),
+        "package.sideload-bundle",
+        params,
+        PhantomData::<Option<RequestGuid>>,
+    )
+    .await?
+    .result?;
+    let Some(guid) = guid else {
+        tracing::info!("Bundle Accepted");
+        return Ok(());
+    };
+    let path = path
+        .ok_or_else(|| Error::new(eyre!("Bundle path is required"), ErrorKind::InvalidRequest))?;
+    let file = File::open(path).await?;
+    let content_length = file.metadata().await?.len();
+    let body = Body::wrap_stream(tokio_util::io::ReaderStream::new(file));
+    let res = ctx
+        .client
+        .post(format!("{}rest/rpc/{}", ctx.base_url, guid))
+        .header(CONTENT_LENGTH, content_length)
+        .body(body)
+        .send()
+        .await?;
+    if res.status().as_u16() == 200 {
+        tracing::info!("Bundle Uploaded")
+    } else {
+        tracing::info!("Bundle Upload failed: {}", res.text().await?)
+    }
+    Ok(())
+}
+
+#[command(subcommands(create))]
+pub fn bundle() -> Result<(), Error> {
+    Ok(())
+}
+
+fn parse_targets(arg: &str, _: &ArgMatches) -> Result<Vec<String>, Error> {
+    Ok(arg.split(',').map(|s| s.trim().to_owned()).collect())
+}
+
+async fn fetch_s9pk(
+    client: &reqwest::Client,
+    registry: &Url,
+    target: &str,
+    arch: &str,
+    dir: &Path,
+) -> Result<PathBuf, Error> {
+    let (id, spec) = target.split_once('@').unwrap_or((target, "*"));
+    let manifest: Manifest = client
+        .get(format!(
+            "{}/package/v0/manifest/{}?spec={}&hardware.arch={}",
+            registry, id, spec, arch
+        ))
+        .send()
+        .await
+        .with_kind(ErrorKind::Registry)?
+        .error_for_status()
+        .with_kind(ErrorKind::Registry)?
+        .json()
+        .await
+        .with_kind(ErrorKind::Registry)?;
+    let res = client
+        .get(format!(
+            "{}/package/v0/{}.s9pk?spec=={}&hardware.arch={}",
+            registry, manifest.id, manifest.version, arch
+        ))
+        .send()
+        .await
+        .with_kind(ErrorKind::Registry)?
+        .error_for_status()
+        .with_kind(ErrorKind::Registry)?;
+    let path = dir.join(format!("{}.s9pk", manifest.id));
+    let mut file = File::create(&path).await?;
+    tokio::io::copy(&mut response_to_reader(res), &mut file).await?;
+    file.sync_all().await?;
+    Ok(path)
+}
+
+/// Header for a regular file in the bundle, with the same normalized metadata as
+/// [append_reproducible](crate::s9pk::builder::append_reproducible) uses
+fn file_header(size: u64) -> tokio_tar::Header {
+    let mut header = tokio_tar::Header::new_gnu();
+    header.set_entry_type(tokio_tar::EntryType::Regular);
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(REPRODUCIBLE_MTIME);
+    header.set_uid(0);
+    header.set_gid(0);
+    header
+}
+
+async fn append_bytes<W: tokio::io::AsyncWrite + Unpin + Send + 'static>(
+    tar: &mut tokio_tar::Builder<W>,
+    path: &str,
+    data: &[u8],
+) -> Result<(), Error> {
+    tar.append_data(&mut file_header(data.len() as u64), path, data)
+        .await?;
+    Ok(())
+}
+
+async fn append_file<W: tokio::io::AsyncWrite + Unpin + Send + 'static>(
+    tar: &mut tokio_tar::Builder<W>,
+    path: &str,
+    src: &Path,
+) -> Result<(), Error> {
+    let file = File::open(src)
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, src.display().to_string()))?;
+    let size = file.metadata().await?.len();
+    tar.append_data(&mut file_header(size), path, file).await?;
+    Ok(())
+}
+
+/// Creates an offline bundle signed with the developer key. Each target is either a local
+/// `.s9pk` file, or `id[@version spec]` to fetch from `--registry` for `--arch`.
+#[command(cli_only, display(display_none))]
+#[instrument(skip_all)]
+pub async fn create(
+    #[context] ctx: SdkContext,
+    #[arg(parse(parse_targets))] targets: Vec<String>,
+    #[arg(short = 'o', long = "output")] output: PathBuf,
+    #[arg(long = "registry")] registry: Option<Url>,
+    #[arg(long = "arch")] arch: Option<String>,
+) -> Result<(), Error> {
+    let key = ctx.developer_key()?;
+    let arch = arch.unwrap_or_else(|| crate::ARCH.to_string());
+    let tmp = std::env::temp_dir().join(format!("s9pb-{}", rand::random::<u64>()));
+    tokio::fs::create_dir_all(&tmp).await?;
+    let res: Result<(), Error> = async {
+        let client = reqwest::Client::new();
+        let mut files = Vec::new();
+        for target in targets {
+            if target.ends_with(".s9pk") {
+                files.push(PathBuf::from(target));
+            } else {
+                let registry = registry.as_ref().ok_or_else(|| {
+                    Error::new(
+                        eyre!("--registry is required to fetch {}", target),
+                        ErrorKind::InvalidRequest,
+                    )
+                })?;
+                files.push(fetch_s9pk(&client, registry, &target, &arch, &tmp).await?);
+            }
+        }
+
+        let mut index = RegistryIndex::default();
+        let mut entries = Vec::new();
+        for file in files {
+            let mut rdr = S9pkReader::open(&file, true).await?;
+            let manifest = rdr.manifest().await?;
+            if index.packages.contains_key(&manifest.id) {
+                return Err(Error::new(
+                    eyre!("Bundle can only contain one version of {}", manifest.id),
+                    ErrorKind::InvalidRequest,
+                ));
+            }
+            index
+                .packages
+                .entry(manifest.id.clone())
+                .or_default()
+                .insert(
+                    manifest.version.clone(),
+                    RegistryVersion {
+                        published_at: Utc::now(),
+                        indexed: true,
+                        s9pks: vec![RegistryS9pk {
+                            arches: manifest.hardware_requirements.arch.clone(),
+                            file: format!("{}.s9pk", manifest.id),
+                            size: tokio::fs::metadata(&file).await?.len(),
+                            hash: rdr.hash_str().map(|h| h.to_owned()),
+                        }],
+                    },
+                );
+            entries.push((manifest.id, manifest.version, file));
+        }
+        let index_bytes = serde_json::to_vec_pretty(&index).with_kind(ErrorKind::Serialization)?;
+        let signature = serde_json::to_vec_pretty(&BundleSignature::sign(&key, &index_bytes)?)
+            .with_kind(ErrorKind::Serialization)?;
+
+        let mut tar = tokio_tar::Builder::new(File::create(&output).await?);
+        append_bytes(&mut tar, INDEX_PATH, &index_bytes).await?;
+        append_bytes(&mut tar, SIGNATURE_PATH, &signature).await?;
+        for (id, version, file) in entries {
+            append_file(
+                &mut tar,
+                &format!("packages/{}/{}/{}.s9pk", id, version, id),
+                &file,
+            )
+            .await?;
+        }
+        let mut file = tar.into_inner().await?;
+        file.flush().await?;
+        file.sync_all().await?;
+        Ok(())
+    }
+    .await;
+    if let Err(e) = tokio::fs::remove_dir_all(&tmp).await {
+        tracing::warn!("Failed to remove {}: {}", tmp.display(), e);
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn verifies_index_signature() {
+        let key = SigningKey::from_bytes(&[3; 32]);
+        let index = br#"{"packages":{}}"#;
+        let signature = BundleSignature::sign(&key, index).unwrap();
+        assert_eq!(signature.verify(index).unwrap(), key.verifying_key());
+        assert!(signature.verify(br#"{"packages":{"evil":{}}}"#).is_err());
+    }
+
+    #[test]
+    fn parses_package_paths() {
+        let (id, version, file) =
+            parse_package_path("packages/bitcoind/25.0.0/bitcoind.s9pk").unwrap();
+        assert_eq!(&*id, "bitcoind");
+        assert_eq!(version.as_str(), "25.0.0");
+        assert_eq!(file, "bitcoind.s9pk");
+        assert!(parse_package_path("packages/bitcoind/25.0.0/../../etc/passwd").is_none());
+        assert!(parse_package_path("index.json").is_none());
+    }
+}
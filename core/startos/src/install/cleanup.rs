@@ -174,10 +174,18 @@ where
     cleanup(ctx, id, &version).await?;
     cleanup_folder(volume_dir, Arc::new(dependents_paths)).await;
     remove_network_keys(secrets, id).await?;
+    crate::config::history::remove_all(secrets, id).await?;
+    crate::config::secret::remove_all(secrets, id).await?;
+    super::rollback::remove_all(ctx, id).await?;
+    crate::volume::snapshot::remove_all(ctx, id).await?;
 
     ctx.db
         .mutate(|d| {
             d.as_package_data_mut().remove(id)?;
+            d.as_server_info_mut()
+                .as_auto_update_mut()
+                .as_packages_mut()
+                .remove(id)?;
             remove_from_current_dependents_lists(
                 d,
                 id,
@@ -0,0 +1,556 @@
+use std::collections::BTreeSet;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use futures::{StreamExt, TryStreamExt};
+use http::header::{CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE};
+use http::StatusCode;
+use reqwest::{Client, Response, Url};
+use serde::{Deserialize, Serialize};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tracing::instrument;
+
+use crate::install::progress::InstallProgress;
+use crate::prelude::*;
+use crate::s9pk::manifest::PackageId;
+use crate::util::Version;
+
+/// Partially downloaded packages are staged here, relative to the data directory, so that they
+/// survive a failed install and can be resumed.
+pub const PKG_DOWNLOAD_DIR: &str = "package-data/tmp/downloads";
+/// Header a registry may use to advertise the hash `S9pkReader` will compute for the package.
+pub const S9PK_HASH_HEADER: &str = "x-s9pk-hash";
+
+pub const DEFAULT_PARALLELISM: usize = 4;
+const CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+const MAX_ATTEMPTS: usize = 5;
+
+pub fn staging_path(datadir: &Path, id: &PackageId, version: &Version) -> PathBuf {
+    datadir
+        .join(PKG_DOWNLOAD_DIR)
+        .join(format!("{}-{}.s9pk.part", id, version))
+}
+
+fn state_path(part: &Path) -> PathBuf {
+    part.with_extension("part.json")
+}
+
+/// What identifies a particular copy of the file on the server. A resumed download is only
+/// continued if this still matches.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Validator {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+impl Validator {
+    fn from_response(res: &Response) -> Self {
+        let header = |name| {
+            res.headers()
+                .get(name)
+                .and_then(|h| h.to_str().ok())
+                .map(|h| h.to_owned())
+        };
+        Validator {
+            // weak validators cannot be used with If-Range
+            etag: header(ETAG).filter(|e| !e.starts_with("W/")),
+            last_modified: header(LAST_MODIFIED),
+        }
+    }
+    fn if_range(&self) -> Option<&str> {
+        self.etag.as_deref().or(self.last_modified.as_deref())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct PartState {
+    size: u64,
+    validator: Validator,
+    chunk_size: u64,
+    complete: BTreeSet<u64>,
+}
+impl PartState {
+    async fn load(path: &Path) -> Option<Self> {
+        serde_json::from_slice(&tokio::fs::read(path).await.ok()?).ok()
+    }
+    async fn save(&self, path: &Path) -> Result<(), Error> {
+        let tmp = path.with_extension("json.tmp");
+        tokio::fs::write(
+            &tmp,
+            serde_json::to_vec(self).with_kind(ErrorKind::Serialization)?,
+        )
+        .await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+    fn completed_bytes(&self) -> u64 {
+        self.complete
+            .iter()
+            .map(|start| self.chunk_size.min(self.size - start))
+            .sum()
+    }
+}
+
+/// Parses `bytes <start>-<end>/<size>`
+fn parse_content_range(header: &str) -> Option<(u64, u64, Option<u64>)> {
+    let (range, size) = header.strip_prefix("bytes ")?.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    Some((
+        start.trim().parse().ok()?,
+        end.trim().parse().ok()?,
+        if size == "*" {
+            None
+        } else {
+            Some(size.trim().parse().ok()?)
+        },
+    ))
+}
+
+fn registry_err(url: &Url, e: impl std::fmt::Display) -> Error {
+    Error::new(eyre!("{}: {}", url, e), ErrorKind::Registry)
+}
+
+/// An s9pk hosted on a registry and any number of mirrors.
+#[derive(Debug)]
+pub struct RemoteS9pk {
+    client: Client,
+    urls: Vec<Url>,
+    size: Option<u64>,
+    ranges: bool,
+    validator: Validator,
+    hash: Option<String>,
+}
+impl RemoteS9pk {
+    /// Finds the first url that responds and learns the size of the package, whether the server
+    /// accepts range requests, and how to tell whether the file changes between requests. `hash`
+    /// is the hash the registry index lists for the package, if any.
+    #[instrument(skip_all)]
+    pub async fn probe(
+        client: &Client,
+        urls: Vec<Url>,
+        hash: Option<String>,
+    ) -> Result<Self, Error> {
+        let mut errors = ErrorCollection::new();
+        for (idx, url) in urls.iter().enumerate() {
+            let res = match client
+                .get(url.clone())
+                .header(RANGE, "bytes=0-0")
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+            {
+                Ok(res) => res,
+                Err(e) => {
+                    tracing::warn!("Failed to reach {}: {}", url, e);
+                    errors.handle(Err::<(), _>(registry_err(url, e)));
+                    continue;
+                }
+            };
+            let (size, ranges) = match res.status() {
+                StatusCode::PARTIAL_CONTENT => (
+                    res.headers()
+                        .get(CONTENT_RANGE)
+                        .and_then(|h| h.to_str().ok())
+                        .and_then(parse_content_range)
+                        .and_then(|(_, _, size)| size),
+                    true,
+                ),
+                _ => (res.content_length(), false),
+            };
+            let validator = Validator::from_response(&res);
+            let mut urls = urls.clone();
+            urls.rotate_left(idx);
+            return Ok(RemoteS9pk {
+                client: client.clone(),
+                urls,
+                size,
+                ranges: ranges && size.is_some(),
+                validator,
+                hash,
+            });
+        }
+        errors.into_result()?;
+        Err(Error::new(
+            eyre!("No url to download package from"),
+            ErrorKind::InvalidRequest,
+        ))
+    }
+
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// The hash the downloaded package must have, if the registry lists one.
+    pub fn hash(&self) -> Option<&str> {
+        self.hash.as_deref()
+    }
+
+    fn url(&self, attempt: usize) -> &Url {
+        &self.urls[attempt % self.urls.len()]
+    }
+
+    async fn backoff(&self, attempt: usize, progress: &InstallProgress) {
+        progress.retries.fetch_add(1, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(500 << attempt.min(5))).await;
+    }
+
+    /// Downloads the package into `part`, picking up where a previous attempt left off, then moves
+    /// it to `dst`.
+    #[instrument(skip_all)]
+    pub async fn download(
+        &self,
+        part: &Path,
+        dst: &Path,
+        parallelism: usize,
+        progress: &InstallProgress,
+    ) -> Result<(), Error> {
+        if let Some(parent) = part.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        match self.size {
+            Some(size) if self.ranges && self.validator.if_range().is_some() => {
+                if let Err(e) = self
+                    .download_ranges(part, size, parallelism.max(1), progress)
+                    .await
+                {
+                    if self.urls.len() < 2 {
+                        return Err(e);
+                    }
+                    tracing::warn!(
+                        "Download from {} failed, trying mirrors: {}",
+                        self.urls[0],
+                        e
+                    );
+                    // A mirror may serve a different copy of the file, which neither the
+                    // validator nor the chunks fetched so far belong to.
+                    tokio::fs::remove_file(part).await.ok();
+                    self.download_whole(part, 1, progress).await?
+                }
+            }
+            _ => self.download_whole(part, 0, progress).await?,
+        }
+        tokio::fs::rename(part, dst).await?;
+        tokio::fs::remove_file(state_path(part)).await.ok();
+        Ok(())
+    }
+
+    /// Downloads the whole file, starting over on every attempt, from the url at index `first`
+    /// and then the ones after it.
+    async fn download_whole(
+        &self,
+        part: &Path,
+        first: usize,
+        progress: &InstallProgress,
+    ) -> Result<(), Error> {
+        tokio::fs::remove_file(state_path(part)).await.ok();
+        let mut attempt = 0;
+        loop {
+            progress.downloaded.store(0, Ordering::SeqCst);
+            let res = async {
+                let url = self.url(first + attempt);
+                let res = self
+                    .client
+                    .get(url.clone())
+                    .send()
+                    .await
+                    .and_then(|r| r.error_for_status())
+                    .map_err(|e| registry_err(url, e))?;
+                let mut file = File::create(part).await?;
+                write_body(res, &mut file, url, progress).await?;
+                file.sync_all().await?;
+                Ok::<_, Error>(())
+            }
+            .await;
+            match res {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt + 1 < MAX_ATTEMPTS => {
+                    tracing::warn!("Download failed, retrying: {}", e);
+                    self.backoff(attempt, progress).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn download_ranges(
+        &self,
+        part: &Path,
+        size: u64,
+        parallelism: usize,
+        progress: &InstallProgress,
+    ) -> Result<(), Error> {
+        let state_path = state_path(part);
+        let mut state = match PartState::load(&state_path).await {
+            Some(state)
+                if state.size == size
+                    && state.validator == self.validator
+                    && tokio::fs::metadata(part)
+                        .await
+                        .map_or(false, |m| m.len() == size) =>
+            {
+                if !state.complete.is_empty() {
+                    tracing::info!(
+                        "Resuming download of {} with {} of {} bytes complete",
+                        part.display(),
+                        state.completed_bytes(),
+                        size
+                    );
+                    progress.resumptions.fetch_add(1, Ordering::SeqCst);
+                }
+                state
+            }
+            _ => {
+                let file = File::create(part).await?;
+                file.set_len(size).await?;
+                file.sync_all().await?;
+                let state = PartState {
+                    size,
+                    validator: self.validator.clone(),
+                    chunk_size: CHUNK_SIZE,
+                    complete: BTreeSet::new(),
+                };
+                state.save(&state_path).await?;
+                state
+            }
+        };
+        progress
+            .downloaded
+            .store(state.completed_bytes(), Ordering::SeqCst);
+
+        let chunk_size = state.chunk_size;
+        let pending = (0..size)
+            .step_by(chunk_size as usize)
+            .filter(|start| !state.complete.contains(start))
+            .collect::<Vec<_>>();
+        let mut chunks = futures::stream::iter(pending)
+            .map(|start| async move {
+                self.fetch_chunk(part, start, (start + chunk_size).min(size), progress)
+                    .await
+                    .map(|_| start)
+            })
+            .buffer_unordered(parallelism);
+        while let Some(start) = chunks.try_next().await? {
+            state.complete.insert(start);
+            state.save(&state_path).await?;
+        }
+        drop(chunks);
+
+        File::open(part).await?.sync_all().await?;
+        Ok(())
+    }
+
+    async fn fetch_chunk(
+        &self,
+        part: &Path,
+        start: u64,
+        end: u64,
+        progress: &InstallProgress,
+    ) -> Result<(), Error> {
+        // chunks are only fetched from the url the validator came from
+        let url = self.url(0);
+        let mut attempt = 0;
+        loop {
+            let mut written = 0;
+            let res = async {
+                let mut req = self
+                    .client
+                    .get(url.clone())
+                    .header(RANGE, format!("bytes={}-{}", start, end - 1));
+                if let Some(validator) = self.validator.if_range() {
+                    req = req.header(IF_RANGE, validator);
+                }
+                let res = req
+                    .send()
+                    .await
+                    .and_then(|r| r.error_for_status())
+                    .map_err(|e| registry_err(url, e))?;
+                if res.status() != StatusCode::PARTIAL_CONTENT {
+                    return Err(Error::new(
+                        eyre!("{} changed while it was being downloaded", url),
+                        ErrorKind::Registry,
+                    ));
+                }
+                let content_range = res
+                    .headers()
+                    .get(CONTENT_RANGE)
+                    .and_then(|h| h.to_str().ok())
+                    .and_then(parse_content_range);
+                if content_range != Some((start, end - 1, Some(self.size.unwrap_or_default()))) {
+                    return Err(registry_err(url, "server returned the wrong range"));
+                }
+                let mut file = OpenOptions::new().write(true).open(part).await?;
+                file.seek(SeekFrom::Start(start)).await?;
+                let mut body = res.bytes_stream();
+                while let Some(bytes) = body.try_next().await.map_err(|e| registry_err(url, e))? {
+                    file.write_all(&bytes).await?;
+                    written += bytes.len() as u64;
+                    progress
+                        .downloaded
+                        .fetch_add(bytes.len() as u64, Ordering::SeqCst);
+                }
+                if written != end - start {
+                    return Err(registry_err(url, "response ended early"));
+                }
+                file.flush().await?;
+                Ok(())
+            }
+            .await;
+            match res {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    progress.downloaded.fetch_sub(written, Ordering::SeqCst);
+                    if attempt + 1 >= MAX_ATTEMPTS {
+                        return Err(e);
+                    }
+                    tracing::warn!(
+                        "Download of bytes {}-{} failed, retrying: {}",
+                        start,
+                        end,
+                        e
+                    );
+                    self.backoff(attempt, progress).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+async fn write_body(
+    res: Response,
+    file: &mut File,
+    url: &Url,
+    progress: &InstallProgress,
+) -> Result<(), Error> {
+    let mut body = res.bytes_stream();
+    while let Some(bytes) = body.try_next().await.map_err(|e| registry_err(url, e))? {
+        file.write_all(&bytes).await?;
+        progress
+            .downloaded
+            .fetch_add(bytes.len() as u64, Ordering::SeqCst);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request};
+
+    use super::*;
+
+    #[test]
+    fn parses_content_range() {
+        assert_eq!(
+            parse_content_range("bytes 0-0/1234"),
+            Some((0, 0, Some(1234)))
+        );
+        assert_eq!(parse_content_range("bytes 10-19/*"), Some((10, 19, None)));
+        assert_eq!(parse_content_range("items 0-0/1"), None);
+    }
+
+    /// Serves `data` with range support, failing every third request part way through.
+    async fn flaky_server(data: Arc<Vec<u8>>) -> (Url, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let make_svc = make_service_fn(move |_| {
+            let data = data.clone();
+            let counter = counter.clone();
+            async move {
+                Ok::<_, std::convert::Infallible>(service_fn(move |req: Request<Body>| {
+                    let data = data.clone();
+                    let n = counter.fetch_add(1, Ordering::SeqCst);
+                    async move {
+                        let range = req
+                            .headers()
+                            .get(RANGE)
+                            .and_then(|h| h.to_str().ok())
+                            .and_then(|h| h.strip_prefix("bytes="))
+                            .and_then(|h| h.split_once('-'))
+                            .map(|(s, e)| {
+                                (s.parse::<usize>().unwrap(), e.parse::<usize>().unwrap())
+                            })
+                            .unwrap_or((0, data.len() - 1));
+                        let mut body = data[range.0..=range.1].to_vec();
+                        if n > 0 && n % 3 == 0 {
+                            body.truncate(body.len() / 2);
+                        }
+                        Ok::<_, std::convert::Infallible>(
+                            http::Response::builder()
+                                .status(StatusCode::PARTIAL_CONTENT)
+                                .header(ETAG, "\"v1\"")
+                                .header(
+                                    CONTENT_RANGE,
+                                    format!("bytes {}-{}/{}", range.0, range.1, data.len()),
+                                )
+                                .body(Body::from(body))
+                                .unwrap(),
+                        )
+                    }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = format!("http://{}/pkg.s9pk", server.local_addr())
+            .parse()
+            .unwrap();
+        tokio::spawn(server);
+        (url, requests)
+    }
+
+    #[tokio::test]
+    async fn downloads_in_parallel_with_retries_and_resumes() {
+        let data = Arc::new(
+            (0..(CHUNK_SIZE * 2 + 100))
+                .map(|i| (i % 251) as u8)
+                .collect::<Vec<_>>(),
+        );
+        let (url, _) = flaky_server(data.clone()).await;
+        let dead: Url = "http://127.0.0.1:1/pkg.s9pk".parse().unwrap();
+        let client = Client::new();
+        let remote = RemoteS9pk::probe(&client, vec![dead, url], None)
+            .await
+            .unwrap();
+        assert_eq!(remote.size(), Some(data.len() as u64));
+        assert!(remote.ranges);
+
+        let dir = std::env::temp_dir().join(format!("startos-download-{}", rand::random::<u64>()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let part = dir.join("pkg.s9pk.part");
+        let dst = dir.join("pkg.s9pk");
+
+        // pretend a previous attempt finished the first chunk
+        PartState {
+            size: data.len() as u64,
+            validator: remote.validator.clone(),
+            chunk_size: CHUNK_SIZE,
+            complete: [0].into_iter().collect(),
+        }
+        .save(&state_path(&part))
+        .await
+        .unwrap();
+        let mut partial = data[..].to_vec();
+        partial[CHUNK_SIZE as usize..].fill(0);
+        tokio::fs::write(&part, &partial).await.unwrap();
+
+        let progress = InstallProgress::new(remote.size());
+        remote.download(&part, &dst, 2, &progress).await.unwrap();
+        assert_eq!(tokio::fs::read(&dst).await.unwrap(), *data);
+        assert_eq!(
+            progress.downloaded.load(Ordering::SeqCst),
+            data.len() as u64
+        );
+        assert_eq!(progress.resumptions.load(Ordering::SeqCst), 1);
+        assert!(progress.retries.load(Ordering::SeqCst) >= 1);
+        assert!(tokio::fs::metadata(state_path(&part)).await.is_err());
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -1,4 +1,5 @@
 use std::collections::BTreeMap;
+use std::future::Future;
 use std::io::SeekFrom;
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
@@ -6,6 +7,7 @@ use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
 
+use clap::ArgMatches;
 use color_eyre::eyre::eyre;
 use emver::VersionRange;
 use futures::future::BoxFuture;
@@ -13,6 +15,7 @@ use futures::{FutureExt, StreamExt, TryStreamExt};
 use http::header::CONTENT_LENGTH;
 use http::{Request, Response, StatusCode};
 use hyper::Body;
+use itertools::Itertools;
 use models::{mime, DataUrl};
 use reqwest::Url;
 use rpc_toolkit::command;
@@ -26,6 +29,9 @@ use tokio_stream::wrappers::ReadDirStream;
 use tracing::instrument;
 
 use self::cleanup::{cleanup_failed, remove_from_current_dependents_lists};
+use self::download::RemoteS9pk;
+use self::rollback::RollbackPoint;
+use crate::config::history::{record_applied, RevisionSource};
 use crate::config::ConfigureContext;
 use crate::context::{CliContext, RpcContext};
 use crate::core::rpc_continuations::{RequestGuid, RpcContinuation};
@@ -51,11 +57,18 @@ use crate::util::docker::CONTAINER_TOOL;
 use crate::util::io::response_to_reader;
 use crate::util::serde::{display_serializable, Port};
 use crate::util::{display_none, AsyncFileExt, Invoke, Version};
+use crate::volume::snapshot::{self, SnapshotReason};
 use crate::volume::{asset_dir, script_dir};
 use crate::{Error, ErrorKind, ResultExt};
 
+pub mod bundle;
 pub mod cleanup;
+pub mod download;
+pub mod plan;
 pub mod progress;
+pub mod rollback;
+pub mod trust;
+pub mod update;
 
 pub const PKG_ARCHIVE_DIR: &str = "package-data/archive";
 pub const PKG_PUBLIC_DIR: &str = "package-data/public";
@@ -125,6 +138,12 @@ impl std::fmt::Display for MinMax {
     }
 }
 
+fn parse_comma_separated(arg: &str, _: &ArgMatches) -> Result<Vec<Url>, Error> {
+    arg.split(',')
+        .map(|s| s.trim().parse().with_kind(ErrorKind::ParseUrl))
+        .collect()
+}
+
 #[command(
     custom_cli(cli_install(async, context(CliContext))),
     display(display_none),
@@ -140,6 +159,11 @@ pub async fn install(
         String,
     >,
     #[arg(long = "version-priority", rename = "version-priority")] version_priority: Option<MinMax>,
+    #[arg(long = "allow-untrusted-key", rename = "allow-untrusted-key", default)]
+    allow_untrusted_key: bool,
+    #[arg(long = "mirrors", parse(parse_comma_separated))] mirrors: Option<Vec<Url>>,
+    #[arg(long = "download-parallelism", rename = "download-parallelism")]
+    download_parallelism: Option<usize>,
 ) -> Result<(), Error> {
     let version_str = match &version_spec {
         None => "*",
@@ -149,6 +173,52 @@ pub async fn install(
     let marketplace_url =
         marketplace_url.unwrap_or_else(|| crate::DEFAULT_MARKETPLACE.parse().unwrap());
     let version_priority = version_priority.unwrap_or_default();
+    let man = fetch_manifest(&ctx, &marketplace_url, &id, &version, version_priority).await?;
+    let downloading = begin_install(
+        ctx.clone(),
+        man.clone(),
+        marketplace_url,
+        version_priority,
+        mirrors.unwrap_or_default(),
+        download_parallelism.unwrap_or(download::DEFAULT_PARALLELISM),
+        allow_untrusted_key,
+    )
+    .await?;
+    tokio::spawn(async move {
+        if let Err(e) = downloading.await {
+            let err_str = format!("Install of {}@{} Failed: {}", man.id, man.version, e);
+            tracing::error!("{}", err_str);
+            tracing::debug!("{:?}", e);
+            if let Err(e) = ctx
+                .notification_manager
+                .notify(
+                    ctx.db.clone(),
+                    Some(man.id),
+                    NotificationLevel::Error,
+                    String::from("Install Failed"),
+                    err_str,
+                    (),
+                    None,
+                )
+                .await
+            {
+                tracing::error!("Failed to issue Notification: {}", e);
+                tracing::debug!("{:?}", e);
+            }
+        }
+        Ok::<_, String>(())
+    });
+
+    Ok(())
+}
+/// Fetches the manifest of the version of `id` matching `version` from the marketplace
+pub async fn fetch_manifest(
+    ctx: &RpcContext,
+    marketplace_url: &Url,
+    id: &str,
+    version: &VersionRange,
+    version_priority: MinMax,
+) -> Result<Manifest, Error> {
     let man: Manifest = ctx
         .client
         .get(with_query_params(
@@ -167,27 +237,99 @@ pub async fn install(
         .json()
         .await
         .with_kind(crate::ErrorKind::Registry)?;
-    let s9pk = ctx
-        .client
-        .get(with_query_params(
-            ctx.clone(),
-            format!(
-                "{}/package/v0/{}.s9pk?spec=={}&version-priority={}",
-                marketplace_url, id, man.version, version_priority,
-            )
-            .parse()?,
-        ))
-        .send()
-        .await
-        .with_kind(crate::ErrorKind::Registry)?
-        .error_for_status()?;
 
-    if *man.id != *id || !man.version.satisfies(&version) {
+    if *man.id != *id || !man.version.satisfies(version) {
         return Err(Error::new(
             eyre!("Fetched package does not match requested id and version"),
             ErrorKind::Registry,
         ));
     }
+    Ok(man)
+}
+
+/// Fetches the hash the marketplace index lists for `id@version`, if it lists one. Downloads are
+/// checked against it whichever mirror they came from. Registries that do not publish hashes
+/// only get the signature check every s9pk goes through.
+pub async fn fetch_hash(
+    ctx: &RpcContext,
+    marketplace_url: &Url,
+    id: &PackageId,
+    version: &Version,
+) -> Result<Option<String>, Error> {
+    #[derive(serde::Deserialize)]
+    struct IndexedManifest {
+        id: PackageId,
+        version: Version,
+    }
+    #[derive(serde::Deserialize)]
+    struct IndexedPackage {
+        manifest: IndexedManifest,
+        hash: Option<String>,
+    }
+
+    let mut url: Url = format!("{}/package/v0/index", marketplace_url).parse()?;
+    url.query_pairs_mut().append_pair(
+        "ids",
+        &json!([{ "id": id, "version": format!("={}", version) }]).to_string(),
+    );
+    let index: Vec<IndexedPackage> = ctx
+        .client
+        .get(with_query_params(ctx.clone(), url))
+        .send()
+        .await
+        .with_kind(crate::ErrorKind::Registry)?
+        .error_for_status()
+        .with_kind(crate::ErrorKind::Registry)?
+        .json()
+        .await
+        .with_kind(crate::ErrorKind::Registry)?;
+    let hash = index
+        .into_iter()
+        .find(|p| &p.manifest.id == id && &p.manifest.version == version)
+        .and_then(|p| p.hash);
+    if hash.is_none() {
+        tracing::info!(
+            "{} does not list a hash for {}@{}, relying on the package signature",
+            marketplace_url,
+            id,
+            version
+        );
+    }
+    Ok(hash)
+}
+
+/// Prepares the install of `man`: registers it in the database and pre-downloads its public
+/// files. Returns the download and install itself, to be spawned or awaited by the caller.
+#[instrument(skip_all)]
+pub async fn begin_install(
+    ctx: RpcContext,
+    man: Manifest,
+    marketplace_url: Url,
+    version_priority: MinMax,
+    mirrors: Vec<Url>,
+    download_parallelism: usize,
+    allow_untrusted_key: bool,
+) -> Result<impl Future<Output = Result<(), Error>> + Send, Error> {
+    let id = &man.id;
+    let hash = fetch_hash(&ctx, &marketplace_url, id, &man.version).await?;
+    let s9pk = RemoteS9pk::probe(
+        &ctx.client,
+        std::iter::once(&marketplace_url)
+            .chain(mirrors.iter())
+            .map(|url| {
+                Ok(with_query_params(
+                    ctx.clone(),
+                    format!(
+                        "{}/package/v0/{}.s9pk?spec=={}&version-priority={}",
+                        url, id, man.version, version_priority,
+                    )
+                    .parse()?,
+                ))
+            })
+            .collect::<Result<_, Error>>()?,
+        hash,
+    )
+    .await?;
 
     let public_dir_path = ctx
         .datadir
@@ -272,7 +414,7 @@ pub async fn install(
         tracing::warn!("Failed to pre-download icon: {}", e);
     }
 
-    let progress = Arc::new(InstallProgress::new(s9pk.content_length()));
+    let progress = Arc::new(InstallProgress::new(s9pk.size()));
     let static_files = StaticFiles::local(&man.id, &man.version, icon_type);
     ctx.db
         .mutate(|db| {
@@ -308,47 +450,24 @@ pub async fn install(
         })
         .await?;
 
-    let downloading = download_install_s9pk(
-        ctx.clone(),
-        man.clone(),
+    Ok(fetch_install_s9pk(
+        ctx,
+        man,
         Some(marketplace_url),
-        Arc::new(InstallProgress::new(s9pk.content_length())),
-        response_to_reader(s9pk),
-        None,
-    );
-    tokio::spawn(async move {
-        if let Err(e) = downloading.await {
-            let err_str = format!("Install of {}@{} Failed: {}", man.id, man.version, e);
-            tracing::error!("{}", err_str);
-            tracing::debug!("{:?}", e);
-            if let Err(e) = ctx
-                .notification_manager
-                .notify(
-                    ctx.db.clone(),
-                    Some(man.id),
-                    NotificationLevel::Error,
-                    String::from("Install Failed"),
-                    err_str,
-                    (),
-                    None,
-                )
-                .await
-            {
-                tracing::error!("Failed to issue Notification: {}", e);
-                tracing::debug!("{:?}", e);
-            }
-        }
-        Ok::<_, String>(())
-    });
-
-    Ok(())
+        progress,
+        s9pk,
+        download_parallelism,
+        allow_untrusted_key,
+    ))
 }
+
 #[command(rpc_only, display(display_none))]
 #[instrument(skip_all)]
 pub async fn sideload(
     #[context] ctx: RpcContext,
     #[arg] manifest: Manifest,
     #[arg] icon: Option<String>,
+    #[arg(rename = "allow-untrusted-key", default)] allow_untrusted_key: bool,
 ) -> Result<RequestGuid, Error> {
     let new_ctx = ctx.clone();
     let guid = RequestGuid::new();
@@ -400,46 +519,7 @@ pub async fn sideload(
                 },
             };
             let progress = Arc::new(InstallProgress::new(content_length));
-            let install_progress = progress.clone();
-
-            new_ctx
-                .db
-                .mutate(|db| {
-                    let pde = match db
-                        .as_package_data()
-                        .as_idx(&manifest.id)
-                        .map(|x| x.de())
-                        .transpose()?
-                    {
-                        Some(PackageDataEntry::Installed(PackageDataEntryInstalled {
-                            installed,
-                            static_files,
-                            ..
-                        })) => PackageDataEntry::Updating(PackageDataEntryUpdating {
-                            install_progress,
-                            installed,
-                            manifest: manifest.clone(),
-                            static_files,
-                        }),
-                        None => PackageDataEntry::Installing(PackageDataEntryInstalling {
-                            install_progress,
-                            static_files: StaticFiles::local(
-                                &manifest.id,
-                                &manifest.version,
-                                &manifest.assets.icon_type(),
-                            ),
-                            manifest: manifest.clone(),
-                        }),
-                        _ => {
-                            return Err(Error::new(
-                                eyre!("Cannot install over a package in a transient state"),
-                                crate::ErrorKind::InvalidRequest,
-                            ))
-                        }
-                    };
-                    db.as_package_data_mut().insert(&manifest.id, &pde)
-                })
-                .await?;
+            mark_installing(&new_ctx, &manifest, progress.clone()).await?;
 
             let (send, recv) = oneshot::channel();
 
@@ -460,6 +540,7 @@ pub async fn sideload(
                         )
                     })),
                     Some(send),
+                    allow_untrusted_key,
                 )
                 .await
                 {
@@ -510,6 +591,88 @@ pub async fn sideload(
     Ok(guid)
 }
 
+/// Registers `manifest` as installing, or as updating if a version of it is already installed
+async fn mark_installing(
+    ctx: &RpcContext,
+    manifest: &Manifest,
+    install_progress: Arc<InstallProgress>,
+) -> Result<(), Error> {
+    ctx.db
+        .mutate(|db| {
+            let pde = match db
+                .as_package_data()
+                .as_idx(&manifest.id)
+                .map(|x| x.de())
+                .transpose()?
+            {
+                Some(PackageDataEntry::Installed(PackageDataEntryInstalled {
+                    installed,
+                    static_files,
+                    ..
+                })) => PackageDataEntry::Updating(PackageDataEntryUpdating {
+                    install_progress,
+                    installed,
+                    manifest: manifest.clone(),
+                    static_files,
+                }),
+                None => PackageDataEntry::Installing(PackageDataEntryInstalling {
+                    install_progress,
+                    static_files: StaticFiles::local(
+                        &manifest.id,
+                        &manifest.version,
+                        &manifest.assets.icon_type(),
+                    ),
+                    manifest: manifest.clone(),
+                }),
+                _ => {
+                    return Err(Error::new(
+                        eyre!("Cannot install over a package in a transient state"),
+                        crate::ErrorKind::InvalidRequest,
+                    ))
+                }
+            };
+            db.as_package_data_mut().insert(&manifest.id, &pde)
+        })
+        .await
+}
+
+/// Installs the s9pk at `path` on this server, like [sideload] does with an uploaded one
+#[instrument(skip_all)]
+pub async fn sideload_s9pk(
+    ctx: RpcContext,
+    path: &Path,
+    allow_untrusted_key: bool,
+) -> Result<(), Error> {
+    let mut reader = S9pkReader::open(path, false).await?;
+    let manifest = reader.manifest().await?;
+    let public_dir_path = ctx
+        .datadir
+        .join(PKG_PUBLIC_DIR)
+        .join(&manifest.id)
+        .join(manifest.version.as_str());
+    tokio::fs::create_dir_all(&public_dir_path).await?;
+    let mut icon_file =
+        File::create(public_dir_path.join(format!("icon.{}", manifest.assets.icon_type()))).await?;
+    tokio::io::copy(&mut reader.icon().await?, &mut icon_file).await?;
+    icon_file.sync_all().await?;
+    drop(reader);
+
+    let progress = Arc::new(InstallProgress::new(Some(
+        tokio::fs::metadata(path).await?.len(),
+    )));
+    mark_installing(&ctx, &manifest, progress.clone()).await?;
+    download_install_s9pk(
+        ctx,
+        manifest,
+        None,
+        progress,
+        File::open(path).await?,
+        None,
+        allow_untrusted_key,
+    )
+    .await
+}
+
 #[instrument(skip_all)]
 async fn cli_install(
     ctx: CliContext,
@@ -517,6 +680,9 @@ async fn cli_install(
     marketplace_url: Option<Url>,
     version_spec: Option<String>,
     version_priority: Option<MinMax>,
+    allow_untrusted_key: bool,
+    mirrors: Option<Vec<Url>>,
+    download_parallelism: Option<usize>,
 ) -> Result<(), RpcError> {
     if target.ends_with(".s9pk") {
         let path = PathBuf::from(target);
@@ -536,7 +702,11 @@ async fn cli_install(
         let guid = rpc_toolkit::command_helpers::call_remote(
             ctx.clone(),
             "package.sideload",
-            serde_json::json!({ "manifest": manifest, "icon": icon_str }),
+            serde_json::json!({
+                "manifest": manifest,
+                "icon": icon_str,
+                "allow-untrusted-key": allow_untrusted_key,
+            }),
             PhantomData::<RequestGuid>,
         )
         .await?
@@ -562,7 +732,7 @@ async fn cli_install(
     } else {
         let params = match (target.split_once("@"), version_spec) {
             (Some((pkg, v)), None) => {
-                serde_json::json!({ "id": pkg, "marketplace-url": marketplace_url, "version-spec": v, "version-priority": version_priority })
+                serde_json::json!({ "id": pkg, "marketplace-url": marketplace_url, "version-spec": v, "version-priority": version_priority, "allow-untrusted-key": allow_untrusted_key, "mirrors": mirrors, "download-parallelism": download_parallelism })
             }
             (Some(_), Some(_)) => {
                 return Err(crate::Error::new(
@@ -572,10 +742,10 @@ async fn cli_install(
                 .into())
             }
             (None, Some(v)) => {
-                serde_json::json!({ "id": target, "marketplace-url": marketplace_url, "version-spec": v, "version-priority": version_priority })
+                serde_json::json!({ "id": target, "marketplace-url": marketplace_url, "version-spec": v, "version-priority": version_priority, "allow-untrusted-key": allow_untrusted_key, "mirrors": mirrors, "download-parallelism": download_parallelism })
             }
             (None, None) => {
-                serde_json::json!({ "id": target, "marketplace-url": marketplace_url, "version-priority": version_priority })
+                serde_json::json!({ "id": target, "marketplace-url": marketplace_url, "version-priority": version_priority, "allow-untrusted-key": allow_untrusted_key, "mirrors": mirrors, "download-parallelism": download_parallelism })
             }
         };
         tracing::debug!("calling package.install");
@@ -597,39 +767,11 @@ pub async fn uninstall(
     #[context] ctx: RpcContext,
     #[arg] id: PackageId,
 ) -> Result<PackageId, Error> {
-    ctx.db
-        .mutate(|db| {
-            let (manifest, static_files, installed) =
-                match db.as_package_data().as_idx(&id).or_not_found(&id)?.de()? {
-                    PackageDataEntry::Installed(PackageDataEntryInstalled {
-                        manifest,
-                        static_files,
-                        installed,
-                    }) => (manifest, static_files, installed),
-                    _ => {
-                        return Err(Error::new(
-                            eyre!("Package is not installed."),
-                            crate::ErrorKind::NotFound,
-                        ));
-                    }
-                };
-            let pde = PackageDataEntry::Removing(PackageDataEntryRemoving {
-                manifest,
-                static_files,
-                removing: installed,
-            });
-            db.as_package_data_mut().insert(&id, &pde)
-        })
-        .await?;
-
+    let removing = begin_uninstall(ctx.clone(), id.clone()).await?;
     let return_id = id.clone();
 
     tokio::spawn(async move {
-        if let Err(e) = async {
-            cleanup::uninstall(&ctx, ctx.secret_store.acquire().await?.as_mut(), &id).await
-        }
-        .await
-        {
+        if let Err(e) = removing.await {
             let err_str = format!("Uninstall of {} Failed: {}", id, e);
             tracing::error!("{}", err_str);
             tracing::debug!("{:?}", e);
@@ -655,14 +797,112 @@ pub async fn uninstall(
     Ok(return_id)
 }
 
+/// Marks `id` as being removed. Returns the removal itself, to be spawned or awaited by the
+/// caller.
+pub async fn begin_uninstall(
+    ctx: RpcContext,
+    id: PackageId,
+) -> Result<impl Future<Output = Result<(), Error>> + Send, Error> {
+    ctx.db
+        .mutate(|db| {
+            let (manifest, static_files, installed) =
+                match db.as_package_data().as_idx(&id).or_not_found(&id)?.de()? {
+                    PackageDataEntry::Installed(PackageDataEntryInstalled {
+                        manifest,
+                        static_files,
+                        installed,
+                    }) => (manifest, static_files, installed),
+                    _ => {
+                        return Err(Error::new(
+                            eyre!("Package is not installed."),
+                            crate::ErrorKind::NotFound,
+                        ));
+                    }
+                };
+            let pde = PackageDataEntry::Removing(PackageDataEntryRemoving {
+                manifest,
+                static_files,
+                removing: installed,
+            });
+            db.as_package_data_mut().insert(&id, &pde)
+        })
+        .await?;
+
+    Ok(async move {
+        let mut secrets = ctx.secret_store.acquire().await?;
+        cleanup::uninstall(&ctx, secrets.as_mut(), &id).await
+    })
+}
+
 #[instrument(skip_all)]
 pub async fn download_install_s9pk(
     ctx: RpcContext,
     temp_manifest: Manifest,
     marketplace_url: Option<Url>,
     progress: Arc<InstallProgress>,
-    mut s9pk: impl AsyncRead + Unpin,
+    s9pk: impl AsyncRead + Unpin,
     download_complete: Option<oneshot::Sender<()>>,
+    allow_untrusted_key: bool,
+) -> Result<(), Error> {
+    stage_install_s9pk(
+        ctx,
+        temp_manifest,
+        marketplace_url,
+        progress,
+        S9pkSource::Stream {
+            s9pk,
+            download_complete,
+        },
+        allow_untrusted_key,
+    )
+    .await
+}
+
+/// Like [download_install_s9pk], but downloads the package itself so that it can resume
+/// interrupted transfers, fetch chunks in parallel, and fall back to mirrors.
+#[instrument(skip_all)]
+pub async fn fetch_install_s9pk(
+    ctx: RpcContext,
+    temp_manifest: Manifest,
+    marketplace_url: Option<Url>,
+    progress: Arc<InstallProgress>,
+    remote: RemoteS9pk,
+    parallelism: usize,
+    allow_untrusted_key: bool,
+) -> Result<(), Error> {
+    stage_install_s9pk(
+        ctx,
+        temp_manifest,
+        marketplace_url,
+        progress,
+        S9pkSource::<tokio::io::Empty>::Remote {
+            remote,
+            parallelism,
+        },
+        allow_untrusted_key,
+    )
+    .await
+}
+
+enum S9pkSource<R> {
+    Stream {
+        s9pk: R,
+        download_complete: Option<oneshot::Sender<()>>,
+    },
+    Remote {
+        remote: RemoteS9pk,
+        parallelism: usize,
+    },
+}
+
+#[instrument(skip_all)]
+async fn stage_install_s9pk<R: AsyncRead + Unpin>(
+    ctx: RpcContext,
+    temp_manifest: Manifest,
+    marketplace_url: Option<Url>,
+    progress: Arc<InstallProgress>,
+    source: S9pkSource<R>,
+    allow_untrusted_key: bool,
 ) -> Result<(), Error> {
     let pkg_id = &temp_manifest.id;
     let version = &temp_manifest.version;
@@ -718,27 +958,54 @@ pub async fn download_install_s9pk(
                 pkg_archive_dir.join(AsRef::<Path>::as_ref(pkg_id).with_extension("s9pk"));
 
             File::delete(&pkg_archive).await?;
-            let mut dst = OpenOptions::new()
-                .create(true)
-                .write(true)
-                .read(true)
-                .open(&pkg_archive)
-                .await?;
-
-            progress
-                .track_download_during(ctx.db.clone(), pkg_id, || async {
-                    let mut progress_writer =
-                        InstallProgressTracker::new(&mut dst, progress.clone());
-                    tokio::io::copy(&mut s9pk, &mut progress_writer).await?;
-                    progress.download_complete();
-                    if let Some(complete) = download_complete {
-                        complete.send(()).unwrap_or_default();
-                    }
-                    Ok(())
-                })
-                .await?;
+            let (dst, expected_hash) = match source {
+                S9pkSource::Stream {
+                    mut s9pk,
+                    download_complete,
+                } => {
+                    let mut dst = OpenOptions::new()
+                        .create(true)
+                        .write(true)
+                        .read(true)
+                        .open(&pkg_archive)
+                        .await?;
+
+                    progress
+                        .track_download_during(ctx.db.clone(), pkg_id, || async {
+                            let mut progress_writer =
+                                InstallProgressTracker::new(&mut dst, progress.clone());
+                            tokio::io::copy(&mut s9pk, &mut progress_writer).await?;
+                            progress.download_complete();
+                            if let Some(complete) = download_complete {
+                                complete.send(()).unwrap_or_default();
+                            }
+                            Ok(())
+                        })
+                        .await?;
 
-            dst.seek(SeekFrom::Start(0)).await?;
+                    dst.seek(SeekFrom::Start(0)).await?;
+                    (dst, None)
+                }
+                S9pkSource::Remote {
+                    remote,
+                    parallelism,
+                } => {
+                    let part = download::staging_path(&ctx.datadir, pkg_id, version);
+                    progress
+                        .track_download_during(ctx.db.clone(), pkg_id, || async {
+                            remote
+                                .download(&part, &pkg_archive, parallelism, &progress)
+                                .await?;
+                            progress.download_complete();
+                            Ok(())
+                        })
+                        .await?;
+                    (
+                        File::open(&pkg_archive).await?,
+                        remote.hash().map(|h| h.to_owned()),
+                    )
+                }
+            };
 
             let progress_reader = InstallProgressTracker::new(dst, progress.clone());
             let mut s9pk_reader = progress
@@ -747,6 +1014,19 @@ pub async fn download_install_s9pk(
                 })
                 .await?;
 
+            if let Some(expected) = expected_hash {
+                if s9pk_reader.hash_str() != Some(expected.as_str()) {
+                    return Err(Error::new(
+                        eyre!(
+                            "Downloaded package has hash {}, but the registry index lists {}",
+                            s9pk_reader.hash_str().unwrap_or_default(),
+                            expected
+                        ),
+                        ErrorKind::ValidateS9pk,
+                    ));
+                }
+            }
+
             install_s9pk(
                 ctx.clone(),
                 pkg_id,
@@ -754,6 +1034,8 @@ pub async fn download_install_s9pk(
                 marketplace_url,
                 &mut s9pk_reader,
                 progress,
+                allow_untrusted_key,
+                None,
             )
             .await?;
 
@@ -781,6 +1063,8 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin + Send + Sync>(
     marketplace_url: Option<Url>,
     rdr: &mut S9pkReader<InstallProgressTracker<R>>,
     progress: Arc<InstallProgress>,
+    allow_untrusted_key: bool,
+    rollback: Option<&RollbackPoint>,
 ) -> Result<(), Error> {
     rdr.validate().await?;
     rdr.validated();
@@ -788,6 +1072,21 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin + Send + Sync>(
     rdr.reset().await?;
     let db = ctx.db.peek().await;
 
+    let installed_key = db
+        .as_package_data()
+        .as_idx(pkg_id)
+        .and_then(|pde| pde.as_installed())
+        .map(|i| i.as_developer_key().de())
+        .transpose()?;
+    let pins = trust::check_developer_key(
+        ctx.secret_store.acquire().await?.as_mut(),
+        pkg_id,
+        &developer_key,
+        installed_key.as_ref(),
+        allow_untrusted_key,
+    )
+    .await?;
+
     tracing::info!("Install {}@{}: Unpacking Manifest", pkg_id, version);
     let manifest = progress
         .track_read_during(ctx.db.clone(), pkg_id, || rdr.manifest())
@@ -1006,6 +1305,24 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin + Send + Sync>(
         current_dependents: current_dependents.clone(),
         current_dependencies: current_dependencies.clone(),
         interface_addresses,
+        resource_limits: match &prev {
+            PackageDataEntry::Updating(PackageDataEntryUpdating { installed, .. }) => {
+                installed.resource_limits.clone()
+            }
+            _ => Default::default(),
+        },
+        volume_quotas: match &prev {
+            PackageDataEntry::Updating(PackageDataEntryUpdating { installed, .. }) => {
+                installed.volume_quotas.clone()
+            }
+            _ => Default::default(),
+        },
+        approved_devices: match &prev {
+            PackageDataEntry::Updating(PackageDataEntryUpdating { installed, .. }) => {
+                installed.approved_devices.clone()
+            }
+            _ => Default::default(),
+        },
     };
     let mut next = PackageDataEntryInstalled {
         installed,
@@ -1022,6 +1339,25 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin + Send + Sync>(
         installed: prev, ..
     }) = &prev
     {
+        if &prev.manifest.version != version {
+            if let Some(prev_manager) = ctx
+                .managers
+                .get(&(pkg_id.clone(), prev.manifest.version.clone()))
+                .await
+            {
+                prev_manager.exit().await;
+            }
+            let snapshot = snapshot::take_auto(&ctx, pkg_id, SnapshotReason::Update).await;
+            if let Err(e) = rollback::save(&ctx, prev, version, snapshot).await {
+                tracing::error!(
+                    "Failed to keep {}@{} for rollback: {}",
+                    pkg_id,
+                    prev.manifest.version,
+                    e
+                );
+                tracing::debug!("{:?}", e);
+            }
+        }
         let prev_is_configured = prev.status.configured;
         let prev_migration = prev
             .manifest
@@ -1046,10 +1382,21 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin + Send + Sync>(
             )
             .map(futures::future::Either::Right);
 
-        let viable_migration = if prev.manifest.version > manifest.version {
-            prev_migration.or(migration)
-        } else {
-            migration.or(prev_migration)
+        let viable_migration = match rollback {
+            Some(
+                point @ RollbackPoint {
+                    snapshot: Some(snapshot),
+                    ..
+                },
+            ) => {
+                tracing::info!("Install {}@{}: Restoring volume snapshot", pkg_id, version);
+                rollback::restore_snapshot(&ctx, point, snapshot, &manifest.volumes).await?;
+                configured = point.configured;
+                None
+            }
+            Some(_) => migration.or(prev_migration),
+            None if prev.manifest.version > manifest.version => prev_migration.or(migration),
+            None => migration.or(prev_migration),
         };
 
         if let Some(f) = viable_migration {
@@ -1105,19 +1452,59 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin + Send + Sync>(
     if let Some((id, version)) = to_cleanup {
         cleanup(&ctx, &id, &version).await?;
     }
+    if let Err(e) = rollback::prune(&ctx, pkg_id, version).await {
+        tracing::error!("Failed to prune rollback points for {}: {}", pkg_id, e);
+        tracing::debug!("{:?}", e);
+    }
 
     if configured && manifest.config.is_some() {
         let breakages = BTreeMap::new();
         let overrides = Default::default();
 
+        let config = match crate::config::plan_migration(&ctx, pkg_id, None).await {
+            Ok(Some(migration)) if !migration.changes.is_empty() => {
+                if let Err(e) = ctx
+                    .notification_manager
+                    .notify(
+                        ctx.db.clone(),
+                        Some(pkg_id.clone()),
+                        NotificationLevel::Info,
+                        "Config Migrated".to_owned(),
+                        format!(
+                            "The config of {} was updated to match version {}: {}",
+                            pkg_id,
+                            version,
+                            migration.changes.iter().map(|c| c.path()).join(", ")
+                        ),
+                        (),
+                        None,
+                    )
+                    .await
+                {
+                    tracing::error!("Failed to notify of config migration of {}: {}", pkg_id, e);
+                    tracing::debug!("{:?}", e);
+                }
+                Some(migration.config)
+            }
+            Ok(_) => None,
+            Err(e) => {
+                tracing::error!("Failed to migrate config of {}: {}", pkg_id, e);
+                tracing::debug!("{:?}", e);
+                None
+            }
+        };
+        let migrated = config.is_some();
         let configure_context = ConfigureContext {
             breakages,
             timeout: None,
-            config: None,
+            config,
             dry_run: false,
             overrides,
         };
-        manager.configure(configure_context).await?;
+        let configured = manager.configure(configure_context).await?;
+        if migrated {
+            record_applied(&ctx, pkg_id, RevisionSource::Migrate, None, &configured).await;
+        }
     }
 
     for to_configure in to_configure.into_iter().filter(|(dep, _)| dep != pkg_id) {
@@ -1134,6 +1521,7 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin + Send + Sync>(
                     dry_run: false,
                 })
                 .await
+                .map(|_| ())
         }
         .await
         {
@@ -1146,6 +1534,13 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin + Send + Sync>(
         manager.start().await;
     }
 
+    for pin in pins {
+        if let Err(e) = async { pin.save(ctx.secret_store.acquire().await?.as_mut()).await }.await {
+            tracing::error!("Failed to pin developer key for {}: {}", pkg_id, e);
+            tracing::debug!("{:?}", e);
+        }
+    }
+
     tracing::info!("Install {}@{}: Complete", pkg_id, version);
 
     Ok(())
@@ -0,0 +1,593 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use clap::ArgMatches;
+use emver::VersionRange;
+use reqwest::Url;
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use super::rollback::begin_rollback;
+use super::update::breakages;
+use super::{begin_install, begin_uninstall, fetch_manifest, MinMax};
+use crate::config::{configure, ConfigureContext};
+use crate::context::RpcContext;
+use crate::db::model::DatabaseModel;
+use crate::notifications::NotificationLevel;
+use crate::prelude::*;
+use crate::s9pk::manifest::{Manifest, PackageId};
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::util::{display_none, Version};
+
+/// A package to install or update, as `id` or `id@<version spec>`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PlanTarget {
+    pub id: PackageId,
+    pub version: VersionRange,
+}
+impl std::str::FromStr for PlanTarget {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (id, version) = match s.split_once('@') {
+            Some((id, version)) => (id, version.parse()?),
+            None => (s, VersionRange::Any),
+        };
+        Ok(PlanTarget {
+            id: id.trim().parse()?,
+            version,
+        })
+    }
+}
+
+fn parse_targets(arg: &str, _: &ArgMatches) -> Result<Vec<PlanTarget>, Error> {
+    arg.split(',').map(|s| s.trim().parse()).collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PlanAction {
+    Install,
+    Update,
+}
+impl std::fmt::Display for PlanAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlanAction::Install => write!(f, "install"),
+            PlanAction::Update => write!(f, "update"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PlanStep {
+    pub id: PackageId,
+    pub action: PlanAction,
+    pub from: Option<Version>,
+    pub to: Version,
+    /// Whether the package was asked for, rather than pulled in as a dependency
+    pub requested: bool,
+    /// Packages in the plan that depend on this one
+    pub required_by: BTreeSet<PackageId>,
+    #[serde(skip)]
+    manifest: Option<Manifest>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Plan {
+    /// In the order they will be installed: every package comes after its dependencies
+    pub steps: Vec<PlanStep>,
+    /// Installed packages outside of the plan whose dependencies would no longer be satisfied
+    pub breakages: BTreeMap<PackageId, String>,
+}
+
+pub(super) fn installed_version(
+    db: &DatabaseModel,
+    id: &PackageId,
+) -> Result<Option<Version>, Error> {
+    db.as_package_data()
+        .as_idx(id)
+        .and_then(|p| p.as_installed())
+        .map(|i| i.as_manifest().as_version().de())
+        .transpose()
+}
+
+fn conflict(
+    id: &PackageId,
+    version: &Version,
+    requirer: &PackageId,
+    range: &VersionRange,
+) -> Error {
+    Error::new(
+        eyre!(
+            "{} requires {} {}, which conflicts with {} {}",
+            requirer,
+            id,
+            range,
+            id,
+            version
+        ),
+        ErrorKind::Dependency,
+    )
+}
+
+/// Orders `deps` (each package mapped to the packages it depends on) so that every package comes
+/// after its dependencies.
+pub(super) fn order(
+    deps: &BTreeMap<PackageId, BTreeSet<PackageId>>,
+) -> Result<Vec<PackageId>, Error> {
+    let mut remaining = deps.clone();
+    let mut ordered = Vec::with_capacity(deps.len());
+    while !remaining.is_empty() {
+        let ready = remaining
+            .iter()
+            .filter(|(_, deps)| deps.iter().all(|d| !remaining.contains_key(d)))
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        if ready.is_empty() {
+            return Err(Error::new(
+                eyre!(
+                    "Circular dependency between {}",
+                    remaining
+                        .keys()
+                        .map(|id| id.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+                ErrorKind::Dependency,
+            ));
+        }
+        for id in ready {
+            remaining.remove(&id);
+            ordered.push(id);
+        }
+    }
+    Ok(ordered)
+}
+
+/// Resolves `targets` and their required dependencies against the marketplace. Dependencies that
+/// are already installed at a satisfying version are left alone.
+#[instrument(skip_all)]
+pub async fn resolve(
+    ctx: &RpcContext,
+    marketplace_url: &Url,
+    targets: &[PlanTarget],
+) -> Result<Plan, Error> {
+    let db = ctx.db.peek().await;
+    let requested: BTreeMap<PackageId, VersionRange> = targets
+        .iter()
+        .map(|t| (t.id.clone(), t.version.clone()))
+        .collect();
+    let mut constraints: BTreeMap<PackageId, Vec<(PackageId, VersionRange)>> = BTreeMap::new();
+    let mut resolved: BTreeMap<PackageId, Manifest> = BTreeMap::new();
+    let mut unchanged: BTreeMap<PackageId, Version> = BTreeMap::new();
+    let mut queue: VecDeque<PackageId> = requested.keys().cloned().collect();
+
+    while let Some(id) = queue.pop_front() {
+        if resolved.contains_key(&id) || unchanged.contains_key(&id) {
+            continue;
+        }
+        let reqs = constraints.get(&id).cloned().unwrap_or_default();
+        let check = |v: &Version| -> Result<(), Error> {
+            for (requirer, range) in &reqs {
+                if !v.satisfies(range) {
+                    return Err(conflict(&id, v, requirer, range));
+                }
+            }
+            Ok(())
+        };
+        let installed = installed_version(&db, &id)?;
+        if let Some(installed) = &installed {
+            if !requested.contains_key(&id) && check(installed).is_ok() {
+                unchanged.insert(id, installed.clone());
+                continue;
+            }
+        }
+
+        let range = requested
+            .get(&id)
+            .or_else(|| reqs.first().map(|(_, r)| r))
+            .cloned()
+            .unwrap_or(VersionRange::Any);
+        let man = fetch_manifest(ctx, marketplace_url, &id, &range, MinMax::Max).await?;
+        check(&man.version)?;
+        if installed.as_ref() == Some(&man.version) {
+            unchanged.insert(id, man.version);
+            continue;
+        }
+
+        for (dep, info) in &man.dependencies.0 {
+            if !info.requirement.required() {
+                continue;
+            }
+            constraints
+                .entry(dep.clone())
+                .or_default()
+                .push((id.clone(), info.version.clone()));
+            if let Some(v) = resolved.get(dep).map(|m| &m.version) {
+                if !v.satisfies(&info.version) {
+                    return Err(conflict(dep, v, &id, &info.version));
+                }
+            } else if let Some(v) = unchanged.get(dep) {
+                if !v.satisfies(&info.version) {
+                    unchanged.remove(dep);
+                    queue.push_back(dep.clone());
+                }
+            } else {
+                queue.push_back(dep.clone());
+            }
+        }
+        resolved.insert(id, man);
+    }
+
+    let deps = resolved
+        .iter()
+        .map(|(id, man)| {
+            (
+                id.clone(),
+                man.dependencies
+                    .0
+                    .iter()
+                    .filter(|(dep, info)| {
+                        info.requirement.required() && resolved.contains_key(*dep)
+                    })
+                    .map(|(dep, _)| dep.clone())
+                    .collect(),
+            )
+        })
+        .collect();
+
+    let mut plan = Plan::default();
+    for id in order(&deps)? {
+        let man = resolved.remove(&id).or_not_found(&id)?;
+        let from = installed_version(&db, &id)?;
+        if from.is_some() {
+            plan.breakages.extend(
+                breakages(&db, &id, &man.version)?
+                    .into_iter()
+                    .filter(|(dependent, _)| !deps.contains_key(dependent)),
+            );
+        }
+        plan.steps.push(PlanStep {
+            action: if from.is_some() {
+                PlanAction::Update
+            } else {
+                PlanAction::Install
+            },
+            from,
+            to: man.version.clone(),
+            requested: requested.contains_key(&id),
+            required_by: constraints
+                .get(&id)
+                .into_iter()
+                .flatten()
+                .map(|(requirer, _)| requirer.clone())
+                .filter(|requirer| deps.contains_key(requirer))
+                .collect(),
+            id,
+            manifest: Some(man),
+        });
+    }
+    Ok(plan)
+}
+
+/// Refuses a plan whose updates could not be reverted if a later step fails, which is the case
+/// when no rollback points are kept.
+fn check_revertible(plan: &Plan, rollback_retention: usize) -> Result<(), Error> {
+    let updates = plan
+        .steps
+        .iter()
+        .filter(|s| s.action == PlanAction::Update)
+        .map(|s| s.id.to_string())
+        .collect::<Vec<_>>();
+    if rollback_retention == 0 && !updates.is_empty() {
+        return Err(Error::new(
+            eyre!(
+                "Plan updates {}, which could not be reverted if it fails because rollback \
+                retention is 0. Set it to at least 1 with `package.rollback.set-retention`",
+                updates.join(", ")
+            ),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+    Ok(())
+}
+
+#[instrument(skip_all)]
+async fn execute(
+    ctx: &RpcContext,
+    marketplace_url: &Url,
+    plan: &Plan,
+    allow_untrusted_key: bool,
+    done: &mut Vec<(PackageId, Option<Version>)>,
+) -> Result<(), Error> {
+    for step in &plan.steps {
+        tracing::info!("Apply Plan: {} {}@{}", step.action, step.id, step.to);
+        let manifest = step.manifest.clone().or_not_found(&step.id)?;
+        let needs_config = manifest.config.is_some();
+        begin_install(
+            ctx.clone(),
+            manifest,
+            marketplace_url.clone(),
+            MinMax::Max,
+            Vec::new(),
+            super::download::DEFAULT_PARALLELISM,
+            allow_untrusted_key,
+        )
+        .await?
+        .await?;
+        done.push((step.id.clone(), step.from.clone()));
+
+        let configured = ctx
+            .db
+            .peek()
+            .await
+            .as_package_data()
+            .as_idx(&step.id)
+            .or_not_found(&step.id)?
+            .as_installed()
+            .or_not_found(&step.id)?
+            .as_status()
+            .as_configured()
+            .de()?;
+        if needs_config && !configured {
+            configure(
+                ctx,
+                &step.id,
+                ConfigureContext {
+                    breakages: BTreeMap::new(),
+                    timeout: None,
+                    config: None,
+                    overrides: BTreeMap::new(),
+                    dry_run: false,
+                },
+            )
+            .await?;
+        }
+    }
+    for step in &plan.steps {
+        crate::control::start(ctx.clone(), step.id.clone()).await?;
+    }
+    Ok(())
+}
+
+/// Undoes the steps of a failed plan, most recent first, waiting for each to finish
+pub(super) async fn revert(
+    ctx: &RpcContext,
+    done: Vec<(PackageId, Option<Version>)>,
+) -> Result<(), Error> {
+    let mut errors = ErrorCollection::new();
+    for (id, from) in done.into_iter().rev() {
+        let res = match from {
+            None => match begin_uninstall(ctx.clone(), id.clone()).await {
+                Ok(removing) => removing.await,
+                Err(e) => Err(e),
+            },
+            Some(from) => match begin_rollback(ctx.clone(), id.clone(), Some(from)).await {
+                Ok(rolling_back) => rolling_back.await,
+                Err(e) => Err(e),
+            },
+        };
+        if let Err(e) = res {
+            tracing::error!("Failed to revert {}: {}", id, e);
+            tracing::debug!("{:?}", e);
+            errors.handle(Err::<(), _>(Error::new(eyre!("{}: {}", id, e), e.kind)));
+        }
+    }
+    errors.into_result()
+}
+
+#[command(
+    rename = "apply-plan",
+    subcommands(self(apply_plan_impl(async, context(RpcContext))), dry),
+    display(display_none),
+    metadata(sync_db = true)
+)]
+pub fn apply_plan(
+    #[arg(parse(parse_targets))] targets: Vec<PlanTarget>,
+    #[arg(short = 'm', long = "marketplace-url", rename = "marketplace-url")]
+    marketplace_url: Option<Url>,
+    #[arg(long = "allow-untrusted-key", rename = "allow-untrusted-key", default)]
+    allow_untrusted_key: bool,
+) -> Result<(Vec<PlanTarget>, Url, bool), Error> {
+    Ok((
+        targets,
+        marketplace_url.unwrap_or_else(|| crate::DEFAULT_MARKETPLACE.parse().unwrap()),
+        allow_untrusted_key,
+    ))
+}
+
+fn display_plan(plan: Plan, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(plan, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "#", "PACKAGE", "ACTION", "FROM", "TO", "REQUIRED BY"]);
+    for (idx, step) in plan.steps.iter().enumerate() {
+        table.add_row(row![
+            &(idx + 1).to_string(),
+            &*step.id,
+            &step.action.to_string(),
+            &step
+                .from
+                .as_ref()
+                .map(|v| v.to_string())
+                .unwrap_or_default(),
+            &step.to.to_string(),
+            &step
+                .required_by
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+        ]);
+    }
+    table.print_tty(false).unwrap();
+    for (id, reason) in plan.breakages {
+        println!("Would break {}: {}", id, reason);
+    }
+}
+
+/// Shows the plan without changing anything
+#[command(display(display_plan))]
+pub async fn dry(
+    #[context] ctx: RpcContext,
+    #[parent_data] (targets, marketplace_url, _): (Vec<PlanTarget>, Url, bool),
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Plan, Error> {
+    resolve(&ctx, &marketplace_url, &targets).await
+}
+
+/// Installs, configures and starts the packages of the plan in dependency order. If any step
+/// fails, every package installed or updated by the plan is reverted.
+#[instrument(skip_all)]
+pub async fn apply_plan_impl(
+    ctx: RpcContext,
+    (targets, marketplace_url, allow_untrusted_key): (Vec<PlanTarget>, Url, bool),
+) -> Result<(), Error> {
+    let plan = resolve(&ctx, &marketplace_url, &targets).await?;
+    if !plan.breakages.is_empty() {
+        return Err(Error::new(
+            eyre!(
+                "Plan would break: {}",
+                plan.breakages
+                    .iter()
+                    .map(|(id, reason)| format!("{} ({})", id, reason))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            ErrorKind::Dependency,
+        ));
+    }
+    check_revertible(
+        &plan,
+        ctx.db
+            .peek()
+            .await
+            .as_server_info()
+            .as_rollback_retention()
+            .de()?,
+    )?;
+    tokio::spawn(async move {
+        let mut done = Vec::new();
+        let (level, title, msg) = match execute(
+            &ctx,
+            &marketplace_url,
+            &plan,
+            allow_untrusted_key,
+            &mut done,
+        )
+        .await
+        {
+            Ok(()) => (
+                NotificationLevel::Success,
+                "Plan Applied",
+                format!("Installed {} package(s)", plan.steps.len()),
+            ),
+            Err(e) => {
+                tracing::error!("Apply Plan Failed: {}", e);
+                tracing::debug!("{:?}", e);
+                let reverted = match revert(&ctx, done).await {
+                    Ok(()) => "Changes made by the plan have been reverted".to_owned(),
+                    Err(e) => format!("Some changes made by the plan could not be reverted: {}", e),
+                };
+                (
+                    NotificationLevel::Error,
+                    "Apply Plan Failed",
+                    format!("{}. {}.", e, reverted),
+                )
+            }
+        };
+        if let Err(e) = ctx
+            .notification_manager
+            .notify(ctx.db.clone(), None, level, title.to_owned(), msg, (), None)
+            .await
+        {
+            tracing::error!("Failed to issue Notification: {}", e);
+            tracing::debug!("{:?}", e);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn id(s: &str) -> PackageId {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_targets() {
+        let target: PlanTarget = "electrs@>=0.9.0".parse().unwrap();
+        assert_eq!(target.id, id("electrs"));
+        assert!(matches!(
+            "bitcoind".parse::<PlanTarget>().unwrap().version,
+            VersionRange::Any
+        ));
+        assert!("bitcoind@nonsense".parse::<PlanTarget>().is_err());
+    }
+
+    #[test]
+    fn orders_dependencies_first() {
+        let deps = [
+            ("btcpayserver", vec!["bitcoind", "lnd"]),
+            ("mempool", vec!["bitcoind", "electrs"]),
+            ("electrs", vec!["bitcoind"]),
+            ("lnd", vec!["bitcoind"]),
+            ("bitcoind", vec![]),
+        ]
+        .into_iter()
+        .map(|(pkg, deps)| (id(pkg), deps.into_iter().map(id).collect()))
+        .collect();
+        let ordered = order(&deps).unwrap();
+        let pos = |s: &str| ordered.iter().position(|p| p == &id(s)).unwrap();
+        assert_eq!(pos("bitcoind"), 0);
+        assert!(pos("electrs") < pos("mempool"));
+        assert!(pos("lnd") < pos("btcpayserver"));
+
+        let cyclic = [("a", vec!["b"]), ("b", vec!["a"])]
+            .into_iter()
+            .map(|(pkg, deps)| (id(pkg), deps.into_iter().map(id).collect()))
+            .collect();
+        assert!(order(&cyclic).is_err());
+    }
+
+    #[test]
+    fn refuses_unrevertible_updates() {
+        let step = |pkg: &str, action, from: Option<&str>| PlanStep {
+            id: id(pkg),
+            action,
+            from: from.map(|v| v.parse().unwrap()),
+            to: "1.0.0".parse().unwrap(),
+            requested: true,
+            required_by: BTreeSet::new(),
+            manifest: None,
+        };
+        let installs = Plan {
+            steps: vec![step("bitcoind", PlanAction::Install, None)],
+            breakages: BTreeMap::new(),
+        };
+        assert!(check_revertible(&installs, 0).is_ok());
+        let updates = Plan {
+            steps: vec![
+                step("bitcoind", PlanAction::Install, None),
+                step("electrs", PlanAction::Update, Some("0.9.0")),
+            ],
+            breakages: BTreeMap::new(),
+        };
+        assert!(check_revertible(&updates, 1).is_ok());
+        assert_eq!(
+            check_revertible(&updates, 0).unwrap_err().kind,
+            ErrorKind::InvalidRequest
+        );
+    }
+}
@@ -24,6 +24,12 @@ pub struct InstallProgress {
     pub validation_complete: AtomicBool,
     pub unpacked: AtomicU64,
     pub unpack_complete: AtomicBool,
+    /// Failed download requests that were retried, possibly against a mirror
+    #[serde(default)]
+    pub retries: AtomicU64,
+    /// Times a partial download was picked up instead of starting over
+    #[serde(default)]
+    pub resumptions: AtomicU64,
 }
 impl InstallProgress {
     pub fn new(size: Option<u64>) -> Self {
@@ -35,6 +41,8 @@ impl InstallProgress {
             validation_complete: AtomicBool::new(false),
             unpacked: AtomicU64::new(0),
             unpack_complete: AtomicBool::new(false),
+            retries: AtomicU64::new(0),
+            resumptions: AtomicU64::new(0),
         }
     }
     pub fn download_complete(&self) {
@@ -0,0 +1,565 @@
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use clap::ArgMatches;
+use reqwest::Url;
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tracing::instrument;
+
+use super::cleanup::cleanup_failed;
+use super::progress::{InstallProgress, InstallProgressTracker};
+use super::{install_s9pk, PKG_ARCHIVE_DIR};
+use crate::context::RpcContext;
+use crate::db::model::{
+    InstalledPackageInfo, PackageDataEntry, PackageDataEntryInstalled, PackageDataEntryUpdating,
+};
+use crate::notifications::NotificationLevel;
+use crate::prelude::*;
+use crate::s9pk::manifest::PackageId;
+use crate::s9pk::reader::S9pkReader;
+use crate::util::display_none;
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::util::Version;
+use crate::volume::snapshot::{self, SnapshotReason, VolumeSnapshot};
+use crate::volume::Volumes;
+
+/// Previous versions of packages are kept here, relative to the data directory.
+pub const PKG_ROLLBACK_DIR: &str = "package-data/rollback";
+
+pub fn default_retention() -> usize {
+    1
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RollbackPoint {
+    pub id: PackageId,
+    pub version: Version,
+    /// The version that was installed over this one
+    pub replaced_by: Version,
+    pub created_at: DateTime<Utc>,
+    pub marketplace_url: Option<Url>,
+    pub configured: bool,
+    /// The volume snapshot taken before the update ran its migrations
+    pub snapshot: Option<String>,
+}
+
+fn package_dir(datadir: &Path, id: &PackageId) -> PathBuf {
+    datadir.join(PKG_ROLLBACK_DIR).join(id)
+}
+
+fn point_dir(datadir: &Path, id: &PackageId, version: &Version) -> PathBuf {
+    package_dir(datadir, id).join(version.as_str())
+}
+
+fn s9pk_name(id: &PackageId) -> PathBuf {
+    AsRef::<Path>::as_ref(id).with_extension("s9pk")
+}
+
+async fn load_points(datadir: &Path, id: &PackageId) -> Result<Vec<RollbackPoint>, Error> {
+    let dir = package_dir(datadir, id);
+    let mut points = Vec::new();
+    if tokio::fs::metadata(&dir).await.is_err() {
+        return Ok(points);
+    }
+    let mut versions = tokio::fs::read_dir(&dir).await?;
+    while let Some(entry) = versions.next_entry().await? {
+        let info = entry.path().join("info.json");
+        match tokio::fs::read(&info).await {
+            Ok(info) => points.push(
+                serde_json::from_slice::<RollbackPoint>(&info)
+                    .with_kind(ErrorKind::Deserialization)?,
+            ),
+            Err(e) => tracing::warn!("Ignoring incomplete rollback point {:?}: {}", info, e),
+        }
+    }
+    points.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+    Ok(points)
+}
+
+/// Keeps the s9pk and a snapshot of the volumes of `prev` so that the update to `next_version`
+/// can be undone. Must be called before any migration runs. `snapshot` is the snapshot already
+/// taken for the update, if any, which is kept instead of taking another one.
+#[instrument(skip_all)]
+pub async fn save(
+    ctx: &RpcContext,
+    prev: &InstalledPackageInfo,
+    next_version: &Version,
+    snapshot: Option<VolumeSnapshot>,
+) -> Result<(), Error> {
+    let retention = ctx
+        .db
+        .peek()
+        .await
+        .as_server_info()
+        .as_rollback_retention()
+        .de()?;
+    if retention == 0 {
+        return Ok(());
+    }
+    let id = &prev.manifest.id;
+    let version = &prev.manifest.version;
+    let dir = point_dir(&ctx.datadir, id, version);
+    if tokio::fs::metadata(&dir).await.is_ok() {
+        tokio::fs::remove_dir_all(&dir).await?;
+    }
+    tokio::fs::create_dir_all(&dir).await?;
+
+    let archive = ctx
+        .datadir
+        .join(PKG_ARCHIVE_DIR)
+        .join(id)
+        .join(version.as_str())
+        .join(s9pk_name(id));
+    if tokio::fs::hard_link(&archive, dir.join(s9pk_name(id)))
+        .await
+        .is_err()
+    {
+        tokio::fs::copy(&archive, dir.join(s9pk_name(id)))
+            .await
+            .with_ctx(|_| (ErrorKind::Filesystem, archive.display().to_string()))?;
+    }
+
+    let snapshot = match snapshot {
+        Some(snapshot) => snapshot::keep_for_rollback(ctx, snapshot).await,
+        None => snapshot::take(ctx, id, SnapshotReason::Rollback, None).await,
+    };
+    let snapshot = match snapshot {
+        Ok(snapshot) => Some(snapshot.id),
+        Err(e) => {
+            tracing::warn!(
+                "Not keeping the volumes of {}@{} for rollback: {}",
+                id,
+                version,
+                e
+            );
+            tracing::debug!("{:?}", e);
+            None
+        }
+    };
+
+    let point = RollbackPoint {
+        id: id.clone(),
+        version: version.clone(),
+        replaced_by: next_version.clone(),
+        created_at: Utc::now(),
+        marketplace_url: prev.marketplace_url.clone(),
+        configured: prev.status.configured,
+        snapshot,
+    };
+    tokio::fs::write(
+        dir.join("info.json"),
+        serde_json::to_vec_pretty(&point).with_kind(ErrorKind::Serialization)?,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Replaces the volumes of the package with the snapshot kept for `point`
+#[instrument(skip_all)]
+pub async fn restore_snapshot(
+    ctx: &RpcContext,
+    point: &RollbackPoint,
+    snapshot: &str,
+    volumes: &Volumes,
+) -> Result<(), Error> {
+    let snapshot = snapshot::get(ctx, &point.id, snapshot).await?;
+    snapshot::restore_volumes(ctx, &snapshot, volumes, &point.version).await
+}
+
+async fn remove_point(ctx: &RpcContext, point: &RollbackPoint) -> Result<(), Error> {
+    if let Some(snapshot) = &point.snapshot {
+        snapshot::remove_by_id(ctx, &point.id, snapshot).await?;
+    }
+    tokio::fs::remove_dir_all(point_dir(&ctx.datadir, &point.id, &point.version)).await?;
+    Ok(())
+}
+
+/// The points that [prune] removes out of `points`, which are sorted newest first.
+fn to_prune(
+    points: Vec<RollbackPoint>,
+    installed: &Version,
+    retention: usize,
+) -> Vec<RollbackPoint> {
+    let mut kept = 0;
+    points
+        .into_iter()
+        .filter(|point| {
+            if &point.version != installed && kept < retention {
+                kept += 1;
+                false
+            } else {
+                true
+            }
+        })
+        .collect()
+}
+
+/// Removes the point for the installed version, and all but the newest points beyond the
+/// configured retention.
+#[instrument(skip_all)]
+pub async fn prune(ctx: &RpcContext, id: &PackageId, installed: &Version) -> Result<(), Error> {
+    let retention = ctx
+        .db
+        .peek()
+        .await
+        .as_server_info()
+        .as_rollback_retention()
+        .de()?;
+    for point in to_prune(load_points(&ctx.datadir, id).await?, installed, retention) {
+        remove_point(ctx, &point).await?;
+    }
+    Ok(())
+}
+
+/// The point to roll back to from `current`: the one for `version` if given, otherwise the newest.
+fn select_point(
+    points: Vec<RollbackPoint>,
+    current: &Version,
+    version: Option<&Version>,
+) -> Option<RollbackPoint> {
+    points
+        .into_iter()
+        .filter(|p| &p.version != current)
+        .find(|p| version.map_or(true, |v| v == &p.version))
+}
+
+/// Removes every point kept for `id`, used when it is uninstalled
+pub async fn remove_all(ctx: &RpcContext, id: &PackageId) -> Result<(), Error> {
+    let dir = package_dir(&ctx.datadir, id);
+    if tokio::fs::metadata(&dir).await.is_ok() {
+        tokio::fs::remove_dir_all(&dir).await?;
+    }
+    Ok(())
+}
+
+#[command(
+    subcommands(self(rollback_impl(async, context(RpcContext))), list, set_retention),
+    display(display_none),
+    metadata(sync_db = true)
+)]
+pub fn rollback(
+    #[arg] id: Option<PackageId>,
+    #[arg(long = "version")] version: Option<Version>,
+) -> Result<(Option<PackageId>, Option<Version>), Error> {
+    Ok((id, version))
+}
+
+/// Reinstalls the version of `id` that was replaced most recently, or `version` if given, and
+/// restores its volumes as they were before it was replaced.
+#[instrument(skip_all)]
+pub async fn rollback_impl(
+    ctx: RpcContext,
+    (id, version): (Option<PackageId>, Option<Version>),
+) -> Result<(), Error> {
+    let id =
+        id.ok_or_else(|| Error::new(eyre!("A package id is required"), ErrorKind::InvalidRequest))?;
+    let rolling_back = begin_rollback(ctx.clone(), id.clone(), version).await?;
+    tokio::spawn(async move {
+        if let Err(e) = rolling_back.await {
+            let err_str = format!("Rollback of {} Failed: {}", id, e);
+            tracing::error!("{}", err_str);
+            tracing::debug!("{:?}", e);
+            if let Err(e) = ctx
+                .notification_manager
+                .notify(
+                    ctx.db.clone(),
+                    Some(id),
+                    NotificationLevel::Error,
+                    String::from("Rollback Failed"),
+                    err_str,
+                    (),
+                    None,
+                )
+                .await
+            {
+                tracing::error!("Failed to issue Notification: {}", e);
+                tracing::debug!("{:?}", e);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Marks `id` as updating to the rollback point for `version`, or the most recent one. Returns
+/// the install itself, to be spawned or awaited by the caller.
+#[instrument(skip_all)]
+pub async fn begin_rollback(
+    ctx: RpcContext,
+    id: PackageId,
+    version: Option<Version>,
+) -> Result<impl Future<Output = Result<(), Error>> + Send, Error> {
+    let current = ctx
+        .db
+        .peek()
+        .await
+        .as_package_data()
+        .as_idx(&id)
+        .or_not_found(&id)?
+        .expect_as_installed()?
+        .as_manifest()
+        .as_version()
+        .de()?;
+    let point = select_point(
+        load_points(&ctx.datadir, &id).await?,
+        &current,
+        version.as_ref(),
+    )
+    .ok_or_else(|| {
+        Error::new(
+            eyre!(
+                "No rollback point for {}{}",
+                id,
+                version.map(|v| format!("@{}", v)).unwrap_or_default()
+            ),
+            ErrorKind::NotFound,
+        )
+    })?;
+
+    let pkg_archive_dir = ctx
+        .datadir
+        .join(PKG_ARCHIVE_DIR)
+        .join(&id)
+        .join(point.version.as_str());
+    tokio::fs::create_dir_all(&pkg_archive_dir).await?;
+    let pkg_archive = pkg_archive_dir.join(s9pk_name(&id));
+    tokio::fs::copy(
+        point_dir(&ctx.datadir, &id, &point.version).join(s9pk_name(&id)),
+        &pkg_archive,
+    )
+    .await?;
+    let manifest = S9pkReader::open(&pkg_archive, false)
+        .await?
+        .manifest()
+        .await?;
+
+    let progress = Arc::new(InstallProgress::new(Some(
+        tokio::fs::metadata(&pkg_archive).await?.len(),
+    )));
+    progress.download_complete();
+    ctx.db
+        .mutate(|db| {
+            let pde = match db.as_package_data().as_idx(&id).or_not_found(&id)?.de()? {
+                PackageDataEntry::Installed(PackageDataEntryInstalled {
+                    installed,
+                    static_files,
+                    ..
+                }) => PackageDataEntry::Updating(PackageDataEntryUpdating {
+                    install_progress: progress.clone(),
+                    static_files,
+                    installed,
+                    manifest: manifest.clone(),
+                }),
+                _ => {
+                    return Err(Error::new(
+                        eyre!("Cannot roll back a package in a transient state"),
+                        ErrorKind::InvalidRequest,
+                    ))
+                }
+            };
+            db.as_package_data_mut().insert(&id, &pde)
+        })
+        .await?;
+
+    Ok(async move {
+        let res = async {
+            let progress_reader =
+                InstallProgressTracker::new(File::open(&pkg_archive).await?, progress.clone());
+            let mut s9pk_reader = progress
+                .track_read_during(ctx.db.clone(), &id, || {
+                    S9pkReader::from_reader(progress_reader, true)
+                })
+                .await?;
+            install_s9pk(
+                ctx.clone(),
+                &id,
+                &point.version,
+                point.marketplace_url.clone(),
+                &mut s9pk_reader,
+                progress,
+                false,
+                Some(&point),
+            )
+            .await
+        }
+        .await;
+        if res.is_err() {
+            if let Err(e) = cleanup_failed(&ctx, &id).await {
+                tracing::error!("Failed to clean up {}@{}: {}", id, point.version, e);
+                tracing::debug!("{:?}", e);
+            }
+        }
+        res
+    })
+}
+
+fn display_points(points: Vec<RollbackPoint>, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(points, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "PACKAGE", "VERSION", "REPLACED BY", "CREATED AT", "SNAPSHOT"]);
+    for point in points {
+        table.add_row(row![
+            &*point.id,
+            &point.version.to_string(),
+            &point.replaced_by.to_string(),
+            &point.created_at.to_rfc3339(),
+            point.snapshot.as_deref().unwrap_or("N/A"),
+        ]);
+    }
+    table.print_tty(false).unwrap();
+}
+
+#[command(display(display_points))]
+pub async fn list(
+    #[context] ctx: RpcContext,
+    #[arg] id: Option<PackageId>,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<RollbackPoint>, Error> {
+    let ids = match id {
+        Some(id) => vec![id],
+        None => ctx
+            .db
+            .peek()
+            .await
+            .as_package_data()
+            .keys()?
+            .into_iter()
+            .collect(),
+    };
+    let mut points = Vec::new();
+    for id in ids {
+        points.extend(load_points(&ctx.datadir, &id).await?);
+    }
+    Ok(points)
+}
+
+/// Sets how many previous versions of each package are kept. 0 disables rollback.
+#[command(rename = "set-retention", display(display_none))]
+pub async fn set_retention(#[context] ctx: RpcContext, #[arg] count: usize) -> Result<(), Error> {
+    ctx.db
+        .mutate(|db| {
+            db.as_server_info_mut()
+                .as_rollback_retention_mut()
+                .ser(&count)
+        })
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn v(s: &str) -> Version {
+        s.parse().unwrap()
+    }
+
+    fn point(version: &str, replaced_by: &str, age_hours: i64) -> RollbackPoint {
+        RollbackPoint {
+            id: "hello-world".parse().unwrap(),
+            version: v(version),
+            replaced_by: v(replaced_by),
+            created_at: Utc::now() - chrono::Duration::hours(age_hours),
+            marketplace_url: None,
+            configured: true,
+            snapshot: None,
+        }
+    }
+
+    fn versions(points: &[RollbackPoint]) -> Vec<String> {
+        points.iter().map(|p| p.version.to_string()).collect()
+    }
+
+    #[test]
+    fn prunes_beyond_retention() {
+        let points = || {
+            vec![
+                point("1.2.0", "1.3.0", 1),
+                point("1.1.0", "1.2.0", 2),
+                point("1.0.0", "1.1.0", 3),
+            ]
+        };
+        assert_eq!(
+            versions(&to_prune(points(), &v("1.3.0"), 1)),
+            ["1.1.0", "1.0.0"]
+        );
+        assert_eq!(versions(&to_prune(points(), &v("1.3.0"), 2)), ["1.0.0"]);
+        assert!(to_prune(points(), &v("1.3.0"), 5).is_empty());
+        assert_eq!(
+            versions(&to_prune(points(), &v("1.3.0"), 0)),
+            ["1.2.0", "1.1.0", "1.0.0"]
+        );
+        // after rolling back to 1.2.0 its own point is no longer needed
+        assert_eq!(
+            versions(&to_prune(points(), &v("1.2.0"), 1)),
+            ["1.2.0", "1.0.0"]
+        );
+    }
+
+    #[test]
+    fn selects_newest_or_requested_point() {
+        let points = || vec![point("1.2.0", "1.3.0", 1), point("1.1.0", "1.2.0", 2)];
+        assert_eq!(
+            select_point(points(), &v("1.3.0"), None).unwrap().version,
+            v("1.2.0")
+        );
+        assert_eq!(
+            select_point(points(), &v("1.3.0"), Some(&v("1.1.0")))
+                .unwrap()
+                .version,
+            v("1.1.0")
+        );
+        assert_eq!(
+            select_point(points(), &v("1.2.0"), None).unwrap().version,
+            v("1.1.0")
+        );
+    }
+
+    #[test]
+    fn no_point_to_roll_back_to() {
+        assert!(select_point(Vec::new(), &v("1.3.0"), None).is_none());
+        assert!(select_point(vec![point("1.2.0", "1.3.0", 1)], &v("1.2.0"), None).is_none());
+        assert!(select_point(
+            vec![point("1.2.0", "1.3.0", 1)],
+            &v("1.3.0"),
+            Some(&v("1.0.0"))
+        )
+        .is_none());
+    }
+
+    #[tokio::test]
+    async fn loads_points_newest_first() {
+        let datadir =
+            std::env::temp_dir().join(format!("startos-rollback-{}", rand::random::<u64>()));
+        let id: PackageId = "hello-world".parse().unwrap();
+        assert!(load_points(&datadir, &id).await.unwrap().is_empty());
+
+        for p in [point("1.1.0", "1.2.0", 2), point("1.2.0", "1.3.0", 1)] {
+            let dir = point_dir(&datadir, &id, &p.version);
+            tokio::fs::create_dir_all(&dir).await.unwrap();
+            tokio::fs::write(dir.join("info.json"), serde_json::to_vec(&p).unwrap())
+                .await
+                .unwrap();
+        }
+        // interrupted before info.json was written
+        tokio::fs::create_dir_all(point_dir(&datadir, &id, &v("1.0.0")))
+            .await
+            .unwrap();
+        assert_eq!(
+            versions(&load_points(&datadir, &id).await.unwrap()),
+            ["1.2.0", "1.1.0"]
+        );
+        tokio::fs::remove_dir_all(&datadir).await.unwrap();
+    }
+}
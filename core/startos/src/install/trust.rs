@@ -0,0 +1,372 @@
+use base64::Engine;
+use chrono::NaiveDateTime;
+use clap::ArgMatches;
+use ed25519_dalek::VerifyingKey;
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use sqlx::{Executor, Postgres};
+use tracing::instrument;
+
+use crate::context::RpcContext;
+use crate::prelude::*;
+use crate::s9pk::manifest::PackageId;
+use crate::util::display_none;
+use crate::util::serde::{display_serializable, IoFormat};
+
+/// How a key ended up in the trust store.
+pub const SOURCE_TOFU: &str = "tofu";
+pub const SOURCE_MANUAL: &str = "manual";
+pub const SOURCE_OVERRIDE: &str = "override";
+
+pub fn encode_key(key: &VerifyingKey) -> String {
+    base64::engine::general_purpose::STANDARD.encode(key.as_bytes())
+}
+
+/// Accepts either a base64 encoded ed25519 public key, or a PEM `PUBLIC KEY` block
+/// (as produced from the key written by `start-sdk init`).
+fn parse_key(key: &str) -> Result<VerifyingKey, Error> {
+    let key = key.trim();
+    if key.starts_with("-----BEGIN") {
+        let bytes =
+            <ed25519::PublicKeyBytes as ed25519::pkcs8::DecodePublicKey>::from_public_key_pem(key)
+                .with_kind(ErrorKind::Pem)?;
+        return VerifyingKey::from_bytes(&bytes.0).with_kind(ErrorKind::InvalidSignature);
+    }
+    let bytes: [u8; 32] = base64::engine::general_purpose::STANDARD
+        .decode(key)
+        .with_kind(ErrorKind::Deserialization)?
+        .try_into()
+        .map_err(|_| {
+            Error::new(
+                eyre!("developer key must be 32 bytes"),
+                ErrorKind::InvalidSignature,
+            )
+        })?;
+    VerifyingKey::from_bytes(&bytes).with_kind(ErrorKind::InvalidSignature)
+}
+
+/// A key accepted by [check_developer_key] that is not in the trust store yet. It is only
+/// written by [TrustPin::save] once the install it was checked for has completed.
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct TrustPin {
+    package_id: PackageId,
+    pubkey: String,
+    source: &'static str,
+}
+impl TrustPin {
+    pub async fn save<Ex>(&self, secrets: &mut Ex) -> Result<(), Error>
+    where
+        for<'a> &'a mut Ex: Executor<'a, Database = Postgres>,
+    {
+        pin(secrets, &self.package_id, &self.pubkey, self.source).await
+    }
+}
+
+/// Checks `key` against the keys trusted for `pkg_id`, returning the pins the caller should save
+/// once the install succeeds.
+///
+/// The first key seen for a package is pinned (trust on first use). A package that was installed
+/// before it had any pins is pinned to the key it was installed with. Any other key is refused
+/// unless `allow_untrusted` is set, in which case it is pinned as well.
+#[instrument(skip_all)]
+pub async fn check_developer_key<Ex>(
+    secrets: &mut Ex,
+    pkg_id: &PackageId,
+    key: &VerifyingKey,
+    installed_key: Option<&VerifyingKey>,
+    allow_untrusted: bool,
+) -> Result<Vec<TrustPin>, Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Postgres>,
+{
+    let trusted = sqlx::query!(
+        "SELECT pubkey FROM trusted_developer_keys WHERE package_id = $1",
+        &**pkg_id
+    )
+    .fetch_all(&mut *secrets)
+    .await?
+    .into_iter()
+    .map(|r| r.pubkey)
+    .collect::<Vec<_>>();
+    check_trusted(pkg_id, key, trusted, installed_key, allow_untrusted)
+}
+
+/// The decision made by [check_developer_key], given the keys currently trusted for `pkg_id`.
+fn check_trusted(
+    pkg_id: &PackageId,
+    key: &VerifyingKey,
+    trusted: Vec<String>,
+    installed_key: Option<&VerifyingKey>,
+    allow_untrusted: bool,
+) -> Result<Vec<TrustPin>, Error> {
+    let encoded = encode_key(key);
+    let new_pin = |pubkey: &str, source| TrustPin {
+        package_id: pkg_id.clone(),
+        pubkey: pubkey.to_owned(),
+        source,
+    };
+
+    let mut pins = Vec::new();
+    let (trusted, pinned_installed) = match (trusted.is_empty(), installed_key) {
+        (true, Some(installed)) => {
+            let installed = encode_key(installed);
+            pins.push(new_pin(&installed, SOURCE_TOFU));
+            (vec![installed], true)
+        }
+        (_, _) => (trusted, false),
+    };
+
+    if trusted.is_empty() {
+        tracing::info!("Pinning developer key {} for {}", encoded, pkg_id);
+        pins.push(new_pin(&encoded, SOURCE_TOFU));
+        return Ok(pins);
+    }
+    if trusted.contains(&encoded) {
+        return Ok(pins);
+    }
+    if allow_untrusted {
+        tracing::warn!(
+            "Installing {} signed by untrusted developer key {} at user request",
+            pkg_id,
+            encoded
+        );
+        pins.push(new_pin(&encoded, SOURCE_OVERRIDE));
+        return Ok(pins);
+    }
+    Err(Error::new(
+        eyre!(
+            "{} is signed by developer key {}, which is not trusted for this package{}. \
+            If this is expected, add it with `package.trust.add` or retry with --allow-untrusted-key",
+            pkg_id,
+            encoded,
+            if pinned_installed {
+                " (the installed version was signed by a different key)"
+            } else {
+                ""
+            }
+        ),
+        ErrorKind::InvalidSignature,
+    ))
+}
+
+/// Checks that a bundle is signed by a key that is already trusted for some package. Bundles can
+/// carry any package, so trust on first use does not apply to them.
+#[instrument(skip_all)]
+pub async fn check_bundle_signer<Ex>(
+    secrets: &mut Ex,
+    key: &VerifyingKey,
+    allow_untrusted: bool,
+) -> Result<(), Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Postgres>,
+{
+    let encoded = encode_key(key);
+    let trusted = sqlx::query_scalar!(
+        "SELECT EXISTS (SELECT 1 FROM trusted_developer_keys WHERE pubkey = $1) AS \"trusted!\"",
+        encoded
+    )
+    .fetch_one(secrets)
+    .await?;
+    if trusted {
+        return Ok(());
+    }
+    if allow_untrusted {
+        tracing::warn!(
+            "Installing bundle signed by untrusted key {} at user request",
+            encoded
+        );
+        return Ok(());
+    }
+    Err(Error::new(
+        eyre!(
+            "Bundle is signed by {}, which is not a trusted developer key. \
+            If this is expected, add it with `package.trust.add` or retry with --allow-untrusted-key",
+            encoded
+        ),
+        ErrorKind::InvalidSignature,
+    ))
+}
+
+async fn pin<Ex>(secrets: &mut Ex, pkg_id: &PackageId, key: &str, source: &str) -> Result<(), Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Postgres>,
+{
+    sqlx::query!(
+        "INSERT INTO trusted_developer_keys (package_id, pubkey, source) VALUES ($1, $2, $3) ON CONFLICT (package_id, pubkey) DO NOTHING",
+        &**pkg_id,
+        key,
+        source
+    )
+    .execute(secrets)
+    .await?;
+    Ok(())
+}
+
+#[command(subcommands(list, add, remove))]
+pub fn trust() -> Result<(), Error> {
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TrustedKey {
+    pub package_id: PackageId,
+    pub pubkey: String,
+    pub source: String,
+    pub created_at: NaiveDateTime,
+}
+
+fn display_trusted_keys(keys: Vec<TrustedKey>, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(keys, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "PACKAGE", "DEVELOPER KEY", "SOURCE", "CREATED AT"]);
+    for key in keys {
+        table.add_row(row![
+            &*key.package_id,
+            &key.pubkey,
+            &key.source,
+            &format!("{}", key.created_at),
+        ]);
+    }
+    table.print_tty(false).unwrap();
+}
+
+#[command(display(display_trusted_keys))]
+pub async fn list(
+    #[context] ctx: RpcContext,
+    #[arg] id: Option<PackageId>,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<TrustedKey>, Error> {
+    sqlx::query!(
+        "SELECT package_id, pubkey, source, created_at FROM trusted_developer_keys WHERE $1::TEXT IS NULL OR package_id = $1 ORDER BY package_id, created_at",
+        id.as_deref()
+    )
+    .fetch_all(&ctx.secret_store)
+    .await?
+    .into_iter()
+    .map(|r| {
+        Ok(TrustedKey {
+            package_id: r.package_id.parse()?,
+            pubkey: r.pubkey,
+            source: r.source,
+            created_at: r.created_at,
+        })
+    })
+    .collect()
+}
+
+#[command(display(display_none))]
+pub async fn add(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg] pubkey: String,
+) -> Result<(), Error> {
+    let key = encode_key(&parse_key(&pubkey)?);
+    pin(
+        ctx.secret_store.acquire().await?.as_mut(),
+        &id,
+        &key,
+        SOURCE_MANUAL,
+    )
+    .await
+}
+
+#[command(display(display_none))]
+pub async fn remove(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg] pubkey: Option<String>,
+) -> Result<(), Error> {
+    let n = if let Some(pubkey) = pubkey {
+        let key = encode_key(&parse_key(&pubkey)?);
+        sqlx::query!(
+            "DELETE FROM trusted_developer_keys WHERE package_id = $1 AND pubkey = $2",
+            &*id,
+            key
+        )
+        .execute(&ctx.secret_store)
+        .await?
+        .rows_affected()
+    } else {
+        sqlx::query!(
+            "DELETE FROM trusted_developer_keys WHERE package_id = $1",
+            &*id
+        )
+        .execute(&ctx.secret_store)
+        .await?
+        .rows_affected()
+    };
+    if n == 0 {
+        return Err(Error::new(
+            eyre!("No trusted developer key found for {}", id),
+            ErrorKind::NotFound,
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_base64_and_pem() {
+        let signing = ed25519_dalek::SigningKey::from_bytes(&[7; 32]);
+        let key = signing.verifying_key();
+        assert_eq!(parse_key(&encode_key(&key)).unwrap(), key);
+        let pem = <ed25519::PublicKeyBytes as ed25519::pkcs8::EncodePublicKey>::to_public_key_pem(
+            &ed25519::PublicKeyBytes(key.to_bytes()),
+            base64ct::LineEnding::default(),
+        )
+        .unwrap();
+        assert_eq!(parse_key(&pem).unwrap(), key);
+        assert!(parse_key("AAAA").is_err());
+    }
+
+    fn key(seed: u8) -> VerifyingKey {
+        ed25519_dalek::SigningKey::from_bytes(&[seed; 32]).verifying_key()
+    }
+
+    fn pinned(pins: &[TrustPin]) -> Vec<(&str, &str)> {
+        pins.iter().map(|p| (p.pubkey.as_str(), p.source)).collect()
+    }
+
+    #[test]
+    fn accepts_trusted_key() {
+        let id: PackageId = "hello-world".parse().unwrap();
+        let pins = check_trusted(&id, &key(1), vec![encode_key(&key(1))], None, false).unwrap();
+        assert!(pins.is_empty());
+    }
+
+    #[test]
+    fn refuses_untrusted_key() {
+        let id: PackageId = "hello-world".parse().unwrap();
+        let err = check_trusted(&id, &key(2), vec![encode_key(&key(1))], None, false).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::InvalidSignature);
+
+        // the installed version's key is pinned, so a different key is refused
+        let err = check_trusted(&id, &key(2), Vec::new(), Some(&key(1)), false).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::InvalidSignature);
+    }
+
+    #[test]
+    fn pins_on_first_use_and_override() {
+        let id: PackageId = "hello-world".parse().unwrap();
+        let pins = check_trusted(&id, &key(1), Vec::new(), None, false).unwrap();
+        assert_eq!(pinned(&pins), [(&*encode_key(&key(1)), SOURCE_TOFU)]);
+
+        let pins = check_trusted(&id, &key(1), Vec::new(), Some(&key(1)), false).unwrap();
+        assert_eq!(pinned(&pins), [(&*encode_key(&key(1)), SOURCE_TOFU)]);
+
+        let pins = check_trusted(&id, &key(2), vec![encode_key(&key(1))], None, true).unwrap();
+        assert_eq!(pinned(&pins), [(&*encode_key(&key(2)), SOURCE_OVERRIDE)]);
+    }
+}
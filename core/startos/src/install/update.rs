@@ -1,18 +1,684 @@
 use std::collections::BTreeMap;
+use std::time::Duration;
 
+use chrono::{DateTime, NaiveTime, TimeZone, Utc};
+use clap::ArgMatches;
+use reqwest::Url;
 use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
 use tracing::instrument;
 
-use crate::config::not_found;
 use crate::context::RpcContext;
-use crate::db::model::CurrentDependents;
+use crate::db::model::DatabaseModel;
+use crate::install::MinMax;
+use crate::notifications::NotificationLevel;
 use crate::prelude::*;
-use crate::s9pk::manifest::PackageId;
-use crate::util::serde::display_serializable;
+use crate::registry::marketplace::with_query_params;
+use crate::s9pk::manifest::{Manifest, PackageId};
+use crate::util::display_none;
+use crate::util::serde::{display_serializable, IoFormat};
 use crate::util::Version;
 use crate::Error;
 
-#[command(subcommands(dry))]
+const CHECK_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpdatePolicy {
+    #[default]
+    Never,
+    /// Send a notification when an update is available
+    Notify,
+    /// Install updates that keep the major version, notify about the rest
+    AutoMinor,
+    AutoAll,
+}
+impl std::str::FromStr for UpdatePolicy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(UpdatePolicy::Never),
+            "notify" => Ok(UpdatePolicy::Notify),
+            "auto-minor" => Ok(UpdatePolicy::AutoMinor),
+            "auto-all" => Ok(UpdatePolicy::AutoAll),
+            _ => Err(Error::new(
+                eyre!("Must be one of \"never\", \"notify\", \"auto-minor\", \"auto-all\"."),
+                ErrorKind::InvalidRequest,
+            )),
+        }
+    }
+}
+impl std::fmt::Display for UpdatePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpdatePolicy::Never => write!(f, "never"),
+            UpdatePolicy::Notify => write!(f, "notify"),
+            UpdatePolicy::AutoMinor => write!(f, "auto-minor"),
+            UpdatePolicy::AutoAll => write!(f, "auto-all"),
+        }
+    }
+}
+
+/// Which releases of a package to update to.
+///
+/// The registry decides what belongs to a channel: it is passed as the `channel` query parameter
+/// of `package/v0/manifest`, which is not part of the v0 registry API. A registry that does not
+/// know about channels ignores it and answers with its stable release, so the beta channel falls
+/// back to stable instead of failing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpdateChannel {
+    #[default]
+    Stable,
+    Beta,
+}
+impl std::str::FromStr for UpdateChannel {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stable" => Ok(UpdateChannel::Stable),
+            "beta" => Ok(UpdateChannel::Beta),
+            _ => Err(Error::new(
+                eyre!("Must be one of \"stable\", \"beta\"."),
+                ErrorKind::InvalidRequest,
+            )),
+        }
+    }
+}
+impl std::fmt::Display for UpdateChannel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpdateChannel::Stable => write!(f, "stable"),
+            UpdateChannel::Beta => write!(f, "beta"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PackageUpdatePolicy {
+    pub policy: UpdatePolicy,
+    pub channel: UpdateChannel,
+    /// Newest version found on the marketplace at the last check
+    pub available: Option<Version>,
+    pub last_checked: Option<DateTime<Utc>>,
+    pub last_result: Option<String>,
+}
+
+/// Daily window (in UTC) during which updates may be installed automatically. Wraps around
+/// midnight if `end` is before `start`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MaintenanceWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+impl MaintenanceWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    /// How long until the window next opens, strictly after `now`.
+    pub fn until_start(&self, now: DateTime<Utc>) -> Duration {
+        let mut start = Utc.from_utc_datetime(&now.date_naive().and_time(self.start));
+        if start <= now {
+            start = start + chrono::Duration::days(1);
+        }
+        (start - now).to_std().unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, HasModel)]
+#[serde(rename_all = "kebab-case")]
+#[model = "Model<Self>"]
+pub struct AutoUpdateConfig {
+    pub packages: BTreeMap<PackageId, PackageUpdatePolicy>,
+    pub maintenance_window: Option<MaintenanceWindow>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Decision {
+    UpToDate,
+    Notify,
+    Install,
+}
+
+fn decide(policy: UpdatePolicy, installed: &Version, available: &Version) -> Decision {
+    if **available <= **installed {
+        return Decision::UpToDate;
+    }
+    match policy {
+        UpdatePolicy::Never => Decision::UpToDate,
+        UpdatePolicy::Notify => Decision::Notify,
+        UpdatePolicy::AutoMinor if available.major() != installed.major() => Decision::Notify,
+        UpdatePolicy::AutoMinor | UpdatePolicy::AutoAll => Decision::Install,
+    }
+}
+
+#[command(subcommands(dry, check, policies, set_policy, set_window))]
 pub async fn update() -> Result<(), Error> {
     Ok(())
 }
+
+/// Lists the installed packages that would no longer have their dependency on `id` satisfied if
+/// it were updated to `version`.
+pub fn breakages(
+    db: &DatabaseModel,
+    id: &PackageId,
+    version: &Version,
+) -> Result<BTreeMap<PackageId, String>, Error> {
+    let mut breakages = BTreeMap::new();
+    let dependents = db
+        .as_package_data()
+        .as_idx(id)
+        .or_not_found(id)?
+        .as_installed()
+        .or_not_found(id)?
+        .as_current_dependents()
+        .keys()?;
+    for dependent in dependents.into_iter().filter(|d| d != id) {
+        if let Some(dep_info) = db
+            .as_package_data()
+            .as_idx(&dependent)
+            .and_then(|p| p.as_installed())
+            .and_then(|i| i.as_manifest().as_dependencies().as_idx(id))
+        {
+            let range = dep_info.as_version().de()?;
+            if !version.satisfies(&range) {
+                breakages.insert(dependent, format!("requires {} {}", id, range));
+            }
+        }
+    }
+    Ok(breakages)
+}
+
+#[command(display(display_serializable))]
+#[instrument(skip_all)]
+pub async fn dry(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg] version: Version,
+) -> Result<BTreeMap<PackageId, String>, Error> {
+    breakages(&ctx.db.peek().await, &id, &version)
+}
+
+/// Fetches the newest release of `id` on `channel`. Stable requests are plain v0 registry
+/// requests, see [UpdateChannel] for what is sent for the others.
+async fn latest_manifest(
+    ctx: &RpcContext,
+    id: &PackageId,
+    marketplace_url: &Url,
+    channel: UpdateChannel,
+) -> Result<Manifest, Error> {
+    let mut url: Url = format!(
+        "{}/package/v0/manifest/{}?spec=*&version-priority={}",
+        marketplace_url,
+        id,
+        MinMax::Max,
+    )
+    .parse()?;
+    if channel != UpdateChannel::Stable {
+        url.query_pairs_mut()
+            .append_pair("channel", &channel.to_string());
+    }
+    ctx.client
+        .get(with_query_params(ctx.clone(), url))
+        .send()
+        .await
+        .with_kind(ErrorKind::Registry)?
+        .error_for_status()
+        .with_kind(ErrorKind::Registry)?
+        .json()
+        .await
+        .with_kind(ErrorKind::Registry)
+}
+
+async fn notify(
+    ctx: &RpcContext,
+    id: &PackageId,
+    level: NotificationLevel,
+    title: &str,
+    msg: String,
+) {
+    if let Err(e) = ctx
+        .notification_manager
+        .notify(
+            ctx.db.clone(),
+            Some(id.clone()),
+            level,
+            title.to_owned(),
+            msg,
+            (),
+            None,
+        )
+        .await
+    {
+        tracing::error!("Failed to issue Notification: {}", e);
+        tracing::debug!("{:?}", e);
+    }
+}
+
+/// Checks the marketplace for a newer version of `id` and acts on it according to its policy.
+/// Returns the version found and a description of what was done.
+#[instrument(skip_all)]
+async fn check_package(
+    ctx: &RpcContext,
+    id: &PackageId,
+    policy: &PackageUpdatePolicy,
+    in_window: bool,
+) -> Result<(Option<Version>, String), Error> {
+    let db = ctx.db.peek().await;
+    let Some(installed) = db
+        .as_package_data()
+        .as_idx(id)
+        .and_then(|p| p.as_installed())
+    else {
+        return Ok((None, "not installed".to_owned()));
+    };
+    let Some(marketplace_url) = installed.as_marketplace_url().de()? else {
+        return Ok((None, "sideloaded".to_owned()));
+    };
+    let current = installed.as_manifest().as_version().de()?;
+    let latest = latest_manifest(ctx, id, &marketplace_url, policy.channel)
+        .await?
+        .version;
+    let is_new = policy.available.as_ref() != Some(&latest);
+
+    let result = match decide(policy.policy, &current, &latest) {
+        Decision::UpToDate => "up to date".to_owned(),
+        Decision::Notify => {
+            if is_new {
+                notify(
+                    ctx,
+                    id,
+                    NotificationLevel::Info,
+                    "Update Available",
+                    format!("{} {} is available (installed: {})", id, latest, current),
+                )
+                .await;
+            }
+            "update available".to_owned()
+        }
+        Decision::Install if !in_window => "waiting for maintenance window".to_owned(),
+        Decision::Install => {
+            let breakages = breakages(&db, id, &latest)?;
+            if !breakages.is_empty() {
+                if is_new {
+                    notify(
+                        ctx,
+                        id,
+                        NotificationLevel::Warning,
+                        "Automatic Update Blocked",
+                        format!(
+                            "Updating {} to {} would break: {}",
+                            id,
+                            latest,
+                            breakages
+                                .iter()
+                                .map(|(dep, reason)| format!("{} ({})", dep, reason))
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        ),
+                    )
+                    .await;
+                }
+                "blocked by dependents".to_owned()
+            } else {
+                super::install(
+                    ctx.clone(),
+                    id.to_string(),
+                    Some(marketplace_url),
+                    Some(format!("={}", latest)),
+                    Some(MinMax::Max),
+                    false,
+                    None,
+                    None,
+                )
+                .await?;
+                notify(
+                    ctx,
+                    id,
+                    NotificationLevel::Info,
+                    "Automatic Update Started",
+                    format!("Updating {} from {} to {}", id, current, latest),
+                )
+                .await;
+                "updating".to_owned()
+            }
+        }
+    };
+    Ok((Some(latest), result))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct UpdateCheck {
+    pub id: PackageId,
+    pub available: Option<Version>,
+    pub result: String,
+}
+
+#[instrument(skip_all)]
+async fn check_all(ctx: &RpcContext) -> Result<Vec<UpdateCheck>, Error> {
+    let cfg = ctx.db.peek().await.as_server_info().as_auto_update().de()?;
+    let in_window = cfg
+        .maintenance_window
+        .map_or(true, |w| w.contains(Utc::now().time()));
+    let mut checks = Vec::new();
+    for (id, policy) in cfg.packages {
+        if policy.policy == UpdatePolicy::Never {
+            continue;
+        }
+        let (available, result) = match check_package(ctx, &id, &policy, in_window).await {
+            Ok(res) => res,
+            Err(e) => {
+                tracing::error!("Failed to check for updates to {}: {}", id, e);
+                tracing::debug!("{:?}", e);
+                (policy.available.clone(), format!("error: {}", e))
+            }
+        };
+        ctx.db
+            .mutate(|db| {
+                if let Some(p) = db
+                    .as_server_info_mut()
+                    .as_auto_update_mut()
+                    .as_packages_mut()
+                    .as_idx_mut(&id)
+                {
+                    let mut p_de = p.de()?;
+                    p_de.available = available.clone();
+                    p_de.last_checked = Some(Utc::now());
+                    p_de.last_result = Some(result.clone());
+                    p.ser(&p_de)?;
+                }
+                Ok(())
+            })
+            .await?;
+        checks.push(UpdateCheck {
+            id,
+            available,
+            result,
+        });
+    }
+    Ok(checks)
+}
+
+/// How long to wait before the next scheduled check: [CHECK_INTERVAL], or less if the maintenance
+/// window opens sooner, so that updates waiting for it are installed when it does.
+fn next_check(window: Option<MaintenanceWindow>, now: DateTime<Utc>) -> Duration {
+    window.map_or(CHECK_INTERVAL, |w| w.until_start(now).min(CHECK_INTERVAL))
+}
+
+pub async fn launch_auto_update_task(ctx: RpcContext) {
+    let mut shutdown = ctx.shutdown.subscribe();
+    loop {
+        if let Err(e) = check_all(&ctx).await {
+            tracing::error!("Automatic update check failed: {}", e);
+            tracing::debug!("{:?}", e);
+        }
+        let window = match ctx
+            .db
+            .peek()
+            .await
+            .as_server_info()
+            .as_auto_update()
+            .as_maintenance_window()
+            .de()
+        {
+            Ok(window) => window,
+            Err(e) => {
+                tracing::error!("Failed to read maintenance window: {}", e);
+                tracing::debug!("{:?}", e);
+                None
+            }
+        };
+        tokio::select! {
+            _ = shutdown.recv() => return,
+            _ = tokio::time::sleep(next_check(window, Utc::now())) => (),
+        }
+    }
+}
+
+fn display_checks(checks: Vec<UpdateCheck>, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(checks, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "PACKAGE", "AVAILABLE", "RESULT"]);
+    for check in checks {
+        table.add_row(row![
+            &*check.id,
+            &check
+                .available
+                .as_ref()
+                .map(|v| v.to_string())
+                .unwrap_or_default(),
+            &check.result,
+        ]);
+    }
+    table.print_tty(false).unwrap();
+}
+
+/// Checks every package with an update policy now rather than waiting for the next scheduled check
+#[command(display(display_checks))]
+pub async fn check(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<UpdateCheck>, Error> {
+    check_all(&ctx).await
+}
+
+fn display_policies(cfg: AutoUpdateConfig, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(cfg, matches);
+    }
+
+    if let Some(window) = cfg.maintenance_window {
+        println!(
+            "Maintenance window: {} - {} UTC",
+            window.start.format("%H:%M"),
+            window.end.format("%H:%M")
+        );
+    }
+    let mut table = Table::new();
+    table
+        .add_row(row![bc => "PACKAGE", "POLICY", "CHANNEL", "AVAILABLE", "LAST CHECKED", "RESULT"]);
+    for (id, policy) in cfg.packages {
+        table.add_row(row![
+            &*id,
+            &policy.policy.to_string(),
+            &policy.channel.to_string(),
+            &policy
+                .available
+                .as_ref()
+                .map(|v| v.to_string())
+                .unwrap_or_default(),
+            &policy
+                .last_checked
+                .map(|t| t.to_rfc3339())
+                .unwrap_or_default(),
+            &policy.last_result.unwrap_or_default(),
+        ]);
+    }
+    table.print_tty(false).unwrap();
+}
+
+#[command(display(display_policies))]
+pub async fn policies(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<AutoUpdateConfig, Error> {
+    ctx.db.peek().await.as_server_info().as_auto_update().de()
+}
+
+#[command(rename = "set-policy", display(display_none))]
+pub async fn set_policy(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg] policy: UpdatePolicy,
+    #[arg(long = "channel")] channel: Option<UpdateChannel>,
+) -> Result<(), Error> {
+    ctx.db
+        .mutate(|db| {
+            db.as_package_data()
+                .as_idx(&id)
+                .and_then(|p| p.as_installed())
+                .or_not_found(&id)?;
+            let packages = db
+                .as_server_info_mut()
+                .as_auto_update_mut()
+                .as_packages_mut();
+            if policy == UpdatePolicy::Never && channel.is_none() {
+                packages.remove(&id)?;
+                return Ok(());
+            }
+            let mut p = packages
+                .as_idx(&id)
+                .map(|p| p.de())
+                .transpose()?
+                .unwrap_or_default();
+            p.policy = policy;
+            if let Some(channel) = channel {
+                p.channel = channel;
+            }
+            packages.insert(&id, &p)
+        })
+        .await
+}
+
+fn parse_time(arg: &str, _: &ArgMatches) -> Result<NaiveTime, Error> {
+    NaiveTime::parse_from_str(arg, "%H:%M")
+        .or_else(|_| NaiveTime::parse_from_str(arg, "%H:%M:%S"))
+        .with_kind(ErrorKind::ParseTimestamp)
+}
+
+/// Sets the daily window (HH:MM, UTC) during which automatic updates are installed. Clears it,
+/// allowing updates at any time, if no window is given.
+#[command(rename = "set-window", display(display_none))]
+pub async fn set_window(
+    #[context] ctx: RpcContext,
+    #[arg(parse(parse_time))] start: Option<NaiveTime>,
+    #[arg(parse(parse_time))] end: Option<NaiveTime>,
+) -> Result<(), Error> {
+    let window = match (start, end) {
+        (Some(start), Some(end)) => Some(MaintenanceWindow { start, end }),
+        (None, None) => None,
+        _ => {
+            return Err(Error::new(
+                eyre!("Both a start and an end time are required"),
+                ErrorKind::InvalidRequest,
+            ))
+        }
+    };
+    ctx.db
+        .mutate(|db| {
+            db.as_server_info_mut()
+                .as_auto_update_mut()
+                .as_maintenance_window_mut()
+                .ser(&window)
+        })
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn v(s: &str) -> Version {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn decides_by_policy() {
+        let installed = v("1.2.0");
+        assert_eq!(
+            decide(UpdatePolicy::AutoAll, &installed, &v("1.2.0")),
+            Decision::UpToDate
+        );
+        assert_eq!(
+            decide(UpdatePolicy::Never, &installed, &v("1.3.0")),
+            Decision::UpToDate
+        );
+        assert_eq!(
+            decide(UpdatePolicy::Notify, &installed, &v("1.3.0")),
+            Decision::Notify
+        );
+        assert_eq!(
+            decide(UpdatePolicy::AutoMinor, &installed, &v("1.3.0")),
+            Decision::Install
+        );
+        assert_eq!(
+            decide(UpdatePolicy::AutoMinor, &installed, &v("2.0.0")),
+            Decision::Notify
+        );
+        assert_eq!(
+            decide(UpdatePolicy::AutoAll, &installed, &v("2.0.0")),
+            Decision::Install
+        );
+    }
+
+    #[test]
+    fn maintenance_window_wraps_midnight() {
+        let t = |s| NaiveTime::parse_from_str(s, "%H:%M").unwrap();
+        let day = MaintenanceWindow {
+            start: t("09:00"),
+            end: t("17:00"),
+        };
+        assert!(day.contains(t("12:00")));
+        assert!(!day.contains(t("17:00")));
+        let night = MaintenanceWindow {
+            start: t("23:00"),
+            end: t("04:00"),
+        };
+        assert!(night.contains(t("23:30")));
+        assert!(night.contains(t("03:00")));
+        assert!(!night.contains(t("12:00")));
+    }
+
+    #[test]
+    fn next_check_waits_for_window() {
+        let t = |s| NaiveTime::parse_from_str(s, "%H:%M").unwrap();
+        let at = |s| {
+            Utc.from_utc_datetime(
+                &chrono::NaiveDate::from_ymd_opt(2023, 11, 1)
+                    .unwrap()
+                    .and_time(t(s)),
+            )
+        };
+        let window = MaintenanceWindow {
+            start: t("03:00"),
+            end: t("04:00"),
+        };
+        assert_eq!(
+            window.until_start(at("01:30")),
+            Duration::from_secs(90 * 60)
+        );
+        assert_eq!(
+            window.until_start(at("03:00")),
+            Duration::from_secs(24 * 60 * 60)
+        );
+        assert_eq!(
+            window.until_start(at("05:00")),
+            Duration::from_secs(22 * 60 * 60)
+        );
+        assert_eq!(
+            next_check(Some(window), at("01:30")),
+            Duration::from_secs(90 * 60)
+        );
+        assert_eq!(next_check(Some(window), at("05:00")), CHECK_INTERVAL);
+        assert_eq!(next_check(None, at("01:30")), CHECK_INTERVAL);
+    }
+}
@@ -32,6 +32,7 @@ pub mod core;
 pub mod db;
 pub mod dependencies;
 pub mod developer;
+pub mod devices;
 pub mod diagnostic;
 pub mod disk;
 pub mod error;
@@ -51,6 +52,7 @@ pub mod prelude;
 pub mod procedure;
 pub mod properties;
 pub mod registry;
+pub mod resources;
 pub mod s9pk;
 pub mod setup;
 pub mod shutdown;
@@ -90,6 +92,7 @@ pub fn echo(#[arg] message: String) -> Result<String, RpcError> {
     notifications::notification,
     backup::backup,
     registry::marketplace::marketplace,
+    registry::server::registry,
 ))]
 pub fn main_api() -> Result<(), RpcError> {
     Ok(())
@@ -115,6 +118,7 @@ pub fn server() -> Result<(), RpcError> {
     action::action,
     install::install,
     install::sideload,
+    install::bundle::sideload_bundle,
     install::uninstall,
     install::list,
     config::config,
@@ -123,8 +127,15 @@ pub fn server() -> Result<(), RpcError> {
     control::restart,
     logs::logs,
     properties::properties,
+    resources::resources,
+    devices::device,
+    volume::volume,
     dependencies::dependency,
     backup::package_backup,
+    install::trust::trust,
+    install::update::update,
+    install::rollback::rollback,
+    install::plan::apply_plan,
 ))]
 pub fn package() -> Result<(), RpcError> {
     Ok(())
@@ -133,6 +144,8 @@ pub fn package() -> Result<(), RpcError> {
 #[command(subcommands(
     version::git_info,
     s9pk::pack,
+    s9pk::s9pk,
+    install::bundle::bundle,
     developer::verify,
     developer::init,
     inspect::inspect,
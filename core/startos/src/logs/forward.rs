@@ -0,0 +1,1110 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::SecondsFormat;
+use clap::ArgMatches;
+use futures::future::BoxFuture;
+use futures::{FutureExt, StreamExt};
+use helpers::AtomicFile;
+use reqwest::{Client, Url};
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncBufReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio::sync::{Mutex, Notify};
+use tokio_rustls::rustls::{Certificate, ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+use tokio_stream::wrappers::LinesStream;
+use tracing::instrument;
+
+use super::{
+    log_source_args, JournalctlEntry, LogEntry, LogPriority, LogSource, STRUCTURED_FIELDS,
+};
+use crate::context::RpcContext;
+use crate::prelude::*;
+use crate::s9pk::manifest::PackageId;
+use crate::util::display_none;
+use crate::util::serde::{display_serializable, IoFormat};
+
+const DEFAULT_BUFFER_SIZE: u64 = 64 * 1024 * 1024;
+const BATCH_SIZE: usize = 256;
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+const SYSTEM_CA_BUNDLE: &str = "/etc/ssl/certs/ca-certificates.crt";
+
+/// Where forwarded logs are sent. The protocol is picked from the url scheme:
+/// - `syslog+tls://host:6514`: RFC 5424 messages over TLS with octet-counting framing (RFC 5425)
+/// - `syslog+tcp://host:601`: the same, without TLS
+/// - `otlp+https://host:4318/v1/logs` or `otlp+http://...`: OpenTelemetry logs over HTTP/JSON
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct LogForwardTarget {
+    pub url: Url,
+    /// PEM encoded CA certificate to trust in addition to the system bundle
+    pub ca_cert: Option<String>,
+}
+impl LogForwardTarget {
+    fn validate(&self) -> Result<(), Error> {
+        match self.url.scheme() {
+            "syslog+tls" | "syslog+tcp" => {
+                if self.url.host_str().is_none() || self.url.port().is_none() {
+                    return Err(Error::new(
+                        eyre!("syslog target must include a host and port"),
+                        ErrorKind::ParseUrl,
+                    ));
+                }
+            }
+            "otlp+http" | "otlp+https" => (),
+            scheme => {
+                return Err(Error::new(
+                    eyre!("unsupported log forwarding scheme: {}", scheme),
+                    ErrorKind::ParseUrl,
+                ))
+            }
+        }
+        if let Some(ca_cert) = &self.ca_cert {
+            openssl::x509::X509::from_pem(ca_cert.as_bytes()).with_kind(ErrorKind::Pem)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct LogForwardConfig {
+    pub target: Option<LogForwardTarget>,
+    pub system: bool,
+    pub kernel: bool,
+    pub tor: bool,
+    pub packages: BTreeMap<PackageId, bool>,
+    /// Maximum size in bytes of the on-disk buffer kept while the remote is unreachable
+    pub buffer_size: Option<u64>,
+}
+impl LogForwardConfig {
+    fn sources(&self) -> Vec<ForwardSource> {
+        let mut res = Vec::new();
+        if self.system {
+            res.push(ForwardSource::System);
+        }
+        if self.kernel {
+            res.push(ForwardSource::Kernel);
+        }
+        if self.tor {
+            res.push(ForwardSource::Tor);
+        }
+        res.extend(
+            self.packages
+                .iter()
+                .filter(|(_, enabled)| **enabled)
+                .map(|(id, _)| ForwardSource::Package(id.clone())),
+        );
+        res
+    }
+}
+
+/// A [LogSource] that can be toggled for forwarding: `system`, `kernel`, `tor` or
+/// `package:<package-id>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ForwardSource {
+    System,
+    Kernel,
+    Tor,
+    Package(PackageId),
+}
+impl ForwardSource {
+    fn log_source(&self) -> LogSource {
+        match self {
+            ForwardSource::System => LogSource::System,
+            ForwardSource::Kernel => LogSource::Kernel,
+            ForwardSource::Tor => LogSource::Unit(crate::net::tor::SYSTEMD_UNIT),
+            ForwardSource::Package(id) => LogSource::Container(id.clone()),
+        }
+    }
+    /// RFC 5424 APP-NAME
+    fn app_name(&self) -> &str {
+        match self {
+            ForwardSource::System => super::SYSTEM_UNIT,
+            ForwardSource::Kernel => "kernel",
+            ForwardSource::Tor => "tor",
+            ForwardSource::Package(id) => id.as_ref(),
+        }
+    }
+    /// RFC 5424 facility: kern, daemon or user
+    fn facility(&self) -> u8 {
+        match self {
+            ForwardSource::Kernel => 0,
+            ForwardSource::System | ForwardSource::Tor => 3,
+            ForwardSource::Package(_) => 1,
+        }
+    }
+}
+impl std::fmt::Display for ForwardSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ForwardSource::System => write!(f, "system"),
+            ForwardSource::Kernel => write!(f, "kernel"),
+            ForwardSource::Tor => write!(f, "tor"),
+            ForwardSource::Package(id) => write!(f, "package:{}", id),
+        }
+    }
+}
+impl std::str::FromStr for ForwardSource {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "system" => ForwardSource::System,
+            "kernel" => ForwardSource::Kernel,
+            "tor" => ForwardSource::Tor,
+            s => match s.strip_prefix("package:") {
+                Some(id) => ForwardSource::Package(id.parse()?),
+                None => {
+                    return Err(Error::new(
+                        eyre!("Invalid log source: {}", s),
+                        ErrorKind::InvalidRequest,
+                    ))
+                }
+            },
+        })
+    }
+}
+impl Serialize for ForwardSource {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        crate::util::serde::serialize_display(self, serializer)
+    }
+}
+impl<'de> Deserialize<'de> for ForwardSource {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        crate::util::serde::deserialize_from_str(deserializer)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ForwardRecord {
+    pub source: ForwardSource,
+    #[serde(flatten)]
+    pub entry: LogEntry,
+}
+
+/// An append-only spool of [ForwardRecord]s on disk. Records stay in the spool until the remote
+/// has accepted them, so nothing is lost while it is unreachable or across restarts. The spool
+/// file itself never grows past `max_size`: delivered records are compacted away once they make
+/// up half of it, or sooner if new records would not fit otherwise.
+pub struct DiskBuffer {
+    dir: PathBuf,
+    max_size: u64,
+    inner: Mutex<DiskBufferInner>,
+    notify: Notify,
+}
+struct DiskBufferInner {
+    file: File,
+    len: u64,
+    offset: u64,
+    dropped: u64,
+}
+async fn open_spool(dir: &Path) -> Result<File, Error> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(dir.join("spool.jsonl"))
+        .await?)
+}
+impl DiskBuffer {
+    pub async fn open(dir: impl AsRef<Path>, max_size: u64) -> Result<Self, Error> {
+        let dir = dir.as_ref().to_owned();
+        tokio::fs::create_dir_all(&dir).await?;
+        let file = open_spool(&dir).await?;
+        let len = file.metadata().await?.len();
+        let offset = match tokio::fs::read_to_string(dir.join("spool.offset")).await {
+            Ok(a) => a.trim().parse::<u64>().unwrap_or(0).min(len),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            dir,
+            max_size,
+            inner: Mutex::new(DiskBufferInner {
+                file,
+                len,
+                offset,
+                dropped: 0,
+            }),
+            notify: Notify::new(),
+        })
+    }
+
+    /// Appends `records`, dropping them if the spool is full. Returns how many were kept.
+    pub async fn push(&self, records: &[ForwardRecord]) -> Result<usize, Error> {
+        let mut inner = self.inner.lock().await;
+        let mut buf = Vec::new();
+        let mut kept = 0;
+        for record in records {
+            let mut line = serde_json::to_vec(record).with_kind(ErrorKind::Serialization)?;
+            line.push(b'\n');
+            if inner.len + (buf.len() + line.len()) as u64 > self.max_size && inner.offset > 0 {
+                self.compact(&mut inner).await?;
+            }
+            if inner.len + (buf.len() + line.len()) as u64 > self.max_size {
+                inner.dropped += 1;
+                continue;
+            }
+            buf.extend_from_slice(&line);
+            kept += 1;
+        }
+        if !buf.is_empty() {
+            inner.file.write_all(&buf).await?;
+            inner.file.flush().await?;
+            inner.len += buf.len() as u64;
+            self.notify.notify_one();
+        }
+        Ok(kept)
+    }
+
+    /// Reads up to `max` records that have not been acknowledged yet, along with the offset to
+    /// pass to [DiskBuffer::ack] once they have been delivered.
+    pub async fn peek(&self, max: usize) -> Result<(Vec<ForwardRecord>, u64), Error> {
+        let mut inner = self.inner.lock().await;
+        let offset = inner.offset;
+        inner.file.seek(std::io::SeekFrom::Start(offset)).await?;
+        let mut lines = BufReader::new(&mut inner.file).lines();
+        let mut records = Vec::new();
+        let mut new_offset = offset;
+        while records.len() < max {
+            let Some(line) = lines.next_line().await? else {
+                break;
+            };
+            new_offset += line.len() as u64 + 1;
+            match serde_json::from_str(&line) {
+                Ok(record) => records.push(record),
+                Err(e) => tracing::warn!("Skipping corrupt log forwarding record: {}", e),
+            }
+        }
+        Ok((records, new_offset))
+    }
+
+    /// Marks everything before `offset` as delivered, truncating the spool once it is drained and
+    /// compacting it once delivered records take up half of the size cap.
+    pub async fn ack(&self, offset: u64) -> Result<(), Error> {
+        let mut inner = self.inner.lock().await;
+        inner.offset = offset.min(inner.len);
+        if inner.offset == inner.len {
+            inner.file.set_len(0).await?;
+            inner.len = 0;
+            inner.offset = 0;
+        } else if inner.offset >= self.max_size / 2 {
+            return self.compact(&mut inner).await;
+        }
+        tokio::fs::write(self.dir.join("spool.offset"), inner.offset.to_string()).await?;
+        Ok(())
+    }
+
+    /// Rewrites the spool without the records before the current offset.
+    async fn compact(&self, inner: &mut DiskBufferInner) -> Result<(), Error> {
+        let mut tmp = AtomicFile::new(self.dir.join("spool.jsonl"), None::<PathBuf>)
+            .await
+            .with_kind(ErrorKind::Filesystem)?;
+        inner
+            .file
+            .seek(std::io::SeekFrom::Start(inner.offset))
+            .await?;
+        tokio::io::copy(&mut inner.file, &mut *tmp).await?;
+        // reset the offset before swapping the spool: a crash in between replays records that
+        // were already delivered instead of skipping ones that were not
+        tokio::fs::write(self.dir.join("spool.offset"), "0").await?;
+        tmp.save().await.with_kind(ErrorKind::Filesystem)?;
+        inner.file = open_spool(&self.dir).await?;
+        inner.len -= inner.offset;
+        inner.offset = 0;
+        Ok(())
+    }
+
+    pub async fn stats(&self) -> BufferStats {
+        let inner = self.inner.lock().await;
+        BufferStats {
+            pending_bytes: inner.len - inner.offset,
+            max_bytes: self.max_size,
+            dropped: inner.dropped,
+        }
+    }
+
+    async fn wait(&self, timeout: Duration) {
+        tokio::time::timeout(timeout, self.notify.notified())
+            .await
+            .ok();
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BufferStats {
+    pub pending_bytes: u64,
+    pub max_bytes: u64,
+    pub dropped: u64,
+}
+
+fn syslog_frame(record: &ForwardRecord, hostname: &str) -> String {
+    let severity = record.entry.priority.unwrap_or(LogPriority::Info) as u8;
+    let pri = record.source.facility() * 8 + severity;
+    let procid = record
+        .entry
+        .fields
+        .get("_PID")
+        .or_else(|| record.entry.fields.get("SYSLOG_PID"))
+        .and_then(|p| p.as_str())
+        .unwrap_or("-");
+    let msg = format!(
+        "<{}>1 {} {} {} {} - - {}",
+        pri,
+        record
+            .entry
+            .timestamp
+            .to_rfc3339_opts(SecondsFormat::Micros, true),
+        if hostname.is_empty() { "-" } else { hostname },
+        record.source.app_name(),
+        procid,
+        record.entry.message
+    );
+    format!("{} {}", msg.len(), msg)
+}
+
+fn otlp_severity(priority: LogPriority) -> u8 {
+    match priority {
+        LogPriority::Emerg => 21,
+        LogPriority::Alert => 20,
+        LogPriority::Crit => 19,
+        LogPriority::Err => 17,
+        LogPriority::Warning => 13,
+        LogPriority::Notice => 10,
+        LogPriority::Info => 9,
+        LogPriority::Debug => 5,
+    }
+}
+
+fn otlp_body(records: &[ForwardRecord], hostname: &str) -> serde_json::Value {
+    let attr = |key: &str, value: &str| serde_json::json!({ "key": key, "value": { "stringValue": value } });
+    let log_records: Vec<_> = records
+        .iter()
+        .map(|r| {
+            let priority = r.entry.priority.unwrap_or(LogPriority::Info);
+            let mut attributes = vec![attr("log.source", &r.source.to_string())];
+            for (k, v) in &r.entry.fields {
+                if let Some(v) = v.as_str() {
+                    attributes.push(attr(&format!("journal.{}", k.to_lowercase()), v));
+                }
+            }
+            serde_json::json!({
+                "timeUnixNano": r
+                    .entry
+                    .timestamp
+                    .timestamp_nanos_opt()
+                    .unwrap_or_default()
+                    .to_string(),
+                "severityNumber": otlp_severity(priority),
+                "severityText": priority.as_str(),
+                "body": { "stringValue": r.entry.message },
+                "attributes": attributes,
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "resourceLogs": [{
+            "resource": {
+                "attributes": [
+                    attr("service.name", "startos"),
+                    attr("host.name", hostname),
+                ],
+            },
+            "scopeLogs": [{
+                "scope": { "name": "startos" },
+                "logRecords": log_records,
+            }],
+        }],
+    })
+}
+
+trait AsyncWriteStream: AsyncWrite + Unpin + Send {}
+impl<T: AsyncWrite + Unpin + Send> AsyncWriteStream for T {}
+
+enum Transport {
+    Syslog {
+        target: LogForwardTarget,
+        hostname: String,
+        conn: Option<Box<dyn AsyncWriteStream>>,
+    },
+    Otlp {
+        client: Client,
+        url: Url,
+        hostname: String,
+    },
+}
+impl Transport {
+    fn new(target: LogForwardTarget, hostname: String, client: Client) -> Result<Self, Error> {
+        target.validate()?;
+        Ok(match target.url.scheme() {
+            "syslog+tls" | "syslog+tcp" => Transport::Syslog {
+                target,
+                hostname,
+                conn: None,
+            },
+            _ => {
+                // `otlp+http` is not a special scheme, so `Url::set_scheme` cannot turn it into
+                // `http`. The url is parsed again without the prefix instead.
+                let url = target.url.as_str();
+                let mut url: Url = url
+                    .strip_prefix("otlp+")
+                    .unwrap_or(url)
+                    .parse()
+                    .with_kind(ErrorKind::ParseUrl)?;
+                if url.path() == "/" || url.path().is_empty() {
+                    url.set_path("/v1/logs");
+                }
+                Transport::Otlp {
+                    client,
+                    url,
+                    hostname,
+                }
+            }
+        })
+    }
+
+    async fn connect_syslog(target: &LogForwardTarget) -> Result<Box<dyn AsyncWriteStream>, Error> {
+        let host = target.url.host_str().unwrap_or_default();
+        let port = target.url.port().unwrap_or(6514);
+        let tcp = TcpStream::connect((host, port))
+            .await
+            .with_ctx(|_| (ErrorKind::Network, format!("{}:{}", host, port)))?;
+        if target.url.scheme() != "syslog+tls" {
+            return Ok(Box::new(tcp));
+        }
+        let mut roots = RootCertStore::empty();
+        let mut pems = Vec::new();
+        match tokio::fs::read(SYSTEM_CA_BUNDLE).await {
+            Ok(a) => pems.push(a),
+            Err(e) => tracing::warn!("Could not read {}: {}", SYSTEM_CA_BUNDLE, e),
+        }
+        if let Some(ca_cert) = &target.ca_cert {
+            pems.push(ca_cert.as_bytes().to_vec());
+        }
+        for pem in pems {
+            for cert in openssl::x509::X509::stack_from_pem(&pem).with_kind(ErrorKind::Pem)? {
+                roots
+                    .add(&Certificate(cert.to_der()?))
+                    .with_kind(ErrorKind::OpenSsl)?;
+            }
+        }
+        let cfg = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let tls = TlsConnector::from(Arc::new(cfg))
+            .connect(host.try_into().with_kind(ErrorKind::ParseUrl)?, tcp)
+            .await
+            .with_kind(ErrorKind::Network)?;
+        Ok(Box::new(tls))
+    }
+
+    async fn send(&mut self, records: &[ForwardRecord]) -> Result<(), Error> {
+        match self {
+            Transport::Syslog {
+                target,
+                hostname,
+                conn,
+            } => {
+                if conn.is_none() {
+                    *conn = Some(Self::connect_syslog(target).await?);
+                }
+                let stream = conn.as_mut().unwrap();
+                let res = async {
+                    for record in records {
+                        stream
+                            .write_all(syslog_frame(record, hostname).as_bytes())
+                            .await?;
+                    }
+                    stream.flush().await
+                }
+                .await;
+                if let Err(e) = res {
+                    *conn = None;
+                    return Err(Error::new(e, ErrorKind::Network));
+                }
+                Ok(())
+            }
+            Transport::Otlp {
+                client,
+                url,
+                hostname,
+            } => {
+                client
+                    .post(url.clone())
+                    .json(&otlp_body(records, hostname))
+                    .timeout(Duration::from_secs(30))
+                    .send()
+                    .await
+                    .with_kind(ErrorKind::Network)?
+                    .error_for_status()
+                    .with_kind(ErrorKind::Network)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+fn cursor_path(dir: &Path, source: &ForwardSource) -> PathBuf {
+    dir.join("cursors")
+        .join(source.to_string().replace(':', "_"))
+}
+
+/// Tails the journal for `source`, resuming after the last cursor written to the buffer.
+async fn read_source(
+    dir: PathBuf,
+    source: ForwardSource,
+    buffer: Arc<DiskBuffer>,
+) -> Result<(), Error> {
+    let cursor_path = cursor_path(&dir, &source);
+    tokio::fs::create_dir_all(dir.join("cursors")).await?;
+    let cursor = tokio::fs::read_to_string(&cursor_path)
+        .await
+        .ok()
+        .filter(|c| !c.trim().is_empty());
+
+    let mut cmd = Command::new("journalctl");
+    cmd.kill_on_drop(true);
+    cmd.arg("--output=json");
+    cmd.arg(format!(
+        "--output-fields=MESSAGE,PRIORITY,{}",
+        STRUCTURED_FIELDS.join(",")
+    ));
+    cmd.arg("--follow");
+    if let Some(cursor) = &cursor {
+        cmd.arg(format!("--after-cursor={}", cursor.trim()));
+    } else {
+        cmd.arg("-n0");
+    }
+    log_source_args(&source.log_source(), &mut cmd);
+    let mut child = cmd.stdout(Stdio::piped()).spawn()?;
+    let out = BufReader::new(
+        child
+            .stdout
+            .take()
+            .ok_or_else(|| Error::new(eyre!("No stdout available"), ErrorKind::Journald))?,
+    );
+    let mut chunks = LinesStream::new(out.lines()).ready_chunks(BATCH_SIZE);
+    while let Some(lines) = chunks.next().await {
+        let mut records = Vec::with_capacity(lines.len());
+        let mut last_cursor = None;
+        for line in lines {
+            let entry = serde_json::from_str::<JournalctlEntry>(&line?)
+                .with_kind(ErrorKind::Deserialization)?;
+            let (cursor, entry) = entry.log_entry()?;
+            last_cursor = Some(cursor);
+            records.push(ForwardRecord {
+                source: source.clone(),
+                entry,
+            });
+        }
+        let kept = buffer.push(&records).await?;
+        if kept < records.len() {
+            tracing::warn!(
+                "Log forwarding buffer is full, dropped {} {} entries",
+                records.len() - kept,
+                source
+            );
+        }
+        if let Some(cursor) = last_cursor {
+            tokio::fs::write(&cursor_path, cursor).await?;
+        }
+    }
+    child.wait().await?;
+    Err(Error::new(
+        eyre!("journalctl exited while following {} logs", source),
+        ErrorKind::Journald,
+    ))
+}
+
+/// Drains the buffer into the remote, backing off while it is unreachable.
+async fn send_buffered(mut transport: Transport, buffer: Arc<DiskBuffer>) {
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        let (records, offset) = match buffer.peek(BATCH_SIZE).await {
+            Ok(a) => a,
+            Err(e) => {
+                tracing::error!("Error reading log forwarding buffer: {}", e);
+                tracing::debug!("{:?}", e);
+                tokio::time::sleep(backoff).await;
+                continue;
+            }
+        };
+        if records.is_empty() {
+            buffer.wait(Duration::from_secs(5)).await;
+            continue;
+        }
+        match transport.send(&records).await {
+            Ok(()) => {
+                backoff = Duration::from_secs(1);
+                if let Err(e) = buffer.ack(offset).await {
+                    tracing::error!("Error updating log forwarding buffer: {}", e);
+                    tracing::debug!("{:?}", e);
+                }
+            }
+            Err(e) => {
+                tracing::warn!("Error forwarding logs, retrying in {:?}: {}", backoff, e);
+                tracing::debug!("{:?}", e);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+fn forward_dir(ctx: &RpcContext) -> PathBuf {
+    ctx.datadir.join("main/logs-forward")
+}
+
+async fn run_forwarder(ctx: &RpcContext, cfg: LogForwardConfig) -> Result<(), Error> {
+    let Some(target) = cfg.target.clone() else {
+        return futures::future::pending().await;
+    };
+    let sources = cfg.sources();
+    if sources.is_empty() {
+        return futures::future::pending().await;
+    }
+    let dir = forward_dir(ctx);
+    let buffer = Arc::new(
+        DiskBuffer::open(
+            dir.join("buffer"),
+            cfg.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE),
+        )
+        .await?,
+    );
+    *ctx.log_forward_buffer.write().await = Some(buffer.clone());
+    let hostname = ctx.account.read().await.hostname.no_dot_host_name();
+    let transport = Transport::new(target, hostname, ctx.client.clone())?;
+    let readers = sources.into_iter().map(|source| {
+        let dir = dir.clone();
+        let buffer = buffer.clone();
+        async move {
+            loop {
+                if let Err(e) = read_source(dir.clone(), source.clone(), buffer.clone()).await {
+                    tracing::error!("Error reading {} logs for forwarding: {}", source, e);
+                    tracing::debug!("{:?}", e);
+                }
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+        .boxed()
+    });
+    let readers: Vec<BoxFuture<'static, ()>> = readers.collect();
+    futures::future::join(
+        futures::future::join_all(readers),
+        send_buffered(transport, buffer),
+    )
+    .await;
+    Ok(())
+}
+
+/// Forwards logs according to the config in the database, restarting whenever it changes.
+#[instrument(skip_all)]
+pub async fn launch_log_forward_task(ctx: RpcContext) {
+    let mut shutdown = ctx.shutdown.subscribe();
+    let mut cfg_recv = ctx.log_forward.subscribe();
+    loop {
+        let cfg = cfg_recv.borrow_and_update().clone();
+        tokio::select! {
+            _ = shutdown.recv() => return,
+            res = cfg_recv.changed() => {
+                if res.is_err() {
+                    return;
+                }
+            }
+            res = run_forwarder(&ctx, cfg) => {
+                if let Err(e) = res {
+                    tracing::error!("Log forwarding failed: {}", e);
+                    tracing::debug!("{:?}", e);
+                }
+                tokio::select! {
+                    _ = shutdown.recv() => return,
+                    _ = cfg_recv.changed() => (),
+                    _ = tokio::time::sleep(Duration::from_secs(30)) => (),
+                }
+            }
+        }
+        *ctx.log_forward_buffer.write().await = None;
+    }
+}
+
+async fn update_config(
+    ctx: &RpcContext,
+    f: impl FnOnce(&mut LogForwardConfig) -> Result<(), Error> + std::panic::UnwindSafe + Send,
+) -> Result<LogForwardConfig, Error> {
+    let cfg = ctx
+        .db
+        .mutate(|db| {
+            let model = db.as_server_info_mut().as_log_forwarding_mut();
+            let mut cfg = model.de()?;
+            f(&mut cfg)?;
+            model.ser(&cfg)?;
+            Ok(cfg)
+        })
+        .await?;
+    ctx.log_forward.send_replace(cfg.clone());
+    Ok(cfg)
+}
+
+#[command(subcommands(get, set_target, remove_target, set_source, set_buffer_size))]
+pub fn forward() -> Result<(), Error> {
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct LogForwardInfo {
+    pub config: LogForwardConfig,
+    pub buffer: Option<BufferStats>,
+}
+
+fn display_forward_info(info: LogForwardInfo, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(info, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "SOURCE", "ENABLED"]);
+    table.add_row(row!["system", info.config.system]);
+    table.add_row(row!["kernel", info.config.kernel]);
+    table.add_row(row!["tor", info.config.tor]);
+    for (id, enabled) in &info.config.packages {
+        table.add_row(row![&format!("package:{}", id), enabled]);
+    }
+    println!(
+        "Target: {}",
+        info.config
+            .target
+            .as_ref()
+            .map(|t| t.url.to_string())
+            .unwrap_or_else(|| "N/A".to_owned())
+    );
+    if let Some(buffer) = &info.buffer {
+        println!(
+            "Buffered: {}/{} bytes ({} dropped)",
+            buffer.pending_bytes, buffer.max_bytes, buffer.dropped
+        );
+    }
+    table.print_tty(false).unwrap();
+}
+
+#[command(display(display_forward_info))]
+pub async fn get(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<LogForwardInfo, Error> {
+    let config = ctx
+        .db
+        .peek()
+        .await
+        .as_server_info()
+        .as_log_forwarding()
+        .de()?;
+    let buffer = match &*ctx.log_forward_buffer.read().await {
+        Some(buffer) => Some(buffer.stats().await),
+        None => None,
+    };
+    Ok(LogForwardInfo { config, buffer })
+}
+
+#[command(rename = "set-target", display(display_none))]
+pub async fn set_target(
+    #[context] ctx: RpcContext,
+    #[arg] url: Url,
+    #[arg(long = "ca-cert", rename = "ca-cert")] ca_cert: Option<String>,
+) -> Result<(), Error> {
+    let target = LogForwardTarget { url, ca_cert };
+    target.validate()?;
+    update_config(&ctx, move |cfg| {
+        cfg.target = Some(target);
+        Ok(())
+    })
+    .await?;
+    Ok(())
+}
+
+#[command(rename = "remove-target", display(display_none))]
+pub async fn remove_target(#[context] ctx: RpcContext) -> Result<(), Error> {
+    update_config(&ctx, |cfg| {
+        cfg.target = None;
+        Ok(())
+    })
+    .await?;
+    Ok(())
+}
+
+#[command(rename = "set-source", display(display_none))]
+pub async fn set_source(
+    #[context] ctx: RpcContext,
+    #[arg] source: ForwardSource,
+    #[arg] enabled: bool,
+) -> Result<(), Error> {
+    if let ForwardSource::Package(id) = &source {
+        if ctx.db.peek().await.as_package_data().as_idx(id).is_none() {
+            return Err(Error::new(
+                eyre!("{} is not installed", id),
+                ErrorKind::NotFound,
+            ));
+        }
+    }
+    update_config(&ctx, move |cfg| {
+        match source {
+            ForwardSource::System => cfg.system = enabled,
+            ForwardSource::Kernel => cfg.kernel = enabled,
+            ForwardSource::Tor => cfg.tor = enabled,
+            ForwardSource::Package(id) => {
+                cfg.packages.insert(id, enabled);
+            }
+        }
+        Ok(())
+    })
+    .await?;
+    Ok(())
+}
+
+#[command(rename = "set-buffer-size", display(display_none))]
+pub async fn set_buffer_size(
+    #[context] ctx: RpcContext,
+    #[arg] bytes: Option<u64>,
+) -> Result<(), Error> {
+    update_config(&ctx, move |cfg| {
+        cfg.buffer_size = bytes;
+        Ok(())
+    })
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn record(message: &str, priority: LogPriority) -> ForwardRecord {
+        ForwardRecord {
+            source: ForwardSource::System,
+            entry: LogEntry {
+                timestamp: Utc::now(),
+                message: message.to_owned(),
+                priority: Some(priority),
+                fields: [("_PID".to_owned(), serde_json::Value::from("1234"))]
+                    .into_iter()
+                    .collect(),
+            },
+        }
+    }
+
+    fn tmp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "startos-log-forward-{}-{}",
+            name,
+            rand::random::<u64>()
+        ))
+    }
+
+    #[test]
+    fn source_roundtrip() {
+        for s in ["system", "kernel", "tor", "package:bitcoind"] {
+            assert_eq!(s.parse::<ForwardSource>().unwrap().to_string(), s);
+        }
+        assert!("nonsense".parse::<ForwardSource>().is_err());
+    }
+
+    #[test]
+    fn syslog_frame_format() {
+        let frame = syslog_frame(&record("hello world", LogPriority::Err), "embassy");
+        let (len, msg) = frame.split_once(' ').unwrap();
+        assert_eq!(len.parse::<usize>().unwrap(), msg.len());
+        // daemon facility (3) * 8 + err (3)
+        assert!(msg.starts_with("<27>1 "));
+        assert!(msg.ends_with(" embassy startd 1234 - - hello world"));
+    }
+
+    #[tokio::test]
+    async fn buffer_persists_until_acked() {
+        let dir = tmp_dir("buffer");
+        let buffer = DiskBuffer::open(&dir, DEFAULT_BUFFER_SIZE).await.unwrap();
+        let records = vec![
+            record("one", LogPriority::Info),
+            record("two", LogPriority::Info),
+            record("three", LogPriority::Info),
+        ];
+        assert_eq!(buffer.push(&records).await.unwrap(), 3);
+        let (peeked, offset) = buffer.peek(2).await.unwrap();
+        assert_eq!(peeked.len(), 2);
+        buffer.ack(offset).await.unwrap();
+        drop(buffer);
+
+        let buffer = DiskBuffer::open(&dir, DEFAULT_BUFFER_SIZE).await.unwrap();
+        let (peeked, offset) = buffer.peek(10).await.unwrap();
+        assert_eq!(peeked.len(), 1);
+        assert_eq!(peeked[0].entry.message, "three");
+        buffer.ack(offset).await.unwrap();
+        assert_eq!(buffer.stats().await.pending_bytes, 0);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn buffer_drops_when_full() {
+        let dir = tmp_dir("full");
+        let buffer = DiskBuffer::open(&dir, 200).await.unwrap();
+        let records: Vec<_> = (0..10)
+            .map(|i| record(&format!("message {}", i), LogPriority::Info))
+            .collect();
+        let kept = buffer.push(&records).await.unwrap();
+        assert!(kept < records.len());
+        assert_eq!(buffer.stats().await.dropped, (records.len() - kept) as u64);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn buffer_compacts_delivered_records() {
+        let dir = tmp_dir("compact");
+        let buffer = DiskBuffer::open(&dir, 1024).await.unwrap();
+        buffer
+            .push(&[record("message 0", LogPriority::Info)])
+            .await
+            .unwrap();
+        // keep one record pending at all times so the spool is never fully drained
+        for i in 1..100 {
+            let kept = buffer
+                .push(&[record(&format!("message {}", i), LogPriority::Info)])
+                .await
+                .unwrap();
+            assert_eq!(kept, 1);
+            let (peeked, offset) = buffer.peek(1).await.unwrap();
+            assert_eq!(peeked[0].entry.message, format!("message {}", i - 1));
+            buffer.ack(offset).await.unwrap();
+            let len = tokio::fs::metadata(dir.join("spool.jsonl"))
+                .await
+                .unwrap()
+                .len();
+            assert!(len <= 1024);
+        }
+        assert_eq!(buffer.stats().await.dropped, 0);
+        drop(buffer);
+
+        let buffer = DiskBuffer::open(&dir, 1024).await.unwrap();
+        let (peeked, _) = buffer.peek(10).await.unwrap();
+        assert_eq!(peeked.len(), 1);
+        assert_eq!(peeked[0].entry.message, "message 99");
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn forwards_to_syslog_listener() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut buf = Vec::new();
+            conn.read_to_end(&mut buf).await.unwrap();
+            String::from_utf8(buf).unwrap()
+        });
+        let mut transport = Transport::new(
+            LogForwardTarget {
+                url: format!("syslog+tcp://127.0.0.1:{}", port).parse().unwrap(),
+                ca_cert: None,
+            },
+            "embassy".to_owned(),
+            Client::new(),
+        )
+        .unwrap();
+        transport
+            .send(&[
+                record("first", LogPriority::Info),
+                record("second", LogPriority::Warning),
+            ])
+            .await
+            .unwrap();
+        drop(transport);
+        let received = server.await.unwrap();
+        let (len, rest) = received.split_once(' ').unwrap();
+        let (first, rest) = rest.split_at(len.parse().unwrap());
+        assert!(first.starts_with("<30>1 "));
+        assert!(first.ends_with("first"));
+        assert!(rest
+            .trim_start_matches(|c: char| c.is_ascii_digit())
+            .starts_with(" <28>1 "));
+        assert!(rest.ends_with("second"));
+    }
+
+    #[tokio::test]
+    async fn forwards_to_otlp_listener() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut buf = Vec::new();
+            let mut chunk = [0; 4096];
+            loop {
+                let n = conn.read(&mut chunk).await.unwrap();
+                buf.extend_from_slice(&chunk[..n]);
+                let req = String::from_utf8_lossy(&buf);
+                if let Some((head, body)) = req.split_once("\r\n\r\n") {
+                    let len = head
+                        .lines()
+                        .find_map(|l| {
+                            l.to_ascii_lowercase()
+                                .strip_prefix("content-length:")
+                                .map(|v| v.trim().parse::<usize>().unwrap())
+                        })
+                        .unwrap();
+                    if body.len() >= len {
+                        break;
+                    }
+                }
+            }
+            conn.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(buf).unwrap()
+        });
+        let mut transport = Transport::new(
+            LogForwardTarget {
+                url: format!("otlp+http://127.0.0.1:{}", port).parse().unwrap(),
+                ca_cert: None,
+            },
+            "embassy".to_owned(),
+            Client::new(),
+        )
+        .unwrap();
+        transport
+            .send(&[record("hello otlp", LogPriority::Err)])
+            .await
+            .unwrap();
+        let received = server.await.unwrap();
+        assert!(received.starts_with("POST /v1/logs "));
+        let (_, body) = received.split_once("\r\n\r\n").unwrap();
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        let log = &body["resourceLogs"][0]["scopeLogs"][0]["logRecords"][0];
+        assert_eq!(log["body"]["stringValue"], "hello otlp");
+        assert_eq!(log["severityNumber"], 17);
+    }
+
+    #[test]
+    fn rejects_unknown_scheme() {
+        assert!(LogForwardTarget {
+            url: "ftp://example.com".parse().unwrap(),
+            ca_cert: None,
+        }
+        .validate()
+        .is_err());
+    }
+}
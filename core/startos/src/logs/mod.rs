@@ -0,0 +1,1120 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::time::{Duration, UNIX_EPOCH};
+
+use async_compression::tokio::write::GzipEncoder;
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::eyre;
+use futures::stream::BoxStream;
+use futures::{FutureExt, SinkExt, Stream, StreamExt, TryStreamExt};
+use http::header::{CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE};
+use http::{Response, StatusCode};
+use hyper::upgrade::Upgraded;
+use hyper::{Body, Error as HyperError};
+use models::ContainerId;
+use rpc_toolkit::command;
+use rpc_toolkit::yajrc::RpcError;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, Command};
+use tokio::task::JoinError;
+use tokio_stream::wrappers::LinesStream;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use tokio_util::io::ReaderStream;
+use tracing::instrument;
+
+use crate::context::{CliContext, RpcContext};
+use crate::core::rpc_continuations::{RequestGuid, RestHandler, RpcContinuation};
+use crate::error::ResultExt;
+use crate::prelude::*;
+use crate::procedure::docker::DockerProcedure;
+use crate::procedure::sidecar::Sidecar;
+use crate::s9pk::manifest::PackageId;
+use crate::util::display_none;
+use crate::util::serde::Reversible;
+use crate::{Error, ErrorKind};
+
+pub mod forward;
+
+#[pin_project::pin_project]
+pub struct LogStream {
+    _child: Child,
+    #[pin]
+    entries: BoxStream<'static, Result<JournalctlEntry, Error>>,
+}
+impl Deref for LogStream {
+    type Target = BoxStream<'static, Result<JournalctlEntry, Error>>;
+    fn deref(&self) -> &Self::Target {
+        &self.entries
+    }
+}
+impl DerefMut for LogStream {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.entries
+    }
+}
+impl Stream for LogStream {
+    type Item = Result<JournalctlEntry, Error>;
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        let this = self.project();
+        Stream::poll_next(this.entries, cx)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+#[instrument(skip_all)]
+async fn ws_handler<
+    WSFut: Future<Output = Result<Result<WebSocketStream<Upgraded>, HyperError>, JoinError>>,
+>(
+    first_entry: Option<LogEntry>,
+    mut logs: LogStream,
+    ws_fut: WSFut,
+) -> Result<(), Error> {
+    let mut stream = ws_fut
+        .await
+        .with_kind(crate::ErrorKind::Network)?
+        .with_kind(crate::ErrorKind::Unknown)?;
+
+    if let Some(first_entry) = first_entry {
+        stream
+            .send(Message::Text(
+                serde_json::to_string(&first_entry).with_kind(ErrorKind::Serialization)?,
+            ))
+            .await
+            .with_kind(ErrorKind::Network)?;
+    }
+
+    let mut ws_closed = false;
+    while let Some(entry) = tokio::select! {
+        a = logs.try_next() => Some(a?),
+        a = stream.try_next() => { a.with_kind(crate::ErrorKind::Network)?; ws_closed = true; None }
+    } {
+        if let Some(entry) = entry {
+            let (_, log_entry) = entry.log_entry()?;
+            stream
+                .send(Message::Text(
+                    serde_json::to_string(&log_entry).with_kind(ErrorKind::Serialization)?,
+                ))
+                .await
+                .with_kind(ErrorKind::Network)?;
+        }
+    }
+
+    if !ws_closed {
+        stream
+            .close(Some(CloseFrame {
+                code: CloseCode::Normal,
+                reason: "Log Stream Finished".into(),
+            }))
+            .await
+            .with_kind(ErrorKind::Network)?;
+    }
+
+    Ok(())
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct LogResponse {
+    entries: Reversible<LogEntry>,
+    start_cursor: Option<String>,
+    end_cursor: Option<String>,
+}
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct LogFollowResponse {
+    start_cursor: Option<String>,
+    guid: RequestGuid,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct LogEntry {
+    pub timestamp: DateTime<Utc>,
+    pub message: String,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<LogPriority>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, serde_json::Value>,
+}
+impl std::fmt::Display for LogEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {}",
+            self.timestamp
+                .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            self.message
+        )
+    }
+}
+
+/// Syslog priority levels, as stored in the journal's `PRIORITY` field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogPriority {
+    Emerg = 0,
+    Alert = 1,
+    Crit = 2,
+    Err = 3,
+    Warning = 4,
+    Notice = 5,
+    Info = 6,
+    Debug = 7,
+}
+impl LogPriority {
+    const ALL: [LogPriority; 8] = [
+        LogPriority::Emerg,
+        LogPriority::Alert,
+        LogPriority::Crit,
+        LogPriority::Err,
+        LogPriority::Warning,
+        LogPriority::Notice,
+        LogPriority::Info,
+        LogPriority::Debug,
+    ];
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogPriority::Emerg => "emerg",
+            LogPriority::Alert => "alert",
+            LogPriority::Crit => "crit",
+            LogPriority::Err => "err",
+            LogPriority::Warning => "warning",
+            LogPriority::Notice => "notice",
+            LogPriority::Info => "info",
+            LogPriority::Debug => "debug",
+        }
+    }
+}
+impl std::fmt::Display for LogPriority {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+impl FromStr for LogPriority {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_ascii_lowercase();
+        Self::ALL
+            .into_iter()
+            .find(|p| p.as_str() == s || (*p as u8).to_string() == s)
+            .or_else(|| match s.as_str() {
+                "emergency" | "panic" => Some(LogPriority::Emerg),
+                "critical" => Some(LogPriority::Crit),
+                "error" => Some(LogPriority::Err),
+                "warn" => Some(LogPriority::Warning),
+                _ => None,
+            })
+            .ok_or_else(|| {
+                Error::new(
+                    eyre!("Invalid log priority: {}", s),
+                    crate::ErrorKind::Deserialization,
+                )
+            })
+    }
+}
+
+/// Narrows down which journal entries are returned. Everything maps directly onto a journalctl
+/// flag, so filtering happens in journald rather than here.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct LogFilter {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub priority: Option<LogPriority>,
+    /// Passed to `journalctl --grep`, which matches with PCRE2. The pattern is checked with the
+    /// `regex` crate, so only the common subset is accepted: lookaround and backreferences are
+    /// rejected. As in journalctl, an all-lowercase pattern matches case-insensitively.
+    pub grep: Option<String>,
+}
+impl LogFilter {
+    fn validate(&self) -> Result<(), Error> {
+        if let (Some(since), Some(until)) = (self.since, self.until) {
+            if since > until {
+                return Err(Error::new(
+                    eyre!("`since` must not be after `until`"),
+                    crate::ErrorKind::InvalidRequest,
+                ));
+            }
+        }
+        if let Some(grep) = &self.grep {
+            regex::Regex::new(grep).with_kind(crate::ErrorKind::InvalidRequest)?;
+        }
+        Ok(())
+    }
+
+    fn apply(&self, cmd: &mut Command) {
+        if let Some(since) = self.since {
+            cmd.arg(format!("--since=@{}", since.timestamp()));
+        }
+        if let Some(until) = self.until {
+            cmd.arg(format!("--until=@{}", until.timestamp()));
+        }
+        if let Some(priority) = self.priority {
+            cmd.arg(format!("--priority={}", priority as u8));
+        }
+        if let Some(grep) = &self.grep {
+            cmd.arg(format!("--grep={}", grep));
+        }
+    }
+}
+
+/// Journal fields returned alongside the message, on top of the priority and timestamp.
+pub(crate) const STRUCTURED_FIELDS: &[&str] = &[
+    "SYSLOG_IDENTIFIER",
+    "SYSLOG_PID",
+    "_PID",
+    "_COMM",
+    "_SYSTEMD_UNIT",
+    "_TRANSPORT",
+    "CONTAINER_NAME",
+    "CONTAINER_ID",
+    "TARGET",
+    "CODE_FILE",
+    "CODE_LINE",
+];
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JournalctlEntry {
+    #[serde(rename = "__REALTIME_TIMESTAMP")]
+    pub timestamp: String,
+    #[serde(rename = "MESSAGE")]
+    #[serde(deserialize_with = "deserialize_log_message")]
+    pub message: String,
+    #[serde(rename = "__CURSOR")]
+    pub cursor: String,
+    #[serde(rename = "PRIORITY")]
+    #[serde(default)]
+    pub priority: Option<String>,
+    #[serde(flatten)]
+    pub fields: BTreeMap<String, serde_json::Value>,
+}
+impl JournalctlEntry {
+    pub(crate) fn log_entry(self) -> Result<(String, LogEntry), Error> {
+        Ok((
+            self.cursor,
+            LogEntry {
+                timestamp: DateTime::<Utc>::from(
+                    UNIX_EPOCH + Duration::from_micros(self.timestamp.parse::<u64>()?),
+                ),
+                message: self.message,
+                priority: self.priority.and_then(|p| p.parse().ok()),
+                fields: self
+                    .fields
+                    .into_iter()
+                    .filter(|(k, v)| !k.starts_with("__") && !v.is_null())
+                    .collect(),
+            },
+        ))
+    }
+}
+
+fn deserialize_log_message<'de, D: serde::de::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<String, D::Error> {
+    struct Visitor;
+    impl<'de> serde::de::Visitor<'de> for Visitor {
+        type Value = String;
+        fn expecting(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(formatter, "a parsable string")
+        }
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            Ok(v.trim().to_owned())
+        }
+        fn visit_unit<E>(self) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            Ok(String::new())
+        }
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: serde::de::SeqAccess<'de>,
+        {
+            String::from_utf8(
+                std::iter::repeat_with(|| seq.next_element::<u8>().transpose())
+                    .take_while(|a| a.is_some())
+                    .flatten()
+                    .collect::<Result<Vec<u8>, _>>()?,
+            )
+            .map(|s| s.trim().to_owned())
+            .map_err(serde::de::Error::custom)
+        }
+    }
+    deserializer.deserialize_any(Visitor)
+}
+
+/// Defining how we are going to filter on a journalctl cli log.
+/// Kernal: (-k --dmesg                 Show kernel message log from the current boot)
+/// Unit: ( -u --unit=UNIT             Show logs from the specified unit
+///     --user-unit=UNIT        Show logs from the specified user unit))
+/// System: Unit is startd, but we also filter on the comm
+/// Container: Filtering containers, like podman/docker is done by filtering on the CONTAINER_NAME
+/// Sidecar: Same as Container, for a sidecar of the package
+#[derive(Debug)]
+pub enum LogSource {
+    Kernel,
+    Unit(&'static str),
+    System,
+    Container(PackageId),
+    Sidecar(PackageId, ContainerId),
+}
+
+pub const SYSTEM_UNIT: &str = "startd";
+
+#[command(
+    custom_cli(cli_logs(async, context(CliContext))),
+    subcommands(self(logs_nofollow(async)), logs_follow),
+    display(display_none)
+)]
+pub async fn logs(
+    #[arg] id: PackageId,
+    #[arg(long = "container")] container: Option<ContainerId>,
+    #[arg(short = 'l', long = "limit")] limit: Option<usize>,
+    #[arg(short = 'c', long = "cursor")] cursor: Option<String>,
+    #[arg(short = 'B', long = "before", default)] before: bool,
+    #[arg(short = 'f', long = "follow", default)] follow: bool,
+    #[arg(short = 'S', long = "since")] since: Option<DateTime<Utc>>,
+    #[arg(short = 'U', long = "until")] until: Option<DateTime<Utc>>,
+    #[arg(short = 'p', long = "priority")] priority: Option<LogPriority>,
+    #[arg(short = 'g', long = "grep")] grep: Option<String>,
+) -> Result<
+    (
+        PackageId,
+        Option<ContainerId>,
+        Option<usize>,
+        Option<String>,
+        bool,
+        bool,
+        LogFilter,
+    ),
+    Error,
+> {
+    Ok((
+        id,
+        container,
+        limit,
+        cursor,
+        before,
+        follow,
+        LogFilter {
+            since,
+            until,
+            priority,
+            grep,
+        },
+    ))
+}
+pub async fn cli_logs(
+    ctx: CliContext,
+    (id, container, limit, cursor, before, follow, filter): (
+        PackageId,
+        Option<ContainerId>,
+        Option<usize>,
+        Option<String>,
+        bool,
+        bool,
+        LogFilter,
+    ),
+) -> Result<(), RpcError> {
+    if follow {
+        if cursor.is_some() {
+            return Err(RpcError::from(Error::new(
+                eyre!("The argument '--cursor <cursor>' cannot be used with '--follow'"),
+                crate::ErrorKind::InvalidRequest,
+            )));
+        }
+        if before {
+            return Err(RpcError::from(Error::new(
+                eyre!("The argument '--before' cannot be used with '--follow'"),
+                crate::ErrorKind::InvalidRequest,
+            )));
+        }
+        cli_logs_generic_follow(
+            ctx,
+            "package.logs.follow",
+            Some((id, container)),
+            limit,
+            filter,
+        )
+        .await
+    } else {
+        cli_logs_generic_nofollow(
+            ctx,
+            "package.logs",
+            Some((id, container)),
+            limit,
+            cursor,
+            before,
+            filter,
+        )
+        .await
+    }
+}
+pub async fn logs_nofollow(
+    _ctx: (),
+    (id, container, limit, cursor, before, _, filter): (
+        PackageId,
+        Option<ContainerId>,
+        Option<usize>,
+        Option<String>,
+        bool,
+        bool,
+        LogFilter,
+    ),
+) -> Result<LogResponse, Error> {
+    fetch_logs(
+        package_log_source(id, container),
+        limit,
+        cursor,
+        before,
+        filter,
+    )
+    .await
+}
+#[command(rpc_only, rename = "follow", display(display_none))]
+pub async fn logs_follow(
+    #[context] ctx: RpcContext,
+    #[parent_data] (id, container, limit, _, _, _, filter): (
+        PackageId,
+        Option<ContainerId>,
+        Option<usize>,
+        Option<String>,
+        bool,
+        bool,
+        LogFilter,
+    ),
+) -> Result<LogFollowResponse, Error> {
+    follow_logs(ctx, package_log_source(id, container), limit, filter).await
+}
+
+fn package_log_source(id: PackageId, container: Option<ContainerId>) -> LogSource {
+    match container {
+        Some(container) => LogSource::Sidecar(id, container),
+        None => LogSource::Container(id),
+    }
+}
+
+/// `id` is the package and, optionally, the sidecar to fetch logs for
+pub async fn cli_logs_generic_nofollow(
+    ctx: CliContext,
+    method: &str,
+    id: Option<(PackageId, Option<ContainerId>)>,
+    limit: Option<usize>,
+    cursor: Option<String>,
+    before: bool,
+    filter: LogFilter,
+) -> Result<(), RpcError> {
+    let (id, container) = id.unzip();
+    let res = rpc_toolkit::command_helpers::call_remote(
+        ctx.clone(),
+        method,
+        serde_json::json!({
+            "id": id,
+            "container": container.flatten(),
+            "limit": limit,
+            "cursor": cursor,
+            "before": before,
+            "since": filter.since,
+            "until": filter.until,
+            "priority": filter.priority,
+            "grep": filter.grep,
+        }),
+        PhantomData::<LogResponse>,
+    )
+    .await?
+    .result?;
+
+    for entry in res.entries.iter() {
+        println!("{}", entry);
+    }
+
+    Ok(())
+}
+
+/// `id` is the package and, optionally, the sidecar to follow logs for
+pub async fn cli_logs_generic_follow(
+    ctx: CliContext,
+    method: &str,
+    id: Option<(PackageId, Option<ContainerId>)>,
+    limit: Option<usize>,
+    filter: LogFilter,
+) -> Result<(), RpcError> {
+    if filter.until.is_some() {
+        return Err(Error::new(
+            eyre!("The argument '--until' cannot be used with '--follow'"),
+            crate::ErrorKind::InvalidRequest,
+        )
+        .into());
+    }
+    let (id, container) = id.unzip();
+    let res = rpc_toolkit::command_helpers::call_remote(
+        ctx.clone(),
+        method,
+        serde_json::json!({
+            "id": id,
+            "container": container.flatten(),
+            "limit": limit,
+            "since": filter.since,
+            "priority": filter.priority,
+            "grep": filter.grep,
+        }),
+        PhantomData::<LogFollowResponse>,
+    )
+    .await?
+    .result?;
+
+    let mut base_url = ctx.base_url.clone();
+    let ws_scheme = match base_url.scheme() {
+        "https" => "wss",
+        "http" => "ws",
+        _ => {
+            return Err(Error::new(
+                eyre!("Cannot parse scheme from base URL"),
+                crate::ErrorKind::ParseUrl,
+            )
+            .into())
+        }
+    };
+    base_url
+        .set_scheme(ws_scheme)
+        .map_err(|_| Error::new(eyre!("Cannot set URL scheme"), crate::ErrorKind::ParseUrl))?;
+    let (mut stream, _) =
+                // base_url is "http://127.0.0.1/", with a trailing slash, so we don't put a leading slash in this path:
+                tokio_tungstenite::connect_async(format!("{}ws/rpc/{}", base_url, res.guid)).await?;
+    while let Some(log) = stream.try_next().await? {
+        if let Message::Text(log) = log {
+            println!("{}", serde_json::from_str::<LogEntry>(&log)?);
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn log_source_args(id: &LogSource, cmd: &mut Command) {
+    match id {
+        LogSource::Kernel => {
+            cmd.arg("-k");
+        }
+        LogSource::Unit(id) => {
+            cmd.arg("-u");
+            cmd.arg(id);
+        }
+        LogSource::System => {
+            cmd.arg("-u");
+            cmd.arg(SYSTEM_UNIT);
+            cmd.arg(format!("_COMM={}", SYSTEM_UNIT));
+        }
+        LogSource::Container(id) => {
+            #[cfg(not(feature = "docker"))]
+            cmd.arg(format!(
+                "SYSLOG_IDENTIFIER={}",
+                DockerProcedure::container_name(&id, None)
+            ));
+            #[cfg(feature = "docker")]
+            cmd.arg(format!(
+                "CONTAINER_NAME={}",
+                DockerProcedure::container_name(&id, None)
+            ));
+        }
+        LogSource::Sidecar(id, container) => {
+            #[cfg(not(feature = "docker"))]
+            cmd.arg(format!(
+                "SYSLOG_IDENTIFIER={}",
+                Sidecar::container_name(&id, &container)
+            ));
+            #[cfg(feature = "docker")]
+            cmd.arg(format!(
+                "CONTAINER_NAME={}",
+                Sidecar::container_name(&id, &container)
+            ));
+        }
+    };
+}
+
+pub async fn journalctl(
+    id: LogSource,
+    limit: usize,
+    cursor: Option<&str>,
+    before: bool,
+    follow: bool,
+    filter: &LogFilter,
+) -> Result<LogStream, Error> {
+    let mut cmd = Command::new("journalctl");
+    cmd.kill_on_drop(true);
+
+    cmd.arg("--output=json");
+    cmd.arg(format!(
+        "--output-fields=MESSAGE,PRIORITY,{}",
+        STRUCTURED_FIELDS.join(",")
+    ));
+    cmd.arg(format!("-n{}", limit));
+    log_source_args(&id, &mut cmd);
+    filter.apply(&mut cmd);
+
+    let cursor_formatted = format!("--after-cursor={}", cursor.unwrap_or(""));
+    if cursor.is_some() {
+        cmd.arg(&cursor_formatted);
+        if before {
+            cmd.arg("--reverse");
+        }
+    }
+    if follow {
+        cmd.arg("--follow");
+    }
+
+    let mut child = cmd.stdout(Stdio::piped()).spawn()?;
+    let out = BufReader::new(
+        child
+            .stdout
+            .take()
+            .ok_or_else(|| Error::new(eyre!("No stdout available"), crate::ErrorKind::Journald))?,
+    );
+
+    let journalctl_entries = LinesStream::new(out.lines());
+
+    let deserialized_entries = journalctl_entries
+        .map_err(|e| Error::new(e, crate::ErrorKind::Journald))
+        .and_then(|s| {
+            futures::future::ready(
+                serde_json::from_str::<JournalctlEntry>(&s)
+                    .with_kind(crate::ErrorKind::Deserialization),
+            )
+        });
+
+    Ok(LogStream {
+        _child: child,
+        entries: deserialized_entries.boxed(),
+    })
+}
+
+#[instrument(skip_all)]
+pub async fn fetch_logs(
+    id: LogSource,
+    limit: Option<usize>,
+    cursor: Option<String>,
+    before: bool,
+    filter: LogFilter,
+) -> Result<LogResponse, Error> {
+    filter.validate()?;
+    let limit = limit.unwrap_or(50);
+    let mut stream = journalctl(id, limit, cursor.as_deref(), before, false, &filter).await?;
+
+    let mut entries = Vec::with_capacity(limit);
+    let mut start_cursor = None;
+
+    if let Some(first) = tokio::time::timeout(Duration::from_secs(1), stream.try_next())
+        .await
+        .ok()
+        .transpose()?
+        .flatten()
+    {
+        let (cursor, entry) = first.log_entry()?;
+        start_cursor = Some(cursor);
+        entries.push(entry);
+    }
+
+    let (mut end_cursor, entries) = stream
+        .try_fold(
+            (start_cursor.clone(), entries),
+            |(_, mut acc), entry| async move {
+                let (cursor, entry) = entry.log_entry()?;
+                acc.push(entry);
+                Ok((Some(cursor), acc))
+            },
+        )
+        .await?;
+    let mut entries = Reversible::new(entries);
+    // reverse again so output is always in increasing chronological order
+    if cursor.is_some() && before {
+        entries.reverse();
+        std::mem::swap(&mut start_cursor, &mut end_cursor);
+    }
+    Ok(LogResponse {
+        entries,
+        start_cursor,
+        end_cursor,
+    })
+}
+
+#[instrument(skip_all)]
+pub async fn follow_logs(
+    ctx: RpcContext,
+    id: LogSource,
+    limit: Option<usize>,
+    filter: LogFilter,
+) -> Result<LogFollowResponse, Error> {
+    filter.validate()?;
+    if filter.until.is_some() {
+        return Err(Error::new(
+            eyre!("`until` cannot be used when following logs"),
+            crate::ErrorKind::InvalidRequest,
+        ));
+    }
+    let limit = limit.unwrap_or(50);
+    let mut stream = journalctl(id, limit, None, false, true, &filter).await?;
+
+    let mut start_cursor = None;
+    let mut first_entry = None;
+
+    if let Some(first) = tokio::time::timeout(Duration::from_secs(1), stream.try_next())
+        .await
+        .ok()
+        .transpose()?
+        .flatten()
+    {
+        let (cursor, entry) = first.log_entry()?;
+        start_cursor = Some(cursor);
+        first_entry = Some(entry);
+    }
+
+    let guid = RequestGuid::new();
+    ctx.add_continuation(
+        guid.clone(),
+        RpcContinuation::ws(
+            Box::new(move |ws_fut| ws_handler(first_entry, stream, ws_fut).boxed()),
+            Duration::from_secs(30),
+        ),
+    )
+    .await;
+    Ok(LogFollowResponse { start_cursor, guid })
+}
+
+/// Bundles the journal of every log source (system, kernel, tor and each package) into a gzipped
+/// tarball for bug reports. Returns the guid of a REST continuation that serves the archive once
+/// it is complete.
+#[command(
+    custom_cli(cli_export(async, context(CliContext))),
+    display(display_none)
+)]
+#[instrument(skip_all)]
+pub async fn export(
+    #[context] ctx: RpcContext,
+    #[arg(short = 'S', long = "since")] since: Option<DateTime<Utc>>,
+    #[arg(short = 'U', long = "until")] until: Option<DateTime<Utc>>,
+    #[arg(short = 'p', long = "priority")] priority: Option<LogPriority>,
+    #[allow(unused_variables)]
+    #[arg(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+) -> Result<RequestGuid, Error> {
+    let filter = LogFilter {
+        since,
+        until,
+        priority,
+        grep: None,
+    };
+    filter.validate()?;
+    let mut sources = vec![
+        ("system".to_owned(), LogSource::System),
+        ("kernel".to_owned(), LogSource::Kernel),
+        (
+            "tor".to_owned(),
+            LogSource::Unit(crate::net::tor::SYSTEMD_UNIT),
+        ),
+    ];
+    let peek = ctx.db.peek().await;
+    for id in peek.as_package_data().keys()? {
+        let containers = peek
+            .as_package_data()
+            .as_idx(&id)
+            .and_then(|p| p.as_installed())
+            .map(|i| i.as_manifest().as_containers().de())
+            .transpose()?
+            .flatten();
+        for container in containers.into_iter().flat_map(|c| c.aux.0.into_keys()) {
+            sources.push((
+                format!("packages/{}.{}", id, container),
+                LogSource::Sidecar(id.clone(), container),
+            ));
+        }
+        sources.push((format!("packages/{}", id), LogSource::Container(id)));
+    }
+    let guid = RequestGuid::new();
+    let tmp_dir = ctx
+        .datadir
+        .join("package-data/tmp")
+        .join(format!("logs-export-{}", guid));
+    let handler: RestHandler = Box::new(move |_| {
+        async move {
+            // the archive is built before responding, so a failure is reported as an error
+            // status instead of a truncated download
+            let archive_path = tmp_dir.join("startos-logs.tar.gz");
+            let archive = async {
+                write_log_archive(sources, filter, &tmp_dir, &archive_path).await?;
+                tokio::fs::File::open(&archive_path)
+                    .await
+                    .with_ctx(|_| (ErrorKind::Filesystem, archive_path.display().to_string()))
+            }
+            .await;
+            // an open handle keeps the archive readable
+            if let Err(e) = tokio::fs::remove_dir_all(&tmp_dir).await {
+                tracing::warn!("Failed to remove {}: {}", tmp_dir.display(), e);
+            }
+            let archive = match archive {
+                Ok(a) => a,
+                Err(e) => {
+                    tracing::error!("Error exporting logs: {}", e);
+                    tracing::debug!("{:?}", e);
+                    return Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body(Body::from(format!("Error exporting logs: {}", e)))
+                        .with_kind(ErrorKind::Network);
+                }
+            };
+            let len = archive.metadata().await?.len();
+            Response::builder()
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, "application/gzip")
+                .header(CONTENT_LENGTH, len)
+                .header(
+                    CONTENT_DISPOSITION,
+                    "attachment; filename=\"startos-logs.tar.gz\"",
+                )
+                .body(Body::wrap_stream(ReaderStream::new(archive)))
+                .with_kind(ErrorKind::Network)
+        }
+        .boxed()
+    });
+    ctx.add_continuation(
+        guid.clone(),
+        RpcContinuation::rest(handler, Duration::from_secs(30)),
+    )
+    .await;
+    Ok(guid)
+}
+
+async fn write_log_archive(
+    sources: Vec<(String, LogSource)>,
+    filter: LogFilter,
+    tmp_dir: &std::path::Path,
+    archive_path: &std::path::Path,
+) -> Result<(), Error> {
+    tokio::fs::create_dir_all(tmp_dir).await?;
+    let archive = tokio::fs::File::create(archive_path)
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, archive_path.display().to_string()))?;
+    let mut tar = tokio_tar::Builder::new(GzipEncoder::new(archive));
+    let tmp_path = tmp_dir.join("source.log");
+    for (name, source) in sources {
+        let mut cmd = Command::new("journalctl");
+        cmd.kill_on_drop(true);
+        cmd.arg("--no-pager");
+        cmd.arg("--output=short-iso-precise");
+        log_source_args(&source, &mut cmd);
+        filter.apply(&mut cmd);
+        let status = cmd
+            .stdout(std::fs::File::create(&tmp_path)?)
+            .stderr(Stdio::null())
+            .status()
+            .await?;
+        if !status.success() {
+            tracing::warn!("journalctl exited with {} exporting {} logs", status, name);
+        }
+        tar.append_path_with_name(&tmp_path, format!("{}.log", name))
+            .await?;
+    }
+    let mut gz = tar.into_inner().await?;
+    gz.shutdown().await?;
+    gz.into_inner().sync_all().await?;
+    Ok(())
+}
+
+async fn cli_export(
+    ctx: CliContext,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    priority: Option<LogPriority>,
+    output: Option<PathBuf>,
+) -> Result<(), RpcError> {
+    let guid = rpc_toolkit::command_helpers::call_remote(
+        ctx.clone(),
+        "server.logs.export",
+        serde_json::json!({
+            "since": since,
+            "until": until,
+            "priority": priority,
+        }),
+        PhantomData::<RequestGuid>,
+    )
+    .await?
+    .result?;
+    let output = output.unwrap_or_else(|| PathBuf::from("startos-logs.tar.gz"));
+    let res = ctx
+        .client
+        .get(format!("{}rest/rpc/{}", ctx.base_url, guid))
+        .send()
+        .await?
+        .error_for_status()?;
+    let mut file = tokio::fs::File::create(&output).await?;
+    let mut body = res.bytes_stream();
+    while let Some(chunk) = body.try_next().await? {
+        file.write_all(&chunk).await?;
+    }
+    file.sync_all().await?;
+    println!("Logs written to {}", output.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn args(cmd: &Command) -> Vec<String> {
+        cmd.as_std()
+            .get_args()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn parses_priority_names_numbers_and_aliases() {
+        assert_eq!("err".parse::<LogPriority>().unwrap(), LogPriority::Err);
+        assert_eq!(
+            " Warning ".parse::<LogPriority>().unwrap(),
+            LogPriority::Warning
+        );
+        assert_eq!("0".parse::<LogPriority>().unwrap(), LogPriority::Emerg);
+        assert_eq!("7".parse::<LogPriority>().unwrap(), LogPriority::Debug);
+        assert_eq!("panic".parse::<LogPriority>().unwrap(), LogPriority::Emerg);
+        assert_eq!(
+            "critical".parse::<LogPriority>().unwrap(),
+            LogPriority::Crit
+        );
+        assert_eq!("error".parse::<LogPriority>().unwrap(), LogPriority::Err);
+        assert_eq!("warn".parse::<LogPriority>().unwrap(), LogPriority::Warning);
+        assert!("8".parse::<LogPriority>().is_err());
+        assert!("verbose".parse::<LogPriority>().is_err());
+    }
+
+    #[test]
+    fn priority_round_trips_through_display() {
+        for p in LogPriority::ALL {
+            assert_eq!(p.to_string().parse::<LogPriority>().unwrap(), p);
+        }
+    }
+
+    #[test]
+    fn validate_rejects_inverted_range() {
+        let now = Utc::now();
+        let filter = LogFilter {
+            since: Some(now),
+            until: Some(now - chrono::Duration::seconds(1)),
+            ..Default::default()
+        };
+        assert!(filter.validate().is_err());
+        let filter = LogFilter {
+            since: Some(now),
+            until: Some(now),
+            ..Default::default()
+        };
+        assert!(filter.validate().is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_bad_grep() {
+        let filter = LogFilter {
+            grep: Some("(unclosed".into()),
+            ..Default::default()
+        };
+        assert!(filter.validate().is_err());
+        let filter = LogFilter {
+            grep: Some("foo(?=bar)".into()),
+            ..Default::default()
+        };
+        assert!(filter.validate().is_err());
+        let filter = LogFilter {
+            grep: Some("^error: .*timed out$".into()),
+            ..Default::default()
+        };
+        assert!(filter.validate().is_ok());
+    }
+
+    #[test]
+    fn apply_maps_onto_journalctl_flags() {
+        let mut cmd = Command::new("journalctl");
+        LogFilter::default().apply(&mut cmd);
+        assert!(args(&cmd).is_empty());
+
+        let mut cmd = Command::new("journalctl");
+        LogFilter {
+            since: Some(Utc.timestamp_opt(1_700_000_000, 0).unwrap()),
+            until: Some(Utc.timestamp_opt(1_700_003_600, 0).unwrap()),
+            priority: Some(LogPriority::Warning),
+            grep: Some("timed out".into()),
+        }
+        .apply(&mut cmd);
+        assert_eq!(
+            args(&cmd),
+            [
+                "--since=@1700000000",
+                "--until=@1700003600",
+                "--priority=4",
+                "--grep=timed out",
+            ]
+        );
+    }
+}
+
+// #[tokio::test]
+// pub async fn test_logs() {
+//     let response = fetch_logs(
+//         // change `tor.service` to an actual journald unit on your machine
+//         // LogSource::Service("tor.service"),
+//         // first run `docker run --name=hello-world.embassy --log-driver=journald hello-world`
+//         LogSource::Container("hello-world".parse().unwrap()),
+//         // Some(5),
+//         None,
+//         None,
+//         // Some("s=1b8c418e28534400856c27b211dd94fd;i=5a7;b=97571c13a1284f87bc0639b5cff5acbe;m=740e916;t=5ca073eea3445;x=f45bc233ca328348".to_owned()),
+//         false,
+//         true,
+//     )
+//     .await
+//     .unwrap();
+//     let serialized = serde_json::to_string_pretty(&response).unwrap();
+//     println!("{}", serialized);
+// }
+
+// #[tokio::test]
+// pub async fn test_logs() {
+//     let mut cmd = Command::new("journalctl");
+//     cmd.kill_on_drop(true);
+
+//     cmd.arg("-f");
+//     cmd.arg("CONTAINER_NAME=hello-world.embassy");
+
+//     let mut child = cmd.stdout(Stdio::piped()).spawn().unwrap();
+//     let out = BufReader::new(
+//         child
+//             .stdout
+//             .take()
+//             .ok_or_else(|| Error::new(eyre!("No stdout available"), crate::ErrorKind::Journald))
+//             .unwrap(),
+//     );
+
+//     let mut journalctl_entries = LinesStream::new(out.lines());
+
+//     while let Some(line) = journalctl_entries.try_next().await.unwrap() {
+//         dbg!(line);
+//     }
+// }
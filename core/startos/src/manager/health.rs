@@ -27,10 +27,14 @@ pub async fn check(ctx: &RpcContext, id: &PackageId) -> Result<(), Error> {
 
     let health_results = if let Some(started) = started {
         tracing::debug!("Checking health of {}", id);
-        manifest
+        let mut results = manifest
             .health_checks
             .check_all(ctx, started, id, &manifest.version, &manifest.volumes)
-            .await?
+            .await?;
+        if let Some(containers) = &manifest.containers {
+            results.extend(containers.aux.check_all(id).await?);
+        }
+        results
     } else {
         return Ok(());
     };
@@ -166,6 +166,7 @@ async fn create_service_manager(
                         }
                     }
                 }
+                seed.stop_sidecars().await;
                 current_state.send_modify(|x| *x = StartStop::Stop);
             }
             (StartStop::Stop, StartStop::Start) => starting_service(
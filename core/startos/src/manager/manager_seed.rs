@@ -34,4 +34,15 @@ impl ManagerSeed {
         }
         Ok(())
     }
+
+    /// Stops the sidecars of the service. Errors are logged, so that one sidecar failing to stop
+    /// does not keep the others running.
+    pub async fn stop_sidecars(&self) {
+        for (id, sidecar) in self.manifest.sidecars() {
+            if let Err(e) = sidecar.stop(&self.manifest.id, id).await {
+                tracing::error!("Could not stop sidecar {} of {}", id, self.manifest.id);
+                tracing::debug!("{:?}", e);
+            }
+        }
+    }
 }
@@ -9,12 +9,13 @@ use container_init::ProcessGroupId;
 use futures::future::BoxFuture;
 use futures::{Future, FutureExt, TryFutureExt};
 use helpers::UnixRpcClient;
-use models::{ErrorKind, OptionExt, PackageId};
+use models::{ContainerId, ErrorKind, OptionExt, PackageId};
 use nix::sys::signal::Signal;
 use persistent_container::PersistentContainer;
 use rand::SeedableRng;
 use sqlx::Connection;
 use start_stop::StartStop;
+use tokio::process::Child;
 use tokio::sync::watch::{self, Sender};
 use tokio::sync::{oneshot, Mutex};
 use tracing::instrument;
@@ -24,7 +25,7 @@ use crate::backup::target::PackageBackupInfo;
 use crate::backup::PackageBackupReport;
 use crate::config::action::ConfigRes;
 use crate::config::spec::ValueSpecPointer;
-use crate::config::ConfigureContext;
+use crate::config::{secret, ConfigureContext, Configured};
 use crate::context::RpcContext;
 use crate::db::model::{CurrentDependencies, CurrentDependencyInfo};
 use crate::dependencies::{
@@ -37,6 +38,7 @@ use crate::net::net_controller::NetService;
 use crate::net::vhost::AlpnInfo;
 use crate::prelude::*;
 use crate::procedure::docker::{DockerContainer, DockerProcedure, LongRunning};
+use crate::procedure::sidecar;
 use crate::procedure::{NoOutput, ProcedureName};
 use crate::s9pk::manifest::Manifest;
 use crate::status::MainStatus;
@@ -169,7 +171,7 @@ impl Manager {
     pub async fn configure(
         &self,
         configure_context: ConfigureContext,
-    ) -> Result<BTreeMap<PackageId, String>, Error> {
+    ) -> Result<Configured, Error> {
         if self._is_transition_restart() {
             self._transition_abort().await;
         } else if self._is_transition_backup() {
@@ -181,11 +183,11 @@ impl Manager {
         let context = self.seed.ctx.clone();
         let id = self.seed.manifest.id.clone();
 
-        let breakages = configure(context, id, configure_context).await?;
+        let configured = configure(context, id, configure_context).await?;
 
         self.restart().await;
 
-        Ok(breakages)
+        Ok(configured)
     }
 
     /// awaiting this does not wait for the backup to complete
@@ -199,6 +201,27 @@ impl Manager {
         self._transition_replace(transition_state).await;
         done.await
     }
+    /// Stops the service while `f` runs, e.g. to restore its volumes, and starts it again
+    /// afterwards if it was running
+    pub async fn with_stopped<F, Fut, T>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        if self._is_transition_backup() {
+            return Err(Error::new(
+                eyre!("Can't stop service because it is backing up"),
+                ErrorKind::InvalidRequest,
+            ));
+        }
+        let was_started = self.manage_container.desired_state().borrow().is_start();
+        self.exit().await;
+        let res = f().await;
+        if was_started {
+            self.start().await;
+        }
+        res
+    }
     pub async fn exit(&self) {
         self._transition_abort().await;
         self.manage_container
@@ -337,7 +360,7 @@ async fn configure(
     ctx: RpcContext,
     id: PackageId,
     mut configure_context: ConfigureContext,
-) -> Result<BTreeMap<PackageId, String>, Error> {
+) -> Result<Configured, Error> {
     let db = ctx.db.peek().await;
     let id = &id;
     let ctx = &ctx;
@@ -351,13 +374,11 @@ async fn configure(
         .de()?;
 
     // get current config and current spec
+    let action = manifest.config.as_ref().or_not_found("Manifest config")?;
     let ConfigRes {
         config: old_config,
         spec,
-    } = manifest
-        .config
-        .as_ref()
-        .or_not_found("Manifest config")?
+    } = action
         .get(ctx, id, &manifest.version, &manifest.volumes)
         .await?;
 
@@ -365,18 +386,29 @@ async fn configure(
     let mut config = if let Some(config) = configure_context.config.or_else(|| old_config.clone()) {
         config
     } else {
-        spec.gen(
+        spec.gen_satisfying(
+            &action.constraints,
             &mut rand::rngs::StdRng::from_entropy(),
             &configure_context.timeout,
         )?
     };
 
-    spec.validate(&manifest)?;
-    spec.matches(&config)?; // check that new config matches spec
+    action.check(&spec, &manifest, &config)?; // check that new config matches spec
 
     // TODO Commit or not?
     spec.update(ctx, &manifest, overrides, &mut config).await?; // dereference pointers in the new config
 
+    // move secrets out of the config before it reaches the service or its dependents. They are
+    // only stored once the config has been applied.
+    let plaintexts = secret::extract(&spec, &mut config);
+    let sealed = if configure_context.dry_run || plaintexts.is_empty() {
+        None
+    } else {
+        let mut secrets_tx = ctx.secret_store.begin().await?;
+        secret::seal(secrets_tx.as_mut(), id, plaintexts).await?;
+        Some(secrets_tx)
+    };
+
     let manifest = db
         .as_package_data()
         .as_idx(id)
@@ -519,8 +551,17 @@ async fn configure(
         }
     }
 
+    let auto_configured = configure_context
+        .overrides
+        .keys()
+        .filter(|pkg| *pkg != id)
+        .cloned()
+        .collect();
     if !configure_context.dry_run {
-        return ctx
+        if let Some(secrets_tx) = sealed {
+            secrets_tx.commit().await?;
+        }
+        let breakages = ctx
             .db
             .mutate(move |db| {
                 remove_from_current_dependents_lists(db, id, &current_dependencies)?;
@@ -556,10 +597,19 @@ async fn configure(
                     .ser(&dependency_config_errs)?;
                 Ok(configure_context.breakages)
             })
-            .await; // add new
+            .await?; // add new
+        return Ok(Configured {
+            config,
+            breakages,
+            auto_configured,
+        });
     }
 
-    Ok(configure_context.breakages)
+    Ok(Configured {
+        config,
+        breakages: configure_context.breakages,
+        auto_configured,
+    })
 }
 
 struct DesiredStateReverter {
@@ -665,6 +715,17 @@ async fn run_main(
         true => None,
     };
 
+    let mut sidecars = match start_sidecars(&seed).await {
+        Ok(a) => a,
+        Err(e) => {
+            seed.stop_sidecars().await;
+            if persistent_container.is_none() {
+                seed.stop_container().await?;
+            }
+            return Err(e);
+        }
+    };
+
     let svc = if let Some(ip) = ip {
         let net = add_network_for_main(&seed, ip).await?;
         started();
@@ -676,14 +737,41 @@ async fn run_main(
     let health = main_health_check_daemon(seed.clone());
     let res = tokio::select! {
         a = runtime => a.map_err(|_| Error::new(eyre!("Manager runtime panicked!"), crate::ErrorKind::Docker)).and_then(|a| a),
-        _ = health => Err(Error::new(eyre!("Health check daemon exited!"), crate::ErrorKind::Unknown))
+        _ = health => Err(Error::new(eyre!("Health check daemon exited!"), crate::ErrorKind::Unknown)),
+        e = sidecar::wait_any(&mut sidecars) => {
+            // the service runs as a unit, so it restarts along with the sidecar
+            if persistent_container.is_none() {
+                seed.stop_container().await?;
+            }
+            Err(e)
+        }
     };
+    seed.stop_sidecars().await;
     if let Some(svc) = svc {
         remove_network_for_main(svc).await?;
     }
     res
 }
 
+/// Starts the sidecars of the service, once its main container exists for them to join
+async fn start_sidecars(seed: &ManagerSeed) -> Result<Vec<(ContainerId, Child)>, Error> {
+    let mut res = Vec::new();
+    for (id, sidecar) in seed.manifest.sidecars() {
+        let child = sidecar
+            .run(
+                &seed.ctx,
+                &seed.manifest.id,
+                &seed.manifest.version,
+                id,
+                &seed.manifest.volumes,
+                &seed.container_name,
+            )
+            .await?;
+        res.push((id.clone(), child));
+    }
+    Ok(res)
+}
+
 /// We want to start up the manifest, but in this case we want to know that we have generated the certificates.
 /// Note for _generated_certificate: Needed to know that before we start the state we have generated the certificate
 async fn start_up_image(seed: Arc<ManagerSeed>) -> Result<Result<NoOutput, (i32, String)>, Error> {
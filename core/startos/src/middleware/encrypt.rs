@@ -1,11 +1,30 @@
 use aes::cipher::{CipherKey, NewCipher, Nonce, StreamCipher};
 use aes::Aes256Ctr;
+use color_eyre::eyre::eyre;
 use hmac::Hmac;
 use josekit::jwk::Jwk;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
 use tracing::instrument;
 
+use crate::{Error, ErrorKind, ResultExt};
+
+/// Prefix identifying a versioned envelope. Legacy (v1) ciphertexts start with 32 random bytes, so a
+/// collision with this is negligible, and anything carrying the prefix is never retried as v1.
+const ENVELOPE_MAGIC: &[u8; 7] = b"S9CRYPT";
+const ENVELOPE_VERSION: u8 = 2;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const HEADER_LEN: usize = ENVELOPE_MAGIC.len() + 1 + SALT_LEN;
+
+/// Content encryption algorithms accepted from clients that opt into the v2 wire format. Every JWE
+/// content encryption is authenticated; pinning v2 to AES-256-GCM is a policy choice, so all
+/// clients of the new format use the same, strongest algorithm.
+const WIRE_V2_ENCS: &[&str] = &["A256GCM"];
+
+/// Key derivation used by the legacy AES-256-CTR format. Only kept to read old ciphertexts.
 pub fn pbkdf2(password: impl AsRef<[u8]>, salt: impl AsRef<[u8]>) -> CipherKey<Aes256Ctr> {
     let mut aeskey = CipherKey::<Aes256Ctr>::default();
     pbkdf2::pbkdf2::<Hmac<Sha256>>(
@@ -18,23 +37,98 @@ pub fn pbkdf2(password: impl AsRef<[u8]>, salt: impl AsRef<[u8]>) -> CipherKey<A
     aeskey
 }
 
-pub fn encrypt_slice(input: impl AsRef<[u8]>, password: impl AsRef<[u8]>) -> Vec<u8> {
-    let prefix: [u8; 32] = rand::random();
-    let aeskey = pbkdf2(password.as_ref(), &prefix[16..]);
-    let ctr = Nonce::<Aes256Ctr>::from_slice(&prefix[..16]);
-    let mut aes = Aes256Ctr::new(&aeskey, ctr);
-    let mut res = Vec::with_capacity(32 + input.as_ref().len());
-    res.extend_from_slice(&prefix[..]);
-    res.extend_from_slice(input.as_ref());
-    aes.apply_keystream(&mut res[32..]);
-    res
+fn argon2id(password: impl AsRef<[u8]>, salt: impl AsRef<[u8]>) -> Result<Vec<u8>, Error> {
+    argon2::hash_raw(
+        password.as_ref(),
+        salt.as_ref(),
+        &argon2::Config {
+            variant: argon2::Variant::Argon2id,
+            hash_length: 32,
+            ..argon2::Config::rfc9106_low_mem()
+        },
+    )
+    .with_kind(ErrorKind::PasswordHashGeneration)
 }
 
-pub fn decrypt_slice(input: impl AsRef<[u8]>, password: impl AsRef<[u8]>) -> Vec<u8> {
-    if input.as_ref().len() < 32 {
+/// Encrypts `input` into a v2 envelope:
+/// `magic || version || salt || nonce || ciphertext || tag`, using AES-256-GCM with a key derived
+/// from `password` with Argon2id. The header (magic, version and salt) is authenticated as AAD.
+pub fn encrypt_slice(
+    input: impl AsRef<[u8]>,
+    password: impl AsRef<[u8]>,
+) -> Result<Vec<u8>, Error> {
+    let salt: [u8; SALT_LEN] = rand::random();
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    let key = argon2id(password, &salt)?;
+    let mut res = Vec::with_capacity(HEADER_LEN + NONCE_LEN + input.as_ref().len() + TAG_LEN);
+    res.extend_from_slice(ENVELOPE_MAGIC);
+    res.push(ENVELOPE_VERSION);
+    res.extend_from_slice(&salt);
+    let mut tag = [0; TAG_LEN];
+    let ciphertext = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        &key,
+        Some(&nonce),
+        &res[..HEADER_LEN],
+        input.as_ref(),
+        &mut tag,
+    )
+    .with_kind(ErrorKind::OpenSsl)?;
+    res.extend_from_slice(&nonce);
+    res.extend_from_slice(&ciphertext);
+    res.extend_from_slice(&tag);
+    Ok(res)
+}
+
+/// Decrypts the output of [encrypt_slice]. Input without the envelope prefix is treated as the
+/// legacy unauthenticated AES-256-CTR format so that data written by older versions stays readable.
+pub fn decrypt_slice(
+    input: impl AsRef<[u8]>,
+    password: impl AsRef<[u8]>,
+) -> Result<Vec<u8>, Error> {
+    let input = input.as_ref();
+    if !input.starts_with(ENVELOPE_MAGIC) {
+        return Ok(legacy_decrypt_slice(input, password));
+    }
+    if input.len() < HEADER_LEN + NONCE_LEN + TAG_LEN {
+        return Err(Error::new(
+            eyre!("encrypted envelope is truncated"),
+            ErrorKind::IncorrectPassword,
+        ));
+    }
+    let version = input[ENVELOPE_MAGIC.len()];
+    if version != ENVELOPE_VERSION {
+        return Err(Error::new(
+            eyre!("unsupported encrypted envelope version {}", version),
+            ErrorKind::VersionIncompatible,
+        ));
+    }
+    let (header, rest) = input.split_at(HEADER_LEN);
+    let salt = &header[ENVELOPE_MAGIC.len() + 1..];
+    let (nonce, rest) = rest.split_at(NONCE_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+    let key = argon2id(password, salt)?;
+    decrypt_aead(
+        Cipher::aes_256_gcm(),
+        &key,
+        Some(nonce),
+        header,
+        ciphertext,
+        tag,
+    )
+    .map_err(|_| {
+        Error::new(
+            eyre!("decryption failed: incorrect password or tampered ciphertext"),
+            ErrorKind::IncorrectPassword,
+        )
+    })
+}
+
+fn legacy_decrypt_slice(input: &[u8], password: impl AsRef<[u8]>) -> Vec<u8> {
+    if input.len() < 32 {
         return Vec::new();
     }
-    let (prefix, rest) = input.as_ref().split_at(32);
+    let (prefix, rest) = input.split_at(32);
     let aeskey = pbkdf2(password.as_ref(), &prefix[16..]);
     let ctr = Nonce::<Aes256Ctr>::from_slice(&prefix[..16]);
     let mut aes = Aes256Ctr::new(&aeskey, ctr);
@@ -43,11 +137,40 @@ pub fn decrypt_slice(input: impl AsRef<[u8]>, password: impl AsRef<[u8]>) -> Vec
     res
 }
 
+/// A JWE (ECDH-ES against [RpcContextSeed::current_secret](crate::context::rpc::RpcContextSeed))
+/// sent by a client. Clients that set `version: 2` must use a content encryption allowed by
+/// `WIRE_V2_ENCS`; payloads without a version are accepted as-is for older clients.
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct EncryptedWire {
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<u8>,
     encrypted: serde_json::Value,
 }
 impl EncryptedWire {
+    /// Produces a v2 payload for `plaintext`, encrypted against the public half of `pubkey`.
+    pub fn encrypt(plaintext: &str, pubkey: &Jwk) -> Result<Self, Error> {
+        let encrypter = josekit::jwe::alg::ecdh_es::EcdhEsJweAlgorithm::EcdhEs
+            .encrypter_from_jwk(pubkey)
+            .map_err(|e| Error::new(eyre!("{}", e), ErrorKind::OpenSsl))?;
+        let mut header = josekit::jwe::JweHeader::new();
+        header.set_content_encryption(WIRE_V2_ENCS[0]);
+        let compact = josekit::jwe::serialize_compact(plaintext.as_bytes(), &header, &encrypter)
+            .map_err(|e| Error::new(eyre!("{}", e), ErrorKind::OpenSsl))?;
+        let mut parts = compact.split('.');
+        let mut encrypted = serde_json::Map::new();
+        for field in ["protected", "encrypted_key", "iv", "ciphertext", "tag"] {
+            let part = parts.next().unwrap_or_default();
+            if !part.is_empty() {
+                encrypted.insert(field.to_owned(), part.into());
+            }
+        }
+        Ok(Self {
+            version: Some(2),
+            encrypted: encrypted.into(),
+        })
+    }
+
     #[instrument(skip_all)]
     pub fn decrypt(self, current_secret: impl AsRef<Jwk>) -> Option<String> {
         let current_secret = current_secret.as_ref();
@@ -71,7 +194,7 @@ impl EncryptedWire {
                 return None;
             }
         };
-        let (decoded, _) = match josekit::jwe::deserialize_json(&encrypted, &decrypter) {
+        let (decoded, header) = match josekit::jwe::deserialize_json(&encrypted, &decrypter) {
             Ok(a) => a,
             Err(e) => {
                 tracing::warn!("Could not decrypt");
@@ -79,6 +202,22 @@ impl EncryptedWire {
                 return None;
             }
         };
+        match self.version {
+            None => (),
+            Some(2) => {
+                if !header
+                    .content_encryption()
+                    .map_or(false, |enc| WIRE_V2_ENCS.contains(&enc))
+                {
+                    tracing::warn!("Rejecting v2 payload with disallowed content encryption");
+                    return None;
+                }
+            }
+            Some(v) => {
+                tracing::warn!("Unsupported encrypted wire version {}", v);
+                return None;
+            }
+        }
         match String::from_utf8(decoded) {
             Ok(a) => Some(a),
             Err(e) => {
@@ -113,3 +252,132 @@ fn test_gen_awk() {
         &encrypted.decrypt(std::sync::Arc::new(private_key)).unwrap()
     );
 }
+
+#[cfg(test)]
+mod test {
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+
+    use super::*;
+
+    fn test_key() -> Jwk {
+        Jwk::generate_ec_key(josekit::jwk::alg::ec::EcCurve::P256).unwrap()
+    }
+
+    fn legacy_encrypt_slice(input: &[u8], password: &[u8]) -> Vec<u8> {
+        let prefix: [u8; 32] = rand::random();
+        let aeskey = pbkdf2(password, &prefix[16..]);
+        let ctr = Nonce::<Aes256Ctr>::from_slice(&prefix[..16]);
+        let mut aes = Aes256Ctr::new(&aeskey, ctr);
+        let mut res = prefix.to_vec();
+        res.extend_from_slice(input);
+        aes.apply_keystream(&mut res[32..]);
+        res
+    }
+
+    #[test]
+    fn envelope_roundtrip() {
+        let ct = encrypt_slice(b"super secret", b"password").unwrap();
+        assert!(ct.starts_with(ENVELOPE_MAGIC));
+        assert_eq!(decrypt_slice(&ct, b"password").unwrap(), b"super secret");
+    }
+
+    #[test]
+    fn envelope_rejects_wrong_password() {
+        let ct = encrypt_slice(b"super secret", b"password").unwrap();
+        assert!(decrypt_slice(&ct, b"wrong password").is_err());
+    }
+
+    #[test]
+    fn envelope_rejects_tampered_ciphertext() {
+        let ct = encrypt_slice(b"super secret", b"password").unwrap();
+        for idx in [
+            ENVELOPE_MAGIC.len() + 1, // salt
+            HEADER_LEN,               // nonce
+            HEADER_LEN + NONCE_LEN,   // ciphertext
+            ct.len() - 1,             // tag
+        ] {
+            let mut tampered = ct.clone();
+            tampered[idx] ^= 1;
+            assert!(
+                decrypt_slice(&tampered, b"password").is_err(),
+                "tampering at byte {} went undetected",
+                idx
+            );
+        }
+        assert!(decrypt_slice(&ct[..ct.len() - 1], b"password").is_err());
+    }
+
+    #[test]
+    fn envelope_rejects_unknown_version() {
+        let mut ct = encrypt_slice(b"super secret", b"password").unwrap();
+        ct[ENVELOPE_MAGIC.len()] = ENVELOPE_VERSION + 1;
+        assert!(decrypt_slice(&ct, b"password").is_err());
+    }
+
+    #[test]
+    fn legacy_ciphertext_still_decrypts() {
+        let ct = legacy_encrypt_slice(b"super secret", b"password");
+        assert_eq!(decrypt_slice(&ct, b"password").unwrap(), b"super secret");
+    }
+
+    #[test]
+    fn wire_v2_roundtrip() {
+        let key = test_key();
+        let wire = EncryptedWire::encrypt("testing12345", &key.to_public_key().unwrap()).unwrap();
+        assert_eq!(wire.version, Some(2));
+        assert_eq!(
+            wire.decrypt(std::sync::Arc::new(key)).as_deref(),
+            Some("testing12345")
+        );
+    }
+
+    #[test]
+    fn wire_rejects_tampered_ciphertext() {
+        let key = test_key();
+        let mut wire =
+            EncryptedWire::encrypt("testing12345", &key.to_public_key().unwrap()).unwrap();
+        let ciphertext = wire.encrypted["ciphertext"].as_str().unwrap().to_owned();
+        let mut bytes = URL_SAFE_NO_PAD.decode(&ciphertext).unwrap();
+        bytes[0] ^= 1;
+        wire.encrypted["ciphertext"] = URL_SAFE_NO_PAD.encode(&bytes).into();
+        assert!(wire.decrypt(std::sync::Arc::new(key)).is_none());
+    }
+
+    #[test]
+    fn wire_v2_rejects_disallowed_encryption() {
+        let key = test_key();
+        let encrypter = josekit::jwe::alg::ecdh_es::EcdhEsJweAlgorithm::EcdhEs
+            .encrypter_from_jwk(&key.to_public_key().unwrap())
+            .unwrap();
+        // authenticated, but not allowed for v2
+        let mut header = josekit::jwe::JweHeader::new();
+        header.set_content_encryption("A128CBC-HS256");
+        let compact =
+            josekit::jwe::serialize_compact(b"testing12345", &header, &encrypter).unwrap();
+        let parts: Vec<_> = compact.split('.').collect();
+        let wire = EncryptedWire {
+            version: Some(2),
+            encrypted: serde_json::json!({
+                "protected": parts[0],
+                "iv": parts[2],
+                "ciphertext": parts[3],
+                "tag": parts[4],
+            }),
+        };
+        assert!(wire
+            .clone()
+            .decrypt(std::sync::Arc::new(key.clone()))
+            .is_none());
+        // the same payload is still accepted through the legacy path
+        assert_eq!(
+            EncryptedWire {
+                version: None,
+                ..wire
+            }
+            .decrypt(std::sync::Arc::new(key))
+            .as_deref(),
+            Some("testing12345")
+        );
+    }
+}
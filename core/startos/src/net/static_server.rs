@@ -30,6 +30,7 @@ use crate::middleware::cors::cors;
 use crate::middleware::db::db as db_middleware;
 use crate::middleware::diagnostic::diagnostic as diagnostic_middleware;
 use crate::net::HttpHandler;
+use crate::registry::server::{self as registry, is_registry_path};
 use crate::{diagnostic_api, install_api, main_api, setup_api, Error, ErrorKind, ResultExt};
 
 static NOT_FOUND: &[u8] = b"Not Found";
@@ -192,6 +193,7 @@ pub async fn main_ui_server_router(ctx: RpcContext) -> Result<HttpHandler, Error
                         .map_err(|err| Error::new(eyre!("{}", err), crate::ErrorKind::Network))
                 }
                 "/ws/db" => subscribe(ctx, req).await,
+                path if is_registry_path(path) => registry::handle(ctx, req).await,
                 path if path.starts_with("/ws/rpc/") => {
                     match RequestGuid::from(path.strip_prefix("/ws/rpc/").unwrap()) {
                         None => {
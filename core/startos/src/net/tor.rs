@@ -25,7 +25,7 @@ use tracing::instrument;
 use crate::context::{CliContext, RpcContext};
 use crate::logs::{
     cli_logs_generic_follow, cli_logs_generic_nofollow, fetch_logs, follow_logs, journalctl,
-    LogFollowResponse, LogResponse, LogSource,
+    LogFilter, LogFollowResponse, LogResponse, LogSource,
 };
 use crate::util::serde::{display_serializable, IoFormat};
 use crate::util::{display_none, Invoke};
@@ -125,16 +125,39 @@ pub async fn cli_logs(
                 crate::ErrorKind::InvalidRequest,
             )));
         }
-        cli_logs_generic_follow(ctx, "net.tor.logs.follow", None, limit).await
+        cli_logs_generic_follow(
+            ctx,
+            "net.tor.logs.follow",
+            None,
+            limit,
+            LogFilter::default(),
+        )
+        .await
     } else {
-        cli_logs_generic_nofollow(ctx, "net.tor.logs", None, limit, cursor, before).await
+        cli_logs_generic_nofollow(
+            ctx,
+            "net.tor.logs",
+            None,
+            limit,
+            cursor,
+            before,
+            LogFilter::default(),
+        )
+        .await
     }
 }
 pub async fn logs_nofollow(
     _ctx: (),
     (limit, cursor, before, _): (Option<usize>, Option<String>, bool, bool),
 ) -> Result<LogResponse, Error> {
-    fetch_logs(LogSource::Unit(SYSTEMD_UNIT), limit, cursor, before).await
+    fetch_logs(
+        LogSource::Unit(SYSTEMD_UNIT),
+        limit,
+        cursor,
+        before,
+        LogFilter::default(),
+    )
+    .await
 }
 
 #[command(rpc_only, rename = "follow", display(display_none))]
@@ -142,7 +165,13 @@ pub async fn logs_follow(
     #[context] ctx: RpcContext,
     #[parent_data] (limit, _, _, _): (Option<usize>, Option<String>, bool, bool),
 ) -> Result<LogFollowResponse, Error> {
-    follow_logs(ctx, LogSource::Unit(SYSTEMD_UNIT), limit).await
+    follow_logs(
+        ctx,
+        LogSource::Unit(SYSTEMD_UNIT),
+        limit,
+        LogFilter::default(),
+    )
+    .await
 }
 
 fn event_handler(_event: AsyncEvent<'static>) -> BoxFuture<'static, Result<(), ConnError>> {
@@ -300,7 +329,15 @@ async fn torctl(
             .invoke(ErrorKind::Tor)
             .await?;
 
-        let logs = journalctl(LogSource::Unit(SYSTEMD_UNIT), 0, None, false, true).await?;
+        let logs = journalctl(
+            LogSource::Unit(SYSTEMD_UNIT),
+            0,
+            None,
+            false,
+            true,
+            &LogFilter::default(),
+        )
+        .await?;
 
         let mut tcp_stream = None;
         for _ in 0..60 {
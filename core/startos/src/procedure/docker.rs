@@ -20,8 +20,11 @@ use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
 use tokio::time::timeout;
 use tracing::instrument;
 
+use super::sidecar::Sidecars;
 use super::ProcedureName;
+use crate::config::secret;
 use crate::context::RpcContext;
+use crate::devices;
 use crate::prelude::*;
 use crate::s9pk::manifest::PackageId;
 use crate::util::docker::{remove_container, CONTAINER_TOOL};
@@ -48,8 +51,8 @@ lazy_static::lazy_static! {
 #[model = "Model<Self>"]
 pub struct DockerContainers {
     pub main: DockerContainer,
-    // #[serde(default)]
-    // pub aux: BTreeMap<String, DockerContainer>,
+    #[serde(default)]
+    pub aux: Sidecars,
 }
 
 /// This is like the docker procedures of the past designs,
@@ -70,6 +73,8 @@ pub struct DockerContainer {
     pub system: bool,
     #[serde(default)]
     pub gpu_acceleration: bool,
+    #[serde(default)]
+    pub resources: ResourceLimits,
 }
 
 impl DockerContainer {
@@ -158,6 +163,8 @@ pub struct DockerProcedure {
     pub shm_size_mb: Option<usize>, // TODO: use postfix sizing? like 1k vs 1m vs 1g
     #[serde(default)]
     pub gpu_acceleration: bool,
+    #[serde(default)]
+    pub resources: ResourceLimits,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, Default)]
@@ -173,6 +180,116 @@ pub struct DockerInject {
     #[serde(default)]
     pub sigterm_timeout: Option<SerdeDuration>,
 }
+/// Limits on what a container may use of the host. Unset limits are left to the container runtime.
+///
+/// Packages declare defaults in their manifest, which the user can override with
+/// `package.resources.set`.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ResourceLimits {
+    /// Weight relative to other containers when CPU is contended (runtime default 1024)
+    #[serde(default)]
+    pub cpu_shares: Option<u64>,
+    /// Maximum number of CPUs, e.g. 1.5
+    #[serde(default)]
+    pub cpus: Option<f64>,
+    #[serde(default)]
+    pub memory_mb: Option<u64>,
+    /// Memory plus swap, so must be at least `memory-mb`
+    #[serde(default)]
+    pub memory_swap_mb: Option<u64>,
+    #[serde(default)]
+    pub pids_limit: Option<u64>,
+    /// Weight relative to other containers for block IO, from 10 to 1000
+    #[serde(default)]
+    pub blkio_weight: Option<u16>,
+}
+impl ResourceLimits {
+    /// `overrides` where set, `self` otherwise
+    pub fn overridden_by(&self, overrides: &Self) -> Self {
+        ResourceLimits {
+            cpu_shares: overrides.cpu_shares.or(self.cpu_shares),
+            cpus: overrides.cpus.or(self.cpus),
+            memory_mb: overrides.memory_mb.or(self.memory_mb),
+            memory_swap_mb: overrides.memory_swap_mb.or(self.memory_swap_mb),
+            pids_limit: overrides.pids_limit.or(self.pids_limit),
+            blkio_weight: overrides.blkio_weight.or(self.blkio_weight),
+        }
+    }
+
+    /// Applies the overrides the user has set for `pkg_id`
+    pub async fn with_overrides(
+        &self,
+        ctx: &RpcContext,
+        pkg_id: &PackageId,
+    ) -> Result<Self, Error> {
+        let overrides = ctx
+            .db
+            .peek()
+            .await
+            .as_package_data()
+            .as_idx(pkg_id)
+            .and_then(|p| p.as_installed())
+            .map(|i| i.as_resource_limits().de())
+            .transpose()?
+            .unwrap_or_default();
+        Ok(self.overridden_by(&overrides))
+    }
+
+    pub fn validate(&self) -> Result<(), color_eyre::eyre::Report> {
+        if self.cpu_shares == Some(0) {
+            color_eyre::eyre::bail!("cpu-shares must be positive");
+        }
+        if let Some(cpus) = self.cpus {
+            if !(cpus > 0.0) || !cpus.is_finite() {
+                color_eyre::eyre::bail!("cpus must be positive");
+            }
+        }
+        if self.memory_mb == Some(0) || self.pids_limit == Some(0) {
+            color_eyre::eyre::bail!("memory-mb and pids-limit must be positive");
+        }
+        if let Some(swap) = self.memory_swap_mb {
+            match self.memory_mb {
+                None => color_eyre::eyre::bail!("memory-swap-mb requires memory-mb"),
+                Some(memory) if swap < memory => {
+                    color_eyre::eyre::bail!("memory-swap-mb must be at least memory-mb")
+                }
+                _ => (),
+            }
+        }
+        if let Some(weight) = self.blkio_weight {
+            if !(10..=1000).contains(&weight) {
+                color_eyre::eyre::bail!("blkio-weight must be between 10 and 1000");
+            }
+        }
+        Ok(())
+    }
+
+    /// Arguments for `run` or `update`
+    pub fn docker_args(&self) -> Vec<OsString> {
+        let mut res = Vec::new();
+        if let Some(cpu_shares) = self.cpu_shares {
+            res.push(format!("--cpu-shares={}", cpu_shares).into());
+        }
+        if let Some(cpus) = self.cpus {
+            res.push(format!("--cpus={}", cpus).into());
+        }
+        if let Some(memory_mb) = self.memory_mb {
+            res.push(format!("--memory={}m", memory_mb).into());
+        }
+        if let Some(memory_swap_mb) = self.memory_swap_mb {
+            res.push(format!("--memory-swap={}m", memory_swap_mb).into());
+        }
+        if let Some(pids_limit) = self.pids_limit {
+            res.push(format!("--pids-limit={}", pids_limit).into());
+        }
+        if let Some(blkio_weight) = self.blkio_weight {
+            res.push(format!("--blkio-weight={}", blkio_weight).into());
+        }
+        res
+    }
+}
+
 impl DockerProcedure {
     pub fn main_docker_procedure(
         container: &DockerContainer,
@@ -189,6 +306,7 @@ impl DockerProcedure {
             sigterm_timeout: injectable.sigterm_timeout,
             shm_size_mb: container.shm_size_mb,
             gpu_acceleration: container.gpu_acceleration,
+            resources: container.resources.clone(),
         }
     }
 
@@ -214,6 +332,7 @@ impl DockerProcedure {
         if expected_io && self.io_format.is_none() {
             color_eyre::eyre::bail!("expected io-format");
         }
+        self.resources.validate()?;
         Ok(())
     }
 
@@ -242,7 +361,10 @@ impl DockerProcedure {
             .arg("--no-healthcheck")
             .kill_on_drop(true);
         remove_container(&container_name, true).await?;
-        cmd.args(self.docker_args(ctx, pkg_id, pkg_version, volumes).await?);
+        cmd.args(
+            self.docker_args(ctx, pkg_id, pkg_version, volumes, name.is_none())
+                .await?,
+        );
         let input_buf = if let (Some(input), Some(format)) = (&input, &self.io_format) {
             cmd.stdin(std::process::Stdio::piped());
             Some(format.to_vec(input)?)
@@ -544,7 +666,7 @@ impl DockerProcedure {
         let mut cmd = tokio::process::Command::new(CONTAINER_TOOL);
         cmd.arg("run").arg("--rm").arg("--network=none");
         cmd.args(
-            self.docker_args(ctx, pkg_id, pkg_version, &volumes.to_readonly())
+            self.docker_args(ctx, pkg_id, pkg_version, &volumes.to_readonly(), false)
                 .await?,
         );
         let input_buf = if let (Some(input), Some(format)) = (&input, &self.io_format) {
@@ -678,6 +800,8 @@ impl DockerProcedure {
         pkg_id: &PackageId,
         pkg_version: &Version,
         volumes: &Volumes,
+        // whether the container runs the service itself, rather than a one-off procedure
+        runtime: bool,
     ) -> Result<Vec<Cow<'_, OsStr>>, Error> {
         let mut res = self.new_docker_args();
         for (volume_id, dst) in &self.mounts {
@@ -705,6 +829,22 @@ impl DockerProcedure {
             res.push(OsStr::new("--shm-size").into());
             res.push(OsString::from(format!("{}m", shm_size_mb)).into());
         }
+        // user overrides only apply to the long-running main container
+        res.extend(self.resources.docker_args().into_iter().map(Cow::Owned));
+        if runtime {
+            res.extend(
+                devices::docker_args(ctx, pkg_id)
+                    .await?
+                    .into_iter()
+                    .map(Cow::Owned),
+            );
+            res.extend(
+                secret::docker_args(ctx, pkg_id)
+                    .await?
+                    .into_iter()
+                    .map(Cow::Owned),
+            );
+        }
         if self.gpu_acceleration {
             fn get_devices<'a>(
                 path: &'a Path,
@@ -874,6 +1014,15 @@ impl LongRunning {
         if let Some(shm_size_mb) = docker.shm_size_mb {
             cmd.arg("--shm-size").arg(format!("{}m", shm_size_mb));
         }
+        cmd.args(
+            docker
+                .resources
+                .with_overrides(ctx, pkg_id)
+                .await?
+                .docker_args(),
+        );
+        cmd.args(devices::docker_args(ctx, pkg_id).await?);
+        cmd.args(secret::docker_args(ctx, pkg_id).await?);
         cmd.arg("--log-driver=journald");
         if docker.system {
             cmd.arg(docker.image.for_package(&SYSTEM_PACKAGE_ID, None));
@@ -961,6 +1110,38 @@ mod tests {
         assert_eq!(CAPACITY_IN, ring.value.len());
     }
 
+    #[test]
+    fn resource_overrides_take_precedence() {
+        let defaults = ResourceLimits {
+            cpus: Some(2.0),
+            memory_mb: Some(2048),
+            pids_limit: Some(512),
+            ..Default::default()
+        };
+        let overrides = ResourceLimits {
+            memory_mb: Some(4096),
+            memory_swap_mb: Some(6144),
+            ..Default::default()
+        };
+        let limits = defaults.overridden_by(&overrides);
+        assert!(limits.validate().is_ok());
+        assert_eq!(
+            limits.docker_args(),
+            vec![
+                OsString::from("--cpus=2"),
+                OsString::from("--memory=4096m"),
+                OsString::from("--memory-swap=6144m"),
+                OsString::from("--pids-limit=512"),
+            ]
+        );
+        assert!(ResourceLimits {
+            memory_swap_mb: Some(1024),
+            ..defaults
+        }
+        .validate()
+        .is_err());
+    }
+
     #[test]
     fn tests_buf_reader_to_lines() {
         let mut reader = BufReader::new("hello\nworld\n".as_bytes());
@@ -19,6 +19,7 @@ use crate::{Error, ErrorKind};
 pub mod docker;
 #[cfg(feature = "js-engine")]
 pub mod js_scripts;
+pub mod sidecar;
 pub use models::ProcedureName;
 
 #[derive(Clone, Debug, Deserialize, Serialize, HasModel)]
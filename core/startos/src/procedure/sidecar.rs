@@ -0,0 +1,303 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use models::{ContainerId, HealthCheckId, ImageId, SYSTEM_PACKAGE_ID};
+use serde::{Deserialize, Serialize};
+use tokio::process::{Child, Command};
+use tracing::instrument;
+
+use super::docker::{DockerProcedure, ResourceLimits, SYSTEM_IMAGES};
+use crate::context::RpcContext;
+use crate::prelude::*;
+use crate::s9pk::manifest::PackageId;
+use crate::status::health_check::{HealthCheckResult, HealthChecks};
+use crate::util::docker::{remove_container, stop_container, CONTAINER_TOOL};
+use crate::util::serde::Duration as SerdeDuration;
+use crate::util::Version;
+use crate::volume::{VolumeId, Volumes};
+
+/// Auxiliary containers of a package, e.g. a database, a cache or a proxy.
+///
+/// Sidecars join the network namespace of the main container, so the package reaches them on
+/// `localhost` and they share its address. The [Manager](crate::manager::Manager) starts them once
+/// the main container is up, and stops them along with it.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Sidecars(pub BTreeMap<ContainerId, Sidecar>);
+impl Sidecars {
+    #[instrument(skip_all)]
+    pub fn validate(
+        &self,
+        volumes: &Volumes,
+        image_ids: &BTreeSet<ImageId>,
+        health_checks: &HealthChecks,
+    ) -> Result<(), Error> {
+        let mut check_ids: BTreeSet<&HealthCheckId> = health_checks.0.keys().collect();
+        for (id, sidecar) in &self.0 {
+            sidecar
+                .validate(volumes, image_ids)
+                .with_ctx(|_| (ErrorKind::ValidateS9pk, format!("Sidecar {}", id)))?;
+            for check_id in sidecar.health_checks.keys() {
+                if !check_ids.insert(check_id) {
+                    return Err(Error::new(
+                        eyre!(
+                            "Health check {} of sidecar {} is already defined",
+                            check_id,
+                            id
+                        ),
+                        ErrorKind::ValidateS9pk,
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the health checks of every sidecar
+    pub async fn check_all(
+        &self,
+        pkg_id: &PackageId,
+    ) -> Result<BTreeMap<HealthCheckId, HealthCheckResult>, Error> {
+        let mut checks = Vec::new();
+        for (id, sidecar) in &self.0 {
+            let container_name = Sidecar::container_name(pkg_id, id);
+            for (check_id, check) in &sidecar.health_checks {
+                let container_name = container_name.clone();
+                checks.push(async move {
+                    Ok::<_, Error>((check_id.clone(), check.check(&container_name).await?))
+                });
+            }
+        }
+        let res = futures::future::try_join_all(checks).await?;
+        Ok(res.into_iter().collect())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Sidecar {
+    pub image: ImageId,
+    #[serde(default)]
+    pub system: bool,
+    /// Defaults to the entrypoint of the image
+    #[serde(default)]
+    pub entrypoint: Option<String>,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub mounts: BTreeMap<VolumeId, PathBuf>,
+    #[serde(default)]
+    pub shm_size_mb: Option<usize>,
+    #[serde(default)]
+    pub sigterm_timeout: Option<SerdeDuration>,
+    #[serde(default)]
+    pub resources: ResourceLimits,
+    #[serde(default)]
+    pub health_checks: BTreeMap<HealthCheckId, SidecarHealthCheck>,
+}
+impl Sidecar {
+    pub fn container_name(pkg_id: &PackageId, id: &ContainerId) -> String {
+        DockerProcedure::container_name(pkg_id, Some(id.as_ref()))
+    }
+
+    pub fn validate(
+        &self,
+        volumes: &Volumes,
+        image_ids: &BTreeSet<ImageId>,
+    ) -> Result<(), color_eyre::eyre::Report> {
+        for volume in self.mounts.keys() {
+            if !volumes.contains_key(volume) {
+                color_eyre::eyre::bail!("unknown volume: {}", volume);
+            }
+        }
+        if self.system {
+            if !SYSTEM_IMAGES.contains(&self.image) {
+                color_eyre::eyre::bail!("unknown system image: {}", self.image);
+            }
+        } else if !image_ids.contains(&self.image) {
+            color_eyre::eyre::bail!("image for {} not contained in package", self.image);
+        }
+        for check in self.health_checks.values() {
+            if check.command.is_empty() {
+                color_eyre::eyre::bail!("health check {} has no command", check.name);
+            }
+        }
+        self.resources.validate()
+    }
+
+    /// Starts the sidecar in the network namespace of `main_container`. The container is removed
+    /// once it exits.
+    #[instrument(skip_all)]
+    pub async fn run(
+        &self,
+        ctx: &RpcContext,
+        pkg_id: &PackageId,
+        pkg_version: &Version,
+        id: &ContainerId,
+        volumes: &Volumes,
+        main_container: &str,
+    ) -> Result<Child, Error> {
+        let container_name = Self::container_name(pkg_id, id);
+        remove_container(&container_name, true).await?;
+        let mut cmd = Command::new(CONTAINER_TOOL);
+        cmd.arg("run")
+            .arg("--rm")
+            .arg(format!("--network=container:{}", main_container))
+            .arg("--name")
+            .arg(&container_name)
+            .arg("--log-driver=journald")
+            .kill_on_drop(true);
+        for (volume_id, dst) in &self.mounts {
+            let Some(volume) = volumes.get(volume_id) else {
+                continue;
+            };
+            let src = volume.path_for(&ctx.datadir, pkg_id, pkg_version, volume_id);
+            if tokio::fs::metadata(&src).await.is_err() {
+                tokio::fs::create_dir_all(&src).await?;
+            }
+            cmd.arg("--mount").arg(format!(
+                "type=bind,src={},dst={}{}",
+                src.display(),
+                dst.display(),
+                if volume.readonly() { ",readonly" } else { "" }
+            ));
+        }
+        if let Some(shm_size_mb) = self.shm_size_mb {
+            cmd.arg("--shm-size").arg(format!("{}m", shm_size_mb));
+        }
+        cmd.args(self.resources.docker_args());
+        if let Some(entrypoint) = &self.entrypoint {
+            cmd.arg("--entrypoint").arg(entrypoint);
+        }
+        if self.system {
+            cmd.arg(self.image.for_package(&SYSTEM_PACKAGE_ID, None));
+        } else {
+            cmd.arg(self.image.for_package(pkg_id, Some(pkg_version)));
+        }
+        cmd.args(&self.args);
+        cmd.stdin(std::process::Stdio::null());
+        cmd.stdout(std::process::Stdio::null());
+        cmd.stderr(std::process::Stdio::null());
+        cmd.spawn().with_kind(ErrorKind::Docker)
+    }
+
+    pub async fn stop(&self, pkg_id: &PackageId, id: &ContainerId) -> Result<(), Error> {
+        match stop_container(
+            &Self::container_name(pkg_id, id),
+            self.sigterm_timeout.map(|d| *d),
+            None,
+        )
+        .await
+        {
+            Err(e) if e.kind == ErrorKind::NotFound => Ok(()),
+            a => a,
+        }
+    }
+}
+
+/// Waits for the first of `sidecars` to exit, or forever if there are none
+pub fn wait_any(sidecars: &mut [(ContainerId, Child)]) -> BoxFuture<'_, Error> {
+    if sidecars.is_empty() {
+        return futures::future::pending().boxed();
+    }
+    futures::future::select_all(sidecars.iter_mut().map(|(id, child)| {
+        async move {
+            match child.wait().await {
+                Ok(status) => Error::new(
+                    eyre!("Sidecar {} exited: {}", id, status),
+                    ErrorKind::Docker,
+                ),
+                Err(e) => Error::new(eyre!("Sidecar {}: {}", id, e), ErrorKind::Docker),
+            }
+        }
+        .boxed()
+    }))
+    .map(|(e, _, _)| e)
+    .boxed()
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SidecarHealthCheck {
+    pub name: String,
+    pub success_message: Option<String>,
+    /// Run inside the sidecar. Exit codes mean the same as for the health checks of the main
+    /// container: 59 disabled, 60 starting, 61 loading with stdout as message.
+    pub command: Vec<String>,
+    pub timeout: Option<SerdeDuration>,
+}
+impl SidecarHealthCheck {
+    #[instrument(skip_all)]
+    pub async fn check(&self, container_name: &str) -> Result<HealthCheckResult, Error> {
+        let mut cmd = Command::new(CONTAINER_TOOL);
+        cmd.arg("exec")
+            .arg(container_name)
+            .args(&self.command)
+            .kill_on_drop(true);
+        let timeout = self.timeout.map_or(Duration::from_secs(30), |d| *d);
+        let output = match tokio::time::timeout(timeout, cmd.output()).await {
+            Ok(output) => output?,
+            Err(_) => {
+                return Ok(HealthCheckResult::Failure {
+                    error: format!("Timed out after {}s", timeout.as_secs()),
+                })
+            }
+        };
+        let message = || {
+            let out = if output.stderr.is_empty() {
+                &output.stdout
+            } else {
+                &output.stderr
+            };
+            String::from_utf8_lossy(out).trim().to_owned()
+        };
+        Ok(match output.status.code() {
+            Some(0) => HealthCheckResult::Success,
+            Some(59) => HealthCheckResult::Disabled,
+            Some(60) => HealthCheckResult::Starting,
+            Some(61) => HealthCheckResult::Loading {
+                message: String::from_utf8_lossy(&output.stdout).trim().to_owned(),
+            },
+            _ => HealthCheckResult::Failure { error: message() },
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rejects_duplicate_health_checks() {
+        let sidecars: Sidecars = serde_json::from_value(serde_json::json!({
+            "db": {
+                "image": "postgres",
+                "health-checks": {
+                    "db-ready": { "name": "Database", "command": ["pg_isready"] }
+                }
+            },
+            "cache": {
+                "image": "redis",
+                "health-checks": {
+                    "db-ready": { "name": "Cache", "command": ["redis-cli", "ping"] }
+                }
+            }
+        }))
+        .unwrap();
+        let images = ["postgres", "redis"]
+            .into_iter()
+            .map(|i| i.parse().unwrap())
+            .collect();
+        let no_checks = HealthChecks(BTreeMap::new());
+        assert!(sidecars
+            .validate(&Volumes::default(), &images, &no_checks)
+            .is_err());
+        let mut db_only = sidecars.clone();
+        db_only.0.retain(|id, _| id.to_string() == "db");
+        assert!(db_only
+            .validate(&Volumes::default(), &images, &no_checks)
+            .is_ok());
+    }
+}
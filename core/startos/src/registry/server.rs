@@ -0,0 +1,889 @@
+use std::collections::BTreeMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use clap::ArgMatches;
+use emver::VersionRange;
+use futures::TryStreamExt;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use models::DataUrl;
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::Mutex;
+use tokio_util::io::{ReaderStream, StreamReader};
+use tracing::instrument;
+
+use crate::context::RpcContext;
+use crate::install::MinMax;
+use crate::prelude::*;
+use crate::s9pk::manifest::{Manifest, PackageId};
+use crate::s9pk::reader::S9pkReader;
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::util::{display_none, Version};
+
+/// Where uploaded packages are kept, relative to the data directory.
+pub const REGISTRY_DIR: &str = "registry";
+
+static INDEX_LOCK: Mutex<()> = Mutex::const_new(());
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RegistryS9pk {
+    /// `None` if the package runs on any architecture
+    pub arches: Option<Vec<String>>,
+    pub file: String,
+    pub size: u64,
+    /// As computed by `S9pkReader`, advertised so clients can check what they downloaded
+    #[serde(default)]
+    pub hash: Option<String>,
+}
+impl RegistryS9pk {
+    fn supports(&self, arch: Option<&str>) -> bool {
+        match (&self.arches, arch) {
+            (Some(arches), Some(arch)) => arches.iter().any(|a| a == arch),
+            _ => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RegistryVersion {
+    pub published_at: DateTime<Utc>,
+    /// Only indexed versions are listed and served; `publish` uploads first and indexes last.
+    pub indexed: bool,
+    pub s9pks: Vec<RegistryS9pk>,
+}
+impl RegistryVersion {
+    fn s9pk_for(&self, arch: Option<&str>) -> Option<&RegistryS9pk> {
+        self.s9pks
+            .iter()
+            .find(|s| s.arches.is_some() && s.supports(arch))
+            .or_else(|| self.s9pks.iter().find(|s| s.supports(arch)))
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RegistryIndex {
+    pub packages: BTreeMap<PackageId, BTreeMap<Version, RegistryVersion>>,
+}
+impl RegistryIndex {
+    async fn load(dir: &Path) -> Result<Self, Error> {
+        match tokio::fs::read(dir.join("index.json")).await {
+            Ok(a) => serde_json::from_slice(&a).with_kind(ErrorKind::Deserialization),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+    async fn save(&self, dir: &Path) -> Result<(), Error> {
+        let tmp = dir.join("index.json.tmp");
+        tokio::fs::write(
+            &tmp,
+            serde_json::to_vec_pretty(self).with_kind(ErrorKind::Serialization)?,
+        )
+        .await?;
+        tokio::fs::rename(&tmp, dir.join("index.json")).await?;
+        Ok(())
+    }
+
+    fn select(
+        &self,
+        id: &PackageId,
+        spec: &VersionRange,
+        priority: MinMax,
+        arch: Option<&str>,
+    ) -> Option<(&Version, &RegistryVersion)> {
+        let mut candidates =
+            self.packages.get(id)?.iter().filter(|(v, info)| {
+                info.indexed && v.satisfies(spec) && info.s9pk_for(arch).is_some()
+            });
+        match priority {
+            MinMax::Min => candidates.next(),
+            MinMax::Max => candidates.last(),
+        }
+    }
+}
+
+fn registry_dir(ctx: &RpcContext) -> PathBuf {
+    ctx.datadir.join(REGISTRY_DIR)
+}
+
+fn version_dir(dir: &Path, id: &PackageId, version: &Version) -> PathBuf {
+    dir.join("packages").join(id).join(version.as_str())
+}
+
+fn arch_key(arches: &Option<Vec<String>>) -> String {
+    match arches {
+        Some(arches) if !arches.is_empty() => {
+            let mut arches = arches.clone();
+            arches.sort();
+            arches.join("+")
+        }
+        _ => "universal".to_owned(),
+    }
+}
+
+fn respond(status: StatusCode, message: impl Into<String>) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(http::header::CONTENT_TYPE, "text/plain")
+        .body(Body::from(message.into()))
+        .unwrap()
+}
+
+fn json_response<T: Serialize>(value: &T) -> Result<Response<Body>, Error> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            serde_json::to_vec(value).with_kind(ErrorKind::Serialization)?,
+        ))
+        .with_kind(ErrorKind::Network)
+}
+
+async fn file_response(path: &Path, mime: &str) -> Result<Response<Body>, Error> {
+    let file = File::open(path)
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()))?;
+    let len = file.metadata().await?.len();
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, mime)
+        .header(http::header::CONTENT_LENGTH, len)
+        .body(Body::wrap_stream(ReaderStream::new(file)))
+        .with_kind(ErrorKind::Network)
+}
+
+/// Parses a single `bytes=<start>-[<end>]` range
+fn parse_range(header: &str, len: u64) -> Option<(u64, u64)> {
+    let (start, end) = header.strip_prefix("bytes=")?.split_once('-')?;
+    let start: u64 = start.trim().parse().ok()?;
+    let end = match end.trim() {
+        "" => len.checked_sub(1)?,
+        end => end.parse::<u64>().ok()?.min(len.checked_sub(1)?),
+    };
+    if start > end {
+        return None;
+    }
+    Some((start, end))
+}
+
+/// Serves an s9pk with support for resuming and parallel downloads via range requests
+async fn s9pk_response(
+    path: &Path,
+    s9pk: &RegistryS9pk,
+    req: &Request<Body>,
+) -> Result<Response<Body>, Error> {
+    let mut file = File::open(path)
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()))?;
+    let len = file.metadata().await?.len();
+    let etag = format!(
+        "\"{}\"",
+        s9pk.hash
+            .clone()
+            .unwrap_or_else(|| format!("{}-{}", s9pk.file, len))
+    );
+    let header = |name| req.headers().get(name).and_then(|h| h.to_str().ok());
+    let range = header(http::header::RANGE)
+        .filter(|_| header(http::header::IF_RANGE).map_or(true, |v| v == etag))
+        .and_then(|r| parse_range(r, len));
+    let mut res = Response::builder()
+        .header(http::header::CONTENT_TYPE, "application/octet-stream")
+        .header(http::header::ACCEPT_RANGES, "bytes")
+        .header(http::header::ETAG, &etag);
+    if let Some(hash) = &s9pk.hash {
+        res = res.header(crate::install::download::S9PK_HASH_HEADER, hash);
+    }
+    if let Some((start, end)) = range {
+        file.seek(SeekFrom::Start(start)).await?;
+        res.status(StatusCode::PARTIAL_CONTENT)
+            .header(
+                http::header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, len),
+            )
+            .header(http::header::CONTENT_LENGTH, end - start + 1)
+            .body(Body::wrap_stream(ReaderStream::new(
+                file.take(end - start + 1),
+            )))
+    } else {
+        res.status(StatusCode::OK)
+            .header(http::header::CONTENT_LENGTH, len)
+            .body(Body::wrap_stream(ReaderStream::new(file)))
+    }
+    .with_kind(ErrorKind::Network)
+}
+
+fn error_response(e: Error) -> Response<Body> {
+    let status = match e.kind {
+        ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        ErrorKind::Authorization | ErrorKind::IncorrectPassword => StatusCode::UNAUTHORIZED,
+        ErrorKind::InvalidRequest
+        | ErrorKind::ParseVersion
+        | ErrorKind::ParseS9pk
+        | ErrorKind::InvalidSignature
+        | ErrorKind::ValidateS9pk => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    if status == StatusCode::INTERNAL_SERVER_ERROR {
+        tracing::error!("Registry error: {}", e);
+        tracing::debug!("{:?}", e);
+    }
+    let mut res = respond(status, e.source.to_string());
+    if status == StatusCode::UNAUTHORIZED {
+        res.headers_mut().insert(
+            http::header::WWW_AUTHENTICATE,
+            http::HeaderValue::from_static("Basic realm=\"registry\""),
+        );
+    }
+    res
+}
+
+fn not_found(what: impl std::fmt::Display) -> Error {
+    Error::new(eyre!("{} not found", what), ErrorKind::NotFound)
+}
+
+struct Query(BTreeMap<String, String>);
+impl Query {
+    fn parse(req: &Request<Body>) -> Self {
+        Self(
+            url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+                .into_owned()
+                .collect(),
+        )
+    }
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(|s| s.as_str())
+    }
+    fn spec(&self) -> Result<VersionRange, Error> {
+        Ok(self.get("spec").unwrap_or("*").parse()?)
+    }
+    fn priority(&self) -> Result<MinMax, Error> {
+        self.get("version-priority")
+            .map(|p| p.parse())
+            .transpose()
+            .map(|p| p.unwrap_or_default())
+    }
+    fn arch(&self) -> Option<&str> {
+        self.get("hardware.arch")
+    }
+}
+
+/// Returns true if `path` is served by the registry.
+pub fn is_registry_path(path: &str) -> bool {
+    let path = path.trim_start_matches('/');
+    path.starts_with("package/v0/") || path.starts_with("admin/v0/")
+}
+
+/// Serves `package/v0/*` to clients and accepts `admin/v0/*` uploads from `registry publish`.
+#[instrument(skip_all)]
+pub async fn handle(ctx: RpcContext, req: Request<Body>) -> Result<Response<Body>, Error> {
+    if !ctx
+        .db
+        .peek()
+        .await
+        .as_server_info()
+        .as_registry_server()
+        .de()?
+    {
+        return Ok(respond(StatusCode::NOT_FOUND, "Not Found"));
+    }
+    let path = req.uri().path().trim_start_matches('/').to_owned();
+    let res = if let Some(path) = path.strip_prefix("package/v0/") {
+        if req.method() == Method::GET {
+            serve_package(&ctx, path, &req).await
+        } else {
+            Ok(respond(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "Method Not Allowed",
+            ))
+        }
+    } else {
+        match (req.method(), path.as_str()) {
+            (&Method::POST, "admin/v0/upload") => match authorize(&ctx, &req).await {
+                Ok(()) => upload(&ctx, req).await,
+                Err(e) => Err(e),
+            },
+            (&Method::POST, "admin/v0/index") => match authorize(&ctx, &req).await {
+                Ok(()) => index(&ctx, req).await,
+                Err(e) => Err(e),
+            },
+            (_, "admin/v0/upload" | "admin/v0/index") => Ok(respond(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "Method Not Allowed",
+            )),
+            _ => Ok(respond(StatusCode::NOT_FOUND, "Not Found")),
+        }
+    };
+    Ok(res.unwrap_or_else(error_response))
+}
+
+async fn authorize(ctx: &RpcContext, req: &Request<Body>) -> Result<(), Error> {
+    let unauthorized = || Error::new(eyre!("Not Authorized"), ErrorKind::Authorization);
+    let creds = req
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Basic "))
+        .and_then(|h| {
+            base64::engine::general_purpose::STANDARD
+                .decode(h.trim())
+                .ok()
+        })
+        .and_then(|h| String::from_utf8(h).ok())
+        .ok_or_else(unauthorized)?;
+    let (user, pass) = creds.split_once(':').ok_or_else(unauthorized)?;
+    let hash = sqlx::query!(
+        "SELECT password_hash FROM registry_users WHERE username = $1",
+        user
+    )
+    .fetch_optional(&ctx.secret_store)
+    .await?
+    .ok_or_else(unauthorized)?
+    .password_hash;
+    crate::auth::check_password(&hash, pass)
+}
+
+async fn serve_package(
+    ctx: &RpcContext,
+    path: &str,
+    req: &Request<Body>,
+) -> Result<Response<Body>, Error> {
+    let dir = registry_dir(ctx);
+    let query = Query::parse(req);
+    let registry = RegistryIndex::load(&dir).await?;
+    match path.split_once('/') {
+        None if path == "info" => {
+            let hostname = ctx.account.read().await.hostname.0.clone();
+            json_response(&serde_json::json!({
+                "name": format!("{} Registry", hostname),
+                "categories": Vec::<String>::new(),
+            }))
+        }
+        None if path == "index" => json_response(&package_index(&dir, &registry, &query).await?),
+        None => {
+            let id: PackageId = path
+                .strip_suffix(".s9pk")
+                .ok_or_else(|| not_found(path))?
+                .parse()?;
+            let (version, info) = registry
+                .select(&id, &query.spec()?, query.priority()?, query.arch())
+                .ok_or_else(|| not_found(&id))?;
+            let s9pk = info.s9pk_for(query.arch()).ok_or_else(|| not_found(&id))?;
+            s9pk_response(&version_dir(&dir, &id, version).join(&s9pk.file), s9pk, req).await
+        }
+        Some(("release-notes", id)) => {
+            let id: PackageId = id.parse()?;
+            let mut notes = BTreeMap::new();
+            for (version, info) in registry.packages.get(&id).ok_or_else(|| not_found(&id))? {
+                if info.indexed {
+                    let manifest = read_manifest(&dir, &id, version).await?;
+                    notes.insert(version.to_string(), manifest.release_notes);
+                }
+            }
+            json_response(&notes)
+        }
+        Some((kind, id)) => {
+            let id: PackageId = id.parse()?;
+            let (version, _) = registry
+                .select(&id, &query.spec()?, query.priority()?, None)
+                .ok_or_else(|| not_found(&id))?;
+            let version_dir = version_dir(&dir, &id, version);
+            match kind {
+                "manifest" => {
+                    file_response(&version_dir.join("manifest.json"), "application/json").await
+                }
+                "license" => file_response(&version_dir.join("LICENSE.md"), "text/markdown").await,
+                "instructions" => {
+                    file_response(&version_dir.join("INSTRUCTIONS.md"), "text/markdown").await
+                }
+                "icon" => {
+                    let manifest = read_manifest(&dir, &id, version).await?;
+                    let icon_type = manifest.assets.icon_type();
+                    file_response(
+                        &version_dir.join(format!("icon.{}", icon_type)),
+                        &format!(
+                            "image/{}",
+                            if icon_type == "svg" {
+                                "svg+xml"
+                            } else {
+                                icon_type
+                            }
+                        ),
+                    )
+                    .await
+                }
+                _ => Err(not_found(kind)),
+            }
+        }
+    }
+}
+
+async fn read_manifest(dir: &Path, id: &PackageId, version: &Version) -> Result<Manifest, Error> {
+    serde_json::from_slice(
+        &tokio::fs::read(version_dir(dir, id, version).join("manifest.json")).await?,
+    )
+    .with_kind(ErrorKind::Deserialization)
+}
+
+async fn icon_data_url(dir: &Path, id: &PackageId, version: &Version) -> Result<String, Error> {
+    let manifest = read_manifest(dir, id, version).await?;
+    Ok(DataUrl::from_path(
+        version_dir(dir, id, version).join(format!("icon.{}", manifest.assets.icon_type())),
+    )
+    .await?
+    .to_string())
+}
+
+#[derive(Deserialize)]
+struct IdVersion {
+    id: PackageId,
+    version: VersionRange,
+}
+
+async fn package_index(
+    dir: &Path,
+    registry: &RegistryIndex,
+    query: &Query,
+) -> Result<Vec<serde_json::Value>, Error> {
+    let ids: Option<Vec<IdVersion>> = query
+        .get("ids")
+        .map(serde_json::from_str)
+        .transpose()
+        .with_kind(ErrorKind::InvalidRequest)?;
+    let search = query.get("query").map(|q| q.to_lowercase());
+    let page: usize = query.get("page").and_then(|p| p.parse().ok()).unwrap_or(1);
+    let per_page: usize = query
+        .get("per-page")
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(20);
+    let arch = query.arch();
+
+    let wanted: Vec<(&PackageId, VersionRange)> = match &ids {
+        Some(ids) => ids
+            .iter()
+            .filter_map(|i| {
+                registry
+                    .packages
+                    .get_key_value(&i.id)
+                    .map(|(id, _)| (id, i.version.clone()))
+            })
+            .collect(),
+        None => registry
+            .packages
+            .keys()
+            .map(|id| (id, VersionRange::Any))
+            .collect(),
+    };
+
+    let mut res = Vec::new();
+    for (id, spec) in wanted {
+        let Some((version, info)) = registry.select(id, &spec, MinMax::Max, arch) else {
+            continue;
+        };
+        let manifest = read_manifest(dir, id, version).await?;
+        if let Some(search) = &search {
+            if !id.to_lowercase().contains(search)
+                && !manifest.title.to_lowercase().contains(search)
+                && !manifest.description.short.to_lowercase().contains(search)
+            {
+                continue;
+            }
+        }
+        let mut dependency_metadata = serde_json::Map::new();
+        for dep in manifest.dependencies.0.keys() {
+            if let Some((dep_version, _)) =
+                registry.select(dep, &VersionRange::Any, MinMax::Max, None)
+            {
+                let dep_manifest = read_manifest(dir, dep, dep_version).await?;
+                dependency_metadata.insert(
+                    dep.to_string(),
+                    serde_json::json!({
+                        "title": dep_manifest.title,
+                        "icon": icon_data_url(dir, dep, dep_version).await?,
+                        "hidden": false,
+                    }),
+                );
+            }
+        }
+        res.push(serde_json::json!({
+            "icon": icon_data_url(dir, id, version).await?,
+            "license": format!("/package/v0/license/{}?spec=={}", id, version),
+            "instructions": format!("/package/v0/instructions/{}?spec=={}", id, version),
+            "manifest": manifest,
+            "categories": Vec::<String>::new(),
+            "versions": registry.packages[id]
+                .iter()
+                .filter(|(_, v)| v.indexed)
+                .map(|(v, _)| v.to_string())
+                .collect::<Vec<_>>(),
+            "dependency-metadata": dependency_metadata,
+            "published-at": info.published_at,
+            "hash": info.s9pk_for(arch).and_then(|s| s.hash.as_deref()),
+        }));
+    }
+    Ok(res
+        .into_iter()
+        .skip(page.saturating_sub(1) * per_page)
+        .take(per_page)
+        .collect())
+}
+
+#[instrument(skip_all)]
+async fn upload(ctx: &RpcContext, req: Request<Body>) -> Result<Response<Body>, Error> {
+    let query = Query::parse(&req);
+    let id: PackageId = query
+        .get("id")
+        .ok_or_else(|| Error::new(eyre!("missing package id"), ErrorKind::InvalidRequest))?
+        .parse()?;
+    let dir = registry_dir(ctx);
+    let tmp_dir = dir.join("tmp");
+    tokio::fs::create_dir_all(&tmp_dir).await?;
+    let tmp_path = tmp_dir.join(format!("{}-{:016x}.s9pk", id, rand::random::<u64>()));
+    let res = async {
+        let mut body = StreamReader::new(
+            req.into_body()
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e)),
+        );
+        let mut tmp = File::create(&tmp_path).await?;
+        let size = tokio::io::copy(&mut body, &mut tmp).await?;
+        tmp.sync_all().await?;
+        drop(tmp);
+
+        let mut s9pk = S9pkReader::open(&tmp_path, true).await?;
+        s9pk.validate().await?;
+        let manifest = s9pk.manifest().await?;
+        if manifest.id != id {
+            return Err(Error::new(
+                eyre!("s9pk is for {}, not {}", manifest.id, id),
+                ErrorKind::InvalidRequest,
+            ));
+        }
+
+        let version_dir = version_dir(&dir, &id, &manifest.version);
+        tokio::fs::create_dir_all(&version_dir).await?;
+        tokio::fs::write(
+            version_dir.join("manifest.json"),
+            serde_json::to_vec_pretty(&manifest).with_kind(ErrorKind::Serialization)?,
+        )
+        .await?;
+        tokio::io::copy(
+            &mut s9pk.license().await?,
+            &mut File::create(version_dir.join("LICENSE.md")).await?,
+        )
+        .await?;
+        tokio::io::copy(
+            &mut s9pk.instructions().await?,
+            &mut File::create(version_dir.join("INSTRUCTIONS.md")).await?,
+        )
+        .await?;
+        tokio::io::copy(
+            &mut s9pk.icon().await?,
+            &mut File::create(version_dir.join(format!("icon.{}", manifest.assets.icon_type())))
+                .await?,
+        )
+        .await?;
+        let hash = s9pk.hash_str().map(|h| h.to_owned());
+        drop(s9pk);
+
+        let arches = manifest.hardware_requirements.arch.clone();
+        let file = format!("{}.s9pk", arch_key(&arches));
+        tokio::fs::rename(&tmp_path, version_dir.join(&file)).await?;
+
+        let _lock = INDEX_LOCK.lock().await;
+        let mut registry = RegistryIndex::load(&dir).await?;
+        let entry = registry
+            .packages
+            .entry(id.clone())
+            .or_default()
+            .entry(manifest.version.clone())
+            .or_insert_with(|| RegistryVersion {
+                published_at: Utc::now(),
+                indexed: false,
+                s9pks: Vec::new(),
+            });
+        entry.published_at = Utc::now();
+        entry.s9pks.retain(|s| s.file != file);
+        entry.s9pks.push(RegistryS9pk {
+            arches,
+            file,
+            size,
+            hash,
+        });
+        registry.save(&dir).await?;
+        tracing::info!("Registry: uploaded {}@{}", id, manifest.version);
+        Ok(respond(StatusCode::OK, "OK"))
+    }
+    .await;
+    if res.is_err() {
+        tokio::fs::remove_file(&tmp_path).await.ok();
+    }
+    res
+}
+
+#[derive(Deserialize)]
+struct IndexReq {
+    id: PackageId,
+    version: Version,
+}
+
+#[instrument(skip_all)]
+async fn index(ctx: &RpcContext, req: Request<Body>) -> Result<Response<Body>, Error> {
+    let body = hyper::body::to_bytes(req.into_body())
+        .await
+        .with_kind(ErrorKind::Network)?;
+    let IndexReq { id, version } =
+        serde_json::from_slice(&body).with_kind(ErrorKind::InvalidRequest)?;
+    let dir = registry_dir(ctx);
+    let _lock = INDEX_LOCK.lock().await;
+    let mut registry = RegistryIndex::load(&dir).await?;
+    registry
+        .packages
+        .get_mut(&id)
+        .and_then(|p| p.get_mut(&version))
+        .ok_or_else(|| not_found(format!("{}@{}", id, version)))?
+        .indexed = true;
+    registry.save(&dir).await?;
+    tracing::info!("Registry: indexed {}@{}", id, version);
+    Ok(respond(StatusCode::OK, "OK"))
+}
+
+#[command(subcommands(enable, disable, user, list, remove))]
+pub fn registry() -> Result<(), Error> {
+    Ok(())
+}
+
+#[command(display(display_none))]
+pub async fn enable(#[context] ctx: RpcContext) -> Result<(), Error> {
+    tokio::fs::create_dir_all(registry_dir(&ctx)).await?;
+    ctx.db
+        .mutate(|db| db.as_server_info_mut().as_registry_server_mut().ser(&true))
+        .await
+}
+
+#[command(display(display_none))]
+pub async fn disable(#[context] ctx: RpcContext) -> Result<(), Error> {
+    ctx.db
+        .mutate(|db| db.as_server_info_mut().as_registry_server_mut().ser(&false))
+        .await
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RegistryPackageInfo {
+    pub id: PackageId,
+    pub version: Version,
+    pub indexed: bool,
+    pub published_at: DateTime<Utc>,
+    pub arches: Vec<String>,
+}
+
+fn display_packages(packages: Vec<RegistryPackageInfo>, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(packages, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "ID", "VERSION", "INDEXED", "PUBLISHED", "ARCHES"]);
+    for pkg in packages {
+        table.add_row(row![
+            &*pkg.id,
+            pkg.version.as_str(),
+            pkg.indexed,
+            pkg.published_at.to_rfc3339(),
+            pkg.arches.join(", "),
+        ]);
+    }
+    table.print_tty(false).unwrap();
+}
+
+#[command(display(display_packages))]
+pub async fn list(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<RegistryPackageInfo>, Error> {
+    let registry = RegistryIndex::load(&registry_dir(&ctx)).await?;
+    Ok(registry
+        .packages
+        .into_iter()
+        .flat_map(|(id, versions)| {
+            versions
+                .into_iter()
+                .map(move |(version, info)| RegistryPackageInfo {
+                    id: id.clone(),
+                    version,
+                    indexed: info.indexed,
+                    published_at: info.published_at,
+                    arches: info.s9pks.iter().map(|s| arch_key(&s.arches)).collect(),
+                })
+        })
+        .collect())
+}
+
+#[command(display(display_none))]
+pub async fn remove(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg(long = "version")] version: Option<Version>,
+) -> Result<(), Error> {
+    let dir = registry_dir(&ctx);
+    let _lock = INDEX_LOCK.lock().await;
+    let mut registry = RegistryIndex::load(&dir).await?;
+    let versions = registry
+        .packages
+        .get_mut(&id)
+        .ok_or_else(|| not_found(&id))?;
+    if let Some(version) = version {
+        versions
+            .remove(&version)
+            .ok_or_else(|| not_found(format!("{}@{}", id, version)))?;
+        tokio::fs::remove_dir_all(version_dir(&dir, &id, &version)).await?;
+        if versions.is_empty() {
+            registry.packages.remove(&id);
+        }
+    } else {
+        registry.packages.remove(&id);
+        tokio::fs::remove_dir_all(dir.join("packages").join(&id)).await?;
+    }
+    registry.save(&dir).await
+}
+
+#[command(subcommands(add_user, remove_user, list_users))]
+pub fn user() -> Result<(), Error> {
+    Ok(())
+}
+
+#[command(rename = "add", display(display_none))]
+pub async fn add_user(
+    #[context] ctx: RpcContext,
+    #[arg] username: String,
+    #[arg] password: String,
+) -> Result<(), Error> {
+    if username.is_empty() || username.contains(':') {
+        return Err(Error::new(
+            eyre!("username must be non-empty and may not contain ':'"),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+    let hash = argon2::hash_encoded(
+        password.as_bytes(),
+        &rand::random::<[u8; 16]>()[..],
+        &argon2::Config::rfc9106_low_mem(),
+    )
+    .with_kind(ErrorKind::PasswordHashGeneration)?;
+    sqlx::query!(
+        "INSERT INTO registry_users (username, password_hash) VALUES ($1, $2) ON CONFLICT (username) DO UPDATE SET password_hash = EXCLUDED.password_hash",
+        username,
+        hash
+    )
+    .execute(&ctx.secret_store)
+    .await?;
+    Ok(())
+}
+
+#[command(rename = "remove", display(display_none))]
+pub async fn remove_user(#[context] ctx: RpcContext, #[arg] username: String) -> Result<(), Error> {
+    let n = sqlx::query!("DELETE FROM registry_users WHERE username = $1", username)
+        .execute(&ctx.secret_store)
+        .await?
+        .rows_affected();
+    if n == 0 {
+        return Err(not_found(format!("user {}", username)));
+    }
+    Ok(())
+}
+
+#[command(rename = "list", display(display_serializable))]
+pub async fn list_users(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<String>, Error> {
+    Ok(
+        sqlx::query!("SELECT username FROM registry_users ORDER BY username")
+            .fetch_all(&ctx.secret_store)
+            .await?
+            .into_iter()
+            .map(|r| r.username)
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn version(s: &str, indexed: bool, arches: Option<&[&str]>) -> (Version, RegistryVersion) {
+        (
+            s.parse().unwrap(),
+            RegistryVersion {
+                published_at: Utc::now(),
+                indexed,
+                s9pks: vec![RegistryS9pk {
+                    arches: arches.map(|a| a.iter().map(|s| s.to_string()).collect()),
+                    file: "x.s9pk".to_owned(),
+                    size: 0,
+                    hash: None,
+                }],
+            },
+        )
+    }
+
+    #[test]
+    fn selects_indexed_versions_for_arch() {
+        let id: PackageId = "hello-world".parse().unwrap();
+        let mut registry = RegistryIndex::default();
+        registry.packages.insert(
+            id.clone(),
+            [
+                version("0.1.0", true, None),
+                version("0.2.0", true, Some(&["aarch64"])),
+                version("0.3.0", false, None),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let any = VersionRange::Any;
+        let pick = |priority, arch| {
+            registry
+                .select(&id, &any, priority, arch)
+                .map(|(v, _)| v.to_string())
+        };
+        assert_eq!(pick(MinMax::Max, Some("x86_64")).as_deref(), Some("0.1.0"));
+        assert_eq!(pick(MinMax::Max, Some("aarch64")).as_deref(), Some("0.2.0"));
+        assert_eq!(pick(MinMax::Min, Some("aarch64")).as_deref(), Some("0.1.0"));
+        assert_eq!(
+            registry
+                .select(&id, &"=0.3.0".parse().unwrap(), MinMax::Max, None)
+                .map(|(v, _)| v.to_string()),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(parse_range("bytes=0-0", 10), Some((0, 0)));
+        assert_eq!(parse_range("bytes=4-", 10), Some((4, 9)));
+        assert_eq!(parse_range("bytes=4-100", 10), Some((4, 9)));
+        assert_eq!(parse_range("bytes=8-2", 10), None);
+        assert_eq!(parse_range("bytes=0-0", 0), None);
+    }
+
+    #[test]
+    fn registry_paths() {
+        assert!(is_registry_path("/package/v0/index"));
+        assert!(is_registry_path("//package/v0/hello.s9pk"));
+        assert!(is_registry_path("/admin/v0/upload"));
+        assert!(!is_registry_path("/marketplace"));
+    }
+}
@@ -0,0 +1,318 @@
+use clap::ArgMatches;
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tracing::instrument;
+
+use crate::context::RpcContext;
+use crate::prelude::*;
+use crate::procedure::docker::{DockerProcedure, ResourceLimits};
+use crate::procedure::PackageProcedure;
+use crate::s9pk::manifest::{Manifest, PackageId};
+use crate::util::docker::CONTAINER_TOOL;
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::util::{display_none, Invoke};
+
+#[command(subcommands(get, set))]
+pub fn resources() -> Result<(), Error> {
+    Ok(())
+}
+
+/// The limits declared by the manifest for the main container
+fn manifest_limits(manifest: &Manifest) -> ResourceLimits {
+    if let Some(containers) = &manifest.containers {
+        containers.main.resources.clone()
+    } else if let PackageProcedure::Docker(main) = &manifest.main {
+        main.resources.clone()
+    } else {
+        ResourceLimits::default()
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ResourceUsage {
+    pub cpu_percent: f64,
+    pub memory_bytes: u64,
+    pub pids: u64,
+    pub block_read_bytes: u64,
+    pub block_write_bytes: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ResourceInfo {
+    /// Declared by the manifest
+    pub defaults: ResourceLimits,
+    /// Set by the user
+    pub overrides: ResourceLimits,
+    /// What the service runs with
+    pub limits: ResourceLimits,
+    /// `None` if the service is not running
+    pub usage: Option<ResourceUsage>,
+}
+
+/// Parses sizes as printed by `stats`, e.g. `1.5GiB`, `12.3MB` or `0B`
+fn parse_size(s: &str) -> Result<u64, Error> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: f64 = num.parse().with_kind(ErrorKind::ParseNumber)?;
+    let multiplier = match unit.trim() {
+        "" | "B" => 1_f64,
+        "kB" | "KB" => 1e3,
+        "MB" => 1e6,
+        "GB" => 1e9,
+        "TB" => 1e12,
+        "KiB" => 1024_f64,
+        "MiB" => 1024_f64.powi(2),
+        "GiB" => 1024_f64.powi(3),
+        "TiB" => 1024_f64.powi(4),
+        unit => {
+            return Err(Error::new(
+                eyre!("unknown unit: {}", unit),
+                ErrorKind::ParseNumber,
+            ))
+        }
+    };
+    Ok((num * multiplier) as u64)
+}
+
+/// Parses a line of `stats --format '{{.CPUPerc}}\t{{.MemUsage}}\t{{.PIDs}}\t{{.BlockIO}}'`
+fn parse_stats(line: &str) -> Result<ResourceUsage, Error> {
+    let mut fields = line.trim().split('\t');
+    let mut next = || {
+        fields
+            .next()
+            .ok_or_else(|| Error::new(eyre!("missing field in stats: {}", line), ErrorKind::Docker))
+    };
+    let cpu_percent = next()?
+        .trim()
+        .trim_end_matches('%')
+        .parse()
+        .with_kind(ErrorKind::ParseNumber)?;
+    let memory = next()?;
+    let pids = next()?.trim();
+    let block_io = next()?;
+    let (memory, _) = memory.split_once('/').unwrap_or((memory, ""));
+    let (read, write) = block_io.split_once('/').unwrap_or((block_io, "0B"));
+    Ok(ResourceUsage {
+        cpu_percent,
+        memory_bytes: parse_size(memory)?,
+        pids: if pids == "--" {
+            0
+        } else {
+            pids.parse().with_kind(ErrorKind::ParseNumber)?
+        },
+        block_read_bytes: parse_size(read)?,
+        block_write_bytes: parse_size(write)?,
+    })
+}
+
+async fn usage(container_name: &str) -> Result<Option<ResourceUsage>, Error> {
+    match Command::new(CONTAINER_TOOL)
+        .arg("stats")
+        .arg("--no-stream")
+        .arg("--format")
+        .arg("{{.CPUPerc}}\t{{.MemUsage}}\t{{.PIDs}}\t{{.BlockIO}}")
+        .arg(container_name)
+        .invoke(ErrorKind::Docker)
+        .await
+    {
+        Err(e)
+            if e.source
+                .to_string()
+                .to_ascii_lowercase()
+                .contains("no such container") =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e),
+        Ok(out) => Ok(Some(parse_stats(std::str::from_utf8(&out)?)?)),
+    }
+}
+
+fn display_resources(info: ResourceInfo, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(info, matches);
+    }
+
+    fn fmt<T: std::fmt::Display>(
+        limit: Option<T>,
+        source: &ResourceLimits,
+        f: fn(&ResourceLimits) -> bool,
+    ) -> String {
+        match limit {
+            Some(limit) if f(source) => format!("{} (override)", limit),
+            Some(limit) => limit.to_string(),
+            None => "-".to_owned(),
+        }
+    }
+    let usage = info.usage.as_ref();
+    let o = &info.overrides;
+    let l = &info.limits;
+    let mut table = Table::new();
+    table.add_row(row![bc => "RESOURCE", "LIMIT", "USAGE"]);
+    table.add_row(row![
+        "cpu-shares",
+        fmt(l.cpu_shares, o, |o| o.cpu_shares.is_some()),
+        "-",
+    ]);
+    table.add_row(row![
+        "cpus",
+        fmt(l.cpus, o, |o| o.cpus.is_some()),
+        usage.map_or("-".to_owned(), |u| format!("{:.2}%", u.cpu_percent)),
+    ]);
+    table.add_row(row![
+        "memory-mb",
+        fmt(l.memory_mb, o, |o| o.memory_mb.is_some()),
+        usage.map_or("-".to_owned(), |u| format!(
+            "{:.1}",
+            u.memory_bytes as f64 / 1024_f64.powi(2)
+        )),
+    ]);
+    table.add_row(row![
+        "memory-swap-mb",
+        fmt(l.memory_swap_mb, o, |o| o.memory_swap_mb.is_some()),
+        "-",
+    ]);
+    table.add_row(row![
+        "pids-limit",
+        fmt(l.pids_limit, o, |o| o.pids_limit.is_some()),
+        usage.map_or("-".to_owned(), |u| u.pids.to_string()),
+    ]);
+    table.add_row(row![
+        "blkio-weight",
+        fmt(l.blkio_weight, o, |o| o.blkio_weight.is_some()),
+        usage.map_or("-".to_owned(), |u| format!(
+            "{} read / {} written",
+            u.block_read_bytes, u.block_write_bytes
+        )),
+    ]);
+    table.print_tty(false).unwrap();
+}
+
+/// Reports the resource limits of a service, and its current usage if it is running
+#[command(display(display_resources))]
+#[instrument(skip_all)]
+pub async fn get(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<ResourceInfo, Error> {
+    let installed = ctx
+        .db
+        .peek()
+        .await
+        .as_package_data()
+        .as_idx(&id)
+        .and_then(|p| p.as_installed())
+        .or_not_found(&id)?
+        .de()?;
+    let defaults = manifest_limits(&installed.manifest);
+    let overrides = installed.resource_limits;
+    Ok(ResourceInfo {
+        limits: defaults.overridden_by(&overrides),
+        defaults,
+        overrides,
+        usage: usage(&DockerProcedure::container_name(&id, None)).await?,
+    })
+}
+
+/// Overrides resource limits of a service. Unspecified limits keep their current override,
+/// unless `--reset` is passed, which clears all overrides first.
+///
+/// Limits are applied to the running container when the container runtime supports it. Limits
+/// that are cleared only go away once the service is restarted.
+#[command(display(display_none), metadata(sync_db = true))]
+#[instrument(skip_all)]
+pub async fn set(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg(long = "cpu-shares")] cpu_shares: Option<u64>,
+    #[arg(long = "cpus")] cpus: Option<f64>,
+    #[arg(long = "memory-mb")] memory_mb: Option<u64>,
+    #[arg(long = "memory-swap-mb")] memory_swap_mb: Option<u64>,
+    #[arg(long = "pids-limit")] pids_limit: Option<u64>,
+    #[arg(long = "blkio-weight")] blkio_weight: Option<u16>,
+    #[arg(long = "reset")] reset: bool,
+) -> Result<(), Error> {
+    let changes = ResourceLimits {
+        cpu_shares,
+        cpus,
+        memory_mb,
+        memory_swap_mb,
+        pids_limit,
+        blkio_weight,
+    };
+    let limits = ctx
+        .db
+        .mutate(|db| {
+            let installed = db
+                .as_package_data_mut()
+                .as_idx_mut(&id)
+                .and_then(|p| p.as_installed_mut())
+                .or_not_found(&id)?;
+            let defaults = manifest_limits(&installed.as_manifest().de()?);
+            let overrides = if reset {
+                changes.clone()
+            } else {
+                installed.as_resource_limits().de()?.overridden_by(&changes)
+            };
+            let limits = defaults.overridden_by(&overrides);
+            limits.validate().with_kind(ErrorKind::InvalidRequest)?;
+            installed.as_resource_limits_mut().ser(&overrides)?;
+            Ok(limits)
+        })
+        .await?;
+
+    let args = limits.docker_args();
+    if args.is_empty() {
+        return Ok(());
+    }
+    let container_name = DockerProcedure::container_name(&id, None);
+    if let Err(e) = Command::new(CONTAINER_TOOL)
+        .arg("update")
+        .args(args)
+        .arg(&container_name)
+        .invoke(ErrorKind::Docker)
+        .await
+    {
+        if !e
+            .source
+            .to_string()
+            .to_ascii_lowercase()
+            .contains("no such container")
+        {
+            tracing::warn!(
+                "Could not update limits of {}, they will apply on restart: {}",
+                container_name,
+                e
+            );
+            tracing::debug!("{:?}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_stats() {
+        let usage = parse_stats("12.50%\t1.5GiB / 3.8GiB\t42\t10.2MB / 3kB\n").unwrap();
+        assert_eq!(usage.cpu_percent, 12.5);
+        assert_eq!(usage.memory_bytes, 3 * 1024 * 1024 * 1024 / 2);
+        assert_eq!(usage.pids, 42);
+        assert_eq!(usage.block_read_bytes, 10_200_000);
+        assert_eq!(usage.block_write_bytes, 3_000);
+        assert!(parse_size("12XB").is_err());
+    }
+}
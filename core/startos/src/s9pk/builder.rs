@@ -1,9 +1,17 @@
-use sha2::{Digest, Sha512};
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom};
+use std::collections::BTreeMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use async_compression::tokio::write::ZstdEncoder;
+use color_eyre::eyre::eyre;
+use sha2::{Digest, Sha256, Sha512};
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt, SeekFrom,
+};
 use tracing::instrument;
 use typed_builder::TypedBuilder;
 
-use super::header::{FileSection, Header};
+use super::header::{Compression, FileSection, Header, STANDARD_SECTIONS, VERSION, VERSION_1};
 use super::manifest::Manifest;
 use super::SIG_CONTEXT;
 use crate::util::io::to_cbor_async_writer;
@@ -29,6 +37,15 @@ pub struct S9pkPacker<
     docker_images: RDockerImages,
     assets: RAssets,
     scripts: Option<RScripts>,
+    /// Additional named sections (e.g. `sbom`). Requires format v2.
+    #[builder(default)]
+    sections: BTreeMap<String, Box<dyn AsyncRead + Unpin + Send + 'a>>,
+    #[builder(default = VERSION)]
+    version: u8,
+    /// Applied to the license, instructions, assets, scripts and additional sections. The icon is
+    /// already compressed, and docker images must stay seekable, so those are always stored as is.
+    #[builder(default = Compression::Zstd)]
+    compression: Compression,
 }
 impl<
         'a,
@@ -44,96 +61,104 @@ impl<
     /// BLOCKING
     #[instrument(skip_all)]
     pub async fn pack(mut self, key: &ed25519_dalek::SigningKey) -> Result<(), Error> {
+        if self.version == VERSION_1 && !self.sections.is_empty() {
+            return Err(Error::new(
+                eyre!("s9pk v1 does not support additional sections"),
+                crate::ErrorKind::Pack,
+            ));
+        }
+        if let Some(label) = self
+            .sections
+            .keys()
+            .find(|label| STANDARD_SECTIONS.contains(&label.as_str()) || label.len() > 255)
+        {
+            return Err(Error::new(
+                eyre!("Invalid section label: {}", label),
+                crate::ErrorKind::Pack,
+            ));
+        }
+        let compression = if self.version == VERSION_1 {
+            Compression::None
+        } else {
+            self.compression
+        };
+
         let header_pos = self.writer.stream_position().await?;
         if header_pos != 0 {
             tracing::warn!("Appending to non-empty file.");
         }
-        let mut header = Header::placeholder();
+        let mut header = Header::placeholder(self.version);
+        // the placeholder must list the same sections as the final header so it has the same size
+        if self.scripts.is_some() {
+            header.table_of_contents.scripts = Some(FileSection::default());
+        }
+        for label in self.sections.keys() {
+            header
+                .table_of_contents
+                .extra
+                .insert(label.clone(), FileSection::default());
+        }
         header.serialize(&mut self.writer).await.with_ctx(|_| {
             (
                 crate::ErrorKind::Serialization,
                 "Writing Placeholder Header",
             )
         })?;
-        let mut position = self.writer.stream_position().await?;
 
         let mut writer = HashWriter::new(Sha512::new(), &mut self.writer);
+        let toc = &mut header.table_of_contents;
         // manifest
-        to_cbor_async_writer(&mut writer, self.manifest).await?;
-        let new_pos = writer.inner_mut().stream_position().await?;
-        header.table_of_contents.manifest = FileSection {
-            position,
-            length: new_pos - position,
-        };
-        position = new_pos;
+        let mut manifest = Vec::new();
+        to_cbor_async_writer(&mut manifest, self.manifest).await?;
+        toc.manifest = write_section(&mut writer, manifest.as_slice(), Compression::None)
+            .await
+            .with_ctx(|_| (crate::ErrorKind::Serialization, "Writing Manifest"))?;
         // license
-        tokio::io::copy(&mut self.license, &mut writer)
+        toc.license = write_section(&mut writer, &mut self.license, compression)
             .await
             .with_ctx(|_| (crate::ErrorKind::Filesystem, "Copying License"))?;
-        let new_pos = writer.inner_mut().stream_position().await?;
-        header.table_of_contents.license = FileSection {
-            position,
-            length: new_pos - position,
-        };
-        position = new_pos;
         // instructions
-        tokio::io::copy(&mut self.instructions, &mut writer)
+        toc.instructions = write_section(&mut writer, &mut self.instructions, compression)
             .await
             .with_ctx(|_| (crate::ErrorKind::Filesystem, "Copying Instructions"))?;
-        let new_pos = writer.inner_mut().stream_position().await?;
-        header.table_of_contents.instructions = FileSection {
-            position,
-            length: new_pos - position,
-        };
-        position = new_pos;
         // icon
-        tokio::io::copy(&mut self.icon, &mut writer)
+        toc.icon = write_section(&mut writer, &mut self.icon, Compression::None)
             .await
             .with_ctx(|_| (crate::ErrorKind::Filesystem, "Copying Icon"))?;
-        let new_pos = writer.inner_mut().stream_position().await?;
-        header.table_of_contents.icon = FileSection {
-            position,
-            length: new_pos - position,
-        };
-        position = new_pos;
         // docker_images
-        tokio::io::copy(&mut self.docker_images, &mut writer)
+        toc.docker_images = write_section(&mut writer, &mut self.docker_images, Compression::None)
             .await
             .with_ctx(|_| (crate::ErrorKind::Filesystem, "Copying Docker Images"))?;
-        let new_pos = writer.inner_mut().stream_position().await?;
-        header.table_of_contents.docker_images = FileSection {
-            position,
-            length: new_pos - position,
-        };
-        position = new_pos;
         // assets
-        tokio::io::copy(&mut self.assets, &mut writer)
+        toc.assets = write_section(&mut writer, &mut self.assets, compression)
             .await
             .with_ctx(|_| (crate::ErrorKind::Filesystem, "Copying Assets"))?;
-        let new_pos = writer.inner_mut().stream_position().await?;
-        header.table_of_contents.assets = FileSection {
-            position,
-            length: new_pos - position,
-        };
-        position = new_pos;
         // scripts
         if let Some(mut scripts) = self.scripts {
-            tokio::io::copy(&mut scripts, &mut writer)
+            toc.scripts = Some(
+                write_section(&mut writer, &mut scripts, compression)
+                    .await
+                    .with_ctx(|_| (crate::ErrorKind::Filesystem, "Copying Scripts"))?,
+            );
+        }
+        // additional sections
+        for (label, mut section) in self.sections {
+            let written = write_section(&mut writer, &mut section, compression)
                 .await
-                .with_ctx(|_| (crate::ErrorKind::Filesystem, "Copying Scripts"))?;
-            let new_pos = writer.inner_mut().stream_position().await?;
-            header.table_of_contents.scripts = Some(FileSection {
-                position,
-                length: new_pos - position,
-            });
-            position = new_pos;
+                .with_ctx(|_| (crate::ErrorKind::Filesystem, format!("Copying {}", label)))?;
+            toc.extra.insert(label, written);
         }
+        let position = writer.inner_mut().stream_position().await?;
 
         // header
         let (hash, _) = writer.finish();
-        self.writer.seek(SeekFrom::Start(header_pos)).await?;
         header.pubkey = key.into();
-        header.signature = key.sign_prehashed(hash, Some(SIG_CONTEXT))?;
+        header.signature = if self.version == VERSION_1 {
+            key.sign_prehashed(hash, Some(SIG_CONTEXT))?
+        } else {
+            key.sign_prehashed(header.toc_digest().await?, Some(SIG_CONTEXT))?
+        };
+        self.writer.seek(SeekFrom::Start(header_pos)).await?;
         header
             .serialize(&mut self.writer)
             .await
@@ -143,3 +168,197 @@ impl<
         Ok(())
     }
 }
+
+/// Copies `src` to the current position of `writer`, returning where it landed
+async fn write_section<W: AsyncWrite + AsyncSeek + Unpin, R: AsyncRead + Unpin>(
+    writer: &mut HashWriter<Sha512, W>,
+    mut src: R,
+    compression: Compression,
+) -> std::io::Result<FileSection> {
+    let position = writer.inner_mut().stream_position().await?;
+    let mut hasher = HashWriter::new(Sha256::new(), &mut *writer);
+    let size = match compression {
+        Compression::None => tokio::io::copy(&mut src, &mut hasher).await?,
+        Compression::Zstd => {
+            let mut encoder = ZstdEncoder::new(&mut hasher);
+            let size = tokio::io::copy(&mut src, &mut encoder).await?;
+            // finishes the zstd frame
+            encoder.shutdown().await?;
+            size
+        }
+    };
+    let (hash, _) = hasher.finish();
+    let length = writer.inner_mut().stream_position().await? - position;
+    Ok(FileSection {
+        position,
+        length,
+        compression,
+        size,
+        hash: Some(hash.finalize().into()),
+    })
+}
+
+/// Timestamp given to every entry of the tarballs built while packing
+pub const REPRODUCIBLE_MTIME: u64 = 0;
+
+/// Whether a symlink at `depth` directories below the root of the tarball points outside of it
+fn link_escapes(depth: usize, target: &Path) -> bool {
+    use std::path::Component;
+
+    let mut depth = depth as isize;
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => (),
+            Component::ParentDir => depth -= 1,
+            Component::RootDir | Component::Prefix(_) => return true,
+        }
+        if depth < 0 {
+            return true;
+        }
+    }
+    false
+}
+
+/// Like `append_dir_all`, but walks directories in sorted order and normalizes the timestamps,
+/// owners and modes of the entries, so the same tree always produces the same tarball.
+///
+/// Symlinks are stored as links, and refused if they point outside of `src`. Anything that is not
+/// a regular file, directory or symlink (fifos, sockets, devices) is refused as well.
+pub async fn append_reproducible<W: AsyncWrite + Unpin + Send + 'static>(
+    builder: &mut tokio_tar::Builder<W>,
+    name: impl AsRef<Path>,
+    src: &Path,
+) -> std::io::Result<()> {
+    use tokio_tar::{EntryType, Header};
+
+    let refuse = |src: &Path, reason: &str| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("cannot pack {}: {}", src.display(), reason),
+        )
+    };
+    let mut queue = vec![(name.as_ref().to_owned(), src.to_owned(), 0)];
+    while let Some((name, src, depth)) = queue.pop() {
+        let meta = tokio::fs::symlink_metadata(&src).await?;
+        let mut header = Header::new_gnu();
+        header.set_mtime(REPRODUCIBLE_MTIME);
+        header.set_uid(0);
+        header.set_gid(0);
+        if meta.is_dir() {
+            header.set_entry_type(EntryType::Directory);
+            header.set_mode(0o755);
+            header.set_size(0);
+            builder
+                .append_data(&mut header, &name, tokio::io::empty())
+                .await?;
+            let mut children = Vec::new();
+            let mut dir = tokio::fs::read_dir(&src).await?;
+            while let Some(child) = dir.next_entry().await? {
+                children.push(child.file_name());
+            }
+            children.sort();
+            // the queue is a stack, so push in reverse to pop in order
+            for child in children.into_iter().rev() {
+                queue.push((name.join(&child), src.join(&child), depth + 1));
+            }
+        } else if meta.is_symlink() {
+            let target = tokio::fs::read_link(&src).await?;
+            // the link itself sits in the directory one level up
+            if link_escapes(depth.saturating_sub(1), &target) {
+                return Err(refuse(
+                    &src,
+                    &format!("symlink to {} leaves the directory", target.display()),
+                ));
+            }
+            header.set_entry_type(EntryType::Symlink);
+            header.set_mode(0o777);
+            header.set_size(0);
+            header.set_link_name(target)?;
+            builder
+                .append_data(&mut header, &name, tokio::io::empty())
+                .await?;
+        } else if meta.is_file() {
+            header.set_entry_type(EntryType::Regular);
+            header.set_mode(if meta.permissions().mode() & 0o111 != 0 {
+                0o755
+            } else {
+                0o644
+            });
+            header.set_size(meta.len());
+            builder
+                .append_data(&mut header, &name, tokio::fs::File::open(&src).await?)
+                .await?;
+        } else {
+            return Err(refuse(&src, "not a regular file, directory or symlink"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use nix::sys::time::TimeVal;
+
+    use super::*;
+
+    #[test]
+    fn detects_escaping_links() {
+        assert!(!link_escapes(0, Path::new("a/b")));
+        assert!(!link_escapes(0, Path::new("./a")));
+        assert!(!link_escapes(1, Path::new("../a")));
+        assert!(link_escapes(0, Path::new("../a")));
+        assert!(link_escapes(1, Path::new("../../a")));
+        assert!(link_escapes(0, Path::new("a/../../b")));
+        assert!(link_escapes(3, Path::new("/etc/passwd")));
+    }
+
+    async fn pack_dir(src: &Path) -> std::io::Result<Vec<u8>> {
+        let mut builder = tokio_tar::Builder::new(Vec::new());
+        append_reproducible(&mut builder, "assets", src).await?;
+        builder.into_inner().await
+    }
+
+    fn touch_all(path: &Path, secs: i64) {
+        let time = TimeVal::new(secs, 0);
+        nix::sys::stat::lutimes(path, &time, &time).unwrap();
+        if path.is_dir() && !path.is_symlink() {
+            for entry in std::fs::read_dir(path).unwrap() {
+                touch_all(&entry.unwrap().path(), secs);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn packs_reproducibly() {
+        let dir = std::env::temp_dir().join(format!("startos-pack-{}", rand::random::<u64>()));
+        tokio::fs::create_dir_all(dir.join("www/js")).await.unwrap();
+        tokio::fs::write(dir.join("www/index.html"), "<html></html>")
+            .await
+            .unwrap();
+        tokio::fs::write(dir.join("www/js/app.js"), "main()")
+            .await
+            .unwrap();
+        tokio::fs::write(dir.join("run.sh"), "#!/bin/sh\n")
+            .await
+            .unwrap();
+        tokio::fs::set_permissions(dir.join("run.sh"), std::fs::Permissions::from_mode(0o700))
+            .await
+            .unwrap();
+        tokio::fs::symlink("../index.html", dir.join("www/js/index.html"))
+            .await
+            .unwrap();
+
+        touch_all(&dir, 1_600_000_000);
+        let first = pack_dir(&dir).await.unwrap();
+        touch_all(&dir, 1_700_000_000);
+        let second = pack_dir(&dir).await.unwrap();
+        assert_eq!(first, second);
+
+        tokio::fs::symlink("../../outside", dir.join("www/escape"))
+            .await
+            .unwrap();
+        assert!(pack_dir(&dir).await.is_err());
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -1,16 +1,20 @@
 use std::borrow::Cow;
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::io::SeekFrom;
 use std::path::Path;
 
+use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
 use color_eyre::eyre::eyre;
 use futures::{FutureExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
-use tokio::io::{AsyncRead, AsyncSeek, AsyncSeekExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, BufReader};
 use tokio_tar::{Archive, Entry};
 
 use crate::util::io::from_cbor_async_reader;
-use crate::{Error, ErrorKind, ARCH};
+use crate::{Error, ErrorKind, ResultExt, ARCH};
+
+/// Package databases larger than this are skipped when building an SBOM
+const PACKAGE_DB_LIMIT: u64 = 64 * 1024 * 1024;
 
 #[derive(Default, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
@@ -93,3 +97,220 @@ impl<R: AsyncRead + Unpin + Send + Sync> AsyncRead for DockerReader<R> {
         }
     }
 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PackageKind {
+    Deb,
+    Apk,
+}
+impl std::fmt::Display for PackageKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PackageKind::Deb => write!(f, "deb"),
+            PackageKind::Apk => write!(f, "apk"),
+        }
+    }
+}
+
+/// A package installed in a docker image, as recorded by its package manager
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SbomPackage {
+    pub name: String,
+    pub version: String,
+    pub kind: PackageKind,
+}
+
+/// The layers of the images in a `docker save` tarball
+#[derive(Debug, Default)]
+pub struct ImageLayers {
+    /// Size of each layer, by its path in the tarball
+    pub layers: BTreeMap<String, u64>,
+    /// Layers of each image, by tag, from bottom to top
+    pub images: BTreeMap<String, Vec<String>>,
+    package_dbs: BTreeMap<String, BTreeMap<PackageKind, Vec<SbomPackage>>>,
+}
+impl ImageLayers {
+    /// Reads a `docker save` tarball. If `read_packages` is set, every layer is also searched for
+    /// dpkg and apk databases.
+    pub async fn scan<R: AsyncRead + Unpin + Send>(
+        rdr: R,
+        read_packages: bool,
+    ) -> Result<Self, Error> {
+        #[derive(Deserialize)]
+        struct ManEntry {
+            #[serde(rename = "RepoTags", default)]
+            tags: Vec<String>,
+            #[serde(rename = "Layers")]
+            layers: Vec<String>,
+        }
+
+        let mut res = ImageLayers::default();
+        let mut archive = Archive::new(rdr);
+        let mut entries = archive.entries()?;
+        while let Some(mut entry) = entries.try_next().await? {
+            let path = entry.path()?.to_string_lossy().into_owned();
+            let size = entry.header().size()?;
+            if path == "manifest.json" {
+                let mut buf = Vec::with_capacity(size as usize);
+                entry.read_to_end(&mut buf).await?;
+                let man_entries = serde_json::from_slice::<Vec<ManEntry>>(&buf)
+                    .with_ctx(|_| (ErrorKind::Deserialization, "manifest.json"))?;
+                for man_entry in man_entries {
+                    for tag in man_entry.tags {
+                        res.images.insert(tag, man_entry.layers.clone());
+                    }
+                }
+            } else if entry.header().entry_type().is_file()
+                && (path.ends_with(".tar") || path.starts_with("blobs/"))
+            {
+                res.layers.insert(path.clone(), size);
+                if read_packages {
+                    let dbs = read_package_dbs(&mut entry).await?;
+                    if !dbs.is_empty() {
+                        res.package_dbs.insert(path, dbs);
+                    }
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    /// Packages installed in the image with `tag`. For each package manager, the database in the
+    /// topmost layer that has one wins.
+    pub fn packages(&self, tag: &str) -> Vec<SbomPackage> {
+        let mut dbs = BTreeMap::new();
+        for layer in self.images.get(tag).into_iter().flatten() {
+            if let Some(layer_dbs) = self.package_dbs.get(layer) {
+                dbs.extend(layer_dbs.iter());
+            }
+        }
+        let mut packages = dbs.into_values().flatten().cloned().collect::<Vec<_>>();
+        packages.sort();
+        packages
+    }
+}
+
+async fn read_package_dbs<R: AsyncRead + Unpin + Send>(
+    mut layer: R,
+) -> Result<BTreeMap<PackageKind, Vec<SbomPackage>>, Error> {
+    let mut head = Vec::with_capacity(512);
+    (&mut layer).take(512).read_to_end(&mut head).await?;
+    let is_gzip = head.starts_with(&[0x1f, 0x8b]);
+    let is_zstd = head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]);
+    let is_tar = head.get(257..262) == Some(&b"ustar"[..]);
+    let rdr = std::io::Cursor::new(head).chain(layer);
+    if is_gzip {
+        read_layer_tar(GzipDecoder::new(BufReader::new(rdr))).await
+    } else if is_zstd {
+        read_layer_tar(ZstdDecoder::new(BufReader::new(rdr))).await
+    } else if is_tar {
+        read_layer_tar(rdr).await
+    } else {
+        // not a layer (e.g. an image config blob)
+        Ok(BTreeMap::new())
+    }
+}
+
+async fn read_layer_tar<R: AsyncRead + Unpin + Send>(
+    rdr: R,
+) -> Result<BTreeMap<PackageKind, Vec<SbomPackage>>, Error> {
+    let mut dbs = BTreeMap::new();
+    let mut archive = Archive::new(rdr);
+    let mut entries = archive.entries()?;
+    while let Some(mut entry) = entries.try_next().await? {
+        let path = entry
+            .path()?
+            .to_string_lossy()
+            .trim_start_matches("./")
+            .to_owned();
+        let kind = match path.as_str() {
+            "var/lib/dpkg/status" => PackageKind::Deb,
+            "lib/apk/db/installed" => PackageKind::Apk,
+            _ => continue,
+        };
+        if entry.header().size()? > PACKAGE_DB_LIMIT {
+            tracing::warn!("Skipping {}: too large", path);
+            continue;
+        }
+        let mut db = String::new();
+        entry.read_to_string(&mut db).await?;
+        dbs.insert(
+            kind,
+            match kind {
+                PackageKind::Deb => parse_dpkg_status(&db),
+                PackageKind::Apk => parse_apk_installed(&db),
+            },
+        );
+    }
+    Ok(dbs)
+}
+
+fn parse_dpkg_status(status: &str) -> Vec<SbomPackage> {
+    status
+        .split("\n\n")
+        .filter_map(|stanza| {
+            let (mut name, mut version, mut installed) = (None, None, false);
+            for line in stanza.lines() {
+                if let Some(a) = line.strip_prefix("Package:") {
+                    name = Some(a.trim());
+                } else if let Some(a) = line.strip_prefix("Version:") {
+                    version = Some(a.trim());
+                } else if let Some(a) = line.strip_prefix("Status:") {
+                    installed = a.split_whitespace().last() == Some("installed");
+                }
+            }
+            Some(SbomPackage {
+                name: name?.to_owned(),
+                version: version?.to_owned(),
+                kind: PackageKind::Deb,
+            })
+            .filter(|_| installed)
+        })
+        .collect()
+}
+
+fn parse_apk_installed(installed: &str) -> Vec<SbomPackage> {
+    installed
+        .split("\n\n")
+        .filter_map(|stanza| {
+            let (mut name, mut version) = (None, None);
+            for line in stanza.lines() {
+                if let Some(a) = line.strip_prefix("P:") {
+                    name = Some(a.trim());
+                } else if let Some(a) = line.strip_prefix("V:") {
+                    version = Some(a.trim());
+                }
+            }
+            Some(SbomPackage {
+                name: name?.to_owned(),
+                version: version?.to_owned(),
+                kind: PackageKind::Apk,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_package_dbs() {
+        let dpkg = "Package: libc6\nStatus: install ok installed\nVersion: 2.36-9\n\nPackage: vim\nStatus: deinstall ok config-files\nVersion: 2:9.0\n";
+        assert_eq!(
+            parse_dpkg_status(dpkg),
+            vec![SbomPackage {
+                name: "libc6".to_owned(),
+                version: "2.36-9".to_owned(),
+                kind: PackageKind::Deb,
+            }]
+        );
+        let apk = "C:Q1abc=\nP:musl\nV:1.2.4-r2\nA:x86_64\n\nP:busybox\nV:1.36.1-r5\n";
+        let packages = parse_apk_installed(apk);
+        assert_eq!(packages.len(), 2);
+        assert_eq!(packages[1].name, "busybox");
+        assert_eq!(packages[1].version, "1.36.1-r5");
+    }
+}
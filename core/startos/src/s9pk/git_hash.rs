@@ -2,7 +2,7 @@ use std::path::Path;
 
 use crate::Error;
 
-#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct GitHash(String);
 
 impl GitHash {
@@ -20,6 +20,10 @@ impl GitHash {
         }
         Ok(GitHash(String::from_utf8(hash.stdout)?))
     }
+    /// Whether the working tree had uncommitted changes
+    pub fn is_modified(&self) -> bool {
+        self.0.trim_end().ends_with("-modified")
+    }
 }
 
 impl AsRef<str> for GitHash {
@@ -2,22 +2,38 @@ use std::collections::BTreeMap;
 
 use color_eyre::eyre::eyre;
 use ed25519_dalek::{Signature, VerifyingKey};
+use sha2::{Digest, Sha512};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 
 use crate::Error;
 
 pub const MAGIC: [u8; 2] = [59, 59];
-pub const VERSION: u8 = 1;
+pub const VERSION_1: u8 = 1;
+pub const VERSION_2: u8 = 2;
+/// The version packed by default. v2 stays opt-in until nodes that can read it are released.
+pub const VERSION: u8 = VERSION_1;
+/// Labels of the sections every s9pk has. Any other label is an additional section.
+pub const STANDARD_SECTIONS: [&str; 7] = [
+    "manifest",
+    "license",
+    "instructions",
+    "icon",
+    "docker_images",
+    "assets",
+    "scripts",
+];
 
 #[derive(Debug)]
 pub struct Header {
+    pub version: u8,
     pub pubkey: VerifyingKey,
     pub signature: Signature,
     pub table_of_contents: TableOfContents,
 }
 impl Header {
-    pub fn placeholder() -> Self {
+    pub fn placeholder(version: u8) -> Self {
         Header {
+            version,
             pubkey: VerifyingKey::default(),
             signature: Signature::from_bytes(&[0; 64]),
             table_of_contents: Default::default(),
@@ -26,10 +42,12 @@ impl Header {
     // MUST BE SAME SIZE REGARDLESS OF DATA
     pub async fn serialize<W: AsyncWriteExt + Unpin>(&self, mut writer: W) -> std::io::Result<()> {
         writer.write_all(&MAGIC).await?;
-        writer.write_all(&[VERSION]).await?;
+        writer.write_all(&[self.version]).await?;
         writer.write_all(self.pubkey.as_bytes()).await?;
         writer.write_all(&self.signature.to_bytes()).await?;
-        self.table_of_contents.serialize(writer).await?;
+        self.table_of_contents
+            .serialize(self.version, writer)
+            .await?;
         Ok(())
     }
     pub async fn deserialize<R: AsyncRead + Unpin>(mut reader: R) -> Result<Self, Error> {
@@ -43,7 +61,7 @@ impl Header {
         }
         let mut version = [0];
         reader.read_exact(&mut version).await?;
-        if version[0] != VERSION {
+        if version[0] != VERSION_1 && version[0] != VERSION_2 {
             return Err(Error::new(
                 eyre!("Unknown Version: {}", version[0]),
                 crate::ErrorKind::ParseS9pk,
@@ -56,14 +74,24 @@ impl Header {
         let mut sig_bytes = [0; 64];
         reader.read_exact(&mut sig_bytes).await?;
         let signature = Signature::from_bytes(&sig_bytes);
-        let table_of_contents = TableOfContents::deserialize(reader).await?;
+        let table_of_contents = TableOfContents::deserialize(version[0], reader).await?;
 
         Ok(Header {
+            version: version[0],
             pubkey,
             signature,
             table_of_contents,
         })
     }
+    /// The digest signed by the developer key in a v2 header. The table of contents carries the
+    /// hash of every section, so signing it covers the whole package.
+    pub async fn toc_digest(&self) -> std::io::Result<Sha512> {
+        let mut toc = Vec::new();
+        self.table_of_contents
+            .serialize(self.version, &mut toc)
+            .await?;
+        Ok(Sha512::new_with_prefix(&toc))
+    }
 }
 
 #[derive(Debug, Default)]
@@ -75,9 +103,43 @@ pub struct TableOfContents {
     pub docker_images: FileSection,
     pub assets: FileSection,
     pub scripts: Option<FileSection>,
+    /// Additional named sections (e.g. `sbom`, `changelog`). v2 only.
+    pub extra: BTreeMap<String, FileSection>,
 }
 impl TableOfContents {
-    pub async fn serialize<W: AsyncWriteExt + Unpin>(&self, mut writer: W) -> std::io::Result<()> {
+    /// Every section by its label, in the order a v2 table lists them
+    pub fn sections(&self) -> BTreeMap<&str, FileSection> {
+        let mut sections: BTreeMap<&str, FileSection> = [
+            ("manifest", self.manifest),
+            ("license", self.license),
+            ("instructions", self.instructions),
+            ("icon", self.icon),
+            ("docker_images", self.docker_images),
+            ("assets", self.assets),
+        ]
+        .into_iter()
+        .collect();
+        if let Some(scripts) = self.scripts {
+            sections.insert("scripts", scripts);
+        }
+        sections.extend(self.extra.iter().map(|(label, s)| (label.as_str(), *s)));
+        sections
+    }
+    pub fn get(&self, label: &str) -> Option<FileSection> {
+        self.sections().get(label).copied()
+    }
+    pub async fn serialize<W: AsyncWriteExt + Unpin>(
+        &self,
+        version: u8,
+        writer: W,
+    ) -> std::io::Result<()> {
+        if version == VERSION_1 {
+            self.serialize_v1(writer).await
+        } else {
+            self.serialize_v2(writer).await
+        }
+    }
+    async fn serialize_v1<W: AsyncWriteExt + Unpin>(&self, mut writer: W) -> std::io::Result<()> {
         let len: u32 = ((1 + "manifest".len() + 16)
             + (1 + "license".len() + 16)
             + (1 + "instructions".len() + 16)
@@ -104,20 +166,51 @@ impl TableOfContents {
             .await?;
         Ok(())
     }
-    pub async fn deserialize<R: AsyncRead + Unpin>(mut reader: R) -> std::io::Result<Self> {
+    async fn serialize_v2<W: AsyncWriteExt + Unpin>(&self, mut writer: W) -> std::io::Result<()> {
+        let sections = self.sections();
+        let len: u32 = sections
+            .keys()
+            .map(|label| FileSection::entry_len_v2(label))
+            .sum::<usize>() as u32;
+        writer.write_all(&u32::to_be_bytes(len)).await?;
+        for (label, section) in sections {
+            section.serialize_entry_v2(label, &mut writer).await?;
+        }
+        Ok(())
+    }
+    pub async fn deserialize<R: AsyncRead + Unpin>(
+        version: u8,
+        mut reader: R,
+    ) -> std::io::Result<Self> {
         let mut toc_len = [0; 4];
         reader.read_exact(&mut toc_len).await?;
         let toc_len = u32::from_be_bytes(toc_len);
         let mut reader = reader.take(toc_len as u64);
         let mut table = BTreeMap::new();
-        while let Some((label, section)) = FileSection::deserialize_entry(&mut reader).await? {
-            table.insert(label, section);
+        if version == VERSION_1 {
+            while let Some((label, section)) = FileSection::deserialize_entry(&mut reader).await? {
+                table.insert(label, section);
+            }
+        } else {
+            let mut prev: Option<Vec<u8>> = None;
+            while let Some((label, section)) =
+                FileSection::deserialize_entry_v2(&mut reader).await?
+            {
+                if prev.as_ref().map_or(false, |prev| prev >= &label) {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "Table of Contents is not sorted by label",
+                    ));
+                }
+                prev = Some(label.clone());
+                table.insert(label, section);
+            }
         }
-        fn from_table(
-            table: &BTreeMap<Vec<u8>, FileSection>,
+        fn take(
+            table: &mut BTreeMap<Vec<u8>, FileSection>,
             label: &str,
         ) -> std::io::Result<FileSection> {
-            table.get(label.as_bytes()).copied().ok_or_else(|| {
+            table.remove(label.as_bytes()).ok_or_else(|| {
                 std::io::Error::new(
                     std::io::ErrorKind::UnexpectedEof,
                     format!("Missing Required Label: {}", label),
@@ -134,21 +227,60 @@ impl TableOfContents {
             }
         }
         Ok(TableOfContents {
-            manifest: from_table(&table, "manifest")?,
-            license: from_table(&table, "license")?,
-            instructions: from_table(&table, "instructions")?,
-            icon: from_table(&table, "icon")?,
-            docker_images: from_table(&table, "docker_images")?,
-            assets: from_table(&table, "assets")?,
-            scripts: table.get("scripts".as_bytes()).cloned(),
+            manifest: take(&mut table, "manifest")?,
+            license: take(&mut table, "license")?,
+            instructions: take(&mut table, "instructions")?,
+            icon: take(&mut table, "icon")?,
+            docker_images: take(&mut table, "docker_images")?,
+            assets: take(&mut table, "assets")?,
+            scripts: table.remove("scripts".as_bytes()),
+            extra: table
+                .into_iter()
+                .map(|(label, section)| {
+                    String::from_utf8(label)
+                        .map(|label| (label, section))
+                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+                })
+                .collect::<Result<_, _>>()?,
         })
     }
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+impl Compression {
+    fn as_byte(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+        }
+    }
+    fn from_byte(byte: u8) -> std::io::Result<Self> {
+        match byte {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Zstd),
+            a => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Unknown Compression: {}", a),
+            )),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Default)]
 pub struct FileSection {
     pub position: u64,
+    /// Length of the section as stored in the file
     pub length: u64,
+    pub compression: Compression,
+    /// Length of the section once decompressed
+    pub size: u64,
+    /// SHA-256 of the section as stored in the file. v2 only.
+    pub hash: Option<[u8; 32]>,
 }
 impl FileSection {
     pub async fn serialize_entry<W: AsyncWriteExt + Unpin>(
@@ -181,7 +313,118 @@ impl FileSection {
             FileSection {
                 position: u64::from_be_bytes(pos),
                 length: u64::from_be_bytes(len),
+                compression: Compression::None,
+                size: u64::from_be_bytes(len),
+                hash: None,
+            },
+        )))
+    }
+    fn entry_len_v2(label: &str) -> usize {
+        1 + label.len() + 1 + 8 + 8 + 8 + 32
+    }
+    pub async fn serialize_entry_v2<W: AsyncWriteExt + Unpin>(
+        self,
+        label: &str,
+        mut writer: W,
+    ) -> std::io::Result<()> {
+        writer.write_all(&[label.len() as u8]).await?;
+        writer.write_all(label.as_bytes()).await?;
+        writer.write_all(&[self.compression.as_byte()]).await?;
+        writer.write_all(&u64::to_be_bytes(self.position)).await?;
+        writer.write_all(&u64::to_be_bytes(self.length)).await?;
+        writer.write_all(&u64::to_be_bytes(self.size)).await?;
+        writer.write_all(&self.hash.unwrap_or_default()).await?;
+        Ok(())
+    }
+    pub async fn deserialize_entry_v2<R: AsyncRead + Unpin>(
+        mut reader: R,
+    ) -> std::io::Result<Option<(Vec<u8>, Self)>> {
+        let mut label_len = [0];
+        let read = reader.read(&mut label_len).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        let mut label = vec![0; label_len[0] as usize];
+        reader.read_exact(&mut label).await?;
+        let mut compression = [0];
+        reader.read_exact(&mut compression).await?;
+        let mut pos = [0; 8];
+        reader.read_exact(&mut pos).await?;
+        let mut len = [0; 8];
+        reader.read_exact(&mut len).await?;
+        let mut size = [0; 8];
+        reader.read_exact(&mut size).await?;
+        let mut hash = [0; 32];
+        reader.read_exact(&mut hash).await?;
+        Ok(Some((
+            label,
+            FileSection {
+                position: u64::from_be_bytes(pos),
+                length: u64::from_be_bytes(len),
+                compression: Compression::from_byte(compression[0])?,
+                size: u64::from_be_bytes(size),
+                hash: Some(hash),
             },
         )))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn roundtrips_v2_table() {
+        let mut header = Header::placeholder(VERSION_2);
+        header.table_of_contents.assets = FileSection {
+            position: 512,
+            length: 100,
+            compression: Compression::Zstd,
+            size: 400,
+            hash: Some([7; 32]),
+        };
+        header
+            .table_of_contents
+            .extra
+            .insert("sbom".to_owned(), FileSection::default());
+        let mut buf = Vec::new();
+        header.serialize(&mut buf).await.unwrap();
+
+        let mut placeholder = Header::placeholder(VERSION_2);
+        placeholder
+            .table_of_contents
+            .extra
+            .insert("sbom".to_owned(), FileSection::default());
+        let mut placeholder_buf = Vec::new();
+        placeholder.serialize(&mut placeholder_buf).await.unwrap();
+        assert_eq!(buf.len(), placeholder_buf.len());
+
+        let read = Header::deserialize(buf.as_slice()).await.unwrap();
+        assert_eq!(read.version, VERSION_2);
+        let assets = read.table_of_contents.assets;
+        assert_eq!(assets.compression, Compression::Zstd);
+        assert_eq!(
+            (assets.position, assets.length, assets.size),
+            (512, 100, 400)
+        );
+        assert_eq!(assets.hash, Some([7; 32]));
+        assert!(read.table_of_contents.scripts.is_none());
+        assert!(read.table_of_contents.extra.contains_key("sbom"));
+    }
+
+    #[tokio::test]
+    async fn reads_v1_table() {
+        let mut header = Header::placeholder(VERSION_1);
+        header.table_of_contents.license = FileSection {
+            position: 300,
+            length: 20,
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        header.serialize(&mut buf).await.unwrap();
+        let read = Header::deserialize(buf.as_slice()).await.unwrap();
+        assert_eq!(read.version, VERSION_1);
+        assert_eq!(read.table_of_contents.license.size, 20);
+        assert!(read.table_of_contents.license.hash.is_none());
+    }
+}
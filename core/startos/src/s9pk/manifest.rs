@@ -2,6 +2,7 @@ use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 
 use color_eyre::eyre::eyre;
+use models::ContainerId;
 pub use models::PackageId;
 use serde::{Deserialize, Serialize};
 use url::Url;
@@ -11,10 +12,12 @@ use crate::action::Actions;
 use crate::backup::BackupActions;
 use crate::config::action::ConfigActions;
 use crate::dependencies::Dependencies;
+use crate::devices::DeviceRequests;
 use crate::migration::Migrations;
 use crate::net::interface::Interfaces;
 use crate::prelude::*;
 use crate::procedure::docker::DockerContainers;
+use crate::procedure::sidecar::Sidecar;
 use crate::procedure::PackageProcedure;
 use crate::status::health_check::HealthChecks;
 use crate::util::serde::Regex;
@@ -70,6 +73,8 @@ pub struct Manifest {
     #[serde(default)]
     pub dependencies: Dependencies,
     pub containers: Option<DockerContainers>,
+    #[serde(default)]
+    pub devices: DeviceRequests,
 
     #[serde(default)]
     pub replaces: Vec<String>,
@@ -100,6 +105,11 @@ impl Manifest {
             .chain(actions)
     }
 
+    /// The auxiliary containers run alongside `containers.main`
+    pub fn sidecars(&self) -> impl Iterator<Item = (&ContainerId, &Sidecar)> {
+        self.containers.iter().flat_map(|c| c.aux.0.iter())
+    }
+
     pub fn with_git_hash(mut self, git_hash: GitHash) -> Self {
         self.git_hash = Some(git_hash);
         self
@@ -1,6 +1,7 @@
 use std::ffi::OsStr;
 use std::path::PathBuf;
 
+use clap::ArgMatches;
 use color_eyre::eyre::eyre;
 use futures::TryStreamExt;
 use imbl::OrdMap;
@@ -10,9 +11,10 @@ use tokio::io::AsyncRead;
 use tracing::instrument;
 
 use crate::context::SdkContext;
-use crate::s9pk::builder::S9pkPacker;
+use crate::s9pk::builder::{append_reproducible, S9pkPacker, REPRODUCIBLE_MTIME};
 use crate::s9pk::docker::DockerMultiArch;
 use crate::s9pk::git_hash::GitHash;
+use crate::s9pk::header::{Compression, STANDARD_SECTIONS, VERSION, VERSION_1, VERSION_2};
 use crate::s9pk::manifest::Manifest;
 use crate::s9pk::reader::S9pkReader;
 use crate::util::display_none;
@@ -32,14 +34,44 @@ pub const SIG_CONTEXT: &[u8] = b"s9pk";
 
 #[command(cli_only, display(display_none))]
 #[instrument(skip_all)]
-pub async fn pack(#[context] ctx: SdkContext, #[arg] path: Option<PathBuf>) -> Result<(), Error> {
-    use tokio::fs::File;
-
+pub async fn pack(
+    #[context] ctx: SdkContext,
+    #[arg] path: Option<PathBuf>,
+    #[arg(long = "format", parse(parse_format))] format: Option<u8>,
+) -> Result<(), Error> {
     let path = if let Some(path) = path {
         path
     } else {
         std::env::current_dir()?
     };
+    pack_dir(path, &ctx.developer_key()?, None, format.unwrap_or(VERSION)).await?;
+
+    Ok(())
+}
+
+fn parse_format(arg: &str, _: &ArgMatches) -> Result<u8, Error> {
+    match arg {
+        "v1" | "1" => Ok(VERSION_1),
+        "v2" | "2" => Ok(VERSION_2),
+        _ => Err(Error::new(
+            eyre!("Unknown s9pk format: {}. Expected v1 or v2.", arg),
+            crate::ErrorKind::Pack,
+        )),
+    }
+}
+
+/// Packs the package source at `path`, to `{id}.s9pk` in `path` unless `outfile_path` is given.
+/// Packing the same tree twice produces the same sections: tar entries are sorted and their
+/// timestamps, owners and modes are normalized.
+#[instrument(skip_all)]
+pub async fn pack_dir(
+    path: PathBuf,
+    key: &ed25519_dalek::SigningKey,
+    outfile_path: Option<PathBuf>,
+    version: u8,
+) -> Result<PathBuf, Error> {
+    use tokio::fs::File;
+
     let manifest_value: Value = if path.join("manifest.toml").exists() {
         IoFormat::Toml
             .from_async_reader(File::open(path.join("manifest.toml")).await?)
@@ -68,9 +100,10 @@ pub async fn pack(#[context] ctx: SdkContext, #[arg] path: Option<PathBuf>) -> R
         tracing::warn!("Unrecognized Manifest Key: {}", k);
     }
 
-    let outfile_path = path.join(format!("{}.s9pk", manifest.id));
-    let mut outfile = File::create(outfile_path).await?;
+    let outfile_path = outfile_path.unwrap_or_else(|| path.join(format!("{}.s9pk", manifest.id)));
+    let mut outfile = File::create(&outfile_path).await?;
     S9pkPacker::builder()
+        .version(version)
         .manifest(&manifest)
         .writer(&mut outfile)
         .license(
@@ -106,7 +139,8 @@ pub async fn pack(#[context] ctx: SdkContext, #[arg] path: Option<PathBuf>) -> R
         .docker_images({
             let docker_images_path = path.join(manifest.assets.docker_images_path());
             let res: Box<dyn AsyncRead + Unpin + Send + Sync> = if tokio::fs::metadata(&docker_images_path).await?.is_dir() {
-                let tars: Vec<_> = tokio_stream::wrappers::ReadDirStream::new(tokio::fs::read_dir(&docker_images_path).await?).try_collect().await?;
+                let mut tars: Vec<_> = tokio_stream::wrappers::ReadDirStream::new(tokio::fs::read_dir(&docker_images_path).await?).try_collect().await?;
+                tars.sort_by_key(|tar| tar.file_name());
                 let mut arch_info = DockerMultiArch::default();
                 for tar in &tars {
                     if tar.path().extension() == Some(OsStr::new("tar")) {
@@ -124,16 +158,13 @@ pub async fn pack(#[context] ctx: SdkContext, #[arg] path: Option<PathBuf>) -> R
                     let mut multiarch_header = tokio_tar::Header::new_gnu();
                     multiarch_header.set_path("multiarch.cbor")?;
                     multiarch_header.set_size(arch_info_cbor.len() as u64);
+                    multiarch_header.set_mtime(REPRODUCIBLE_MTIME);
                     multiarch_header.set_cksum();
                     docker_images.append(&multiarch_header, std::io::Cursor::new(arch_info_cbor)).await?;
                     for tar in tars
                     {
-                        docker_images
-                            .append_path_with_name(
-                                tar.path(),
-                                tar.file_name(),
-                            )
-                            .await?;
+                        append_reproducible(&mut docker_images, tar.file_name(), &tar.path())
+                        .await?;
                     }
                     Ok::<_, std::io::Error>(())
                 }, 1024 * 1024))
@@ -161,12 +192,12 @@ pub async fn pack(#[context] ctx: SdkContext, #[arg] path: Option<PathBuf>) -> R
                 let mut assets = tokio_tar::Builder::new(w);
                 for asset_volume in asset_volumes
                 {
-                    assets
-                        .append_dir_all(
-                            &asset_volume,
-                            path.join(&assets_path).join(&asset_volume),
-                        )
-                        .await?;
+                    append_reproducible(
+                        &mut assets,
+                        &asset_volume,
+                        &path.join(&assets_path).join(&asset_volume),
+                    )
+                    .await?;
                 }
                 Ok::<_, std::io::Error>(())
             }, 1024 * 1024)
@@ -188,17 +219,181 @@ pub async fn pack(#[context] ctx: SdkContext, #[arg] path: Option<PathBuf>) -> R
             }
         })
         .build()
-        .pack(&ctx.developer_key()?)
+        .pack(key)
         .await?;
     outfile.sync_all().await?;
 
-    Ok(())
+    Ok(outfile_path)
 }
 
 #[command(rename = "s9pk", cli_only, display(display_none))]
-pub async fn verify(#[arg] path: PathBuf) -> Result<(), Error> {
+pub async fn verify(
+    #[arg] path: PathBuf,
+    #[arg(long = "reproduce")] reproduce: Option<PathBuf>,
+) -> Result<(), Error> {
     let mut s9pk = S9pkReader::open(path, true).await?;
     s9pk.validate().await?;
+    if let Some(src) = reproduce {
+        reproduce_s9pk(&mut s9pk, src).await?;
+    }
+
+    Ok(())
+}
+
+/// Packs the source at `src` and checks that every section matches `s9pk`. The source must already
+/// be built (i.e. its docker image tarballs exist), exactly as it was when `s9pk` was packed.
+#[instrument(skip_all)]
+async fn reproduce_s9pk(s9pk: &mut S9pkReader, src: PathBuf) -> Result<(), Error> {
+    let manifest = s9pk.manifest().await?;
+    let git_hash = GitHash::from_path(&src).await?;
+    match &manifest.git_hash {
+        Some(published) if published != &git_hash => {
+            return Err(Error::new(
+                eyre!(
+                    "{} was packed from {}, but the source is at {}",
+                    manifest.id,
+                    published.as_ref().trim(),
+                    git_hash.as_ref().trim()
+                ),
+                crate::ErrorKind::ValidateS9pk,
+            ));
+        }
+        Some(_) => (),
+        None => tracing::warn!(
+            "{} does not record the commit it was packed from",
+            manifest.id
+        ),
+    }
+    if git_hash.is_modified() {
+        tracing::warn!("{} has uncommitted changes", src.display());
+    }
+
+    // the signature is not compared, so any key will do
+    let key = ed25519_dalek::SigningKey::from_bytes(&rand::random());
+    let rebuilt_path = std::env::temp_dir().join(format!(
+        "{}-reproduce-{}.s9pk",
+        manifest.id,
+        rand::random::<u64>()
+    ));
+    let res: Result<(), Error> = async {
+        pack_dir(src, &key, Some(rebuilt_path.clone()), s9pk.version()).await?;
+        let mut rebuilt = S9pkReader::open(&rebuilt_path, false).await?;
+        let mut labels = s9pk.section_labels();
+        labels.extend(rebuilt.section_labels());
+        labels.sort();
+        labels.dedup();
+        let mut mismatched = Vec::new();
+        for label in labels {
+            let published = s9pk.content_hash(&label).await.ok();
+            let reproduced = rebuilt.content_hash(&label).await.ok();
+            if published.is_none() || published != reproduced {
+                mismatched.push(label);
+            }
+        }
+        if !mismatched.is_empty() {
+            return Err(Error::new(
+                eyre!(
+                    "{} does not match its source. Differing sections: {}",
+                    manifest.id,
+                    mismatched.join(", ")
+                ),
+                crate::ErrorKind::ValidateS9pk,
+            ));
+        }
+        Ok(())
+    }
+    .await;
+    if let Err(e) = tokio::fs::remove_file(&rebuilt_path).await {
+        tracing::warn!("Failed to remove {}: {}", rebuilt_path.display(), e);
+    }
+    res
+}
+
+#[command(subcommands(convert))]
+pub fn s9pk() -> Result<(), Error> {
+    Ok(())
+}
+
+/// Rewrites an s9pk in another format version, re-signed with the developer key
+#[command(cli_only, display(display_none))]
+#[instrument(skip_all)]
+pub async fn convert(
+    #[context] ctx: SdkContext,
+    #[arg] path: PathBuf,
+    #[arg(short = 'o', long = "output")] output: Option<PathBuf>,
+    #[arg(long = "to", parse(parse_format))] to: Option<u8>,
+    #[arg(rename = "no-compress", long = "no-compress")] no_compress: bool,
+) -> Result<(), Error> {
+    use tokio::fs::File;
+
+    let to = to.unwrap_or(VERSION);
+    let key = ctx.developer_key()?;
+    let mut src = S9pkReader::open(&path, true).await?;
+    if src.developer_key() != &key.verifying_key() {
+        tracing::warn!(
+            "{} is signed by a different developer key. The converted package will be signed by yours.",
+            path.display()
+        );
+    }
+    let manifest = src.manifest().await?;
+    let extra = src
+        .section_labels()
+        .into_iter()
+        .filter(|label| !STANDARD_SECTIONS.contains(&label.as_str()))
+        .collect::<Vec<_>>();
+    if to == VERSION_1 && !extra.is_empty() {
+        tracing::warn!(
+            "s9pk v1 does not support additional sections. Dropping: {}",
+            extra.join(", ")
+        );
+    }
+
+    // every section needs its own handle on the file
+    let mut license = S9pkReader::open(&path, false).await?;
+    let mut instructions = S9pkReader::open(&path, false).await?;
+    let mut icon = S9pkReader::open(&path, false).await?;
+    let mut docker_images = S9pkReader::open(&path, false).await?;
+    let mut assets = S9pkReader::open(&path, false).await?;
+    let mut extra_rdrs = Vec::new();
+    if to != VERSION_1 {
+        for label in extra {
+            extra_rdrs.push((label, S9pkReader::open(&path, false).await?));
+        }
+    }
+    let mut sections = std::collections::BTreeMap::new();
+    for (label, rdr) in &mut extra_rdrs {
+        if let Some(section) = rdr.section(label).await? {
+            sections.insert(
+                label.clone(),
+                Box::new(section) as Box<dyn AsyncRead + Unpin + Send + '_>,
+            );
+        }
+    }
+
+    let output = output.unwrap_or_else(|| path.clone());
+    let tmp_path = output.with_extension("s9pk.tmp");
+    let mut outfile = File::create(&tmp_path).await?;
+    S9pkPacker::builder()
+        .manifest(&manifest)
+        .writer(&mut outfile)
+        .license(license.license().await?)
+        .instructions(instructions.instructions().await?)
+        .icon(icon.icon().await?)
+        .docker_images(docker_images.docker_images_raw().await?)
+        .assets(assets.assets().await?)
+        .scripts(src.scripts().await?)
+        .sections(sections)
+        .version(to)
+        .compression(if no_compress {
+            Compression::None
+        } else {
+            Compression::Zstd
+        })
+        .build()
+        .pack(&key)
+        .await?;
+    outfile.sync_all().await?;
+    tokio::fs::rename(&tmp_path, &output).await?;
 
     Ok(())
 }
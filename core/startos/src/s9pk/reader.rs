@@ -1,4 +1,4 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::io::SeekFrom;
 use std::ops::Range;
 use std::path::Path;
@@ -6,17 +6,18 @@ use std::pin::Pin;
 use std::str::FromStr;
 use std::task::{Context, Poll};
 
+use async_compression::tokio::bufread::ZstdDecoder;
 use color_eyre::eyre::eyre;
 use digest::Output;
 use ed25519_dalek::VerifyingKey;
 use futures::TryStreamExt;
 use models::ImageId;
-use sha2::{Digest, Sha512};
+use sha2::{Digest, Sha256, Sha512};
 use tokio::fs::File;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, ReadBuf};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, BufReader, ReadBuf};
 use tracing::instrument;
 
-use super::header::{FileSection, Header, TableOfContents};
+use super::header::{Compression, FileSection, Header, TableOfContents, VERSION_1};
 use super::manifest::{Manifest, PackageId};
 use super::SIG_CONTEXT;
 use crate::install::progress::InstallProgressTracker;
@@ -84,6 +85,43 @@ impl<'a, R: AsyncSeek + Unpin> AsyncSeek for ReadHandle<'a, R> {
     }
 }
 
+/// A section of an s9pk, decompressed as it is read
+#[pin_project::pin_project(project = SectionReaderProj)]
+pub enum SectionReader<'a, R = File> {
+    Raw(#[pin] ReadHandle<'a, R>),
+    Zstd(#[pin] ZstdDecoder<BufReader<ReadHandle<'a, R>>>),
+}
+impl<'a, R: AsyncRead + Unpin> SectionReader<'a, R> {
+    fn new(hdl: ReadHandle<'a, R>, compression: Compression) -> Self {
+        match compression {
+            Compression::None => SectionReader::Raw(hdl),
+            Compression::Zstd => SectionReader::Zstd(ZstdDecoder::new(BufReader::new(hdl))),
+        }
+    }
+    pub async fn to_vec(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            SectionReader::Raw(hdl) => hdl.to_vec().await,
+            mut a => {
+                let mut buf = Vec::new();
+                a.read_to_end(&mut buf).await?;
+                Ok(buf)
+            }
+        }
+    }
+}
+impl<'a, R: AsyncRead + Unpin> AsyncRead for SectionReader<'a, R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.project() {
+            SectionReaderProj::Raw(a) => a.poll_read(cx, buf),
+            SectionReaderProj::Zstd(a) => a.poll_read(cx, buf),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ImageTag {
     pub package_id: PackageId,
@@ -145,6 +183,7 @@ impl FromStr for ImageTag {
 }
 
 pub struct S9pkReader<R: AsyncRead + AsyncSeek + Unpin + Send + Sync = File> {
+    version: u8,
     hash: Option<Output<Sha512>>,
     hash_string: Option<String>,
     developer_key: VerifyingKey,
@@ -170,7 +209,7 @@ impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> S9pkReader<InstallProgressT
 impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> S9pkReader<R> {
     #[instrument(skip_all)]
     pub async fn validate(&mut self) -> Result<(), Error> {
-        if self.toc.icon.length > 102_400 {
+        if self.toc.icon.size > 102_400 {
             // 100 KiB
             return Err(Error::new(
                 eyre!("icon must be less than 100KiB"),
@@ -213,6 +252,13 @@ impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> S9pkReader<R> {
         man.main
             .validate(&man.eos_version, &man.volumes, &validated_image_ids, false)
             .with_ctx(|_| (crate::ErrorKind::ValidateS9pk, "Main"))?;
+        if let Some(containers) = &man.containers {
+            containers
+                .main
+                .resources
+                .validate()
+                .with_ctx(|_| (crate::ErrorKind::ValidateS9pk, "Main Container"))?;
+        }
         man.migrations.validate(
             containers,
             &man.eos_version,
@@ -262,7 +308,13 @@ impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> S9pkReader<R> {
                 .validate(&man.eos_version, &man.volumes, &validated_image_ids, true)
                 .with_ctx(|_| (crate::ErrorKind::ValidateS9pk, "Properties"))?;
         }
+        if let Some(containers) = &man.containers {
+            containers
+                .aux
+                .validate(&man.volumes, &validated_image_ids, &man.health_checks)?;
+        }
         man.volumes.validate(&man.interfaces)?;
+        man.devices.validate()?;
 
         Ok(())
     }
@@ -299,7 +351,23 @@ impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> S9pkReader<R> {
         let header = Header::deserialize(&mut rdr).await?;
 
         let (hash, hash_string) = if check_sig {
+            if header.version != VERSION_1 {
+                header.pubkey.verify_prehashed(
+                    header.toc_digest().await?,
+                    Some(SIG_CONTEXT),
+                    &header.signature,
+                )?;
+            }
+            // sections are checked in the same pass, in order of position
+            let mut sections = header
+                .table_of_contents
+                .sections()
+                .into_iter()
+                .filter_map(|(label, s)| Some((label.to_owned(), s, s.hash?, Sha256::new())))
+                .collect::<Vec<_>>();
+            sections.sort_by_key(|(_, s, _, _)| s.position);
             let mut hasher = Sha512::new();
+            let mut pos = rdr.stream_position().await?;
             let mut buf = [0; 1024];
             let mut read;
             while {
@@ -307,11 +375,29 @@ impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> S9pkReader<R> {
                 read != 0
             } {
                 hasher.update(&buf[0..read]);
+                for (_, section, _, section_hasher) in &mut sections {
+                    let start = section.position.max(pos);
+                    let end = (section.position + section.length).min(pos + read as u64);
+                    if start < end {
+                        section_hasher.update(&buf[(start - pos) as usize..(end - pos) as usize]);
+                    }
+                }
+                pos += read as u64;
+            }
+            for (label, _, expected, section_hasher) in sections {
+                if section_hasher.finalize().as_slice() != expected {
+                    return Err(Error::new(
+                        eyre!("Hash mismatch for section {}", label),
+                        crate::ErrorKind::ValidateS9pk,
+                    ));
+                }
             }
             let hash = hasher.clone().finalize();
-            header
-                .pubkey
-                .verify_prehashed(hasher, Some(SIG_CONTEXT), &header.signature)?;
+            if header.version == VERSION_1 {
+                header
+                    .pubkey
+                    .verify_prehashed(hasher, Some(SIG_CONTEXT), &header.signature)?;
+            }
             (
                 Some(hash),
                 Some(base32::encode(
@@ -326,6 +412,7 @@ impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> S9pkReader<R> {
         let pos = rdr.stream_position().await?;
 
         Ok(S9pkReader {
+            version: header.version,
             hash_string,
             hash,
             developer_key: header.pubkey,
@@ -335,6 +422,29 @@ impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> S9pkReader<R> {
         })
     }
 
+    /// The format version of the s9pk: 1 or 2
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Labels of every section in the package, including additional ones like `sbom`
+    pub fn section_labels(&self) -> Vec<String> {
+        self.toc
+            .sections()
+            .into_keys()
+            .map(|label| label.to_owned())
+            .collect()
+    }
+
+    /// Decompressed size of every section, by label
+    pub fn section_sizes(&self) -> BTreeMap<String, u64> {
+        self.toc
+            .sections()
+            .into_iter()
+            .map(|(label, section)| (label.to_owned(), section.size))
+            .collect()
+    }
+
     pub fn hash(&self) -> Option<&Output<Sha512>> {
         self.hash.as_ref()
     }
@@ -367,8 +477,76 @@ impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> S9pkReader<R> {
         })
     }
 
-    pub async fn manifest_raw(&mut self) -> Result<ReadHandle<'_, R>, Error> {
-        self.read_handle(self.toc.manifest).await
+    async fn section_reader<'a>(
+        &'a mut self,
+        section: FileSection,
+    ) -> Result<SectionReader<'a, R>, Error> {
+        let compression = section.compression;
+        Ok(SectionReader::new(
+            self.read_handle(section).await?,
+            compression,
+        ))
+    }
+
+    /// Checks a single section against the hash in the signed table of contents, without reading
+    /// the rest of the package. Only meaningful if the header signature has been checked.
+    #[instrument(skip_all)]
+    pub async fn verify_section(&mut self, label: &str) -> Result<(), Error> {
+        let section = self.toc.get(label).ok_or_else(|| {
+            Error::new(
+                eyre!("s9pk has no section {}", label),
+                crate::ErrorKind::NotFound,
+            )
+        })?;
+        let expected = section.hash.ok_or_else(|| {
+            Error::new(
+                eyre!("s9pk v{} does not contain section hashes", self.version),
+                crate::ErrorKind::ValidateS9pk,
+            )
+        })?;
+        let mut hdl = self.read_handle(section).await?;
+        let mut hasher = Sha256::new();
+        let mut buf = [0; 1024];
+        let mut read;
+        while {
+            read = hdl.read(&mut buf).await?;
+            read != 0
+        } {
+            hasher.update(&buf[0..read]);
+        }
+        if hasher.finalize().as_slice() != expected {
+            return Err(Error::new(
+                eyre!("Hash mismatch for section {}", label),
+                crate::ErrorKind::ValidateS9pk,
+            ));
+        }
+        Ok(())
+    }
+
+    /// SHA-256 of the decompressed contents of a section. Unlike the hash in the table of
+    /// contents, this does not depend on the format version or compression.
+    pub async fn content_hash(&mut self, label: &str) -> Result<[u8; 32], Error> {
+        let section = self.toc.get(label).ok_or_else(|| {
+            Error::new(
+                eyre!("s9pk has no section {}", label),
+                crate::ErrorKind::NotFound,
+            )
+        })?;
+        let mut rdr = self.section_reader(section).await?;
+        let mut hasher = Sha256::new();
+        let mut buf = [0; 1024];
+        let mut read;
+        while {
+            read = rdr.read(&mut buf).await?;
+            read != 0
+        } {
+            hasher.update(&buf[0..read]);
+        }
+        Ok(hasher.finalize().into())
+    }
+
+    pub async fn manifest_raw(&mut self) -> Result<SectionReader<'_, R>, Error> {
+        self.section_reader(self.toc.manifest).await
     }
 
     pub async fn manifest(&mut self) -> Result<Manifest, Error> {
@@ -377,30 +555,49 @@ impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> S9pkReader<R> {
             .with_ctx(|_| (crate::ErrorKind::ParseS9pk, "Deserializing Manifest (CBOR)"))
     }
 
-    pub async fn license(&mut self) -> Result<ReadHandle<'_, R>, Error> {
-        self.read_handle(self.toc.license).await
+    pub async fn license(&mut self) -> Result<SectionReader<'_, R>, Error> {
+        self.section_reader(self.toc.license).await
     }
 
-    pub async fn instructions(&mut self) -> Result<ReadHandle<'_, R>, Error> {
-        self.read_handle(self.toc.instructions).await
+    pub async fn instructions(&mut self) -> Result<SectionReader<'_, R>, Error> {
+        self.section_reader(self.toc.instructions).await
     }
 
-    pub async fn icon(&mut self) -> Result<ReadHandle<'_, R>, Error> {
-        self.read_handle(self.toc.icon).await
+    pub async fn icon(&mut self) -> Result<SectionReader<'_, R>, Error> {
+        self.section_reader(self.toc.icon).await
     }
 
     pub async fn docker_images(&mut self) -> Result<DockerReader<ReadHandle<'_, R>>, Error> {
+        if self.toc.docker_images.compression != Compression::None {
+            return Err(Error::new(
+                eyre!("docker_images section must not be compressed"),
+                crate::ErrorKind::ParseS9pk,
+            ));
+        }
         DockerReader::new(self.read_handle(self.toc.docker_images).await?).await
     }
 
-    pub async fn assets(&mut self) -> Result<ReadHandle<'_, R>, Error> {
-        self.read_handle(self.toc.assets).await
+    /// The docker_images section exactly as stored, without selecting an architecture
+    pub async fn docker_images_raw(&mut self) -> Result<ReadHandle<'_, R>, Error> {
+        self.read_handle(self.toc.docker_images).await
+    }
+
+    pub async fn assets(&mut self) -> Result<SectionReader<'_, R>, Error> {
+        self.section_reader(self.toc.assets).await
     }
 
-    pub async fn scripts(&mut self) -> Result<Option<ReadHandle<'_, R>>, Error> {
+    pub async fn scripts(&mut self) -> Result<Option<SectionReader<'_, R>>, Error> {
         Ok(match self.toc.scripts {
             None => None,
-            Some(a) => Some(self.read_handle(a).await?),
+            Some(a) => Some(self.section_reader(a).await?),
+        })
+    }
+
+    /// An additional section such as `sbom`, if the package has one
+    pub async fn section(&mut self, label: &str) -> Result<Option<SectionReader<'_, R>>, Error> {
+        Ok(match self.toc.extra.get(label).copied() {
+            None => None,
+            Some(a) => Some(self.section_reader(a).await?),
         })
     }
 }
@@ -1,18 +1,24 @@
 use std::path::Path;
+use std::time::Duration;
 
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use clap::ArgMatches;
 use color_eyre::eyre::eyre;
 use rpc_toolkit::command;
 use sqlx::{Pool, Postgres};
+use tokio::process::Command;
+use tokio::sync::broadcast::Receiver;
 use tracing::instrument;
 
 use crate::context::RpcContext;
-use crate::util::display_none;
+use crate::shutdown::Shutdown;
 use crate::util::serde::{display_serializable, IoFormat};
+use crate::util::{display_none, Invoke};
 use crate::{Error, ErrorKind};
 
 static SSH_AUTHORIZED_KEYS_FILE: &str = "/home/start9/.ssh/authorized_keys";
+static SSH_TRUSTED_CA_KEYS_FILE: &str = "/etc/ssh/startos_trusted_user_ca_keys";
+static SSHD_CONFIG_DROP_IN: &str = "/etc/ssh/sshd_config.d/startos.conf";
 
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct PubKey(
@@ -28,6 +34,9 @@ pub struct SshKeyResponse {
     pub fingerprint: String,
     pub hostname: String,
     pub created_at: String,
+    pub from: Option<String>,
+    pub command: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
 }
 impl std::fmt::Display for SshKeyResponse {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -50,15 +59,81 @@ impl std::str::FromStr for PubKey {
     }
 }
 
-#[command(subcommands(add, delete, list,))]
+/// Options applied to a key in `authorized_keys`. Values end up inside double quotes, so anything
+/// that could break out of the quoted string is rejected up front rather than escaped.
+#[derive(Debug, Default, Clone)]
+pub struct KeyOptions {
+    pub from: Option<String>,
+    pub command: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+impl KeyOptions {
+    fn validate(&self) -> Result<(), Error> {
+        for (name, value) in [("from", &self.from), ("command", &self.command)] {
+            if let Some(value) = value {
+                if value.is_empty() || value.contains(|c| matches!(c, '"' | '\\' | '\n' | '\r')) {
+                    return Err(Error::new(
+                        eyre!("Invalid value for SSH key option `{}`", name),
+                        ErrorKind::InvalidRequest,
+                    ));
+                }
+            }
+        }
+        if let Some(expires_at) = self.expires_at {
+            if expires_at <= Utc::now() {
+                return Err(Error::new(
+                    eyre!("SSH key expiration must be in the future"),
+                    ErrorKind::InvalidRequest,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn render(&self) -> Option<String> {
+        let mut opts = Vec::new();
+        if let Some(from) = &self.from {
+            opts.push(format!("from=\"{}\"", from));
+        }
+        if let Some(command) = &self.command {
+            opts.push(format!("command=\"{}\"", command));
+        }
+        if let Some(expires_at) = &self.expires_at {
+            // sshd enforces this on its own; the expiry task only cleans up afterwards
+            opts.push(format!(
+                "expiry-time=\"{}\"",
+                expires_at.format("%Y%m%d%H%M%SZ")
+            ));
+        }
+        if opts.is_empty() {
+            None
+        } else {
+            Some(opts.join(","))
+        }
+    }
+}
+
+#[command(subcommands(add, delete, list, ca, config))]
 pub fn ssh() -> Result<(), Error> {
     Ok(())
 }
 
 #[command(display(display_none))]
 #[instrument(skip_all)]
-pub async fn add(#[context] ctx: RpcContext, #[arg] key: PubKey) -> Result<SshKeyResponse, Error> {
+pub async fn add(
+    #[context] ctx: RpcContext,
+    #[arg] key: PubKey,
+    #[arg(long = "from")] from: Option<String>,
+    #[arg(long = "command")] command: Option<String>,
+    #[arg(long = "expires-at")] expires_at: Option<DateTime<Utc>>,
+) -> Result<SshKeyResponse, Error> {
     let pool = &ctx.secret_store;
+    let options = KeyOptions {
+        from,
+        command,
+        expires_at,
+    };
+    options.validate()?;
     // check fingerprint for duplicates
     let fp = key.0.fingerprint_md5();
     match sqlx::query!("SELECT * FROM ssh_keys WHERE fingerprint = $1", fp)
@@ -70,10 +145,13 @@ pub async fn add(#[context] ctx: RpcContext, #[arg] key: PubKey) -> Result<SshKe
             let raw_key = format!("{}", key.0);
             let created_at = Utc::now().to_rfc3339();
             sqlx::query!(
-                "INSERT INTO ssh_keys (fingerprint, openssh_pubkey, created_at) VALUES ($1, $2, $3)",
+                "INSERT INTO ssh_keys (fingerprint, openssh_pubkey, created_at, from_restriction, force_command, expires_at) VALUES ($1, $2, $3, $4, $5, $6)",
                 fp,
                 raw_key,
-                created_at
+                created_at,
+                options.from,
+                options.command,
+                options.expires_at.map(|e| e.naive_utc()),
             )
             .execute(pool)
             .await?;
@@ -85,6 +163,9 @@ pub async fn add(#[context] ctx: RpcContext, #[arg] key: PubKey) -> Result<SshKe
                 fingerprint: fp,
                 hostname: key.0.comment.unwrap_or(String::new()).to_owned(),
                 created_at,
+                from: options.from,
+                command: options.command,
+                expires_at: options.expires_at,
             })
         }
         Some(_) => Err(Error::new(eyre!("Duplicate ssh key"), ErrorKind::Duplicate)),
@@ -127,6 +208,9 @@ fn display_all_ssh_keys(all: Vec<SshKeyResponse>, matches: &ArgMatches) {
         "ALGORITHM",
         "FINGERPRINT",
         "HOSTNAME",
+        "FROM",
+        "COMMAND",
+        "EXPIRES AT",
     ]);
     for key in all {
         let row = row![
@@ -134,6 +218,11 @@ fn display_all_ssh_keys(all: Vec<SshKeyResponse>, matches: &ArgMatches) {
             &key.alg,
             &key.fingerprint,
             &key.hostname,
+            key.from.as_deref().unwrap_or("N/A"),
+            key.command.as_deref().unwrap_or("N/A"),
+            &key.expires_at
+                .map(|e| e.to_rfc3339())
+                .unwrap_or_else(|| "N/A".to_owned()),
         ];
         table.add_row(row);
     }
@@ -150,7 +239,7 @@ pub async fn list(
 ) -> Result<Vec<SshKeyResponse>, Error> {
     let pool = &ctx.secret_store;
     // list keys in DB and return them
-    let entries = sqlx::query!("SELECT fingerprint, openssh_pubkey, created_at FROM ssh_keys")
+    let entries = sqlx::query!("SELECT fingerprint, openssh_pubkey, created_at, from_restriction, force_command, expires_at FROM ssh_keys")
         .fetch_all(pool)
         .await?;
     Ok(entries
@@ -166,23 +255,190 @@ pub async fn list(
                 fingerprint,
                 hostname,
                 created_at,
+                from: r.from_restriction,
+                command: r.force_command,
+                expires_at: r.expires_at.map(|e| DateTime::from_utc(e, Utc)),
             }
         })
         .collect())
 }
 
+#[command(subcommands(add_ca, delete_ca, list_ca))]
+pub fn ca() -> Result<(), Error> {
+    Ok(())
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SshCaKeyResponse {
+    pub alg: String,
+    pub fingerprint: String,
+    pub comment: String,
+    pub created_at: String,
+}
+
+#[command(rename = "add", display(display_none))]
+#[instrument(skip_all)]
+pub async fn add_ca(
+    #[context] ctx: RpcContext,
+    #[arg] key: PubKey,
+) -> Result<SshCaKeyResponse, Error> {
+    let pool = &ctx.secret_store;
+    let fp = key.0.fingerprint_md5();
+    if sqlx::query!(
+        "SELECT fingerprint FROM ssh_ca_keys WHERE fingerprint = $1",
+        fp
+    )
+    .fetch_optional(pool)
+    .await?
+    .is_some()
+    {
+        return Err(Error::new(
+            eyre!("Duplicate ssh CA key"),
+            ErrorKind::Duplicate,
+        ));
+    }
+    let raw_key = format!("{}", key.0);
+    let created_at = Utc::now().to_rfc3339();
+    sqlx::query!(
+        "INSERT INTO ssh_ca_keys (fingerprint, openssh_pubkey, created_at) VALUES ($1, $2, $3)",
+        fp,
+        raw_key,
+        created_at
+    )
+    .execute(pool)
+    .await?;
+    sync_sshd_config(pool).await?;
+    Ok(SshCaKeyResponse {
+        alg: key.0.keytype().to_owned(),
+        fingerprint: fp,
+        comment: key.0.comment.unwrap_or_default(),
+        created_at,
+    })
+}
+
+#[command(rename = "delete", display(display_none))]
+#[instrument(skip_all)]
+pub async fn delete_ca(
+    #[context] ctx: RpcContext,
+    #[arg] fingerprint: String,
+) -> Result<(), Error> {
+    let pool = &ctx.secret_store;
+    let n = sqlx::query!(
+        "DELETE FROM ssh_ca_keys WHERE fingerprint = $1",
+        fingerprint
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+    if n == 0 {
+        return Err(Error::new(
+            eyre!("SSH CA Key Not Found"),
+            ErrorKind::NotFound,
+        ));
+    }
+    sync_sshd_config(pool).await
+}
+
+#[command(rename = "list", display(display_serializable))]
+#[instrument(skip_all)]
+pub async fn list_ca(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<SshCaKeyResponse>, Error> {
+    sqlx::query!("SELECT openssh_pubkey, created_at FROM ssh_ca_keys")
+        .fetch_all(&ctx.secret_store)
+        .await?
+        .into_iter()
+        .map(|r| {
+            let k = r.openssh_pubkey.parse::<PubKey>()?.0;
+            Ok(SshCaKeyResponse {
+                alg: k.keytype().to_owned(),
+                fingerprint: k.fingerprint_md5(),
+                comment: k.comment.unwrap_or_default(),
+                created_at: r.created_at,
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SshdConfig {
+    pub password_auth: bool,
+    pub port: u16,
+}
+impl SshdConfig {
+    pub async fn load(pool: &Pool<Postgres>) -> Result<Self, Error> {
+        let r = sqlx::query!("SELECT password_auth, port FROM ssh_config WHERE id = 0")
+            .fetch_one(pool)
+            .await?;
+        Ok(Self {
+            password_auth: r.password_auth,
+            port: r.port as u16,
+        })
+    }
+}
+
+/// Change the settings rendered into the managed sshd drop-in. Without arguments this just
+/// returns the current settings.
+#[command(display(display_serializable))]
+#[instrument(skip_all)]
+pub async fn config(
+    #[context] ctx: RpcContext,
+    #[arg(long = "password-auth")] password_auth: Option<bool>,
+    #[arg(long = "port")] port: Option<u16>,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<SshdConfig, Error> {
+    let pool = &ctx.secret_store;
+    if port == Some(0) {
+        return Err(Error::new(
+            eyre!("SSH port cannot be 0"),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+    if password_auth.is_some() || port.is_some() {
+        sqlx::query!(
+            "UPDATE ssh_config SET password_auth = COALESCE($1, password_auth), port = COALESCE($2, port) WHERE id = 0",
+            password_auth,
+            port.map(|p| p as i32),
+        )
+        .execute(pool)
+        .await?;
+        sync_sshd_config(pool).await?;
+    }
+    SshdConfig::load(pool).await
+}
+
 #[instrument(skip_all)]
 pub async fn sync_keys_from_db<P: AsRef<Path>>(
     pool: &Pool<Postgres>,
     dest: P,
 ) -> Result<(), Error> {
     let dest = dest.as_ref();
-    let keys = sqlx::query!("SELECT openssh_pubkey FROM ssh_keys")
-        .fetch_all(pool)
-        .await?;
+    let keys = sqlx::query!(
+        "SELECT openssh_pubkey, from_restriction, force_command, expires_at FROM ssh_keys"
+    )
+    .fetch_all(pool)
+    .await?;
     let contents: String = keys
         .into_iter()
-        .map(|k| format!("{}\n", k.openssh_pubkey))
+        .map(|k| {
+            let options = KeyOptions {
+                from: k.from_restriction,
+                command: k.force_command,
+                expires_at: k.expires_at.map(|e| DateTime::from_utc(e, Utc)),
+            };
+            if let Some(options) = options.render() {
+                format!("{} {}\n", options, k.openssh_pubkey)
+            } else {
+                format!("{}\n", k.openssh_pubkey)
+            }
+        })
         .collect();
     let ssh_dir = dest.parent().ok_or_else(|| {
         Error::new(
@@ -195,3 +451,123 @@ pub async fn sync_keys_from_db<P: AsRef<Path>>(
     }
     std::fs::write(dest, contents).map_err(|e| e.into())
 }
+
+/// Renders the trusted CA keys and the managed sshd drop-in, reloading sshd if either changed.
+#[instrument(skip_all)]
+pub async fn sync_sshd_config(pool: &Pool<Postgres>) -> Result<(), Error> {
+    let cfg = SshdConfig::load(pool).await?;
+    let ca_keys: String = sqlx::query!("SELECT openssh_pubkey FROM ssh_ca_keys")
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|k| format!("{}\n", k.openssh_pubkey))
+        .collect();
+    let mut drop_in = String::new();
+    drop_in.push_str("# This file is managed by StartOS. Use `ssh config` to change it.\n");
+    drop_in.push_str(&format!("Port {}\n", cfg.port));
+    drop_in.push_str(&format!(
+        "PasswordAuthentication {}\n",
+        if cfg.password_auth { "yes" } else { "no" }
+    ));
+    if !ca_keys.is_empty() {
+        drop_in.push_str(&format!("TrustedUserCAKeys {}\n", SSH_TRUSTED_CA_KEYS_FILE));
+    }
+
+    let mut changed = false;
+    for (path, contents) in [
+        (SSH_TRUSTED_CA_KEYS_FILE, ca_keys),
+        (SSHD_CONFIG_DROP_IN, drop_in),
+    ] {
+        if tokio::fs::read_to_string(path).await.ok().as_deref() != Some(contents.as_str()) {
+            if let Some(parent) = Path::new(path).parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            tokio::fs::write(path, contents).await?;
+            changed = true;
+        }
+    }
+    if changed {
+        Command::new("systemctl")
+            .arg("reload-or-restart")
+            .arg("ssh")
+            .invoke(ErrorKind::OpenSsh)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Removes keys past their `expires-at` from the database and the live key file.
+#[instrument(skip_all)]
+pub async fn launch_ssh_key_expiry_task<F: FnMut() -> Receiver<Option<Shutdown>>>(
+    pool: &Pool<Postgres>,
+    mut mk_shutdown: F,
+) {
+    let mut shutdown = mk_shutdown();
+    loop {
+        match sqlx::query!("DELETE FROM ssh_keys WHERE expires_at <= (now() AT TIME ZONE 'utc')")
+            .execute(pool)
+            .await
+        {
+            Ok(res) if res.rows_affected() > 0 => {
+                tracing::info!("Removed {} expired SSH key(s)", res.rows_affected());
+                if let Err(e) = sync_keys_from_db(pool, SSH_AUTHORIZED_KEYS_FILE).await {
+                    tracing::error!("Error syncing SSH keys: {}", e);
+                    tracing::debug!("{:?}", e);
+                }
+            }
+            Ok(_) => (),
+            Err(e) => {
+                tracing::error!("Error removing expired SSH keys: {}", e);
+                tracing::debug!("{:?}", e);
+            }
+        }
+        tokio::select! {
+            _ = shutdown.recv() => return,
+            _ = tokio::time::sleep(Duration::from_secs(60)) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn rejects_options_that_break_out_of_quotes() {
+        for value in ["", "10.0.0.1\"", "a\\b", "ls\nrm -rf /", "ls\r"] {
+            let options = KeyOptions {
+                command: Some(value.to_owned()),
+                ..Default::default()
+            };
+            assert!(options.validate().is_err(), "{:?}", value);
+        }
+        let expired = KeyOptions {
+            expires_at: Some(Utc::now() - chrono::Duration::minutes(1)),
+            ..Default::default()
+        };
+        assert!(expired.validate().is_err());
+        let valid = KeyOptions {
+            from: Some("10.0.0.0/8,!10.0.0.1".to_owned()),
+            command: Some("/usr/bin/backup --quiet".to_owned()),
+            expires_at: Some(Utc::now() + chrono::Duration::days(1)),
+        };
+        assert!(valid.validate().is_ok());
+        assert!(KeyOptions::default().validate().is_ok());
+    }
+
+    #[test]
+    fn renders_authorized_keys_options() {
+        assert_eq!(KeyOptions::default().render(), None);
+        let options = KeyOptions {
+            from: Some("10.0.0.0/8".to_owned()),
+            command: Some("/usr/bin/backup".to_owned()),
+            expires_at: Some(Utc.with_ymd_and_hms(2030, 1, 2, 3, 4, 5).unwrap()),
+        };
+        assert_eq!(
+            options.render().as_deref(),
+            Some("from=\"10.0.0.0/8\",command=\"/usr/bin/backup\",expiry-time=\"20300102030405Z\"")
+        );
+    }
+}
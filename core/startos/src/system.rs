@@ -1,7 +1,7 @@
 use std::collections::BTreeSet;
 use std::fmt;
 
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use clap::ArgMatches;
 use color_eyre::eyre::eyre;
 use futures::FutureExt;
@@ -16,8 +16,8 @@ use tracing::instrument;
 use crate::context::{CliContext, RpcContext};
 use crate::disk::util::{get_available, get_used};
 use crate::logs::{
-    cli_logs_generic_follow, cli_logs_generic_nofollow, fetch_logs, follow_logs, LogFollowResponse,
-    LogResponse, LogSource,
+    cli_logs_generic_follow, cli_logs_generic_nofollow, fetch_logs, follow_logs, LogFilter,
+    LogFollowResponse, LogPriority, LogResponse, LogSource,
 };
 use crate::prelude::*;
 use crate::shutdown::Shutdown;
@@ -200,7 +200,12 @@ pub async fn time(
 
 #[command(
     custom_cli(cli_logs(async, context(CliContext))),
-    subcommands(self(logs_nofollow(async)), logs_follow),
+    subcommands(
+        self(logs_nofollow(async)),
+        logs_follow,
+        crate::logs::export,
+        crate::logs::forward::forward
+    ),
     display(display_none)
 )]
 pub async fn logs(
@@ -208,12 +213,27 @@ pub async fn logs(
     #[arg(short = 'c', long = "cursor")] cursor: Option<String>,
     #[arg(short = 'B', long = "before", default)] before: bool,
     #[arg(short = 'f', long = "follow", default)] follow: bool,
-) -> Result<(Option<usize>, Option<String>, bool, bool), Error> {
-    Ok((limit, cursor, before, follow))
+    #[arg(short = 'S', long = "since")] since: Option<DateTime<Utc>>,
+    #[arg(short = 'U', long = "until")] until: Option<DateTime<Utc>>,
+    #[arg(short = 'p', long = "priority")] priority: Option<LogPriority>,
+    #[arg(short = 'g', long = "grep")] grep: Option<String>,
+) -> Result<(Option<usize>, Option<String>, bool, bool, LogFilter), Error> {
+    Ok((
+        limit,
+        cursor,
+        before,
+        follow,
+        LogFilter {
+            since,
+            until,
+            priority,
+            grep,
+        },
+    ))
 }
 pub async fn cli_logs(
     ctx: CliContext,
-    (limit, cursor, before, follow): (Option<usize>, Option<String>, bool, bool),
+    (limit, cursor, before, follow, filter): (Option<usize>, Option<String>, bool, bool, LogFilter),
 ) -> Result<(), RpcError> {
     if follow {
         if cursor.is_some() {
@@ -228,24 +248,24 @@ pub async fn cli_logs(
                 crate::ErrorKind::InvalidRequest,
             )));
         }
-        cli_logs_generic_follow(ctx, "server.logs.follow", None, limit).await
+        cli_logs_generic_follow(ctx, "server.logs.follow", None, limit, filter).await
     } else {
-        cli_logs_generic_nofollow(ctx, "server.logs", None, limit, cursor, before).await
+        cli_logs_generic_nofollow(ctx, "server.logs", None, limit, cursor, before, filter).await
     }
 }
 pub async fn logs_nofollow(
     _ctx: (),
-    (limit, cursor, before, _): (Option<usize>, Option<String>, bool, bool),
+    (limit, cursor, before, _, filter): (Option<usize>, Option<String>, bool, bool, LogFilter),
 ) -> Result<LogResponse, Error> {
-    fetch_logs(LogSource::System, limit, cursor, before).await
+    fetch_logs(LogSource::System, limit, cursor, before, filter).await
 }
 
 #[command(rpc_only, rename = "follow", display(display_none))]
 pub async fn logs_follow(
     #[context] ctx: RpcContext,
-    #[parent_data] (limit, _, _, _): (Option<usize>, Option<String>, bool, bool),
+    #[parent_data] (limit, _, _, _, filter): (Option<usize>, Option<String>, bool, bool, LogFilter),
 ) -> Result<LogFollowResponse, Error> {
-    follow_logs(ctx, LogSource::System, limit).await
+    follow_logs(ctx, LogSource::System, limit, filter).await
 }
 
 #[command(
@@ -279,16 +299,39 @@ pub async fn cli_kernel_logs(
                 crate::ErrorKind::InvalidRequest,
             )));
         }
-        cli_logs_generic_follow(ctx, "server.kernel-logs.follow", None, limit).await
+        cli_logs_generic_follow(
+            ctx,
+            "server.kernel-logs.follow",
+            None,
+            limit,
+            LogFilter::default(),
+        )
+        .await
     } else {
-        cli_logs_generic_nofollow(ctx, "server.kernel-logs", None, limit, cursor, before).await
+        cli_logs_generic_nofollow(
+            ctx,
+            "server.kernel-logs",
+            None,
+            limit,
+            cursor,
+            before,
+            LogFilter::default(),
+        )
+        .await
     }
 }
 pub async fn kernel_logs_nofollow(
     _ctx: (),
     (limit, cursor, before, _): (Option<usize>, Option<String>, bool, bool),
 ) -> Result<LogResponse, Error> {
-    fetch_logs(LogSource::Kernel, limit, cursor, before).await
+    fetch_logs(
+        LogSource::Kernel,
+        limit,
+        cursor,
+        before,
+        LogFilter::default(),
+    )
+    .await
 }
 
 #[command(rpc_only, rename = "follow", display(display_none))]
@@ -296,7 +339,7 @@ pub async fn kernel_logs_follow(
     #[context] ctx: RpcContext,
     #[parent_data] (limit, _, _, _): (Option<usize>, Option<String>, bool, bool),
 ) -> Result<LogFollowResponse, Error> {
-    follow_logs(ctx, LogSource::Kernel, limit).await
+    follow_logs(ctx, LogSource::Kernel, limit, LogFilter::default()).await
 }
 
 #[derive(Serialize, Deserialize)]
@@ -61,3 +61,19 @@ pub async fn lshw() -> Result<Vec<LshwDevice>, Error> {
         .collect(),
     )
 }
+
+/// Bus addresses, e.g. `pci@0000:00:02.0` or `usb@1:3`, of the devices of any lshw `class`
+pub async fn businfo(class: &str) -> Result<Vec<String>, Error> {
+    Ok(serde_json::from_slice::<Vec<serde_json::Value>>(
+        &Command::new("lshw")
+            .arg("-json")
+            .arg("-class")
+            .arg(class)
+            .invoke(crate::ErrorKind::Lshw)
+            .await?,
+    )
+    .with_kind(crate::ErrorKind::Deserialization)?
+    .into_iter()
+    .filter_map(|v| v.get("businfo")?.as_str().map(|s| s.to_owned()))
+    .collect())
+}
@@ -4,7 +4,10 @@ use sqlx::PgPool;
 
 use super::v0_3_4::V0_3_0_COMPAT;
 use super::{v0_3_5, VersionT};
+use crate::install::update::AutoUpdateConfig;
+use crate::logs::forward::LogForwardConfig;
 use crate::prelude::*;
+use crate::volume::snapshot::AutoSnapshotConfig;
 
 const V0_3_5_1: emver::Version = emver::Version::new(0, 3, 5, 1);
 
@@ -23,8 +26,35 @@ impl VersionT for Version {
     fn compat(&self) -> &'static VersionRange {
         &V0_3_0_COMPAT
     }
-    async fn up(&self, _db: PatchDb, _secrets: &PgPool) -> Result<(), Error> {
-        Ok(())
+    async fn up(&self, db: PatchDb, _secrets: &PgPool) -> Result<(), Error> {
+        let defaults = [
+            ("log-forwarding", to_value(&LogForwardConfig::default())?),
+            ("registry-server", to_value(&false)?),
+            ("auto-update", to_value(&AutoUpdateConfig::default())?),
+            (
+                "rollback-retention",
+                to_value(&crate::install::rollback::default_retention())?,
+            ),
+            (
+                "volume-usage-thresholds",
+                to_value(&crate::volume::default_usage_thresholds())?,
+            ),
+            ("auto-snapshot", to_value(&AutoSnapshotConfig::default())?),
+        ];
+        db.mutate(|v| {
+            let server_info = <&mut Value>::from(v.as_server_info_mut())
+                .as_object_mut()
+                .ok_or_else(|| {
+                    Error::new(eyre!("server-info is not an object"), ErrorKind::Database)
+                })?;
+            for (key, default) in defaults {
+                if server_info.get(key).is_none() {
+                    server_info.insert(key.into(), default);
+                }
+            }
+            Ok(())
+        })
+        .await
     }
     async fn down(&self, _db: PatchDb, _secrets: &PgPool) -> Result<(), Error> {
         Ok(())
@@ -0,0 +1,635 @@
+use std::collections::BTreeMap;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::ArgMatches;
+pub use helpers::script_dir;
+pub use models::VolumeId;
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::context::RpcContext;
+use crate::disk::quota::{self, QuotaBackend};
+use crate::net::interface::{InterfaceId, Interfaces};
+use crate::net::PACKAGE_CERT_PATH;
+use crate::notifications::NotificationLevel;
+use crate::prelude::*;
+use crate::s9pk::manifest::PackageId;
+use crate::status::MainStatus;
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::util::{display_none, Version};
+use crate::{Error, ResultExt};
+
+pub mod snapshot;
+
+pub const PKG_VOLUME_DIR: &str = "package-data/volumes";
+pub const BACKUP_DIR: &str = "/media/embassy/backups";
+
+const USAGE_CHECK_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Percentages of a volume quota at which a notification is issued
+pub fn default_usage_thresholds() -> Vec<u8> {
+    vec![80, 95]
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Volumes(BTreeMap<VolumeId, Volume>);
+impl Volumes {
+    #[instrument(skip_all)]
+    pub fn validate(&self, interfaces: &Interfaces) -> Result<(), Error> {
+        for (id, volume) in &self.0 {
+            volume
+                .validate(interfaces)
+                .with_ctx(|_| (crate::ErrorKind::ValidateS9pk, format!("Volume {}", id)))?;
+            if let Volume::Backup { .. } = volume {
+                return Err(Error::new(
+                    eyre!("Invalid volume type \"backup\""),
+                    ErrorKind::ParseS9pk,
+                )); // Volume::Backup is for internal use and shouldn't be declared in manifest
+            }
+        }
+        Ok(())
+    }
+    #[instrument(skip_all)]
+    pub async fn install(
+        &self,
+        ctx: &RpcContext,
+        pkg_id: &PackageId,
+        version: &Version,
+    ) -> Result<(), Error> {
+        let overrides = quota_overrides(ctx, pkg_id).await?;
+        for (volume_id, volume) in &self.0 {
+            volume
+                .install(
+                    &ctx.datadir,
+                    pkg_id,
+                    version,
+                    volume_id,
+                    overrides.get(volume_id).copied(),
+                )
+                .await?; // TODO: concurrent?
+        }
+        Ok(())
+    }
+    pub fn get_path_for(
+        &self,
+        path: &PathBuf,
+        pkg_id: &PackageId,
+        version: &Version,
+        volume_id: &VolumeId,
+    ) -> Option<PathBuf> {
+        self.0
+            .get(volume_id)
+            .map(|volume| volume.path_for(path, pkg_id, version, volume_id))
+    }
+    pub fn to_readonly(&self) -> Self {
+        Volumes(
+            self.0
+                .iter()
+                .map(|(id, volume)| {
+                    let mut volume = volume.clone();
+                    volume.set_readonly();
+                    (id.clone(), volume)
+                })
+                .collect(),
+        )
+    }
+}
+impl Deref for Volumes {
+    type Target = BTreeMap<VolumeId, Volume>;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+impl DerefMut for Volumes {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+impl Map for Volumes {
+    type Key = VolumeId;
+    type Value = Volume;
+}
+
+pub fn data_dir<P: AsRef<Path>>(datadir: P, pkg_id: &PackageId, volume_id: &VolumeId) -> PathBuf {
+    datadir
+        .as_ref()
+        .join(PKG_VOLUME_DIR)
+        .join(pkg_id)
+        .join("data")
+        .join(volume_id)
+}
+
+pub fn asset_dir<P: AsRef<Path>>(datadir: P, pkg_id: &PackageId, version: &Version) -> PathBuf {
+    datadir
+        .as_ref()
+        .join(PKG_VOLUME_DIR)
+        .join(pkg_id)
+        .join("assets")
+        .join(version.as_str())
+}
+
+pub fn backup_dir(pkg_id: &PackageId) -> PathBuf {
+    Path::new(BACKUP_DIR).join(pkg_id).join("data")
+}
+
+pub fn cert_dir(pkg_id: &PackageId, interface_id: &InterfaceId) -> PathBuf {
+    Path::new(PACKAGE_CERT_PATH).join(pkg_id).join(interface_id)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "kebab-case")]
+pub enum Volume {
+    #[serde(rename_all = "kebab-case")]
+    Data {
+        #[serde(skip)]
+        readonly: bool,
+        /// Default size limit, the user may override it
+        #[serde(default)]
+        quota_mb: Option<u64>,
+    },
+    #[serde(rename_all = "kebab-case")]
+    Assets {},
+    #[serde(rename_all = "kebab-case")]
+    Pointer {
+        package_id: PackageId,
+        volume_id: VolumeId,
+        path: PathBuf,
+        readonly: bool,
+    },
+    #[serde(rename_all = "kebab-case")]
+    Certificate { interface_id: InterfaceId },
+    #[serde(rename_all = "kebab-case")]
+    Backup { readonly: bool },
+}
+impl Volume {
+    #[instrument(skip_all)]
+    pub fn validate(&self, interfaces: &Interfaces) -> Result<(), color_eyre::eyre::Report> {
+        match self {
+            Volume::Certificate { interface_id } => {
+                if !interfaces.0.contains_key(interface_id) {
+                    color_eyre::eyre::bail!("unknown interface: {}", interface_id);
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+    pub async fn install(
+        &self,
+        path: &PathBuf,
+        pkg_id: &PackageId,
+        version: &Version,
+        volume_id: &VolumeId,
+        quota_override: Option<u64>,
+    ) -> Result<(), Error> {
+        match self {
+            Volume::Data { quota_mb, .. } => {
+                let path = self.path_for(path, pkg_id, version, volume_id);
+                let existed = tokio::fs::metadata(&path).await.is_ok();
+                let mut backend = quota::create_dir(&path).await?;
+                if backend.is_none() && existed {
+                    // volumes from before quotas were supported
+                    backend = quota::convert_dir(&path).await.unwrap_or_else(|e| {
+                        tracing::warn!("Failed to convert {}: {}", path.display(), e);
+                        tracing::debug!("{:?}", e);
+                        None
+                    });
+                }
+                let Some(quota_mb) = quota_override.or(*quota_mb) else {
+                    return Ok(());
+                };
+                match backend {
+                    Some(backend) => {
+                        if let Err(e) =
+                            quota::set_limit(&path, backend, Some(quota_mb * 1024 * 1024)).await
+                        {
+                            tracing::warn!("Failed to limit size of {}: {}", path.display(), e);
+                            tracing::debug!("{:?}", e);
+                        }
+                    }
+                    None => {
+                        tracing::warn!(
+                            "Size of {} cannot be limited on this filesystem",
+                            path.display()
+                        );
+                    }
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+    pub fn path_for(
+        &self,
+        data_dir_path: impl AsRef<Path>,
+        pkg_id: &PackageId,
+        version: &Version,
+        volume_id: &VolumeId,
+    ) -> PathBuf {
+        match self {
+            Volume::Data { .. } => data_dir(&data_dir_path, pkg_id, volume_id),
+            Volume::Assets {} => asset_dir(&data_dir_path, pkg_id, version).join(volume_id),
+            Volume::Pointer {
+                package_id,
+                volume_id,
+                path,
+                ..
+            } => data_dir(&data_dir_path, package_id, volume_id).join(if path.is_absolute() {
+                path.strip_prefix("/").unwrap()
+            } else {
+                path.as_ref()
+            }),
+            Volume::Certificate { interface_id } => cert_dir(pkg_id, &interface_id),
+            Volume::Backup { .. } => backup_dir(pkg_id),
+        }
+    }
+
+    pub fn pointer_path(&self, data_dir_path: impl AsRef<Path>) -> Option<PathBuf> {
+        if let Volume::Pointer {
+            path,
+            package_id,
+            volume_id,
+            ..
+        } = self
+        {
+            Some(
+                data_dir(data_dir_path.as_ref(), package_id, volume_id).join(
+                    if path.is_absolute() {
+                        path.strip_prefix("/").unwrap()
+                    } else {
+                        path.as_ref()
+                    },
+                ),
+            )
+        } else {
+            None
+        }
+    }
+
+    pub fn set_readonly(&mut self) {
+        match self {
+            Volume::Data { readonly, .. } => {
+                *readonly = true;
+            }
+            Volume::Pointer { readonly, .. } => {
+                *readonly = true;
+            }
+            Volume::Backup { readonly } => {
+                *readonly = true;
+            }
+            _ => (),
+        }
+    }
+    pub fn quota_mb(&self) -> Option<u64> {
+        match self {
+            Volume::Data { quota_mb, .. } => *quota_mb,
+            _ => None,
+        }
+    }
+    pub fn readonly(&self) -> bool {
+        match self {
+            Volume::Data { readonly, .. } => *readonly,
+            Volume::Assets {} => true,
+            Volume::Pointer { readonly, .. } => *readonly,
+            Volume::Certificate { .. } => true,
+            Volume::Backup { readonly } => *readonly,
+        }
+    }
+}
+
+/// The quotas set by the user, which take precedence over those declared by the manifest
+async fn quota_overrides(
+    ctx: &RpcContext,
+    pkg_id: &PackageId,
+) -> Result<BTreeMap<VolumeId, u64>, Error> {
+    ctx.db
+        .peek()
+        .await
+        .as_package_data()
+        .as_idx(pkg_id)
+        .and_then(|p| p.as_installed())
+        .map(|i| i.as_volume_quotas().de())
+        .transpose()
+        .map(|o| o.unwrap_or_default())
+}
+
+#[command(subcommands(usage, set_quota, set_thresholds, snapshot::snapshot))]
+pub fn volume() -> Result<(), Error> {
+    Ok(())
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct VolumeUsage {
+    pub id: VolumeId,
+    pub used_bytes: u64,
+    pub quota_mb: Option<u64>,
+    /// Whether the quota was set by the user rather than the manifest
+    pub overridden: bool,
+    /// `None` if the quota cannot be enforced for this volume
+    pub backend: Option<QuotaBackend>,
+}
+
+/// Returns the highest of `thresholds` that `used_bytes` has reached
+fn reached_threshold(thresholds: &[u8], used_bytes: u64, quota_mb: u64) -> Option<u8> {
+    let quota_bytes = quota_mb.saturating_mul(1024 * 1024);
+    thresholds
+        .iter()
+        .copied()
+        .filter(|t| used_bytes as u128 * 100 >= quota_bytes as u128 * *t as u128)
+        .max()
+}
+
+#[instrument(skip_all)]
+/// Measures the data volumes of `id`, or only those with a quota if `limited_only`
+async fn package_usage(
+    ctx: &RpcContext,
+    id: &PackageId,
+    limited_only: bool,
+) -> Result<Vec<VolumeUsage>, Error> {
+    let installed = ctx
+        .db
+        .peek()
+        .await
+        .as_package_data()
+        .as_idx(id)
+        .and_then(|p| p.as_installed())
+        .or_not_found(id)?
+        .de()?;
+    let mut res = Vec::new();
+    for (volume_id, volume) in &*installed.manifest.volumes {
+        if !matches!(volume, Volume::Data { .. }) {
+            continue;
+        }
+        let overridden = installed.volume_quotas.get(volume_id).copied();
+        let quota_mb = overridden.or(volume.quota_mb());
+        if limited_only && quota_mb.is_none() {
+            continue;
+        }
+        let path = data_dir(&ctx.datadir, id, volume_id);
+        if tokio::fs::metadata(&path).await.is_err() {
+            continue;
+        }
+        let backend = quota::backend(&path).await?;
+        res.push(VolumeUsage {
+            id: volume_id.clone(),
+            used_bytes: quota::usage(&path, backend).await?,
+            quota_mb,
+            overridden: overridden.is_some(),
+            backend,
+        });
+    }
+    Ok(res)
+}
+
+fn display_usage(usage: Vec<VolumeUsage>, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(usage, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "VOLUME", "USED (MB)", "QUOTA (MB)", "USED (%)"]);
+    for volume in usage {
+        let used_mb = volume.used_bytes as f64 / 1024_f64.powi(2);
+        table.add_row(row![
+            &*volume.id,
+            format!("{:.1}", used_mb),
+            match (volume.quota_mb, volume.backend) {
+                (None, _) => "-".to_owned(),
+                (Some(q), None) => format!("{} (not enforced)", q),
+                (Some(q), _) if volume.overridden => format!("{} (override)", q),
+                (Some(q), _) => q.to_string(),
+            },
+            volume.quota_mb.map_or("-".to_owned(), |q| format!(
+                "{:.1}",
+                used_mb * 100.0 / q as f64
+            )),
+        ]);
+    }
+    table.print_tty(false).unwrap();
+}
+
+/// Reports the disk usage of the data volumes of a service
+#[command(display(display_usage))]
+pub async fn usage(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<VolumeUsage>, Error> {
+    package_usage(&ctx, &id, false).await
+}
+
+/// Overrides the quota of a data volume, or restores the one declared by the manifest with
+/// `--reset`. Volumes created before quotas were supported are converted first, which requires the
+/// package to be stopped.
+#[command(rename = "set-quota", display(display_none), metadata(sync_db = true))]
+#[instrument(skip_all)]
+pub async fn set_quota(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg] volume: VolumeId,
+    #[arg(long = "quota-mb")] quota_mb: Option<u64>,
+    #[arg(long = "reset")] reset: bool,
+) -> Result<(), Error> {
+    if quota_mb.is_none() == !reset {
+        return Err(Error::new(
+            eyre!("exactly one of --quota-mb and --reset is required"),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+    let quota_mb = ctx
+        .db
+        .mutate(|db| {
+            let installed = db
+                .as_package_data_mut()
+                .as_idx_mut(&id)
+                .and_then(|p| p.as_installed_mut())
+                .or_not_found(&id)?;
+            let default = match installed.as_manifest().as_volumes().de()?.get(&volume) {
+                Some(v @ Volume::Data { .. }) => v.quota_mb(),
+                _ => {
+                    return Err(Error::new(
+                        eyre!("{} has no data volume {}", id, volume),
+                        ErrorKind::NotFound,
+                    ))
+                }
+            };
+            let overrides = installed.as_volume_quotas_mut();
+            if let Some(quota_mb) = quota_mb {
+                overrides.insert(&volume, &quota_mb)?;
+                Ok(Some(quota_mb))
+            } else {
+                overrides.remove(&volume)?;
+                Ok(default)
+            }
+        })
+        .await?;
+
+    let path = data_dir(&ctx.datadir, &id, &volume);
+    let mut backend = quota::backend(&path).await?;
+    if backend.is_none() && QuotaBackend::detect(&path).await?.is_some() {
+        let status = ctx
+            .db
+            .peek()
+            .await
+            .as_package_data()
+            .as_idx(&id)
+            .and_then(|p| p.as_installed())
+            .or_not_found(&id)?
+            .as_status()
+            .as_main()
+            .de()?;
+        if !matches!(status, MainStatus::Stopped) {
+            return Err(Error::new(
+                eyre!(
+                    "quota was saved, but {} was created before volume quotas were supported. \
+                    Stop {} and retry to convert it",
+                    path.display(),
+                    id
+                ),
+                ErrorKind::InvalidRequest,
+            ));
+        }
+        backend = quota::convert_dir(&path).await?;
+    }
+    match backend {
+        Some(backend) => {
+            quota::set_limit(&path, backend, quota_mb.map(|mb| mb * 1024 * 1024)).await
+        }
+        None => Err(Error::new(
+            eyre!(
+                "quota was saved, but {} cannot be limited on this filesystem",
+                path.display()
+            ),
+            ErrorKind::Filesystem,
+        )),
+    }
+}
+
+fn parse_thresholds(arg: &str, _: &ArgMatches) -> Result<Vec<u8>, Error> {
+    arg.split(',')
+        .filter(|s| !s.trim().is_empty())
+        .map(|s| s.trim().parse().with_kind(ErrorKind::ParseNumber))
+        .collect()
+}
+
+/// Sets the percentages of a volume quota at which a notification is issued, e.g. `80,95`
+#[command(rename = "set-thresholds", display(display_none))]
+pub async fn set_thresholds(
+    #[context] ctx: RpcContext,
+    #[arg(parse(parse_thresholds))] thresholds: Vec<u8>,
+) -> Result<(), Error> {
+    if let Some(t) = thresholds.iter().find(|t| **t == 0 || **t > 100) {
+        return Err(Error::new(
+            eyre!("threshold must be between 1 and 100: {}", t),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+    ctx.db
+        .mutate(|db| {
+            db.as_server_info_mut()
+                .as_volume_usage_thresholds_mut()
+                .ser(&thresholds)
+        })
+        .await
+}
+
+async fn check_usage(
+    ctx: &RpcContext,
+    reached: &mut BTreeMap<(PackageId, VolumeId), u8>,
+) -> Result<(), Error> {
+    let peek = ctx.db.peek().await;
+    let thresholds = peek.as_server_info().as_volume_usage_thresholds().de()?;
+    let ids: Vec<PackageId> = peek
+        .as_package_data()
+        .keys()?
+        .into_iter()
+        .filter(|id| {
+            peek.as_package_data()
+                .as_idx(id)
+                .and_then(|p| p.as_installed())
+                .is_some()
+        })
+        .collect();
+    reached.retain(|(id, _), _| ids.contains(id));
+    for id in ids {
+        for volume in package_usage(ctx, &id, true).await? {
+            let key = (id.clone(), volume.id.clone());
+            let Some(threshold) = volume
+                .quota_mb
+                .and_then(|q| reached_threshold(&thresholds, volume.used_bytes, q))
+            else {
+                reached.remove(&key);
+                continue;
+            };
+            if reached.get(&key).map_or(false, |t| *t >= threshold) {
+                reached.insert(key, threshold);
+                continue;
+            }
+            reached.insert(key, threshold);
+            let (level, title) = if threshold >= 100 {
+                (NotificationLevel::Error, "Volume Full")
+            } else {
+                (NotificationLevel::Warning, "Volume Almost Full")
+            };
+            ctx.notification_manager
+                .notify(
+                    ctx.db.clone(),
+                    Some(id.clone()),
+                    level,
+                    title.to_owned(),
+                    format!(
+                        "Volume {} of {} has used {}% of its {} MB quota",
+                        volume.id,
+                        id,
+                        threshold,
+                        volume.quota_mb.unwrap_or_default()
+                    ),
+                    (),
+                    None,
+                )
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Periodically checks the usage of every data volume with a quota, and notifies once each time a
+/// volume reaches a higher threshold
+pub async fn launch_volume_usage_task(ctx: RpcContext) {
+    let mut shutdown = ctx.shutdown.subscribe();
+    let mut reached = BTreeMap::new();
+    loop {
+        if let Err(e) = check_usage(&ctx, &mut reached).await {
+            tracing::error!("Failed to check volume usage: {}", e);
+            tracing::debug!("{:?}", e);
+        }
+        tokio::select! {
+            _ = shutdown.recv() => return,
+            _ = tokio::time::sleep(USAGE_CHECK_INTERVAL) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reaches_highest_threshold() {
+        let thresholds = default_usage_thresholds();
+        let mb = 1024 * 1024;
+        assert_eq!(reached_threshold(&thresholds, 79 * mb, 100), None);
+        assert_eq!(reached_threshold(&thresholds, 80 * mb, 100), Some(80));
+        assert_eq!(reached_threshold(&thresholds, 99 * mb, 100), Some(95));
+        assert_eq!(reached_threshold(&[], 200 * mb, 100), None);
+    }
+}
@@ -0,0 +1,608 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use clap::ArgMatches;
+use helpers::{Rsync, RsyncOptions};
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tracing::instrument;
+
+use super::{data_dir, Volume, VolumeId, Volumes};
+use crate::context::RpcContext;
+use crate::disk::quota::{self, QuotaBackend};
+use crate::prelude::*;
+use crate::s9pk::manifest::PackageId;
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::util::{display_none, Invoke, Version};
+
+/// Snapshots of package volumes are kept here, relative to the data directory. It must be on the
+/// same filesystem as the volumes for btrfs snapshots.
+pub const PKG_SNAPSHOT_DIR: &str = "package-data/snapshots";
+
+fn package_dir(datadir: &Path, id: &PackageId) -> PathBuf {
+    datadir.join(PKG_SNAPSHOT_DIR).join(id)
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, HasModel)]
+#[serde(rename_all = "kebab-case")]
+#[model = "Model<Self>"]
+pub struct AutoSnapshotConfig {
+    /// Snapshot before `config.set`
+    pub before_config: bool,
+    /// Snapshot before updates and rollbacks
+    pub before_update: bool,
+    /// How many automatic snapshots are kept per package, manual ones are never pruned
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+}
+impl Default for AutoSnapshotConfig {
+    fn default() -> Self {
+        Self {
+            before_config: false,
+            before_update: false,
+            keep: default_keep(),
+        }
+    }
+}
+
+fn default_keep() -> usize {
+    3
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SnapshotReason {
+    Manual,
+    Config,
+    Update,
+    /// Kept for `package.rollback`, and removed along with its rollback point
+    Rollback,
+}
+impl std::fmt::Display for SnapshotReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Manual => write!(f, "manual"),
+            Self::Config => write!(f, "config"),
+            Self::Update => write!(f, "update"),
+            Self::Rollback => write!(f, "rollback"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SnapshotMethod {
+    /// A read-only btrfs snapshot of the volume subvolume
+    Btrfs,
+    /// A reflink copy where the filesystem supports it, a full copy otherwise
+    Copy,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct VolumeSnapshot {
+    pub id: String,
+    pub package_id: PackageId,
+    pub version: Version,
+    pub created_at: DateTime<Utc>,
+    pub reason: SnapshotReason,
+    pub label: Option<String>,
+    pub volumes: BTreeMap<VolumeId, SnapshotMethod>,
+}
+impl VolumeSnapshot {
+    fn dir(&self, datadir: &Path) -> PathBuf {
+        package_dir(datadir, &self.package_id).join(&self.id)
+    }
+}
+
+async fn load(datadir: &Path, id: &PackageId) -> Result<Vec<VolumeSnapshot>, Error> {
+    let dir = package_dir(datadir, id);
+    let mut snapshots = Vec::new();
+    if tokio::fs::metadata(&dir).await.is_err() {
+        return Ok(snapshots);
+    }
+    let mut entries = tokio::fs::read_dir(&dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let info = entry.path().join("info.json");
+        match tokio::fs::read(&info).await {
+            Ok(info) => snapshots.push(
+                serde_json::from_slice::<VolumeSnapshot>(&info)
+                    .with_kind(ErrorKind::Deserialization)?,
+            ),
+            Err(e) => tracing::warn!("Ignoring incomplete snapshot {:?}: {}", info, e),
+        }
+    }
+    snapshots.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+    Ok(snapshots)
+}
+
+async fn save_info(datadir: &Path, snapshot: &VolumeSnapshot) -> Result<(), Error> {
+    tokio::fs::write(
+        snapshot.dir(datadir).join("info.json"),
+        serde_json::to_vec_pretty(snapshot).with_kind(ErrorKind::Serialization)?,
+    )
+    .await?;
+    Ok(())
+}
+
+async fn find(datadir: &Path, id: &PackageId, snapshot: &str) -> Result<VolumeSnapshot, Error> {
+    load(datadir, id)
+        .await?
+        .into_iter()
+        .find(|s| s.id == snapshot)
+        .ok_or_else(|| {
+            Error::new(
+                eyre!("No snapshot {} of {}", snapshot, id),
+                ErrorKind::NotFound,
+            )
+        })
+}
+
+async fn btrfs_snapshot(src: &Path, dst: &Path, readonly: bool) -> Result<(), Error> {
+    let mut cmd = Command::new("btrfs");
+    cmd.arg("subvolume").arg("snapshot");
+    if readonly {
+        cmd.arg("-r");
+    }
+    cmd.arg(src)
+        .arg(dst)
+        .invoke(ErrorKind::Filesystem)
+        .await
+        .map(|_| ())
+}
+
+fn free_space(path: &Path) -> Result<u64, Error> {
+    let stat = nix::sys::statvfs::statvfs(path)
+        .with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()))?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+async fn copy(src: &Path, dst: &Path) -> Result<(), Error> {
+    if Command::new("cp")
+        .arg("-a")
+        .arg("--reflink=always")
+        .arg(src)
+        .arg(dst)
+        .invoke(ErrorKind::Filesystem)
+        .await
+        .is_ok()
+    {
+        return Ok(());
+    }
+    remove(dst).await?;
+    tokio::fs::create_dir_all(dst).await?;
+    Rsync::new(src.join(""), dst.join(""), RsyncOptions::default())
+        .await?
+        .wait()
+        .await
+}
+
+/// Removes `path`, whether it is a subvolume or a plain directory
+async fn remove(path: &Path) -> Result<(), Error> {
+    if tokio::fs::metadata(path).await.is_err() {
+        return Ok(());
+    }
+    if quota::is_subvolume(path).await {
+        Command::new("btrfs")
+            .arg("subvolume")
+            .arg("delete")
+            .arg(path)
+            .invoke(ErrorKind::Filesystem)
+            .await?;
+    } else {
+        tokio::fs::remove_dir_all(path)
+            .await
+            .with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()))?;
+    }
+    Ok(())
+}
+
+async fn remove_snapshot(datadir: &Path, snapshot: &VolumeSnapshot) -> Result<(), Error> {
+    let dir = snapshot.dir(datadir);
+    for volume in snapshot.volumes.keys() {
+        remove(&dir.join("volumes").join(volume)).await?;
+    }
+    remove(&dir).await
+}
+
+/// Snapshots every data volume of `id`. Volumes that cannot be snapshotted with btrfs are copied,
+/// which fails up front if there is not enough free space for the copies.
+#[instrument(skip_all)]
+pub async fn take(
+    ctx: &RpcContext,
+    id: &PackageId,
+    reason: SnapshotReason,
+    label: Option<String>,
+) -> Result<VolumeSnapshot, Error> {
+    let manifest = ctx
+        .db
+        .peek()
+        .await
+        .as_package_data()
+        .as_idx(id)
+        .and_then(|p| p.as_installed())
+        .or_not_found(id)?
+        .as_manifest()
+        .de()?;
+    let created_at = Utc::now();
+    let base = created_at.format("%Y%m%d-%H%M%S").to_string();
+    let mut snapshot_id = base.clone();
+    let mut n = 1;
+    while tokio::fs::metadata(package_dir(&ctx.datadir, id).join(&snapshot_id))
+        .await
+        .is_ok()
+    {
+        n += 1;
+        snapshot_id = format!("{}-{}", base, n);
+    }
+    let mut snapshot = VolumeSnapshot {
+        id: snapshot_id,
+        package_id: id.clone(),
+        version: manifest.version.clone(),
+        created_at,
+        reason,
+        label,
+        volumes: BTreeMap::new(),
+    };
+    let dir = snapshot.dir(&ctx.datadir);
+    tokio::fs::create_dir_all(dir.join("volumes")).await?;
+    let res = async {
+        let mut volumes = Vec::new();
+        let mut required = 0;
+        for (volume_id, volume) in &*manifest.volumes {
+            if !matches!(volume, Volume::Data { .. }) {
+                continue;
+            }
+            let src = data_dir(&ctx.datadir, id, volume_id);
+            if tokio::fs::metadata(&src).await.is_err() {
+                continue;
+            }
+            let method = if quota::backend(&src).await? == Some(QuotaBackend::Btrfs) {
+                SnapshotMethod::Btrfs
+            } else {
+                required += quota::usage(&src, None).await?;
+                SnapshotMethod::Copy
+            };
+            volumes.push((volume_id, src, method));
+        }
+        let available = free_space(&dir)?;
+        if required > available {
+            return Err(Error::new(
+                eyre!(
+                    "Copying the volumes of {} needs {} bytes, but only {} are free",
+                    id,
+                    required,
+                    available
+                ),
+                ErrorKind::Filesystem,
+            ));
+        }
+        for (volume_id, src, method) in volumes {
+            let dst = dir.join("volumes").join(volume_id);
+            match method {
+                SnapshotMethod::Btrfs => btrfs_snapshot(&src, &dst, true).await?,
+                SnapshotMethod::Copy => copy(&src, &dst).await?,
+            }
+            snapshot.volumes.insert(volume_id.clone(), method);
+        }
+        save_info(&ctx.datadir, &snapshot).await
+    }
+    .await;
+    if let Err(e) = res {
+        if let Err(e) = remove_snapshot(&ctx.datadir, &snapshot).await {
+            tracing::error!("Failed to clean up snapshot {}: {}", snapshot.id, e);
+            tracing::debug!("{:?}", e);
+        }
+        return Err(e);
+    }
+    Ok(snapshot)
+}
+
+/// Takes a snapshot if enabled for `reason`, and prunes the automatic snapshots beyond the
+/// configured retention. Failures are logged rather than returned so they never block the
+/// operation that triggered the snapshot. Returns the snapshot if one was taken and kept.
+#[instrument(skip_all)]
+pub async fn take_auto(
+    ctx: &RpcContext,
+    id: &PackageId,
+    reason: SnapshotReason,
+) -> Option<VolumeSnapshot> {
+    let res = async {
+        let cfg = ctx
+            .db
+            .peek()
+            .await
+            .as_server_info()
+            .as_auto_snapshot()
+            .de()?;
+        let enabled = match reason {
+            SnapshotReason::Manual | SnapshotReason::Rollback => false,
+            SnapshotReason::Config => cfg.before_config,
+            SnapshotReason::Update => cfg.before_update,
+        };
+        if !enabled {
+            return Ok(None);
+        }
+        let taken = take(ctx, id, reason, None).await?;
+        let mut kept = Some(taken);
+        for snapshot in load(&ctx.datadir, id)
+            .await?
+            .into_iter()
+            .filter(|s| matches!(s.reason, SnapshotReason::Config | SnapshotReason::Update))
+            .skip(cfg.keep)
+        {
+            if kept.as_ref().map_or(false, |k| k.id == snapshot.id) {
+                kept = None;
+            }
+            remove_snapshot(&ctx.datadir, &snapshot).await?;
+        }
+        Ok::<_, Error>(kept)
+    }
+    .await;
+    match res {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            tracing::error!("Failed to snapshot volumes of {} ({}): {}", id, reason, e);
+            tracing::debug!("{:?}", e);
+            None
+        }
+    }
+}
+
+/// Hands `snapshot` over to a rollback point, so it is no longer pruned with the automatic ones
+pub async fn keep_for_rollback(
+    ctx: &RpcContext,
+    mut snapshot: VolumeSnapshot,
+) -> Result<VolumeSnapshot, Error> {
+    snapshot.reason = SnapshotReason::Rollback;
+    save_info(&ctx.datadir, &snapshot).await?;
+    Ok(snapshot)
+}
+
+/// Removes the snapshot `snapshot` of `id`, if it still exists
+pub async fn remove_by_id(ctx: &RpcContext, id: &PackageId, snapshot: &str) -> Result<(), Error> {
+    if let Some(snapshot) = load(&ctx.datadir, id)
+        .await?
+        .into_iter()
+        .find(|s| s.id == snapshot)
+    {
+        remove_snapshot(&ctx.datadir, &snapshot).await?;
+    }
+    Ok(())
+}
+
+/// Replaces the data volumes of `id` with those in `snapshot`. The service must not be running.
+#[instrument(skip_all)]
+pub async fn restore_volumes(
+    ctx: &RpcContext,
+    snapshot: &VolumeSnapshot,
+    volumes: &Volumes,
+    version: &Version,
+) -> Result<(), Error> {
+    let id = &snapshot.package_id;
+    let dir = snapshot.dir(&ctx.datadir);
+    for (volume_id, method) in &snapshot.volumes {
+        let src = dir.join("volumes").join(volume_id);
+        let dst = data_dir(&ctx.datadir, id, volume_id);
+        match method {
+            SnapshotMethod::Btrfs => {
+                remove(&dst).await?;
+                btrfs_snapshot(&src, &dst, false).await?;
+            }
+            SnapshotMethod::Copy => {
+                tokio::fs::create_dir_all(&dst).await?;
+                Rsync::new(src.join(""), dst.join(""), RsyncOptions::default())
+                    .await?
+                    .wait()
+                    .await?;
+            }
+        }
+    }
+    // restored subvolumes are new, so their quotas have to be set again
+    volumes.install(ctx, id, version).await
+}
+
+/// Looks up the snapshot `snapshot` of `id`
+pub async fn get(
+    ctx: &RpcContext,
+    id: &PackageId,
+    snapshot: &str,
+) -> Result<VolumeSnapshot, Error> {
+    find(&ctx.datadir, id, snapshot).await
+}
+
+/// Removes every snapshot of `id`, used when it is uninstalled
+pub async fn remove_all(ctx: &RpcContext, id: &PackageId) -> Result<(), Error> {
+    for snapshot in load(&ctx.datadir, id).await? {
+        remove_snapshot(&ctx.datadir, &snapshot).await?;
+    }
+    remove(&package_dir(&ctx.datadir, id)).await
+}
+
+#[command(subcommands(create, list, restore, delete, set_auto))]
+pub fn snapshot() -> Result<(), Error> {
+    Ok(())
+}
+
+/// Snapshots the data volumes of a service
+#[command(display(display_serializable))]
+pub async fn create(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg(long = "label")] label: Option<String>,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<VolumeSnapshot, Error> {
+    take(&ctx, &id, SnapshotReason::Manual, label).await
+}
+
+fn display_snapshots(snapshots: Vec<VolumeSnapshot>, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(snapshots, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "PACKAGE", "SNAPSHOT", "VERSION", "CREATED", "REASON", "LABEL"]);
+    for snapshot in snapshots {
+        table.add_row(row![
+            &*snapshot.package_id,
+            &snapshot.id,
+            snapshot.version.as_str(),
+            &snapshot.created_at.to_rfc3339(),
+            &snapshot.reason.to_string(),
+            snapshot.label.as_deref().unwrap_or_default(),
+        ]);
+    }
+    table.print_tty(false).unwrap();
+}
+
+/// Lists the snapshots of a service, or of every service, newest first
+#[command(display(display_snapshots))]
+pub async fn list(
+    #[context] ctx: RpcContext,
+    #[arg] id: Option<PackageId>,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<VolumeSnapshot>, Error> {
+    let ids = match id {
+        Some(id) => vec![id],
+        None => ctx
+            .db
+            .peek()
+            .await
+            .as_package_data()
+            .keys()?
+            .into_iter()
+            .collect(),
+    };
+    let mut snapshots = Vec::new();
+    for id in ids {
+        snapshots.extend(load(&ctx.datadir, &id).await?);
+    }
+    Ok(snapshots)
+}
+
+/// Replaces the data volumes of a service with a snapshot. The service is stopped during the
+/// restore and started again afterwards if it was running.
+#[command(display(display_none), metadata(sync_db = true))]
+#[instrument(skip_all)]
+pub async fn restore(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg] snapshot: String,
+) -> Result<(), Error> {
+    let snapshot = find(&ctx.datadir, &id, &snapshot).await?;
+    let manifest = ctx
+        .db
+        .peek()
+        .await
+        .as_package_data()
+        .as_idx(&id)
+        .or_not_found(&id)?
+        .expect_as_installed()?
+        .as_manifest()
+        .de()?;
+    if snapshot.version != manifest.version {
+        return Err(Error::new(
+            eyre!(
+                "Snapshot {} was taken from {}@{}, use package.rollback to restore a previous version",
+                snapshot.id,
+                id,
+                snapshot.version
+            ),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+    let manager = ctx
+        .managers
+        .get(&(id.clone(), manifest.version.clone()))
+        .await
+        .ok_or_else(|| {
+            Error::new(
+                eyre!("There is no manager running for {}", id),
+                ErrorKind::Unknown,
+            )
+        })?;
+    manager
+        .with_stopped(|| restore_volumes(&ctx, &snapshot, &manifest.volumes, &manifest.version))
+        .await
+}
+
+/// Deletes a snapshot
+#[command(display(display_none))]
+pub async fn delete(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg] snapshot: String,
+) -> Result<(), Error> {
+    let snapshot = find(&ctx.datadir, &id, &snapshot).await?;
+    remove_snapshot(&ctx.datadir, &snapshot).await
+}
+
+/// Configures automatic snapshots before `config.set` and before updates. Unspecified options
+/// keep their current value.
+#[command(rename = "set-auto", display(display_none))]
+pub async fn set_auto(
+    #[context] ctx: RpcContext,
+    #[arg(long = "before-config")] before_config: Option<bool>,
+    #[arg(long = "before-update")] before_update: Option<bool>,
+    #[arg(long = "keep")] keep: Option<usize>,
+) -> Result<(), Error> {
+    ctx.db
+        .mutate(|db| {
+            let cfg = db.as_server_info_mut().as_auto_snapshot_mut();
+            if let Some(before_config) = before_config {
+                cfg.as_before_config_mut().ser(&before_config)?;
+            }
+            if let Some(before_update) = before_update {
+                cfg.as_before_update_mut().ser(&before_update)?;
+            }
+            if let Some(keep) = keep {
+                cfg.as_keep_mut().ser(&keep)?;
+            }
+            Ok(())
+        })
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn auto_snapshots_are_opt_in() {
+        let cfg = AutoSnapshotConfig::default();
+        assert!(!cfg.before_config && !cfg.before_update);
+        let cfg: AutoSnapshotConfig = serde_json::from_value(serde_json::json!({
+            "before-config": true,
+            "before-update": false,
+        }))
+        .unwrap();
+        assert!(cfg.before_config);
+        assert_eq!(cfg.keep, default_keep());
+    }
+
+    #[test]
+    fn snapshot_dir_is_per_package() {
+        let snapshot: VolumeSnapshot = serde_json::from_value(serde_json::json!({
+            "id": "20261019-120000",
+            "package-id": "bitcoind",
+            "version": "0.1.0",
+            "created-at": "2026-10-19T12:00:00Z",
+            "reason": "config",
+            "label": null,
+            "volumes": { "main": "btrfs" },
+        }))
+        .unwrap();
+        assert_eq!(
+            snapshot.dir(Path::new("/embassy-data")),
+            Path::new("/embassy-data/package-data/snapshots/bitcoind/20261019-120000")
+        );
+    }
+}
@@ -14,16 +14,21 @@ export abstract class ApiService {
 
   async encrypt(toEncrypt: string): Promise<Encrypted> {
     if (!this.pubkey) throw new Error('No pubkey found!')
-    const encrypted = await jose.JWE.createEncrypt(this.pubkey!)
+    const encrypted = await jose.JWE.createEncrypt(
+      { contentAlg: 'A256GCM' },
+      this.pubkey!,
+    )
       .update(toEncrypt)
       .final()
     return {
+      version: 2,
       encrypted,
     }
   }
 }
 
 type Encrypted = {
+  version?: number
   encrypted: string
 }
 
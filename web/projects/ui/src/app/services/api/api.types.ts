@@ -491,6 +491,7 @@ declare global {
 }
 
 export type Encrypted = {
+  version?: number
   encrypted: string
 }
 
@@ -9,7 +9,7 @@ import {
   RPCOptions,
 } from '@start9labs/shared'
 import { ApiService } from './embassy-api.service'
-import { RR } from './api.types'
+import { Encrypted, RR } from './api.types'
 import { parsePropertiesPermissive } from 'src/app/util/properties.util'
 import { ConfigService } from '../config.service'
 import { webSocket, WebSocketSubjectConfig } from 'rxjs/webSocket'
@@ -19,6 +19,7 @@ import { DOCUMENT } from '@angular/common'
 import { DataModel } from '../patch-db/data-model'
 import { PatchDB, pathFromArray, Update } from 'src/app/services/patch-db/patch-db.service'
 import { getServerInfo } from 'src/app/util/get-server-info'
+import * as jose from 'node-jose'
 
 @Injectable()
 export class LiveApiService extends ApiService {
@@ -66,7 +67,10 @@ export class LiveApiService extends ApiService {
   // auth
 
   async login(params: RR.LoginReq): Promise<RR.loginRes> {
-    return this.rpcRequest({ method: 'auth.login', params })
+    return this.rpcRequest({
+      method: 'auth.login',
+      params: { ...params, password: await this.encrypt(params.password) },
+    })
   }
 
   async logout(params: RR.LogoutReq): Promise<RR.LogoutRes> {
@@ -84,7 +88,30 @@ export class LiveApiService extends ApiService {
   async resetPassword(
     params: RR.ResetPasswordReq,
   ): Promise<RR.ResetPasswordRes> {
-    return this.rpcRequest({ method: 'auth.reset-password', params })
+    return this.rpcRequest({
+      method: 'auth.reset-password',
+      params: {
+        'old-password': await this.encrypt(params['old-password']),
+        'new-password': await this.encrypt(params['new-password']),
+      },
+    })
+  }
+
+  // passwords are sent in the authenticated v2 wire format, encrypted to the server's key
+  private async encrypt(toEncrypt: string): Promise<Encrypted> {
+    const pubkey = await jose.JWK.asKey(
+      await this.rpcRequest<object>({ method: 'auth.get-pubkey', params: {} }),
+    )
+    const encrypted = await jose.JWE.createEncrypt(
+      { contentAlg: 'A256GCM' },
+      pubkey,
+    )
+      .update(toEncrypt)
+      .final()
+    return {
+      version: 2,
+      encrypted,
+    }
   }
 
   // server
@@ -47,6 +47,8 @@ const PROGRESS: InstallProgress = {
   'validation-complete': false,
   unpacked: 0,
   'unpack-complete': false,
+  retries: 0,
+  resumptions: 0,
 }
 
 @Injectable()
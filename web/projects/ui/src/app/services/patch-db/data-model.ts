@@ -373,4 +373,6 @@ export interface InstallProgress {
   readonly 'validation-complete': boolean
   readonly unpacked: number
   readonly 'unpack-complete': boolean
+  readonly retries: number
+  readonly resumptions: number
 }